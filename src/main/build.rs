@@ -11,6 +11,7 @@ fn main() -> Result<()> {
             "src/general/network/proto_src/metric.proto",
             "src/general/network/proto_src/remote_sys.proto",
             "src/general/network/proto_src/data.proto",
+            "src/general/network/proto_src/cluster.proto",
             "src/general/app/app_shared/process_rpc_proto.proto",
         ],
         &["src/"],
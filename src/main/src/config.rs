@@ -1,67 +1,263 @@
-use crate::sys::NodeID;
+use crate::{
+    result::{WSResult, WsFormatErr, WsNetworkLogicErr},
+    sys::NodeID,
+};
 use core::panic;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+/// Cluster membership seen by this node.
+///
+/// `peers` and `master` are shared by every clone, so all modules holding a copy observe
+/// membership changes applied through [`NodesConfig::apply_members`] and
+/// master changes applied through [`NodesConfig::set_master`].
 #[derive(Debug, Clone)]
 pub struct NodesConfig {
-    pub peers: HashMap<NodeID, NodeConfig>,
+    peers: Arc<RwLock<Members>>,
+    master: Arc<RwLock<MasterState>>,
     pub this: (NodeID, NodeConfig),
     pub file_dir: PathBuf,
+    /// copies kept of each data split when neither the upload nor the key pattern asks for more
+    pub replication_factor: u32,
+    /// large files are reed-solomon coded instead of replicated when set
+    pub erasure_coding: Option<ErasureCodingConfig>,
+    /// how master picks the worker running a function
+    pub node_selector: NodeSelectPolicy,
+    /// master relays function calls and app uploads to the workers instead of redirecting
+    /// clients, so the workers' http ports needn't be reachable by them
+    pub master_proxy: bool,
+    /// peers must prove they hold the cluster key before their messages are accepted when set,
+    /// the messages are then encrypted by keys derived from it
+    pub p2p_auth: Option<P2PAuthConfig>,
+}
+
+#[derive(Debug, Default)]
+struct Members {
+    /// master term of the last installed member list
+    term: u64,
+    /// bumped by the master on every join/leave
+    version: u64,
+    peers: HashMap<NodeID, NodeConfig>,
+}
+
+#[derive(Debug)]
+struct MasterState {
+    /// election term, 0 for the statically configured master
+    term: u64,
+    /// last known master
+    node: Option<NodeID>,
+    /// false once a newer term started and its winner is not known yet
+    confirmed: bool,
 }
 
 impl NodesConfig {
-    pub fn get_nodeconfig(&self, id: NodeID) -> &NodeConfig {
+    pub fn new(
+        this: (NodeID, NodeConfig),
+        peers: HashMap<NodeID, NodeConfig>,
+        file_dir: PathBuf,
+    ) -> Self {
+        // the node with `master` spec leads until an election happens
+        let static_master = if this.1.is_master() {
+            Some(this.0)
+        } else {
+            peers
+                .iter()
+                .find(|(_, conf)| conf.is_master())
+                .map(|(id, _)| *id)
+        };
+        Self {
+            peers: Arc::new(RwLock::new(Members {
+                term: 0,
+                version: 0,
+                peers,
+            })),
+            master: Arc::new(RwLock::new(MasterState {
+                term: 0,
+                node: static_master,
+                confirmed: true,
+            })),
+            this,
+            file_dir,
+            replication_factor: DEFAULT_REPLICATION_FACTOR,
+            erasure_coding: None,
+            node_selector: NodeSelectPolicy::default(),
+            master_proxy: false,
+            p2p_auth: None,
+        }
+    }
+    pub fn get_nodeconfig(&self, id: NodeID) -> Option<NodeConfig> {
         if self.this.0 == id {
-            &self.this.1
+            Some(self.this.1.clone())
         } else {
-            self.peers.get(&id).unwrap_or_else(|| {
-                panic!("peers {:?}", self.peers);
-            })
+            self.peers.read().peers.get(&id).cloned()
         }
     }
     pub fn node_cnt(&self) -> usize {
-        self.peers.len() + 1
+        self.peers.read().peers.len() + 1
     }
     pub fn this_node(&self) -> NodeID {
         self.this.0
     }
-    pub fn get_master_node(&self) -> NodeID {
-        if self.this.1.is_master() {
-            return self.this.0;
+    /// The current master, or the last known one while an election is going on.
+    pub fn get_master_node(&self) -> WSResult<NodeID> {
+        self.master_node()
+            .ok_or_else(|| WsNetworkLogicErr::NoMaster { this: self.this.0 }.into())
+    }
+    /// Like [`NodesConfig::get_master_node`], `None` when no master is known yet.
+    pub fn master_node(&self) -> Option<NodeID> {
+        self.master.read().node
+    }
+    /// `(term, master)` once the master of the current term is confirmed.
+    pub fn confirmed_master(&self) -> Option<(u64, NodeID)> {
+        let master = self.master.read();
+        if !master.confirmed {
+            return None;
+        }
+        master.node.map(|node| (master.term, node))
+    }
+    /// Whether this node is the confirmed master of the current term.
+    pub fn this_is_master(&self) -> bool {
+        let master = self.master.read();
+        master.confirmed && master.node == Some(self.this.0)
+    }
+    pub fn master_term(&self) -> u64 {
+        self.master.read().term
+    }
+    /// Move to `term` without knowing its master yet. Returns false if `term` is not newer.
+    pub fn observe_term(&self, term: u64) -> bool {
+        let mut master = self.master.write();
+        if term <= master.term {
+            return false;
+        }
+        master.term = term;
+        master.confirmed = false;
+        true
+    }
+    /// Install `node` as the master of `term`.
+    ///
+    /// Returns false for a stale term or when another master is already confirmed for `term`.
+    pub fn set_master(&self, term: u64, node: NodeID) -> bool {
+        let mut master = self.master.write();
+        if term < master.term
+            || (term == master.term && master.confirmed && master.node != Some(node))
+        {
+            return false;
+        }
+        master.term = term;
+        master.node = Some(node);
+        master.confirmed = true;
+        true
+    }
+    /// Stop acting as master, the candidates elect a new one in a newer term.
+    pub fn step_down(&self) {
+        let mut master = self.master.write();
+        if master.node == Some(self.this.0) {
+            master.confirmed = false;
         }
-        *self
-            .peers
-            .iter()
-            .find(|(_, config)| config.is_master())
-            .unwrap_or_else(|| {
-                panic!("peers {:?}", self.peers);
-            })
-            .0
     }
     pub fn get_meta_kv_nodes(&self) -> HashSet<NodeID> {
         self.peers
+            .read()
+            .peers
             .iter()
-            .filter(|(_, config)| config.spec.contains("meta"))
+            .filter(|(_, config)| config.is_meta())
             .map(|(id, _)| *id)
             .collect()
     }
+    /// Nodes allowed to take over master duties, this node included.
+    pub fn get_master_candidates(&self) -> Vec<NodeID> {
+        self.all_nodes()
+            .into_iter()
+            .filter(|(_, config)| config.can_be_master())
+            .map(|(id, _)| id)
+            .collect()
+    }
+    /// Worker nodes, this node included if it is a worker.
     pub fn get_worker_nodes(&self) -> HashSet<NodeID> {
-        self.peers
+        let mut workers: HashSet<NodeID> = self
+            .peers
+            .read()
+            .peers
             .iter()
             .filter(|(_, config)| config.is_worker())
             .map(|(id, _)| *id)
-            .collect()
+            .collect();
+        if self.this.1.is_worker() {
+            let _ = workers.insert(self.this.0);
+        }
+        workers
     }
     pub fn node_exist(&self, id: NodeID) -> bool {
-        self.peers.contains_key(&id) || self.this.0 == id
+        self.this.0 == id || self.peers.read().peers.contains_key(&id)
     }
-    pub fn all_nodes_iter<'a>(&'a self) -> impl Iterator<Item = (&'a NodeID, &'a NodeConfig)> {
-        self.peers.iter().chain(Some((&self.this.0, &self.this.1)))
+    /// Snapshot of all members including this node, sorted by node id.
+    pub fn all_nodes(&self) -> Vec<(NodeID, NodeConfig)> {
+        let mut all: Vec<_> = self
+            .peers
+            .read()
+            .peers
+            .iter()
+            .map(|(id, conf)| (*id, conf.clone()))
+            .chain(Some((self.this.0, self.this.1.clone())))
+            .collect();
+        all.sort_by_key(|(id, _)| *id);
+        all
+    }
+    /// Snapshot of the peers, this node excluded.
+    pub fn peers(&self) -> HashMap<NodeID, NodeConfig> {
+        self.peers.read().peers.clone()
+    }
+    pub fn find_peer_by_addr(&self, addr: &SocketAddr) -> Option<NodeID> {
+        self.peers
+            .read()
+            .peers
+            .iter()
+            .find_map(|(id, peer)| if peer.addr == *addr { Some(*id) } else { None })
+    }
+    pub fn members_version(&self) -> u64 {
+        self.peers.read().version
+    }
+    /// Replace the member list if `(term, version)` is newer than the local one.
+    ///
+    /// `term` is the master term the list was published in, so a list from a newly
+    /// elected master wins over versions bumped by an outdated one.
+    /// `members` is the full cluster view from the master and may contain this node,
+    /// which is skipped. Returns the (added, removed) peers, empty when the update is stale.
+    pub fn apply_members(
+        &self,
+        term: u64,
+        version: u64,
+        members: impl IntoIterator<Item = (NodeID, NodeConfig)>,
+    ) -> (Vec<(NodeID, NodeConfig)>, Vec<(NodeID, NodeConfig)>) {
+        let mut locked = self.peers.write();
+        if (term, version) <= (locked.term, locked.version) {
+            return (vec![], vec![]);
+        }
+        let new_peers: HashMap<NodeID, NodeConfig> = members
+            .into_iter()
+            .filter(|(id, _)| *id != self.this.0)
+            .collect();
+        let added = new_peers
+            .iter()
+            .filter(|(id, conf)| locked.peers.get(id).map_or(true, |old| old.addr != conf.addr))
+            .map(|(id, conf)| (*id, conf.clone()))
+            .collect();
+        let removed = locked
+            .peers
+            .iter()
+            .filter(|(id, conf)| new_peers.get(id).map_or(true, |new| new.addr != conf.addr))
+            .map(|(id, conf)| (*id, conf.clone()))
+            .collect();
+        locked.term = term;
+        locked.version = version;
+        locked.peers = new_peers;
+        (added, removed)
     }
 }
 
@@ -82,9 +278,19 @@ impl NodeConfig {
     pub fn is_worker(&self) -> bool {
         self.spec.contains("worker")
     }
+    pub fn is_meta(&self) -> bool {
+        self.spec.contains("meta")
+    }
+    /// `meta` nodes elect the master among themselves, the configured master is always one of them
+    pub fn can_be_master(&self) -> bool {
+        self.is_master() || self.is_meta()
+    }
     pub fn set_domain(&mut self, domain: Option<String>) {
         self.domain = domain;
     }
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
     fn get_http_domain<'a>(&'a self) -> Option<&'a str> {
         // check domain valid
         self.domain
@@ -109,9 +315,65 @@ impl NodeConfig {
     }
 }
 
+pub const DEFAULT_REPLICATION_FACTOR: u32 = 1;
+
+fn default_erasure_min_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// `k` data shards plus `m` parity shards, each on its own node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErasureCodingConfig {
+    pub data_shards: u32,
+    pub parity_shards: u32,
+    /// files smaller than this stay replicated
+    #[serde(default = "default_erasure_min_size")]
+    pub min_size: u64,
+}
+
+/// Policy of picking the worker running a function, see `master::node_selector`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeSelectPolicy {
+    #[default]
+    Random,
+    /// the node with the most free cpu and memory and the fewest running tasks
+    LeastLoaded,
+    /// straw2 by the same load weight, spreads each function in proportion to it
+    Straw2,
+    /// each function sticks to one node while the workers don't change
+    ConsistentHash,
+}
+
+/// Pre-shared key of the cluster, every node proves it holds the key for its own `NodeID`
+/// when connecting, see `general::network::p2p_auth`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct P2PAuthConfig {
+    pub psk: String,
+}
+
+impl std::fmt::Debug for P2PAuthConfig {
+    // the node config is logged
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("P2PAuthConfig")
+            .field("psk", &"<redacted>")
+            .finish()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct YamlConfig {
     pub nodes: HashMap<NodeID, NodeConfig>,
+    #[serde(default)]
+    pub replication_factor: Option<u32>,
+    #[serde(default)]
+    pub erasure_coding: Option<ErasureCodingConfig>,
+    #[serde(default)]
+    pub node_selector: NodeSelectPolicy,
+    #[serde(default)]
+    pub master_proxy: bool,
+    #[serde(default)]
+    pub p2p_auth: Option<P2PAuthConfig>,
     // pub this: NodeID,
 }
 
@@ -126,13 +388,46 @@ fn read_yaml_config(file_path: impl AsRef<Path>) -> YamlConfig {
     })
 }
 
-pub fn read_config(this_id: NodeID, file_path: impl AsRef<Path>) -> NodesConfig {
+pub fn read_config(this_id: NodeID, file_path: impl AsRef<Path>) -> WSResult<NodesConfig> {
     let config_path = file_path.as_ref().join("files/node_config.yaml");
     let mut yaml_config = read_yaml_config(config_path);
 
-    NodesConfig {
-        this: (this_id, yaml_config.nodes.remove(&this_id).unwrap()),
-        peers: yaml_config.nodes,
-        file_dir: file_path.as_ref().to_path_buf(),
+    let mut config = NodesConfig::new(
+        (this_id, yaml_config.nodes.remove(&this_id).unwrap()),
+        yaml_config.nodes,
+        file_path.as_ref().to_path_buf(),
+    );
+    if let Some(factor) = yaml_config.replication_factor {
+        if factor == 0 {
+            return Err(WsFormatErr::NodeConfigErr {
+                field: "replication_factor".to_owned(),
+                reason: "must be at least 1".to_owned(),
+            }
+            .into());
+        }
+        config.replication_factor = factor;
+    }
+    if let Some(erasure_coding) = &yaml_config.erasure_coding {
+        if erasure_coding.data_shards == 0 || erasure_coding.parity_shards == 0 {
+            return Err(WsFormatErr::NodeConfigErr {
+                field: "erasure_coding".to_owned(),
+                reason: "needs at least 1 data and 1 parity shard".to_owned(),
+            }
+            .into());
+        }
+    }
+    config.erasure_coding = yaml_config.erasure_coding;
+    config.node_selector = yaml_config.node_selector;
+    config.master_proxy = yaml_config.master_proxy;
+    if let Some(p2p_auth) = &yaml_config.p2p_auth {
+        if p2p_auth.psk.is_empty() {
+            return Err(WsFormatErr::NodeConfigErr {
+                field: "p2p_auth.psk".to_owned(),
+                reason: "must not be empty".to_owned(),
+            }
+            .into());
+        }
     }
+    config.p2p_auth = yaml_config.p2p_auth;
+    Ok(config)
 }
@@ -147,7 +147,7 @@ impl NativeAppFunc for FunctionAppCheckpoint {
                 instman
                     .view
                     .appmeta_manager()
-                    .load_app_file(&appname, datameta)
+                    .load_app_file(&appname, Some(datameta))
                     .await
                     .map_err(|err| {
                         tracing::error!("native app FunctionAppCheckpoint load app file failed to checkpoint err: {:?}", err);
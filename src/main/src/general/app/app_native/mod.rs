@@ -8,7 +8,9 @@ use super::{
     KeyPattern, NodeTag,
 };
 use crate::general::app::instance::{Instance, InstanceTrait};
+use crate::general::app::limits::FnLimits;
 use crate::general::app::m_executor::{FnExeCtxAsync, FnExeCtxSync};
+use crate::general::app::version::VERSION_SEP;
 use crate::general::data::m_data_general::DATA_UID_PREFIX_APP_META;
 use crate::new_map;
 use crate::result::{WSResult, WsFuncError};
@@ -87,6 +89,15 @@ impl From<NativeAppInstance> for Instance {
     }
 }
 
+/// Datasets of app versions, the one of the plain app name only points to the active version
+fn app_meta_key_pattern() -> KeyPattern {
+    KeyPattern::new(format!(
+        "{}{{app:path}}{}{{version:int}}",
+        DATA_UID_PREFIX_APP_META, VERSION_SEP
+    ))
+    .unwrap()
+}
+
 pub fn native_apps() -> HashMap<String, AppMeta> {
     let mut nativeapps = HashMap::new();
     // https://fvd360f8oos.feishu.cn/wiki/GGUnw0H1diVoHSkgm3vcMhtbnjI
@@ -98,9 +109,9 @@ pub fn native_apps() -> HashMap<String, AppMeta> {
     //     checkpoint:
     //         inner_dataset:
     //         app_{}:
-    //         - trigger_by_write:
-    //             condition: checkpointable
+    //         - trigger_by_write
     //         - get
+    // checkpoint only checkpoints jar apps itself, predicate functions aren't conditions
     let _ = nativeapps.insert(
         "app_checkpoint".to_string(),
         AppMeta::new(
@@ -110,35 +121,43 @@ pub fn native_apps() -> HashMap<String, AppMeta> {
                     sync_async: super::FnSyncAsyncSupport::Sync,
                     calls: vec![],
                     data_accesses: Some(new_map!(HashMap {
-                        KeyPattern(DATA_UID_PREFIX_APP_META.to_string()) => DataAccess {
+                        app_meta_key_pattern() => DataAccess {
                             get: true,
                             set: false,
                             delete: false,
                             event: None,
+                            ttl_ms: None,
+                            replicas: None,
                         }
                     })),
                     affinity: Some(AffinityRule {
                         tags: vec![NodeTag::Worker],
                         nodes: AffinityPattern::All,
                     }),
+                    anti_affinity: None,
+                    schedule: None,
+                    limits: FnLimits::default(),
                 },
                 "checkpoint".to_string() => FnMeta {
                     sync_async: super::FnSyncAsyncSupport::Async,
                     calls: vec![],
                     data_accesses: Some(new_map!(HashMap {
-                        KeyPattern(DATA_UID_PREFIX_APP_META.to_string()) => DataAccess {
+                        app_meta_key_pattern() => DataAccess {
                             get: true,
                             set: false,
                             delete: false,
-                            event: Some(DataEventTrigger::WriteWithCondition {
-                                condition: "checkpointable".to_string(),
-                            }),
+                            event: Some(DataEventTrigger::Write),
+                            ttl_ms: None,
+                            replicas: None,
                         }
                     })),
                     affinity: Some(AffinityRule {
                         tags: vec![NodeTag::Worker],
                         nodes: AffinityPattern::All,
                     }),
+                    anti_affinity: None,
+                    schedule: None,
+                    limits: FnLimits::default(),
                 },
             }),
         ),
@@ -7,9 +7,13 @@ use crate::result::{WSResult, WsFuncError};
 use async_trait::async_trait;
 use std::{mem::ManuallyDrop, path::Path};
 use wasmedge_sdk::{
-    config::{CommonConfigOptions, ConfigBuilder, HostRegistrationConfigOptions},
+    config::{
+        CommonConfigOptions, ConfigBuilder, HostRegistrationConfigOptions, RuntimeConfigOptions,
+        StatisticsConfigOptions,
+    },
+    error::{CoreCommonError, CoreError, WasmEdgeError},
     r#async::AsyncState,
-    Module, VmBuilder,
+    Module, Statistics, VmBuilder,
 };
 use wasmedge_sdk::{Vm, WasmValue};
 
@@ -142,15 +146,23 @@ impl InstanceTrait for WasmInstance {
 
 // pub fn new_java_instance(_config: NewJavaInstanceConfig) -> ProcessInstance {}
 
+/// `max_memory_mb` caps the linear memory of the instance
 pub fn new_wasm_instance(
     file_dir: impl AsRef<Path>,
     instance_name: &str,
     id: u64,
+    max_memory_mb: Option<u64>,
 ) -> OwnedInstance {
-    let config = ConfigBuilder::new(CommonConfigOptions::default())
+    let mut config = ConfigBuilder::new(CommonConfigOptions::default())
         .with_host_registration_config(HostRegistrationConfigOptions::default().wasi(true))
-        .build()
-        .expect("failed to create config");
+        // cost of instructions is counted to limit the cpu time of calls
+        .with_statistics_config(StatisticsConfigOptions::default().measure_cost(true));
+    if let Some(max_memory_mb) = max_memory_mb {
+        let pages = (max_memory_mb * 1024 * 1024 / WASM_PAGE_SIZE).min(u32::MAX as u64) as u32;
+        config =
+            config.with_runtime_config(RuntimeConfigOptions::default().max_memory_pages(pages));
+    }
+    let config = config.build().expect("failed to create config");
     let module = Module::from_file(
         Some(&config),
         file_dir
@@ -161,6 +173,7 @@ pub fn new_wasm_instance(
     let import = wasm_host_funcs::new_import_obj();
     let vm = VmBuilder::new()
         .with_config(config)
+        .with_statistics(Statistics::new().expect("failed to create statistics"))
         // .with_wasi_context(WasiContext::default())
         .build()
         .unwrap_or_else(|err| panic!("failed to create vm: {:?}", err));
@@ -171,6 +184,37 @@ pub fn new_wasm_instance(
     return OwnedInstance::WasmInstance(vm);
 }
 
+/// Id the instance was created with by [`new_wasm_instance`].
+pub fn instance_id(vm: &WasmInstance, instance_name: &str) -> Option<u64> {
+    vm.instance_name().strip_prefix(instance_name)?.parse().ok()
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Limit the instructions run by the next call, None to remove the limit.
+pub fn set_instr_budget(vm: &mut WasmInstance, budget: Option<u64>) {
+    let Some(stat) = vm.statistics_mut() else {
+        return;
+    };
+    // the cost counted by the vm accumulates over calls
+    let limit = budget.map_or(u64::MAX, |budget| stat.cost().saturating_add(budget));
+    stat.set_cost_limit(limit);
+}
+
+/// Size of the linear memory of the app module.
+pub fn memory_mb(vm: &WasmInstance) -> Option<u64> {
+    let module = vm.named_module(vm.instance_name()).ok()?;
+    let memory = module.memory("memory").ok()?;
+    Some((memory.size() + (1 << 20) - 1) >> 20)
+}
+
+pub fn is_instr_budget_exceeded(err: &WasmEdgeError) -> bool {
+    matches!(
+        err,
+        WasmEdgeError::Core(CoreError::Common(CoreCommonError::CostLimitExceeded))
+    )
+}
+
 // #[cfg(target_os = "macos")]
 // impl Clone for WasmInstance {
 //     fn clone(&self) -> Self {
@@ -214,3 +258,34 @@ impl WasmEngine for WasmerEngine {
             .map_err(|err| crate::result::WSError::NotImplemented)
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{is_instr_budget_exceeded, new_wasm_instance, set_instr_budget};
+    use crate::general::app::instance::{InstanceTrait, OwnedInstance};
+
+    /// `(module (func (export "spin") (loop (br 0))))`
+    const SPIN_WASM: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type: () -> ()
+        0x03, 0x02, 0x01, 0x00, // func 0 of type 0
+        0x07, 0x08, 0x01, 0x04, b's', b'p', b'i', b'n', 0x00, 0x00, // export "spin"
+        0x0a, 0x09, 0x01, 0x07, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x0b, // loop br 0
+    ];
+
+    #[test]
+    fn test_runaway_wasm_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let app_dir = dir.path().join("apps/spin");
+        std::fs::create_dir_all(&app_dir).unwrap();
+        std::fs::write(app_dir.join("app.wasm"), SPIN_WASM).unwrap();
+        let OwnedInstance::WasmInstance(mut vm) = new_wasm_instance(dir.path(), "spin", 0, None);
+
+        // the endless loop ends with the budget instead of running on
+        set_instr_budget(&mut vm, Some(1_000_000));
+        let Err(err) = vm.run_func(Some(&vm.instance_name()), "spin", vec![]) else {
+            panic!("the endless loop returned");
+        };
+        assert!(is_instr_budget_exceeded(&err), "{:?}", err);
+    }
+}
@@ -4,7 +4,7 @@ use crate::general::network::proto::{
     self,
     kv::{KeyRange, KvPair, KvRequest, KvRequests, KvResponses},
 };
-use crate::general::network::proto_ext::ProtoExtKvResponse;
+use crate::general::network::proto_ext::{KvRequestExt, ProtoExtKeyRange, ProtoExtKvResponse};
 use moka::sync::Cache;
 use std::{sync::atomic::AtomicI32, time::Duration};
 #[cfg(target_os = "macos")]
@@ -114,30 +114,22 @@ const SET_ID: usize = 1;
 const GET_ID: usize = 2;
 const LOCK_ID: usize = 3;
 const DELETE_ID: usize = 4;
+const SCAN_ID: usize = 5;
+const CAS_ID: usize = 6;
+/// no args, runs the whole batch as one txn
+const TXN_ID: usize = 7;
 
-type KvBatchOpe = (i32, i32, i32);
-#[cfg_attr(target_os = "linux", async_host_function)]
-async fn kv_batch_ope<T>(
-    caller: Caller,
-    args: Vec<WasmValue>,
-    _ctx: *mut T,
-) -> Result<Vec<WasmValue>, HostFuncError> {
-    let opes_arg_ptr = args[0].to_i32();
-    let opes_arg_len = args[1].to_i32();
-    let _opes_id = utils::mutref::<i32>(&caller, args[2].to_i32());
-    let args = utils::i32slice(&caller, opes_arg_ptr, opes_arg_len);
-    let func_ctx = unsafe {
-        #[cfg(feature = "unsafe-log")]
-        tracing::debug!("current_app_fn_ctx begin");
-        let res = utils::current_app_fn_ctx(&caller).0.as_mut();
-        #[cfg(feature = "unsafe-log")]
-        tracing::debug!("current_app_fn_ctx end");
-        res
-    };
+/// Scan flag, all keys starting with the start key.
+const SCAN_PREFIX: i32 = 1;
+/// Scan flag, deletes the keys scanned.
+const SCAN_DELETE: i32 = 2;
 
-    // request and response mem position
+/// Requests of a `kv_batch_ope` call and whether they run as one txn, `bytes` reads the guest
+/// memory at a ptr and len.
+fn decode_kv_batch(args: &[i32], bytes: impl Fn(i32, i32) -> Vec<u8>) -> (Vec<KvRequest>, bool) {
     let ope_cnt = args[0];
     let mut requests: Vec<KvRequest> = vec![];
+    let mut txn = false;
     let mut cur_idx = 1;
     // tracing::debug!("args:{:?}", args);
     // Construct the requests
@@ -146,15 +138,17 @@ async fn kv_batch_ope<T>(
         match ope_type as usize {
             // set
             SET_ID => {
-                let key = utils::u8slice(&caller, args[cur_idx + 1], args[cur_idx + 2]);
-                let value = utils::u8slice(&caller, args[cur_idx + 3], args[cur_idx + 4]);
+                let key = bytes(args[cur_idx + 1], args[cur_idx + 2]);
+                let value = bytes(args[cur_idx + 3], args[cur_idx + 4]);
                 requests.push(KvRequest {
                     op: Some(proto::kv::kv_request::Op::Set(
                         proto::kv::kv_request::KvPutRequest {
                             kv: Some(KvPair {
-                                key: key.to_owned(),
-                                values: vec![value.to_owned()],
+                                key,
+                                values: vec![value],
                             }),
+                            fencing_token: 0,
+                            ttl_ms: 0,
                         },
                     )),
                 });
@@ -162,16 +156,15 @@ async fn kv_batch_ope<T>(
             }
             // get
             GET_ID => {
-                let key = utils::u8slice(&caller, args[cur_idx + 1], args[cur_idx + 2]);
+                let key = bytes(args[cur_idx + 1], args[cur_idx + 2]);
                 // tracing::debug!("ptr:{} len:{} get key:{:?}", args[cur_idx + 1], args[cur_idx + 2],key);
                 requests.push(KvRequest {
                     op: Some(proto::kv::kv_request::Op::Get(
                         proto::kv::kv_request::KvGetRequest {
                             idxs: vec![0],
-                            range: Some(KeyRange {
-                                start: key.to_owned(),
-                                end: vec![],
-                            }),
+                            range: Some(KeyRange::new_range(key, vec![])),
+                            limit: 0,
+                            continuation: vec![],
                         },
                     )),
                 });
@@ -179,7 +172,7 @@ async fn kv_batch_ope<T>(
             }
             // lock
             LOCK_ID => {
-                let key = utils::u8slice(&caller, args[cur_idx + 1], args[cur_idx + 2]);
+                let key = bytes(args[cur_idx + 1], args[cur_idx + 2]);
                 // // first bit
                 // let read_or_write = args[cur_idx + 3] & 1 == 1;
                 // <0 means get
@@ -193,34 +186,102 @@ async fn kv_batch_ope<T>(
                             } else {
                                 vec![release_id as u32]
                             },
-                            range: Some(KeyRange {
-                                start: key.to_owned(),
-                                end: vec![],
-                            }),
+                            range: Some(KeyRange::new_range(key, vec![])),
+                            ttl_ms: 0,
+                            renew: false,
                         },
                     )),
                 });
                 cur_idx += 5;
             }
             DELETE_ID => {
-                let key = utils::u8slice(&caller, args[cur_idx + 1], args[cur_idx + 2]);
+                let key = bytes(args[cur_idx + 1], args[cur_idx + 2]);
                 requests.push(KvRequest {
                     op: Some(proto::kv::kv_request::Op::Delete(
                         proto::kv::kv_request::KvDeleteRequest {
-                            range: Some(KeyRange {
-                                start: key.to_owned(),
-                                end: vec![],
-                            }),
+                            range: Some(KeyRange::new_range(key, vec![])),
+                            limit: 0,
+                            continuation: vec![],
                         },
                     )),
                 });
                 cur_idx += 3;
             }
+            // range scan: flags (SCAN_PREFIX, SCAN_DELETE), start ptr, start len,
+            // end ptr, end len (exclusive, ignored for a prefix), limit (0 takes the server's max),
+            // continuation ptr, continuation len (empty for the first page)
+            SCAN_ID => {
+                let flags = args[cur_idx + 1];
+                let start = bytes(args[cur_idx + 2], args[cur_idx + 3]);
+                let range = if flags & SCAN_PREFIX != 0 {
+                    KeyRange::new_prefix(start)
+                } else {
+                    KeyRange::new_range(start, bytes(args[cur_idx + 4], args[cur_idx + 5]))
+                };
+                let limit = args[cur_idx + 6].max(0) as u32;
+                let continuation = bytes(args[cur_idx + 7], args[cur_idx + 8]);
+                requests.push(if flags & SCAN_DELETE != 0 {
+                    KvRequest::new_delete_range(range, limit, continuation)
+                } else {
+                    KvRequest::new_get_range(range, vec![0], limit, continuation)
+                });
+                cur_idx += 9;
+            }
+            // key ptr, key len, value ptr, value len (<0 deletes the key),
+            // expected version low 32 bits, high 32 bits
+            CAS_ID => {
+                let key = bytes(args[cur_idx + 1], args[cur_idx + 2]);
+                let expected_version =
+                    (args[cur_idx + 5] as u32 as u64) | ((args[cur_idx + 6] as u32 as u64) << 32);
+                requests.push(if args[cur_idx + 4] < 0 {
+                    KvRequest::new_cas_delete(key, expected_version)
+                } else {
+                    let value = bytes(args[cur_idx + 3], args[cur_idx + 4]);
+                    KvRequest::new_cas(
+                        KvPair {
+                            key,
+                            values: vec![value],
+                        },
+                        expected_version,
+                    )
+                });
+                cur_idx += 7;
+            }
+            TXN_ID => {
+                txn = true;
+                cur_idx += 1;
+            }
             _ => {
                 panic!("not implemented, reqs{:?},{:X}", requests, ope_type);
             }
         }
     }
+    (requests, txn)
+}
+
+type KvBatchOpe = (i32, i32, i32);
+#[cfg_attr(target_os = "linux", async_host_function)]
+async fn kv_batch_ope<T>(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    _ctx: *mut T,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let opes_arg_ptr = args[0].to_i32();
+    let opes_arg_len = args[1].to_i32();
+    let _opes_id = utils::mutref::<i32>(&caller, args[2].to_i32());
+    let args = utils::i32slice(&caller, opes_arg_ptr, opes_arg_len);
+    let func_ctx = unsafe {
+        #[cfg(feature = "unsafe-log")]
+        tracing::debug!("current_app_fn_ctx begin");
+        let res = utils::current_app_fn_ctx(&caller).0.as_mut();
+        #[cfg(feature = "unsafe-log")]
+        tracing::debug!("current_app_fn_ctx end");
+        res
+    };
+
+    let (requests, txn) = decode_kv_batch(args, |ptr, len| {
+        utils::u8slice(&caller, ptr, len).to_owned()
+    });
     // tracing::debug!("requests:{:?}", requests);
     let _prev_kv_opeid = func_ctx
         .event_ctx_mut()
@@ -234,6 +295,7 @@ async fn kv_batch_ope<T>(
                 app: func_ctx.app().to_owned(),
                 func: func_ctx.func().to_owned(),
                 prev_kv_opeid: _prev_kv_opeid,
+                txn,
             },
         )
         .await
@@ -345,3 +407,72 @@ impl HostFuncRegister for KvFuncsRegister {
         // .unwrap()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{decode_kv_batch, SCAN_DELETE, SCAN_ID, SCAN_PREFIX, TXN_ID};
+    use crate::general::network::proto::{self, kv::KeyRange};
+
+    #[test]
+    fn test_decode_kv_batch_scan() {
+        // guest memory: "a", "m", the continuation "c" and "p"
+        let mem = b"amcp";
+        let bytes = |ptr: i32, len: i32| mem[ptr as usize..(ptr + len) as usize].to_vec();
+        let scan_id = SCAN_ID as i32;
+        let args = [
+            3,
+            // keys in ["a", "m"), 10 at most, from "c"
+            scan_id,
+            0,
+            0,
+            1,
+            1,
+            1,
+            10,
+            2,
+            1,
+            // delete the keys starting with "p"
+            scan_id,
+            SCAN_PREFIX | SCAN_DELETE,
+            3,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            TXN_ID as i32,
+        ];
+        let (requests, txn) = decode_kv_batch(&args, bytes);
+        assert!(txn);
+        assert_eq!(requests.len(), 2);
+
+        let Some(proto::kv::kv_request::Op::Get(get)) = &requests[0].op else {
+            panic!("require get range, got {:?}", requests[0]);
+        };
+        assert_eq!(
+            get.range,
+            Some(KeyRange {
+                start: b"a".to_vec(),
+                end: b"m".to_vec(),
+                prefix: false,
+            })
+        );
+        assert_eq!(get.limit, 10);
+        assert_eq!(get.continuation, b"c".to_vec());
+
+        let Some(proto::kv::kv_request::Op::Delete(delete)) = &requests[1].op else {
+            panic!("require delete range, got {:?}", requests[1]);
+        };
+        assert_eq!(
+            delete.range,
+            Some(KeyRange {
+                start: b"p".to_vec(),
+                end: vec![],
+                prefix: true,
+            })
+        );
+        assert_eq!(delete.limit, 0);
+        assert!(delete.continuation.is_empty());
+    }
+}
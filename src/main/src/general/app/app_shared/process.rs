@@ -112,6 +112,12 @@ impl ProcessInstance {
             // );
         }
     }
+    /// Pid of the app process, the one reported by the app once verified
+    pub fn pid(&self) -> Option<PID> {
+        let state = self.state.0.read();
+        let (child, checked_pid) = state.1.as_ref()?;
+        checked_pid.or_else(|| child.id())
+    }
     pub fn new(app: String, app_type: AppType) -> Self {
         Self {
             app_type,
@@ -247,3 +253,27 @@ impl InstanceTrait for ProcessInstance {
 // pub struct InstanceManagerProcessState {
 
 // }
+
+#[cfg(test)]
+mod test {
+    use super::ProcessInstance;
+    use crate::general::app::AppType;
+
+    #[tokio::test]
+    async fn test_kill_process_instance() {
+        let instance = ProcessInstance::new("test_app".to_owned(), AppType::Jar);
+        let child = tokio::process::Command::new("sleep")
+            .arg("100")
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        instance.bind_process(child);
+        instance.bind_checked_pid(pid);
+        assert_eq!(instance.pid(), Some(pid));
+
+        // a runaway app is gone once killed, not just detached
+        instance.kill().await;
+        assert_eq!(instance.pid(), None);
+        assert!(!std::path::Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
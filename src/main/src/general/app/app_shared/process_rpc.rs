@@ -195,6 +195,40 @@ impl RpcCustom for ProcessRpc {
                     e
                 }
             },
+
+            7 => match proc_proto::KvTxnRequest::decode(buf) {
+                Ok(req) => {
+                    tracing::debug!("function requested for kv txn");
+                    let proc_rpc = self.clone();
+                    let srctaskid = req.fn_taskid();
+                    let conn = conn.clone();
+                    let _ = tokio::spawn(async move {
+                        let proc_rpc_res = proc_rpc
+                            .0
+                            .kv_user_client()
+                            .kv_requests(srctaskid, req.to_proto_kvrequests())
+                            .await;
+                        match proc_rpc_res {
+                            Ok(res) => {
+                                if let Err(e) = rpc_model::send_resp::<proc_proto::KvTxnRequest>(
+                                    conn,
+                                    taskid,
+                                    proc_proto::KvTxnResponse::from(res),
+                                )
+                                .await
+                                {
+                                    tracing::warn!("send kv txn response failed: {:?}", e);
+                                }
+                            }
+                            Err(e) => {
+                                tracing::warn!("function kv txn failed, error: {:?}", e);
+                            }
+                        }
+                    });
+                    return true;
+                }
+                Err(e) => e,
+            },
             id => {
                 tracing::warn!("handle_remote_call: unsupported id: {}", id);
                 return false;
@@ -241,6 +275,18 @@ impl MsgIdBind for proc_proto::KvResponse {
     }
 }
 
+impl MsgIdBind for proc_proto::KvTxnRequest {
+    fn id() -> u16 {
+        7
+    }
+}
+
+impl MsgIdBind for proc_proto::KvTxnResponse {
+    fn id() -> u16 {
+        8
+    }
+}
+
 impl ReqMsg for FuncCallReq {
     type Resp = FuncCallResp;
 }
@@ -249,6 +295,10 @@ impl ReqMsg for proc_proto::KvRequest {
     type Resp = proc_proto::KvResponse;
 }
 
+impl ReqMsg for proc_proto::KvTxnRequest {
+    type Resp = proc_proto::KvTxnResponse;
+}
+
 pub async fn call_func(
     srcfnid: proc_proto::FnTaskId,
     app: &str,
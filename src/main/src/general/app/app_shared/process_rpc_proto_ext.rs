@@ -11,6 +11,25 @@ impl From<proc_proto::FnTaskId> for proto::FnTaskId {
     }
 }
 
+impl From<proto::kv::KvPair> for proc_proto::KvPair {
+    fn from(kv: proto::kv::KvPair) -> Self {
+        proc_proto::KvPair {
+            key: kv.key,
+            values: kv.values,
+        }
+    }
+}
+
+impl From<proc_proto::KeyRange> for proto::kv::KeyRange {
+    fn from(range: proc_proto::KeyRange) -> Self {
+        proto::kv::KeyRange {
+            start: range.start,
+            end: range.end,
+            prefix: range.prefix,
+        }
+    }
+}
+
 impl From<proto::kv::KvResponse> for proc_proto::KvResponse {
     fn from(response: proto::kv::KvResponse) -> Self {
         proc_proto::KvResponse {
@@ -19,27 +38,68 @@ impl From<proto::kv::KvResponse> for proc_proto::KvResponse {
                     proc_proto::kv_response::Resp::Get(proc_proto::kv_response::KvGetResponse {
                         idxs: get.idxs,
                         values: get.values,
+                        kvs: get.kvs.into_iter().map(|kv| kv.into()).collect(),
+                        continuation: get.continuation,
+                        version: get.version,
                     })
                 }
                 proto::kv::kv_response::Resp::PutOrDel(put_or_del) => {
-                    let kv = put_or_del.kv.unwrap();
                     proc_proto::kv_response::Resp::PutOrDel(
                         proc_proto::kv_response::KvPutOrDelResponse {
-                            kv: Some(proc_proto::KvPair {
-                                key: kv.key,
-                                values: kv.values,
-                            }),
+                            kv: put_or_del.kv.map(|kv| kv.into()),
+                            kvs: put_or_del.kvs.into_iter().map(|kv| kv.into()).collect(),
+                            continuation: put_or_del.continuation,
                         },
                     )
                 }
                 proto::kv::kv_response::Resp::LockId(lock_id) => {
                     proc_proto::kv_response::Resp::LockId(lock_id)
                 }
+                // process functions don't lock yet
+                proto::kv::kv_response::Resp::LockGrant(grant) => {
+                    proc_proto::kv_response::Resp::LockId(grant.lock_id)
+                }
+                proto::kv::kv_response::Resp::Cas(cas) => {
+                    proc_proto::kv_response::Resp::Cas(proc_proto::kv_response::KvCasResponse {
+                        success: cas.success,
+                        version: cas.version,
+                    })
+                }
+                proto::kv::kv_response::Resp::Watch(watch) => {
+                    proc_proto::kv_response::Resp::Watch(proc_proto::kv_response::KvWatchResponse {
+                        events: watch
+                            .events
+                            .into_iter()
+                            .map(|event| proc_proto::KvWatchEvent {
+                                key: event.key,
+                                delete: event.delete,
+                                version: event.version,
+                                revision: event.revision,
+                            })
+                            .collect(),
+                        revision: watch.revision,
+                        compacted: watch.compacted,
+                        err_msg: watch.err_msg,
+                    })
+                }
             }),
         }
     }
 }
 
+impl From<proto::kv::KvResponses> for proc_proto::KvTxnResponse {
+    fn from(responses: proto::kv::KvResponses) -> Self {
+        proc_proto::KvTxnResponse {
+            responses: responses
+                .responses
+                .into_iter()
+                .map(|resp| resp.into())
+                .collect(),
+            abort_reason: responses.txn_abort_reason,
+        }
+    }
+}
+
 pub trait ProcRpcReqExt {
     fn app_fn(&self) -> &str;
     fn app_name(&self) -> &str {
@@ -57,6 +117,8 @@ impl ProcRpcReqExt for proc_proto::KvRequest {
             Some(proc_proto::kv_request::Op::Set(set)) => set.app_fn.as_str(),
             Some(proc_proto::kv_request::Op::Get(get)) => get.app_fn.as_str(),
             Some(proc_proto::kv_request::Op::Delete(delete)) => delete.app_fn.as_str(),
+            Some(proc_proto::kv_request::Op::Cas(cas)) => cas.app_fn.as_str(),
+            Some(proc_proto::kv_request::Op::Watch(watch)) => watch.app_fn.as_str(),
             None => panic!("no app_fn in kv request"),
         }
     }
@@ -71,77 +133,102 @@ impl ProcRpcReqExt for proc_proto::KvRequest {
             Some(proc_proto::kv_request::Op::Delete(delete)) => {
                 proto::FnTaskId::from(delete.src_task_id.clone().unwrap())
             }
+            Some(proc_proto::kv_request::Op::Cas(cas)) => {
+                proto::FnTaskId::from(cas.src_task_id.clone().unwrap())
+            }
+            Some(proc_proto::kv_request::Op::Watch(watch)) => {
+                proto::FnTaskId::from(watch.src_task_id.clone().unwrap())
+            }
             None => panic!("no fn_taskid in kv request"),
         }
     }
 }
 
+impl ProcRpcReqExt for proc_proto::KvTxnRequest {
+    fn app_fn(&self) -> &str {
+        self.app_fn.as_str()
+    }
+    fn fn_taskid(&self) -> proto::FnTaskId {
+        proto::FnTaskId::from(self.src_task_id.clone().unwrap())
+    }
+}
+
 pub trait ProcRpcExtKvReq {
     fn to_proto_kvrequests(self) -> proto::kv::KvRequests;
 }
 
+fn to_proto_kvrequest(req: proc_proto::KvRequest) -> proto::kv::KvRequest {
+    let op = match req.op {
+        Some(proc_proto::kv_request::Op::Set(set)) => {
+            let kv = set.kv.unwrap();
+            proto::kv::kv_request::Op::Set(proto::kv::kv_request::KvPutRequest {
+                kv: Some(proto::kv::KvPair {
+                    key: kv.key,
+                    values: kv.values,
+                }),
+                fencing_token: 0,
+                ttl_ms: set.ttl_ms,
+            })
+        }
+        Some(proc_proto::kv_request::Op::Get(get)) => {
+            proto::kv::kv_request::Op::Get(proto::kv::kv_request::KvGetRequest {
+                range: get.range.map(Into::into),
+                idxs: get.idxs,
+                limit: get.limit,
+                continuation: get.continuation,
+            })
+        }
+        Some(proc_proto::kv_request::Op::Delete(delete)) => {
+            proto::kv::kv_request::Op::Delete(proto::kv::kv_request::KvDeleteRequest {
+                range: delete.range.map(Into::into),
+                limit: delete.limit,
+                continuation: delete.continuation,
+            })
+        }
+        Some(proc_proto::kv_request::Op::Cas(cas)) => {
+            let kv = cas.kv.unwrap();
+            proto::kv::kv_request::Op::Cas(proto::kv::kv_request::KvCasRequest {
+                kv: Some(proto::kv::KvPair {
+                    key: kv.key,
+                    values: kv.values,
+                }),
+                expected_version: cas.expected_version,
+                delete: cas.delete,
+            })
+        }
+        Some(proc_proto::kv_request::Op::Watch(watch)) => {
+            proto::kv::kv_request::Op::Watch(proto::kv::KvWatchRequest {
+                range: watch.range.map(Into::into),
+                from_revision: watch.from_revision,
+                timeout_ms: watch.timeout_ms,
+                limit: watch.limit,
+            })
+        }
+        None => panic!("no op in kv request"),
+    };
+    proto::kv::KvRequest { op: Some(op) }
+}
+
 impl ProcRpcExtKvReq for proc_proto::KvRequest {
     fn to_proto_kvrequests(self) -> proto::kv::KvRequests {
-        let app = self.app_name().to_string();
-        let func = self.func_name().to_string();
+        proto::kv::KvRequests {
+            app: self.app_name().to_string(),
+            func: self.func_name().to_string(),
+            requests: vec![to_proto_kvrequest(self)],
+            prev_kv_opeid: 0,
+            txn: false,
+        }
+    }
+}
 
-        match self.op {
-            Some(proc_proto::kv_request::Op::Set(set)) => {
-                let kv = set.kv.unwrap();
-                proto::kv::KvRequests {
-                    app,
-                    func,
-                    requests: vec![proto::kv::KvRequest {
-                        op: Some(proto::kv::kv_request::Op::Set(
-                            proto::kv::kv_request::KvPutRequest {
-                                kv: Some(proto::kv::KvPair {
-                                    key: kv.key,
-                                    values: kv.values,
-                                }),
-                            },
-                        )),
-                    }],
-                    prev_kv_opeid: 0,
-                }
-            }
-            Some(proc_proto::kv_request::Op::Get(get)) => {
-                let range = get.range.unwrap();
-                proto::kv::KvRequests {
-                    app,
-                    func,
-                    requests: vec![proto::kv::KvRequest {
-                        op: Some(proto::kv::kv_request::Op::Get(
-                            proto::kv::kv_request::KvGetRequest {
-                                range: Some(proto::kv::KeyRange {
-                                    start: range.start,
-                                    end: range.end,
-                                }),
-                                idxs: get.idxs,
-                            },
-                        )),
-                    }],
-                    prev_kv_opeid: 0,
-                }
-            }
-            Some(proc_proto::kv_request::Op::Delete(delete)) => {
-                let range = delete.range.unwrap();
-                proto::kv::KvRequests {
-                    app,
-                    func,
-                    requests: vec![proto::kv::KvRequest {
-                        op: Some(proto::kv::kv_request::Op::Delete(
-                            proto::kv::kv_request::KvDeleteRequest {
-                                range: Some(proto::kv::KeyRange {
-                                    start: range.start,
-                                    end: range.end,
-                                }),
-                            },
-                        )),
-                    }],
-                    prev_kv_opeid: 0,
-                }
-            }
-            None => panic!("no op in kv request"),
+impl ProcRpcExtKvReq for proc_proto::KvTxnRequest {
+    fn to_proto_kvrequests(self) -> proto::kv::KvRequests {
+        proto::kv::KvRequests {
+            app: self.app_name().to_string(),
+            func: self.func_name().to_string(),
+            requests: self.requests.into_iter().map(to_proto_kvrequest).collect(),
+            prev_kv_opeid: 0,
+            txn: true,
         }
     }
 }
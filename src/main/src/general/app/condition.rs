@@ -0,0 +1,508 @@
+//! Condition expressions of conditional data triggers.
+//!
+//! ```yaml
+//! kvs:
+//!   order_{}:
+//!   - trigger_by_write:
+//!       condition: key ~= "^order_vip" && size < 4096 && json.state == "paid"
+//! ```
+//!
+//! - `key`: the written key (without the internal uid prefix)
+//! - `size`: total bytes of the written data items
+//! - `json.a.b[0]`: a field of the written value parsed as json, `null` if absent
+//! - literals: `"str"`, `1.5`, `true`, `false`, `null`
+//! - compare with `==`, `!=`, `<`, `<=`, `>`, `>=`, or `~=` for a regex match
+//! - combine with `&&`, `||`, `!` and parentheses
+//!
+//! Conditions are parsed once when the app meta is built or decoded, a [`TriggerCondition`]
+//! is stored as its source.
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct ConditionParseErr {
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ConditionParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.msg, self.pos)
+    }
+}
+
+/// What a written data looks like to a condition.
+pub struct ConditionInput<'a> {
+    pub key: &'a str,
+    pub size: u64,
+    /// first data item, only present when it's small enough to be sent to the master
+    pub value: Option<&'a [u8]>,
+}
+
+#[derive(Debug, Clone)]
+enum PathSeg {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Key,
+    Size,
+    Json(Vec<PathSeg>),
+    Lit(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(Operand, CmpOp, Operand),
+    Match(Operand, Regex),
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone)]
+pub struct TriggerCondition {
+    src: String,
+    expr: Expr,
+}
+
+impl TriggerCondition {
+    pub fn parse(src: &str) -> Result<Self, ConditionParseErr> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            cur: 0,
+            src_len: src.len(),
+        };
+        let expr = parser.parse_or()?;
+        if let Some((pos, tok)) = parser.tokens.get(parser.cur) {
+            return Err(parser.err_at(*pos, format!("unexpected {:?}", tok)));
+        }
+        Ok(Self {
+            src: src.to_owned(),
+            expr,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.src
+    }
+
+    pub fn eval(&self, input: &ConditionInput) -> bool {
+        let json = input
+            .value
+            .and_then(|v| serde_json::from_slice::<Value>(v).ok())
+            .unwrap_or(Value::Null);
+        eval_expr(&self.expr, input, &json)
+    }
+}
+
+fn eval_expr(expr: &Expr, input: &ConditionInput, json: &Value) -> bool {
+    match expr {
+        Expr::And(l, r) => eval_expr(l, input, json) && eval_expr(r, input, json),
+        Expr::Or(l, r) => eval_expr(l, input, json) || eval_expr(r, input, json),
+        Expr::Not(e) => !eval_expr(e, input, json),
+        Expr::Cmp(l, op, r) => {
+            let (l, r) = (eval_operand(l, input, json), eval_operand(r, input, json));
+            match (op, &l, &r) {
+                (CmpOp::Eq, _, _) => value_eq(&l, &r),
+                (CmpOp::Ne, _, _) => !value_eq(&l, &r),
+                (_, Value::Number(a), Value::Number(b)) => {
+                    let (a, b) = (a.as_f64().unwrap(), b.as_f64().unwrap());
+                    cmp_ord(*op, a.partial_cmp(&b))
+                }
+                (_, Value::String(a), Value::String(b)) => cmp_ord(*op, Some(a.cmp(b))),
+                _ => false,
+            }
+        }
+        Expr::Match(operand, re) => match eval_operand(operand, input, json) {
+            Value::String(s) => re.is_match(&s),
+            _ => false,
+        },
+        Expr::Truthy(operand) => eval_operand(operand, input, json) == Value::Bool(true),
+    }
+}
+
+impl fmt::Display for TriggerCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.src)
+    }
+}
+
+impl Serialize for TriggerCondition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.src)
+    }
+}
+
+impl<'de> Deserialize<'de> for TriggerCondition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let src = String::deserialize(deserializer)?;
+        Self::parse(&src).map_err(|err| {
+            serde::de::Error::custom(format!("invalid trigger condition {:?}, {}", src, err))
+        })
+    }
+}
+
+fn eval_operand(operand: &Operand, input: &ConditionInput, json: &Value) -> Value {
+    match operand {
+        Operand::Key => Value::String(input.key.to_owned()),
+        Operand::Size => Value::from(input.size),
+        Operand::Lit(v) => v.clone(),
+        Operand::Json(path) => {
+            let mut cur = json;
+            for seg in path {
+                let next = match seg {
+                    PathSeg::Field(f) => cur.get(f.as_str()),
+                    PathSeg::Index(i) => cur.get(*i),
+                };
+                match next {
+                    Some(next) => cur = next,
+                    None => return Value::Null,
+                }
+            }
+            cur.clone()
+        }
+    }
+}
+
+fn value_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        // 1 == 1.0
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn cmp_ord(op: CmpOp, ord: Option<std::cmp::Ordering>) -> bool {
+    use std::cmp::Ordering::*;
+    match (op, ord) {
+        (_, None) => false,
+        (CmpOp::Lt, Some(o)) => o == Less,
+        (CmpOp::Le, Some(o)) => o != Greater,
+        (CmpOp::Gt, Some(o)) => o == Greater,
+        (CmpOp::Ge, Some(o)) => o != Less,
+        (CmpOp::Eq, Some(o)) => o == Equal,
+        (CmpOp::Ne, Some(o)) => o != Equal,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+}
+
+const OPS: [&str; 15] = [
+    "&&", "||", "==", "!=", "<=", ">=", "~=", "<", ">", "!", "(", ")", ".", "[", "]",
+];
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, ConditionParseErr> {
+    let mut tokens = vec![];
+    let chars: Vec<(usize, char)> = src.char_indices().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let (pos, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ConditionParseErr {
+                            pos,
+                            msg: "unterminated string".to_owned(),
+                        })
+                    }
+                    Some((_, '"')) => break,
+                    // only quotes and backslashes are escaped, regex escapes are kept as is
+                    Some((_, '\\')) if matches!(chars.get(i + 1), Some((_, '"' | '\\'))) => {
+                        s.push(chars[i + 1].1);
+                        i += 2;
+                    }
+                    Some((_, c)) => {
+                        s.push(*c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push((pos, Token::Str(s)));
+        } else if c.is_ascii_digit()
+            || (c == '-' && matches!(chars.get(i + 1), Some((_, d)) if d.is_ascii_digit()))
+        {
+            i += 1;
+            while matches!(chars.get(i), Some((_, d)) if d.is_ascii_digit() || *d == '.') {
+                i += 1;
+            }
+            let end = chars.get(i).map(|(p, _)| *p).unwrap_or(src.len());
+            let num = src[pos..end]
+                .parse::<f64>()
+                .map_err(|_| ConditionParseErr {
+                    pos,
+                    msg: format!("invalid number {}", &src[pos..end]),
+                })?;
+            tokens.push((pos, Token::Num(num)));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut s = String::new();
+            while let Some((_, c)) = chars.get(i) {
+                if !(c.is_alphanumeric() || *c == '_') {
+                    break;
+                }
+                s.push(*c);
+                i += 1;
+            }
+            tokens.push((pos, Token::Ident(s)));
+        } else if let Some(op) = OPS.iter().find(|op| src[pos..].starts_with(**op)) {
+            tokens.push((pos, Token::Op(op)));
+            i += op.len();
+        } else {
+            return Err(ConditionParseErr {
+                pos,
+                msg: format!("unexpected char {:?}", c),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    cur: usize,
+    src_len: usize,
+}
+
+impl Parser {
+    fn err_at(&self, pos: usize, msg: String) -> ConditionParseErr {
+        ConditionParseErr { pos, msg }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.cur).map(|(_, t)| t)
+    }
+
+    fn pos(&self) -> usize {
+        self.tokens
+            .get(self.cur)
+            .map(|(p, _)| *p)
+            .unwrap_or(self.src_len)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.cur += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, ConditionParseErr> {
+        match self.tokens.get(self.cur) {
+            Some((_, t)) => {
+                self.cur += 1;
+                Ok(t.clone())
+            }
+            None => Err(self.err_at(self.src_len, "unexpected end".to_owned())),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ConditionParseErr> {
+        let mut l = self.parse_and()?;
+        while self.eat_op("||") {
+            l = Expr::Or(Box::new(l), Box::new(self.parse_and()?));
+        }
+        Ok(l)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ConditionParseErr> {
+        let mut l = self.parse_unary()?;
+        while self.eat_op("&&") {
+            l = Expr::And(Box::new(l), Box::new(self.parse_unary()?));
+        }
+        Ok(l)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ConditionParseErr> {
+        if self.eat_op("!") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.eat_op("(") {
+            let e = self.parse_or()?;
+            if !self.eat_op(")") {
+                return Err(self.err_at(self.pos(), "expected ')'".to_owned()));
+            }
+            return Ok(e);
+        }
+        let lpos = self.pos();
+        let l = self.parse_operand()?;
+        let op = match self.peek() {
+            Some(Token::Op(op)) => match *op {
+                "==" => Some(CmpOp::Eq),
+                "!=" => Some(CmpOp::Ne),
+                "<" => Some(CmpOp::Lt),
+                "<=" => Some(CmpOp::Le),
+                ">" => Some(CmpOp::Gt),
+                ">=" => Some(CmpOp::Ge),
+                "~=" => None,
+                _ => return self.bare_operand(lpos, l),
+            },
+            _ => return self.bare_operand(lpos, l),
+        };
+        self.cur += 1;
+        let rpos = self.pos();
+        match op {
+            Some(op) => Ok(Expr::Cmp(l, op, self.parse_operand()?)),
+            None => match self.next()? {
+                Token::Str(re) => {
+                    let re = Regex::new(&re)
+                        .map_err(|e| self.err_at(rpos, format!("invalid regex: {}", e)))?;
+                    Ok(Expr::Match(l, re))
+                }
+                _ => Err(self.err_at(rpos, "'~=' expects a regex string".to_owned())),
+            },
+        }
+    }
+
+    fn bare_operand(&self, pos: usize, operand: Operand) -> Result<Expr, ConditionParseErr> {
+        match operand {
+            Operand::Json(_) | Operand::Lit(Value::Bool(_)) => Ok(Expr::Truthy(operand)),
+            _ => Err(self.err_at(pos, "expected a comparison".to_owned())),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, ConditionParseErr> {
+        let pos = self.pos();
+        match self.next()? {
+            Token::Str(s) => Ok(Operand::Lit(Value::String(s))),
+            Token::Num(n) => Ok(Operand::Lit(Value::from(n))),
+            Token::Ident(id) => match id.as_str() {
+                "key" => Ok(Operand::Key),
+                "size" => Ok(Operand::Size),
+                "true" => Ok(Operand::Lit(Value::Bool(true))),
+                "false" => Ok(Operand::Lit(Value::Bool(false))),
+                "null" => Ok(Operand::Lit(Value::Null)),
+                "json" => {
+                    let mut path = vec![];
+                    loop {
+                        if self.eat_op(".") {
+                            let pos = self.pos();
+                            match self.next()? {
+                                Token::Ident(f) => path.push(PathSeg::Field(f)),
+                                Token::Str(f) => path.push(PathSeg::Field(f)),
+                                _ => return Err(self.err_at(pos, "expected a field".to_owned())),
+                            }
+                        } else if self.eat_op("[") {
+                            let pos = self.pos();
+                            match self.next()? {
+                                Token::Num(n) if n >= 0.0 && n.fract() == 0.0 => {
+                                    path.push(PathSeg::Index(n as usize))
+                                }
+                                _ => return Err(self.err_at(pos, "expected an index".to_owned())),
+                            }
+                            if !self.eat_op("]") {
+                                return Err(self.err_at(self.pos(), "expected ']'".to_owned()));
+                            }
+                        } else {
+                            break;
+                        }
+                    }
+                    Ok(Operand::Json(path))
+                }
+                _ => Err(self.err_at(pos, format!("unknown operand {}", id))),
+            },
+            Token::Op(op) => Err(self.err_at(pos, format!("unexpected {}", op))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConditionInput, TriggerCondition};
+
+    fn eval(cond: &str, key: &str, value: &str) -> bool {
+        TriggerCondition::parse(cond)
+            .unwrap()
+            .eval(&ConditionInput {
+                key,
+                size: value.len() as u64,
+                value: Some(value.as_bytes()),
+            })
+    }
+
+    #[test]
+    fn test_trigger_condition() {
+        let value = r#"{"state":"paid","amount":12.5,"items":[{"id":3}],"vip":true}"#;
+        assert!(eval(r#"key ~= "^order_\d+$""#, "order_12", value));
+        assert!(!eval(r#"key ~= "^order_\d+$""#, "order_x", value));
+        assert!(eval("size > 10 && size <= 1024", "k", value));
+        assert!(eval(
+            r#"json.state == "paid" && json.amount >= 12"#,
+            "k",
+            value
+        ));
+        assert!(eval("json.items[0].id == 3 && json.vip", "k", value));
+        assert!(eval("json.missing == null || false", "k", value));
+        assert!(!eval(
+            r#"!(json.state != "paid") && json.amount < 1"#,
+            "k",
+            value
+        ));
+        assert!(!eval("json.vip", "k", "not json"));
+
+        // stored as the source, parsed again when decoded
+        let cond = TriggerCondition::parse(r#"key ~= "^order_" && size > 0"#).unwrap();
+        let decoded: TriggerCondition =
+            bincode::deserialize(&bincode::serialize(&cond).unwrap()).unwrap();
+        assert_eq!(decoded.as_str(), cond.as_str());
+        assert!(decoded.eval(&ConditionInput {
+            key: "order_1",
+            size: 1,
+            value: None,
+        }));
+        assert!(
+            bincode::deserialize::<TriggerCondition>(&bincode::serialize("size >").unwrap())
+                .is_err()
+        );
+
+        for bad in [
+            "",
+            "size",
+            "size >",
+            r#"key ~= "(""#,
+            "key ~= 1",
+            "(size > 1",
+            r#"json.state == "paid"#,
+            "size > 1 size",
+            "json[x] == 1",
+            // predicate functions aren't evaluated by the master
+            "checkpointable",
+            "checkpointable && size > 0",
+        ] {
+            assert!(
+                TriggerCondition::parse(bad).is_err(),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+}
@@ -1,12 +1,16 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use axum::extract::{DefaultBodyLimit, Multipart, Path};
-use axum::http::StatusCode;
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, FromRequest, Multipart, Path};
+use axum::http::{Request, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::{routing::post, Router};
+use axum::routing::{any, get, post};
+use axum::Router;
 use lazy_static::lazy_static;
 
+use crate::master::http_proxy::{self, ProxiedRequest};
 use crate::master::m_master::ScheduleWorkload;
+use crate::result::{WSError, WsFuncError, WsNetworkLogicErr};
 use crate::util;
 
 lazy_static! {
@@ -33,21 +37,55 @@ pub(super) fn binds(router: Router, view: super::View) -> Router {
     router
         .route("/appmgmt/upload_app", post(upload_app))
         .layer(DefaultBodyLimit::disable())
-        .route("/:app/:fn", post(call_app_fn))
+        .route("/appmgmt/versions/:app", get(app_versions))
+        .route(
+            "/appmgmt/activate/:app/:version",
+            post(activate_app_version),
+        )
+        .route("/appmgmt/rollback/:app", post(rollback_app))
+        .route(
+            "/appmgmt/canary/:app/:version/:percent",
+            post(set_app_canary),
+        )
+        .route("/:app/:fn", any(call_app_fn))
     // .layer(RequestBodyLimitLayer::new(
     //     250 * 1024 * 1024, /* 250mb */
     // ))
 }
 
-async fn call_app_fn(Path((app, func)): Path<(String, String)>, body: String) -> Response {
+/// Requests are served by the elected master or by workers, a standby master candidate is
+/// neither.
+fn standby_response() -> Response {
+    let nodes_config = &view().p2p().nodes_config;
+    let err: WSError = WsNetworkLogicErr::NotMaster {
+        this: nodes_config.this_node(),
+        master: nodes_config.master_node(),
+    }
+    .into();
+    (StatusCode::SERVICE_UNAVAILABLE, format!("err: {:?}", err)).into_response()
+}
+
+/// `app` may be pinned to a version as `<app>@v<version>`
+async fn call_app_fn(
+    Path((app, func)): Path<(String, String)>,
+    request: Request<Body>,
+) -> Response {
     tracing::debug!("handle func request app: {}, func: {}", app, func);
-    if view().p2p().nodes_config.this.1.is_master() {
+    let nodes_config = &view().p2p().nodes_config;
+    if !nodes_config.this_is_master() && !nodes_config.this.1.is_worker() {
+        return standby_response();
+    }
+    if nodes_config.this_is_master() {
         tracing::debug!("app: {:?}, func: {:?}", app, func);
         view()
             .http_handler()
-            .handle_request(&format!("{app}/{func}"), body)
+            .handle_request(&format!("{app}/{func}"), request)
             .await
     } else {
+        let body = match String::from_request(request, &()).await {
+            Ok(body) => body,
+            Err(rejection) => return rejection.into_response(),
+        };
         // # call instance run
         let req_arrive_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -76,24 +114,40 @@ async fn call_app_fn(Path((app, func)): Path<(String, String)>, body: String) ->
         match res {
             Ok(Some(res)) => (StatusCode::OK, res).into_response(),
             Ok(None) => StatusCode::OK.into_response(),
+            Err(WSError::WsFuncError(WsFuncError::NodeDraining)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "node is draining").into_response()
+            }
             Err(e) => (StatusCode::BAD_REQUEST, format!("err: {:?}", e)).into_response(),
         }
     }
 }
 
-async fn upload_app(mut multipart: Multipart) -> Response {
+async fn upload_app(request: Request<Body>) -> Response {
     tracing::debug!("upload_app called");
     // only worker can upload app
-    if view().p2p().nodes_config.this.1.is_master() {
-        let tar = view().master().schedule(ScheduleWorkload::JavaAppConstruct);
-
-        tracing::debug!("redirect 2 worker");
-        return tar
-            .http_redirect(&view().p2p().nodes_config)
-            .into_response();
+    let nodes_config = &view().p2p().nodes_config;
+    if !nodes_config.this_is_master() && !nodes_config.this.1.is_worker() {
+        return standby_response();
     }
+    if nodes_config.this_is_master() {
+        // the upload is streamed on to the worker as it comes
+        return match to_worker(ScheduleWorkload::JavaAppConstruct, request).await {
+            Some(resp) => resp,
+            // the node stopped being master meanwhile
+            None => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "err: not relayed to a worker",
+            )
+                .into_response(),
+        };
+    }
+    let mut multipart = match Multipart::from_request(request, &()).await {
+        Ok(multipart) => multipart,
+        Err(rejection) => return rejection.into_response(),
+    };
 
     let mut tasks = vec![];
+    let mut uploaded = serde_json::Map::new();
     while let Some(field) = multipart.next_field().await.unwrap() {
         let name = field.name().unwrap().to_string();
         // let file_name = field.file_name().unwrap().to_string();
@@ -118,13 +172,157 @@ async fn upload_app(mut multipart: Multipart) -> Response {
     for (t, app) in tasks {
         let res = t.await.unwrap();
         match res {
+            Err(WSError::WsFuncError(WsFuncError::InvalidAppMeta { problems, .. })) => {
+                let mut errmsg = format!("Invalid app.yaml of app {}:", app);
+                for problem in problems {
+                    errmsg.push_str(&format!("\n  {}", problem));
+                }
+                tracing::warn!(errmsg);
+                return (StatusCode::BAD_REQUEST, errmsg).into_response();
+            }
             Err(e) => {
                 let errmsg = format!("Failed to upload app {}: {}", app, e);
                 tracing::warn!(errmsg);
-                return (StatusCode::INTERNAL_SERVER_ERROR, errmsg).into_response();
+                return (app_mgmt_err_status(&e), errmsg).into_response();
             }
-            Ok(_) => {}
+            Ok(version) => {
+                let _ = uploaded.insert(app, version.into());
+            }
+        }
+    }
+    // app -> the uploaded version
+    (
+        StatusCode::OK,
+        serde_json::Value::Object(uploaded).to_string(),
+    )
+        .into_response()
+}
+
+/// Apps are managed by workers, the master redirects the requests to one of them,
+/// or relays them in `master_proxy` mode. `None` on workers that aren't the elected master.
+async fn to_worker(wl: ScheduleWorkload, request: Request<Body>) -> Option<Response> {
+    let nodes_config = &view().p2p().nodes_config;
+    if !nodes_config.this_is_master() {
+        if !nodes_config.this.1.is_worker() {
+            return Some(standby_response());
+        }
+        return None;
+    }
+    if nodes_config.master_proxy {
+        let req = ProxiedRequest::from(request);
+        tracing::debug!("proxy {} to worker", req.path);
+        return Some(
+            http_proxy::proxy_to_worker(nodes_config, req, |tried| async move {
+                view()
+                    .master()
+                    .schedule_excluding(wl, &tried)
+                    .map(|tar| tar.0)
+                    .ok_or_else(|| {
+                        (StatusCode::SERVICE_UNAVAILABLE, "err: no worker available")
+                            .into_response()
+                    })
+            })
+            .await,
+        );
+    }
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let tar = view().master().schedule(wl);
+    tracing::debug!("redirect {} to worker {}", path, tar.0);
+    Some(match tar.http_redirect(nodes_config, path) {
+        Ok(redirect) => redirect.into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("err: {:?}", e)).into_response(),
+    })
+}
+
+async fn app_mgmt_to_worker(request: Request<Body>) -> Option<Response> {
+    to_worker(ScheduleWorkload::AppMgmt, request).await
+}
+
+fn app_mgmt_err_status(err: &WSError) -> StatusCode {
+    match err {
+        WSError::WsFuncError(
+            WsFuncError::AppNotFound { .. } | WsFuncError::AppVersionNotFound { .. },
+        ) => StatusCode::NOT_FOUND,
+        WSError::WsFuncError(
+            WsFuncError::AppVersionExists { .. } | WsFuncError::AppNoPreviousVersion { .. },
+        ) => StatusCode::CONFLICT,
+        WSError::WsFuncError(
+            WsFuncError::InvalidAppName { .. } | WsFuncError::InvalidAppCanary { .. },
+        ) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn app_mgmt_err_response(err: WSError) -> Response {
+    tracing::warn!("app management failed, err: {:?}", err);
+    (app_mgmt_err_status(&err), format!("err: {:?}", err)).into_response()
+}
+
+async fn app_versions(Path(app): Path<String>, request: Request<Body>) -> Response {
+    if let Some(redirect) = app_mgmt_to_worker(request).await {
+        return redirect;
+    }
+    match view().appmeta_manager().get_app_versions(&app).await {
+        Ok(Some(versions)) => {
+            (StatusCode::OK, serde_json::to_string(&versions).unwrap()).into_response()
+        }
+        Ok(None) => app_mgmt_err_response(WsFuncError::AppNotFound { app }.into()),
+        Err(e) => app_mgmt_err_response(e),
+    }
+}
+
+async fn activate_app_version(
+    Path((app, version)): Path<(String, u64)>,
+    request: Request<Body>,
+) -> Response {
+    if let Some(redirect) = app_mgmt_to_worker(request).await {
+        return redirect;
+    }
+    match view()
+        .appmeta_manager()
+        .activate_app_version(&app, version)
+        .await
+    {
+        Ok(()) => {
+            let res = serde_json::json!({ "app": app, "active": version });
+            (StatusCode::OK, res.to_string()).into_response()
+        }
+        Err(e) => app_mgmt_err_response(e),
+    }
+}
+
+async fn rollback_app(Path(app): Path<String>, request: Request<Body>) -> Response {
+    if let Some(redirect) = app_mgmt_to_worker(request).await {
+        return redirect;
+    }
+    match view().appmeta_manager().rollback_app(&app).await {
+        Ok(version) => {
+            let res = serde_json::json!({ "app": app, "active": version });
+            (StatusCode::OK, res.to_string()).into_response()
+        }
+        Err(e) => app_mgmt_err_response(e),
+    }
+}
+
+async fn set_app_canary(
+    Path((app, version, percent)): Path<(String, u64, u8)>,
+    request: Request<Body>,
+) -> Response {
+    if let Some(redirect) = app_mgmt_to_worker(request).await {
+        return redirect;
+    }
+    match view()
+        .appmeta_manager()
+        .set_app_canary(&app, version, percent)
+        .await
+    {
+        Ok(()) => {
+            let res = serde_json::json!({ "app": app, "canary": version, "percent": percent });
+            (StatusCode::OK, res.to_string()).into_response()
         }
+        Err(e) => app_mgmt_err_response(e),
     }
-    (StatusCode::OK).into_response()
 }
@@ -6,6 +6,7 @@ use crate::general::app::app_shared::SharedInstance;
 use crate::general::app::instance::Instance;
 use crate::general::app::m_executor::FnExeCtxAsync;
 use crate::general::app::m_executor::FnExeCtxSync;
+use crate::general::app::version::{self, AppVersions};
 use crate::general::app::AppMetaManager;
 use crate::general::m_os::OperatingSystem;
 use crate::general::network::m_p2p::P2PModule;
@@ -26,8 +27,10 @@ use crate::{
 use crate::{logical_module_view_impl, util};
 use async_trait::async_trait;
 use crossbeam_skiplist::SkipMap;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use enum_as_inner::EnumAsInner;
+use parking_lot::RwLock;
 use std::{
     collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
@@ -36,7 +39,7 @@ use std::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tokio::io::AsyncWriteExt;
 use tokio::sync::broadcast;
@@ -45,6 +48,10 @@ use ws_derive::LogicalModule;
 
 use super::OwnedInstance;
 
+/// Longest wait for the running calls of a draining instance.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(600);
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
 pub struct LRUCache<R> {
     capacity: usize,
     cache: HashMap<String, R>,
@@ -137,10 +144,14 @@ pub struct OwnedEachAppCache {
     next_instance_id: AtomicU64,
     using: AtomicU64,
     getting: Notify,
+    /// memory cap of the instances, see [`AppMeta::max_memory_mb`](crate::general::app::AppMeta::max_memory_mb)
+    max_memory_mb: RwLock<Option<u64>>,
+    /// instances with smaller ids were created under an older memory cap
+    cap_first_instance_id: AtomicU64,
 }
 
 impl OwnedEachAppCache {
-    pub fn new() -> Self {
+    pub fn new(max_memory_mb: Option<u64>) -> Self {
         Self {
             cache: moka::sync::CacheBuilder::new(100)
                 .time_to_live(Duration::from_secs(60))
@@ -148,6 +159,8 @@ impl OwnedEachAppCache {
             next_instance_id: AtomicU64::new(0),
             using: AtomicU64::new(0),
             getting: Notify::new(),
+            max_memory_mb: RwLock::new(max_memory_mb),
+            cap_first_instance_id: AtomicU64::new(0),
         }
     }
     pub async fn get(&self, file_dir: impl AsRef<Path>, instance_name: &str) -> OwnedInstance {
@@ -175,20 +188,48 @@ impl OwnedEachAppCache {
             }
         }
 
+        let max_memory_mb = self.max_memory_mb.read();
         wasm::new_wasm_instance(
             file_dir,
             instance_name,
             self.next_instance_id.fetch_add(1, Ordering::Relaxed),
+            *max_memory_mb,
         )
     }
-    pub fn put(&self, value: OwnedInstance) {
+    pub fn put(&self, instance_name: &str, value: OwnedInstance) {
+        let OwnedInstance::WasmInstance(vm) = &value;
+        // created under an older memory cap
+        if wasm::instance_id(vm, instance_name).map_or(true, |id| {
+            id < self.cap_first_instance_id.load(Ordering::Acquire)
+        }) {
+            self.discard();
+            return;
+        }
         self.cache.insert(
             self.next_instance_id.fetch_add(1, Ordering::Relaxed),
             value.into(),
         );
+        self.discard();
+    }
+    /// Give up an instance got before without reusing it
+    pub fn discard(&self) {
         let _ = self.using.fetch_sub(1, Ordering::Relaxed);
         self.getting.notify_waiters();
     }
+    /// Apply a changed memory cap, the instances created before are dropped instead of reused.
+    pub fn set_max_memory_mb(&self, max_memory_mb: Option<u64>) {
+        let mut cap = self.max_memory_mb.write();
+        if *cap == max_memory_mb {
+            return;
+        }
+        *cap = max_memory_mb;
+        // new instances are created under the write lock of the cap
+        self.cap_first_instance_id.store(
+            self.next_instance_id.load(Ordering::Relaxed),
+            Ordering::Release,
+        );
+        self.cache.invalidate_all();
+    }
 }
 
 #[derive(EnumAsInner)]
@@ -222,6 +263,11 @@ pub struct InstanceManager {
     /// instance addr 2 running function
     pub instance_running_function: DashMap<String, UnsafeFunctionCtx>,
     pub next_instance_id: AtomicU64,
+    /// instance name 2 count of the calls running on its instances
+    in_flight: DashMap<String, u64>,
+    /// app 2 the versions the calls not pinned to a version run,
+    /// see [`InstanceManager::drain_unrouted_versions`]
+    routed_versions: DashMap<String, Vec<u64>>,
     pub view: InstanceManagerView,
 }
 
@@ -231,6 +277,22 @@ logical_module_view_impl!(InstanceManagerView, p2p, P2PModule);
 logical_module_view_impl!(InstanceManagerView, appmeta_manager, AppMetaManager);
 logical_module_view_impl!(InstanceManagerView, instance_manager, InstanceManager);
 
+/// A call counted in [`InstanceManager::in_flight`] by [`InstanceManager::load_instance`],
+/// uncounted if loading its instance panics or is cancelled.
+struct InFlightCall<'a> {
+    instman: &'a InstanceManager,
+    instance_name: &'a str,
+    loaded: bool,
+}
+
+impl Drop for InFlightCall<'_> {
+    fn drop(&mut self) {
+        if !self.loaded {
+            self.instman.call_finished(self.instance_name);
+        }
+    }
+}
+
 pub enum UnsafeFunctionCtx {
     Sync(NonNull<FnExeCtxSync>),
     Async(NonNull<FnExeCtxAsync>),
@@ -252,6 +314,8 @@ impl LogicalModule for InstanceManager {
             file_dir: args.nodes_config.file_dir.clone(),
             instance_running_function: DashMap::new(),
             next_instance_id: AtomicU64::new(0),
+            in_flight: DashMap::new(),
+            routed_versions: DashMap::new(),
             view: InstanceManagerView::new(args.logical_modules_ref.clone()),
         }
     }
@@ -321,35 +385,95 @@ impl InstanceManager {
     //     Ok(())
     // }
 
-    pub fn finish_using(&self, instance_name: &str, instance: Instance) {
+    /// `reuse` is false when the instance is not trusted any more, like after going over limits
+    pub fn finish_using(&self, instance_name: &str, instance: Instance, reuse: bool) {
+        if !matches!(instance, Instance::Native(_)) {
+            self.call_finished(instance_name);
+        }
         match instance {
             Instance::Owned(v) => {
-                self.app_instances
-                    .get_or_insert(instance_name.to_owned(), OwnedEachAppCache::new().into())
+                // the app is removed or updated when its cache is gone, drop the old instance
+                let Some(cache) = self.app_instances.get(instance_name) else {
+                    return;
+                };
+                let cache = cache
                     .value()
                     .as_owned()
-                    .expect("supposed to be owned, just inserted in prev line")
-                    .put(v);
+                    .expect("owned instance is supposed to be from owned cache");
+                if reuse {
+                    cache.put(instance_name, v);
+                } else {
+                    cache.discard();
+                }
             }
             Instance::Shared(v) => drop(v),
             Instance::Native(_) => {}
         }
     }
 
+    /// Apply the memory cap of the app to its cached instances, the app meta may change after
+    /// the cache is created.
+    pub fn update_memory_cap(&self, instance_name: &str, max_memory_mb: Option<u64>) {
+        if let Some(cache) = self.app_instances.get(instance_name) {
+            if let EachAppCache::Owned(cache) = cache.value() {
+                cache.set_max_memory_mb(max_memory_mb);
+            }
+        }
+    }
+
     pub async fn load_instance(&self, app_type: &AppType, instance_name: &str) -> Instance {
-        match &app_type {
+        let mut call = (*app_type != AppType::Native).then(|| {
+            // before getting the instance, so a draining one is never handed out
+            *self.in_flight.entry(instance_name.to_owned()).or_insert(0) += 1;
+            InFlightCall {
+                instman: self,
+                instance_name,
+                loaded: false,
+            }
+        });
+        let instance = match &app_type {
             AppType::Jar => self.get_process_instance(app_type, instance_name).into(),
-            AppType::Wasm => self
-                .app_instances
-                .get_or_insert(instance_name.to_owned(), OwnedEachAppCache::new().into())
-                .value()
-                .as_owned()
-                .expect("wasm is supposed to be owned, just inserted in prev line")
-                .get(&self.file_dir, instance_name)
-                .await
-                .into(),
+            AppType::Wasm => {
+                let cache = match self.app_instances.get(instance_name) {
+                    Some(cache) => cache,
+                    None => {
+                        let max_memory_mb = match self
+                            .view
+                            .appmeta_manager()
+                            .get_app_meta(instance_name)
+                            .await
+                        {
+                            Ok(Some((appmeta, _))) => appmeta.max_memory_mb(),
+                            Ok(None) => None,
+                            Err(err) => {
+                                tracing::warn!(
+                                    "get app meta of {} failed, instances are not memory capped, err: {:?}",
+                                    instance_name,
+                                    err
+                                );
+                                None
+                            }
+                        };
+                        self.app_instances.get_or_insert(
+                            instance_name.to_owned(),
+                            OwnedEachAppCache::new(max_memory_mb).into(),
+                        )
+                    }
+                };
+                cache
+                    .value()
+                    .as_owned()
+                    .expect("wasm is supposed to be owned")
+                    .get(&self.file_dir, instance_name)
+                    .await
+                    .into()
+            }
             AppType::Native => NativeAppInstance::new().into(),
+        };
+        if let Some(call) = &mut call {
+            call.loaded = true;
         }
+        instance
     }
 
     /// Synchronous version of instance loading
@@ -368,6 +492,89 @@ impl InstanceManager {
         }
     }
 
+    fn call_finished(&self, instance_name: &str) {
+        if let Entry::Occupied(mut calls) = self.in_flight.entry(instance_name.to_owned()) {
+            *calls.get_mut() -= 1;
+            if *calls.get() == 0 {
+                let _ = calls.remove();
+            }
+        }
+    }
+
+    /// Apps with instances loaded on this node, reported to master for scheduling.
+    pub fn warm_apps(&self) -> Vec<String> {
+        let mut apps: Vec<String> = self
+            .app_instances
+            .iter()
+            .map(|entry| version::split_versioned_app(entry.key()).0.to_owned())
+            .collect();
+        apps.sort();
+        apps.dedup();
+        apps
+    }
+
+    /// Drain the instances of the versions of `app` calls not pinned to a version no longer run,
+    /// once the active version or the canary changed.
+    ///
+    /// Calls not pinned to a version stop coming to them, they are dropped after their running
+    /// calls finish. Calls pinned to an old version start new instances.
+    pub fn drain_unrouted_versions(&self, app: &str, versions: &AppVersions) {
+        let routed: Vec<u64> = versions.routed().collect();
+        if self.routed_versions.get(app).as_deref() == Some(&routed) {
+            return;
+        }
+        let _ = self.routed_versions.insert(app.to_owned(), routed.clone());
+        for entry in self.app_instances.iter() {
+            let name = entry.key();
+            match version::split_versioned_app(name) {
+                (name_app, Some(v)) if name_app == app && !routed.contains(&v) => {
+                    let view = self.view.clone();
+                    let name = name.clone();
+                    let _ = tokio::spawn(async move {
+                        view.instance_manager().drain_instances(&name).await;
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn drain_instances(&self, instance_name: &str) {
+        let begin = Instant::now();
+        loop {
+            // holding the entry, no call gets the instances until they are removed
+            let removed = match self.in_flight.entry(instance_name.to_owned()) {
+                Entry::Vacant(_) => Some(self.app_instances.remove(instance_name)),
+                Entry::Occupied(calls) if begin.elapsed() > DRAIN_TIMEOUT => {
+                    tracing::warn!(
+                        "drain instances of {} timeout, {} calls are still running",
+                        instance_name,
+                        calls.get()
+                    );
+                    Some(self.app_instances.remove(instance_name))
+                }
+                Entry::Occupied(_) => None,
+            };
+            if let Some(removed) = removed {
+                if let Some(instances) = removed {
+                    instances.value().kill().await;
+                }
+                tracing::info!("instances of {} drained", instance_name);
+                return;
+            }
+            tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Kills the process of a shared app, failing the calls running on it. The next call of the
+    /// app starts a new one.
+    pub async fn kill_process_instance(&self, instance_name: &str) {
+        if let Some(removed) = self.app_instances.remove(instance_name) {
+            tracing::warn!("killing the process of app {}", instance_name);
+            removed.value().kill().await;
+        }
+    }
+
     pub async fn drap_app_instances(&self, app: &str) {
         let _inss = self.app_instances.remove(app);
         // if let Some(inss) = inss {
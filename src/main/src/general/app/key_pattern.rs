@@ -0,0 +1,221 @@
+use crate::result::WsFormatErr;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+/// Pattern of the data keys a function accesses or is triggered by, compiled once when built.
+///
+/// - `{}` or `{name}`: a capture of letters and digits
+/// - `{name:int}`: digits, passed to the function as a number
+/// - `{name:word}`: letters, digits, `_` and `-`
+/// - `{name:path}`: letters, digits, `_`, `-` and `/`
+/// - `{name:[a-f0-9]}`: one or more chars of the class
+/// - `*`: any letters, digits, `_`, `-` and `/`, not captured
+///
+/// `user_{uid:int}_{day}` matches `user_42_mon` with captures `{"uid": 42, "day": "mon"}`,
+/// captures without a name are named by their position.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct KeyPattern {
+    src: String,
+    re: Regex,
+    captures: Vec<(String, CaptureType)>,
+    literal_prefix_len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CaptureType {
+    Int,
+    Str,
+}
+
+const ALNUM: &str = "[a-zA-Z0-9]+";
+const WORD: &str = r"[a-zA-Z0-9_\-]+";
+const PATH: &str = r"[a-zA-Z0-9_\-/]+";
+const WILDCARD: &str = r"[a-zA-Z0-9_\-/]*";
+
+impl KeyPattern {
+    pub fn new(src: String) -> Result<Self, WsFormatErr> {
+        let invalid = |reason: String| WsFormatErr::KeyPatternFormatErr {
+            key_pattern: src.clone(),
+            reason,
+        };
+        let mut re = String::from("^");
+        let mut captures = vec![];
+        let mut literal_prefix_len = None;
+        let mut rest = src.as_str();
+        while let Some(idx) = rest.find(|c| c == '{' || c == '}' || c == '*') {
+            re.push_str(&regex::escape(&rest[..idx]));
+            let _ = literal_prefix_len.get_or_insert(src.len() - rest.len() + idx);
+            let tail = &rest[idx..];
+            if tail.starts_with('*') {
+                re.push_str(WILDCARD);
+                rest = &tail[1..];
+                continue;
+            }
+            if tail.starts_with('}') {
+                return Err(invalid("unpaired '}'".to_owned()));
+            }
+            let Some(end) = tail.find('}') else {
+                return Err(invalid("unpaired '{'".to_owned()));
+            };
+            let placeholder = &tail[1..end];
+            let (name, class) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+            let name = if name.is_empty() {
+                captures.len().to_string()
+            } else if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                name.to_owned()
+            } else {
+                return Err(invalid(format!("invalid capture name {:?}", name)));
+            };
+            if captures.iter().any(|(n, _)| *n == name) {
+                return Err(invalid(format!("duplicated capture {:?}", name)));
+            }
+            let (class_re, ty) = match class {
+                "" | "alnum" => (ALNUM.to_owned(), CaptureType::Str),
+                "int" => ("[0-9]+".to_owned(), CaptureType::Int),
+                "word" => (WORD.to_owned(), CaptureType::Str),
+                "path" => (PATH.to_owned(), CaptureType::Str),
+                class if class.starts_with('[') && class.ends_with(']') && class.len() > 2 => {
+                    (format!("{}+", class), CaptureType::Str)
+                }
+                class => return Err(invalid(format!("unknown capture class {:?}", class))),
+            };
+            re.push_str(&format!("(?P<c{}>{})", captures.len(), class_re));
+            captures.push((name, ty));
+            rest = &tail[end + 1..];
+        }
+        re.push_str(&regex::escape(rest));
+        re.push('$');
+        let re = Regex::new(&re).map_err(|err| invalid(err.to_string()))?;
+        Ok(Self {
+            literal_prefix_len: literal_prefix_len.unwrap_or(src.len()),
+            src,
+            re,
+            captures,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.src
+    }
+
+    /// The part before the first capture or wildcard, every matched key starts with it.
+    pub fn literal_prefix(&self) -> &str {
+        &self.src[..self.literal_prefix_len]
+    }
+
+    pub fn match_key(&self, key: &str) -> bool {
+        self.re.is_match(key)
+    }
+
+    /// Captured values of the key, None if the key doesn't match.
+    pub fn captures(&self, key: &str) -> Option<BTreeMap<String, Value>> {
+        let caps = self.re.captures(key)?;
+        Some(
+            self.captures
+                .iter()
+                .enumerate()
+                .map(|(idx, (name, ty))| {
+                    let v = &caps[format!("c{}", idx).as_str()];
+                    let v = match ty {
+                        CaptureType::Int => v
+                            .parse::<i64>()
+                            .map(Value::from)
+                            .unwrap_or_else(|_| Value::from(v)),
+                        CaptureType::Str => Value::from(v),
+                    };
+                    (name.clone(), v)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl TryFrom<String> for KeyPattern {
+    type Error = String;
+    fn try_from(src: String) -> Result<Self, Self::Error> {
+        Self::new(src).map_err(|err| err.to_string())
+    }
+}
+
+impl From<KeyPattern> for String {
+    fn from(pattern: KeyPattern) -> Self {
+        pattern.src
+    }
+}
+
+impl PartialEq for KeyPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.src == other.src
+    }
+}
+
+impl Eq for KeyPattern {}
+
+impl Hash for KeyPattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.src.hash(state);
+    }
+}
+
+impl fmt::Debug for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KeyPattern").field(&self.src).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyPattern;
+    use serde_json::json;
+
+    #[test]
+    fn test_key_pattern_captures() {
+        let pattern = KeyPattern::new("user_{uid:int}_{day}".to_owned()).unwrap();
+        assert_eq!(pattern.literal_prefix(), "user_");
+        assert_eq!(
+            serde_json::to_value(pattern.captures("user_42_mon").unwrap()).unwrap(),
+            json!({"uid": 42, "day": "mon"})
+        );
+        assert!(!pattern.match_key("user_x_mon"));
+        assert!(!pattern.match_key("user_42_mon_extra"));
+
+        let pattern = KeyPattern::new("logs/{}/{file:path}.{ext:[a-z]}*".to_owned()).unwrap();
+        assert_eq!(
+            serde_json::to_value(pattern.captures("logs/n1/2024/01-02_x.gz-bak").unwrap()).unwrap(),
+            json!({"0": "n1", "file": "2024/01-02_x", "ext": "gz"})
+        );
+        // special chars of regex are literals
+        assert!(!KeyPattern::new("a.b".to_owned()).unwrap().match_key("axb"));
+
+        for bad in [
+            "a_{",
+            "a_}",
+            "{1x}",
+            "{a}{a}",
+            "{a:float}",
+            "{a:[}",
+            "{a:[]}",
+        ] {
+            assert!(
+                KeyPattern::new(bad.to_owned()).is_err(),
+                "{} should be rejected",
+                bad
+            );
+        }
+
+        // kept as its source in app metas
+        let encoded = bincode::serialize(&pattern).unwrap();
+        let decoded: KeyPattern = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, pattern);
+        assert!(decoded.match_key("logs/a/b.c"));
+    }
+}
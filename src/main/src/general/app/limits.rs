@@ -0,0 +1,382 @@
+//! Resource limits of functions, enforced by the `Executor` on each call.
+//!
+//! ```yaml
+//! fns:
+//!   resize:
+//!     limits:
+//!       timeout_ms: 3000     # wall clock time of a call
+//!       cpu_time_ms: 1000    # cpu time of a call
+//!       max_memory_mb: 256
+//! ```
+//!
+//! - Wasm: instances are shared by the functions of an app, so the linear memory of an instance is
+//!   capped by the largest `max_memory_mb` of them and each call is checked against its own.
+//!   The cpu time and the timeout are turned into an instruction budget, so busy loops are stopped
+//!   by the vm.
+//! - Jar: the app process is sampled while the call runs and the call fails once it goes over the
+//!   limits. The process serves all functions of the app, its usage during the call is accounted to
+//!   the call. A call can't be stopped alone inside it, so the process is killed with the other
+//!   calls running on it and the next call starts a new one.
+//! - Native: functions run inside the worker, only `timeout_ms` and `cpu_time_ms` apply.
+//!   Sync calls run on their own thread and are asked to stop through [`SyncStop`] once over the
+//!   limits, a call not returning within [`SYNC_STOP_GRACE`] is left behind.
+use crate::result::WsFuncError;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Instructions of the wasm interpreter taken as one millisecond of cpu time.
+pub const WASM_INSTRS_PER_CPU_MS: u64 = 100_000;
+
+/// Interval of sampling the process of a Jar app while a call runs.
+pub const PROCESS_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval of checking a sync call running on its thread.
+pub const SYNC_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time a sync call over its limits has to return once asked to stop.
+pub const SYNC_STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// Asks a sync call to stop once it went over its limits, the call checks it while it runs.
+#[derive(Debug, Clone, Default)]
+pub struct SyncStop(Arc<AtomicBool>);
+
+impl SyncStop {
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FnLimits {
+    pub timeout_ms: Option<u64>,
+    pub cpu_time_ms: Option<u64>,
+    pub max_memory_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitViolation {
+    Timeout,
+    CpuTime,
+    Memory,
+}
+
+impl FnLimits {
+    pub fn from_yaml(v: &serde_yaml::Value) -> Result<Self, String> {
+        let map = v
+            .as_mapping()
+            .ok_or_else(|| "limits must be a map".to_owned())?;
+        let mut limits = FnLimits::default();
+        for (key, value) in map {
+            let field = match key.as_str() {
+                Some("timeout_ms") => &mut limits.timeout_ms,
+                Some("cpu_time_ms") => &mut limits.cpu_time_ms,
+                Some("max_memory_mb") => &mut limits.max_memory_mb,
+                _ => return Err(format!("unknown limit {:?}", key)),
+            };
+            match value.as_u64() {
+                Some(v) if v > 0 => *field = Some(v),
+                _ => return Err(format!("{:?} must be a positive integer", key)),
+            }
+        }
+        Ok(limits)
+    }
+
+    /// Instruction budget of a wasm call and the limit it stands for.
+    ///
+    /// The vm runs a call without yielding, so the timeout is enforced by the budget too,
+    /// taking the wall clock time of the call as cpu time.
+    pub fn wasm_instr_budget(&self) -> Option<(u64, LimitViolation)> {
+        let budget = |ms: u64| ms.saturating_mul(WASM_INSTRS_PER_CPU_MS);
+        match (self.cpu_time_ms, self.timeout_ms) {
+            (Some(cpu), Some(timeout)) if timeout < cpu => {
+                Some((budget(timeout), LimitViolation::Timeout))
+            }
+            (Some(cpu), _) => Some((budget(cpu), LimitViolation::CpuTime)),
+            (None, Some(timeout)) => Some((budget(timeout), LimitViolation::Timeout)),
+            (None, None) => None,
+        }
+    }
+
+    pub fn violated(&self, app: &str, func: &str, violation: LimitViolation) -> WsFuncError {
+        let (app, func) = (app.to_owned(), func.to_owned());
+        match violation {
+            LimitViolation::Timeout => WsFuncError::FnTimeout {
+                app,
+                func,
+                timeout_ms: self.timeout_ms.unwrap_or_default(),
+            },
+            LimitViolation::CpuTime => WsFuncError::FnCpuTimeExceeded {
+                app,
+                func,
+                cpu_time_ms: self.cpu_time_ms.unwrap_or_default(),
+            },
+            LimitViolation::Memory => WsFuncError::FnMemoryExceeded {
+                app,
+                func,
+                max_memory_mb: self.max_memory_mb.unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Check the cpu time and memory used by a call.
+    pub fn check_usage(
+        &self,
+        cpu_time: Duration,
+        memory_mb: Option<u64>,
+    ) -> Option<LimitViolation> {
+        if matches!(self.cpu_time_ms, Some(limit) if cpu_time > Duration::from_millis(limit)) {
+            return Some(LimitViolation::CpuTime);
+        }
+        match (self.max_memory_mb, memory_mb) {
+            (Some(limit), Some(used)) if used > limit => Some(LimitViolation::Memory),
+            _ => None,
+        }
+    }
+}
+
+/// Cpu time consumed by the calling thread.
+pub fn thread_cpu_time() -> Duration {
+    clock_time(libc::CLOCK_THREAD_CPUTIME_ID)
+}
+
+fn clock_time(clock: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // never fails with a valid clock id and pointer
+    let _ = unsafe { libc::clock_gettime(clock, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Runs a blocking call on its own thread and returns once it's done or over the limits.
+///
+/// A call over the limits is asked to stop and given [`SYNC_STOP_GRACE`] to return, what it
+/// returns is dropped. One that doesn't return is left running on its thread.
+pub fn run_sync_limited<T, F>(limits: &FnLimits, call: F) -> Result<T, LimitViolation>
+where
+    T: Send + 'static,
+    F: FnOnce(SyncStop) -> T + Send + 'static,
+{
+    let stop = SyncStop::default();
+    if limits.timeout_ms.is_none() && limits.cpu_time_ms.is_none() {
+        return Ok(call(stop));
+    }
+    let begin = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let handle = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(call(stop));
+        })
+    };
+    let clock = {
+        use std::os::unix::thread::JoinHandleExt;
+        let mut clock: libc::clockid_t = 0;
+        // the thread can't be reaped before it's joined, so its clock stays valid
+        let res = unsafe { libc::pthread_getcpuclockid(handle.as_pthread_t(), &mut clock) };
+        (res == 0).then_some(clock)
+    };
+    let violation = loop {
+        match rx.recv_timeout(SYNC_CHECK_INTERVAL) {
+            Ok(res) => return Ok(res),
+            Err(RecvTimeoutError::Disconnected) => match handle.join() {
+                Err(panic) => std::panic::resume_unwind(panic),
+                Ok(()) => unreachable!("the result is sent before the thread ends"),
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        if matches!(limits.timeout_ms, Some(ms) if begin.elapsed() > Duration::from_millis(ms)) {
+            break LimitViolation::Timeout;
+        }
+        if let Some(clock) = clock {
+            if let Some(violation) = limits.check_usage(clock_time(clock), None) {
+                break violation;
+            }
+        }
+    };
+
+    stop.0.store(true, Ordering::Relaxed);
+    match rx.recv_timeout(SYNC_STOP_GRACE) {
+        Err(RecvTimeoutError::Timeout) => {
+            tracing::warn!(
+                "sync call over its limits ({:?}) didn't stop, left running",
+                violation
+            );
+        }
+        // returned or panicked, either way it's over
+        _ => {
+            let _ = handle.join();
+        }
+    }
+    Err(violation)
+}
+
+/// Runs a future in this process, accumulating the cpu time of the threads polling it.
+/// Resolves to None once it goes over `limit`.
+pub struct CpuTimed<F> {
+    inner: F,
+    used: Duration,
+    limit: Option<Duration>,
+}
+
+impl<F: Future + Unpin> CpuTimed<F> {
+    pub fn new(inner: F, limit_ms: Option<u64>) -> Self {
+        Self {
+            inner,
+            used: Duration::ZERO,
+            limit: limit_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+impl<F: Future + Unpin> Future for CpuTimed<F> {
+    type Output = Option<F::Output>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let begin = thread_cpu_time();
+        let res = Pin::new(&mut self.inner).poll(cx);
+        self.used += thread_cpu_time().saturating_sub(begin);
+        if matches!(self.limit, Some(limit) if self.used > limit) {
+            return Poll::Ready(None);
+        }
+        res.map(Some)
+    }
+}
+
+/// Cpu time and resident memory of a process, read from procfs.
+pub fn process_usage(pid: u32) -> Option<(Duration, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name may contain spaces, fields are counted after it
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let ticks = if ticks > 0 { ticks as u64 } else { 100 };
+    let cpu_time = Duration::from_millis((utime + stime) * 1000 / ticks);
+
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let rss_kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some((cpu_time, rss_kb / 1024))
+}
+
+/// Samples the process while a call runs, resolves when the call goes over the limits.
+pub async fn watch_process(pid: u32, limits: &FnLimits) -> LimitViolation {
+    let Some((cpu_begin, _)) = process_usage(pid) else {
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(PROCESS_SAMPLE_INTERVAL).await;
+        let Some((cpu, memory_mb)) = process_usage(pid) else {
+            // process exited, the call will fail by itself
+            return std::future::pending().await;
+        };
+        if let Some(violation) = limits.check_usage(cpu.saturating_sub(cpu_begin), Some(memory_mb))
+        {
+            return violation;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run_sync_limited, CpuTimed, FnLimits, LimitViolation};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn test_fn_limits() {
+        let v = serde_yaml::from_str("{timeout_ms: 3000, max_memory_mb: 64}").unwrap();
+        let limits = FnLimits::from_yaml(&v).unwrap();
+        assert_eq!(limits.timeout_ms, Some(3000));
+        assert_eq!(limits.cpu_time_ms, None);
+        assert_eq!(
+            limits.check_usage(Duration::from_secs(10), Some(65)),
+            Some(LimitViolation::Memory)
+        );
+        assert_eq!(limits.check_usage(Duration::from_secs(10), Some(64)), None);
+
+        for bad in ["[1]", "{timeout_ms: 0}", "{timeout_ms: -1}", "{cpu: 1}"] {
+            let v = serde_yaml::from_str(bad).unwrap();
+            assert!(
+                FnLimits::from_yaml(&v).is_err(),
+                "{} should be rejected",
+                bad
+            );
+        }
+
+        // a busy future is stopped once over its cpu time
+        let busy = std::future::poll_fn(|cx| {
+            let begin = std::time::Instant::now();
+            while begin.elapsed() < Duration::from_millis(5) {}
+            cx.waker().wake_by_ref();
+            std::task::Poll::<()>::Pending
+        });
+        let res = futures::executor::block_on(CpuTimed::new(busy, Some(20)));
+        assert!(res.is_none());
+        let res = futures::executor::block_on(CpuTimed::new(Box::pin(async { 1 }), Some(20)));
+        assert_eq!(res, Some(1));
+
+        // the timeout is a part of the wasm budget
+        let limits = FnLimits {
+            timeout_ms: Some(10),
+            cpu_time_ms: Some(20),
+            max_memory_mb: None,
+        };
+        assert_eq!(
+            limits.wasm_instr_budget().map(|(_, limit)| limit),
+            Some(LimitViolation::Timeout)
+        );
+
+        // a blocking call over its limits is stopped before it's given up
+        let stopped = Arc::new(AtomicBool::new(false));
+        let res = run_sync_limited(&limits, {
+            let stopped = stopped.clone();
+            move |stop| {
+                while !stop.requested() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                stopped.store(true, Ordering::Relaxed);
+            }
+        });
+        assert_eq!(res, Err(LimitViolation::Timeout));
+        assert!(stopped.load(Ordering::Relaxed));
+        let limits = FnLimits {
+            cpu_time_ms: Some(20),
+            ..Default::default()
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let res = run_sync_limited(&limits, {
+            let stopped = stopped.clone();
+            move |stop| {
+                let begin = std::time::Instant::now();
+                while begin.elapsed() < Duration::from_secs(5) && !stop.requested() {}
+                stopped.store(true, Ordering::Relaxed);
+            }
+        });
+        assert_eq!(res, Err(LimitViolation::CpuTime));
+        assert!(stopped.load(Ordering::Relaxed));
+        assert_eq!(run_sync_limited(&limits, |_| 1), Ok(1));
+    }
+}
@@ -1,9 +1,16 @@
+use crate::general::app::app_owned::wasm;
 use crate::general::app::instance::m_instance_manager::InstanceManager;
 use crate::general::app::instance::m_instance_manager::UnsafeFunctionCtx;
 use crate::general::app::instance::InstanceTrait;
+use crate::general::app::instance::{Instance, OwnedInstance};
+use crate::general::app::limits::{self, CpuTimed, FnLimits, LimitViolation, SyncStop};
+use crate::general::app::version;
 use crate::general::app::AppType;
 use crate::general::app::FnMeta;
 use crate::general::data::m_data_general::DATA_UID_PREFIX_FN_KV;
+use crate::general::data::m_dist_lock::DistLock;
+use crate::general::m_metric_publisher::MetricPublisher;
+use crate::general::network::m_membership::Membership;
 use crate::general::network::m_p2p::RPCCaller;
 use crate::general::network::m_p2p::TaskId;
 use crate::general::network::proto::FnTaskId;
@@ -28,16 +35,19 @@ use crate::{
 };
 use async_trait::async_trait;
 use dashmap::DashMap;
+use rand::Rng;
 use serde::Deserialize;
 use serde::Serialize;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
+    collections::BTreeMap,
     ptr::NonNull,
-    sync::atomic::{AtomicU32, AtomicUsize},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
     time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::broadcast;
 use tokio::sync::oneshot;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 #[cfg(target_os = "linux")]
 use ws_derive::LogicalModule;
@@ -56,6 +66,12 @@ pub enum EventCtx {
         opeid: Option<u32>,
         src_task_id: proto::FnTaskId,
     },
+    Schedule {
+        tick_ms: i64,
+    },
+    KvExpire {
+        key: Vec<u8>,
+    },
 }
 
 impl EventCtx {
@@ -205,6 +221,7 @@ impl Into<AppType> for FnExeCtxSyncAllowedType {
 
 pub struct FnExeCtxSync {
     inner: FnExeCtx,
+    stop: SyncStop,
 }
 
 impl FnExeCtxSync {
@@ -229,8 +246,14 @@ impl FnExeCtxSync {
                 _func_meta: func_meta,
                 _dummy_private: (),
             },
+            stop: SyncStop::default(),
         }
     }
+
+    /// True once the call went over its limits, it's supposed to return as soon as it can.
+    pub fn stop_requested(&self) -> bool {
+        self.stop.requested()
+    }
 }
 
 // impl FnExeCtx {
@@ -254,6 +277,12 @@ logical_module_view_impl!(ExecutorView, p2p, P2PModule);
 logical_module_view_impl!(ExecutorView, appmeta_manager, AppMetaManager);
 logical_module_view_impl!(ExecutorView, instance_manager, InstanceManager);
 logical_module_view_impl!(ExecutorView, executor, Executor);
+logical_module_view_impl!(ExecutorView, metric_publisher, MetricPublisher);
+logical_module_view_impl!(ExecutorView, membership, Membership);
+logical_module_view_impl!(ExecutorView, dist_lock, DistLock);
+
+/// How long a draining node waits for its running tasks.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(LogicalModule)]
 pub struct Executor {
@@ -271,6 +300,39 @@ pub struct Executor {
     rpc_caller_listen_for_task_done: RPCCaller<proto::ListenForTaskDoneReq>,
     rpc_handler_listen_for_task_done: RPCHandler<proto::ListenForTaskDoneReq>,
     rpc_handler_add_wait_target: RPCHandler<proto::AddWaitTargetReq>,
+
+    /// set on drain, new tasks are rejected
+    draining: AtomicBool,
+    running_tasks: AtomicUsize,
+    running_tasks_done: Notify,
+    /// the tasks counted by `running_tasks`, reported failed when the drain times out on them
+    running_task_infos: DashMap<u64, RunningTaskInfo>,
+    next_running_task: AtomicU64,
+
+    /// (app, fn) -> the latest schedule tick taken, master may dispatch a tick again
+    schedule_ticks: DashMap<(String, String), i64>,
+}
+
+/// Counts a task as running until dropped.
+struct RunningTask<'a> {
+    executor: &'a Executor,
+    seq: u64,
+}
+
+struct RunningTaskInfo {
+    app: String,
+    func: String,
+    /// None for http calls, whose callers see the connection close
+    task_id: Option<FnTaskId>,
+}
+
+impl Drop for RunningTask<'_> {
+    fn drop(&mut self) {
+        let _ = self.executor.running_task_infos.remove(&self.seq);
+        if self.executor.running_tasks.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.executor.running_tasks_done.notify_waiters();
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -278,6 +340,32 @@ struct FnDataEventArg {
     src_called_by: NodeID,
     src_taskid: u32,
     trigger_data_key: String,
+    /// values captured by the key pattern binding the function
+    key_captures: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FnScheduleArg {
+    schedule_tick_ms: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FnDataExpireArg {
+    expired_data_key: String,
+    /// values captured by the key pattern binding the function
+    key_captures: BTreeMap<String, serde_json::Value>,
+}
+
+/// The data key seen by the function, non native apps don't see the fkv prefix.
+fn trigger_data_key_of(app_type: AppType, key: &[u8]) -> String {
+    let key_str = std::str::from_utf8(key).unwrap();
+    match app_type {
+        AppType::Jar | AppType::Wasm => key_str
+            .strip_prefix(DATA_UID_PREFIX_FN_KV)
+            .unwrap_or(key_str)
+            .to_string(),
+        AppType::Native => key_str.to_string(),
+    }
 }
 
 /// Base trait for function execution contexts
@@ -292,6 +380,8 @@ pub trait FnExeCtxBase {
     fn event_ctx_mut(&mut self) -> &mut EventCtx;
     /// Get fn type
     fn app_type(&self) -> AppType;
+    /// Get the function meta
+    fn func_meta(&self) -> &FnMeta;
     /// format arg to pass to function
     fn format_arg_to_pass(&self) -> String {
         match &self.event_ctx() {
@@ -299,28 +389,37 @@ pub trait FnExeCtxBase {
             EventCtx::KvSet {
                 key, src_task_id, ..
             } => {
-                let key_str = std::str::from_utf8(&key).unwrap();
-                let trigger_data_key = match self.app_type() {
-                    AppType::Jar | AppType::Wasm => {
-                        // remove prefix fkv
-                        key_str
-                            .strip_prefix(DATA_UID_PREFIX_FN_KV)
-                            .unwrap_or(key_str)
-                            .to_string()
-                    }
-                    AppType::Native => {
-                        // keep the key as is
-                        key_str.to_string()
-                    }
-                };
-
+                let trigger_data_key = trigger_data_key_of(self.app_type(), key);
+                let key_captures = self
+                    .func_meta()
+                    .triggered_by(&trigger_data_key)
+                    .and_then(|(pattern, _)| pattern.captures(&trigger_data_key))
+                    .unwrap_or_default();
                 let arg = FnDataEventArg {
+                    key_captures,
                     trigger_data_key,
                     src_called_by: src_task_id.call_node_id,
                     src_taskid: src_task_id.task_id,
                 };
                 serde_json::to_string(&arg).unwrap()
             }
+            EventCtx::Schedule { tick_ms } => serde_json::to_string(&FnScheduleArg {
+                schedule_tick_ms: *tick_ms,
+            })
+            .unwrap(),
+            EventCtx::KvExpire { key } => {
+                let expired_data_key = trigger_data_key_of(self.app_type(), key);
+                let key_captures = self
+                    .func_meta()
+                    .expiry_triggered_by(&expired_data_key)
+                    .and_then(|pattern| pattern.captures(&expired_data_key))
+                    .unwrap_or_default();
+                serde_json::to_string(&FnDataExpireArg {
+                    expired_data_key,
+                    key_captures,
+                })
+                .unwrap()
+            }
         }
     }
 }
@@ -341,6 +440,9 @@ impl FnExeCtxBase for FnExeCtxAsync {
     fn app_type(&self) -> AppType {
         self.inner.app_type
     }
+    fn func_meta(&self) -> &FnMeta {
+        &self.inner._func_meta
+    }
 }
 
 impl FnExeCtxBase for FnExeCtxSync {
@@ -359,6 +461,9 @@ impl FnExeCtxBase for FnExeCtxSync {
     fn app_type(&self) -> AppType {
         self.inner.app_type
     }
+    fn func_meta(&self) -> &FnMeta {
+        &self.inner._func_meta
+    }
 }
 
 impl Executor {
@@ -543,6 +648,13 @@ impl LogicalModule for Executor {
 
             task_subwait_by: DashMap::new(),
             task_subwait_for: DashMap::new(),
+
+            draining: AtomicBool::new(false),
+            running_tasks: AtomicUsize::new(0),
+            running_tasks_done: Notify::new(),
+            running_task_infos: DashMap::new(),
+            next_running_task: AtomicU64::new(0),
+            schedule_ticks: DashMap::new(),
         }
     }
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
@@ -552,9 +664,94 @@ impl LogicalModule for Executor {
         //     .regist_rpc::<proto::sche::ScheReq, _>();
         Ok(vec![])
     }
+    async fn shutdown(&self) -> WSResult<()> {
+        self.drain(DRAIN_TIMEOUT).await;
+        Ok(())
+    }
 }
 
 impl Executor {
+    /// Tasks running on this node, reported to master for scheduling.
+    pub fn running_task_cnt(&self) -> usize {
+        self.running_tasks.load(Ordering::SeqCst)
+    }
+
+    /// None once the node is draining.
+    fn begin_task(
+        &self,
+        app: &str,
+        func: &str,
+        task_id: Option<FnTaskId>,
+    ) -> Option<RunningTask<'_>> {
+        let _ = self.running_tasks.fetch_add(1, Ordering::SeqCst);
+        let seq = self.next_running_task.fetch_add(1, Ordering::Relaxed);
+        let _ = self.running_task_infos.insert(
+            seq,
+            RunningTaskInfo {
+                app: app.to_owned(),
+                func: func.to_owned(),
+                task_id,
+            },
+        );
+        let running = RunningTask {
+            executor: self,
+            seq,
+        };
+        // checked after counting, so the drain either sees this task or it sees the flag
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(running)
+    }
+
+    /// Reject new tasks and wait up to `timeout` for the running ones.
+    /// The ones still running are reported failed to their callers, which retry them elsewhere
+    /// instead of waiting for a node going away.
+    async fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        // leave first, so master stops scheduling to this node while the running tasks finish
+        if !self.view.p2p().nodes_config.this_is_master() {
+            let _ = self
+                .view
+                .membership()
+                .leave()
+                .await
+                .todo_handle("leave cluster on drain failed");
+        }
+        let wait_done = async {
+            loop {
+                let done = self.running_tasks_done.notified();
+                if self.running_tasks.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                done.await;
+            }
+        };
+        if tokio::time::timeout(timeout, wait_done).await.is_ok() {
+            return;
+        }
+        tracing::warn!(
+            "drain timeout, {} tasks still running",
+            self.running_tasks.load(Ordering::SeqCst)
+        );
+        let unfinished: Vec<(String, String, Option<FnTaskId>)> = self
+            .running_task_infos
+            .iter()
+            .map(|info| (info.app.clone(), info.func.clone(), info.task_id.clone()))
+            .collect();
+        for (app, func, task_id) in unfinished {
+            tracing::warn!(
+                "task {:?} of app {} fn {} unfinished on drain",
+                task_id,
+                app,
+                func
+            );
+            if let Some(task_id) = task_id {
+                self.handle_exec_result(&task_id, Err(WsFuncError::NodeDraining.into()));
+            }
+        }
+    }
+
     pub fn register_sub_task(&self) -> proto::FnTaskId {
         let taskid = self
             .sub_task_id
@@ -596,12 +793,37 @@ impl Executor {
         req: proto::DistributeTaskReq,
     ) {
         tracing::debug!("receive distribute task: {:?}", req);
+        let Some(_running) = self.begin_task(&req.app, &req.func, req.task_id.clone()) else {
+            tracing::warn!(
+                "reject distribute task of app {}, node is draining",
+                req.app
+            );
+            if let Err(err) = resp
+                .send_resp(DistributeTaskResp {
+                    success: false,
+                    err_msg: "node is draining".to_owned(),
+                })
+                .await
+            {
+                tracing::error!("send distribute task resp failed with err: {}", err);
+            }
+            return;
+        };
         // alert src to wait for this task
 
         let app = req.app.to_owned();
         let func = req.func.to_owned();
         // todo
-        let (appmeta, _) = match self.view.appmeta_manager().get_app_meta(&app).await {
+        let res = match self.route_call(&app).await {
+            Ok(routed) => self
+                .view
+                .appmeta_manager()
+                .get_app_meta(&routed)
+                .await
+                .map(|appmeta| appmeta.map(|(appmeta, _)| (routed, appmeta))),
+            Err(err) => Err(err),
+        };
+        let (routed, appmeta) = match res {
             Ok(Some(appmeta)) => appmeta,
             Ok(None) => {
                 tracing::warn!("app {} not found in data meta", app);
@@ -631,6 +853,7 @@ impl Executor {
             }
         };
 
+        let app = appmeta.versioned_name(&routed);
         let apptype = appmeta.app_type.clone();
         let Some(fnmeta) = appmeta.get_fn_meta(&func) else {
             tracing::warn!("func {} not found, exist:{:?}", func, appmeta.fns());
@@ -645,6 +868,30 @@ impl Executor {
             }
             return;
         };
+        self.view
+            .instance_manager()
+            .update_memory_cap(&app, appmeta.max_memory_mb());
+
+        if let Some(distribute_task_req::Trigger::ScheduleTick(tick)) = &req.trigger {
+            if !self.take_schedule_tick(&req.app, &req.func, tick.tick_ms) {
+                tracing::debug!(
+                    "schedule tick {} of {}/{} taken before",
+                    tick.tick_ms,
+                    req.app,
+                    req.func
+                );
+                if let Err(err) = resp
+                    .send_resp(DistributeTaskResp {
+                        success: true,
+                        err_msg: "".to_owned(),
+                    })
+                    .await
+                {
+                    tracing::error!("send distribute task resp failed with err: {}", err);
+                }
+                return;
+            }
+        }
 
         //费新文
         // distribute task requires sync support
@@ -736,7 +983,7 @@ impl Executor {
                         return;
                     }
                 },
-                req.app,
+                app.clone(),
                 req.func,
                 fnmeta.clone(),
                 req.task_id.unwrap(), // as TaskId,
@@ -751,6 +998,12 @@ impl Executor {
                         opeid: Some(write.opeid),
                         src_task_id: req.trigger_src_task_id.unwrap(),
                     },
+                    distribute_task_req::Trigger::ScheduleTick(tick) => EventCtx::Schedule {
+                        tick_ms: tick.tick_ms,
+                    },
+                    distribute_task_req::Trigger::EventExpire(expire) => {
+                        EventCtx::KvExpire { key: expire.key }
+                    }
                 },
             );
 
@@ -793,7 +1046,7 @@ impl Executor {
                         return;
                     }
                 },
-                req.app,
+                app.clone(),
                 req.func,
                 fnmeta.clone(),
                 req.task_id.unwrap(),
@@ -808,6 +1061,12 @@ impl Executor {
                         opeid: Some(write.opeid),
                         src_task_id: req.trigger_src_task_id.unwrap(),
                     },
+                    distribute_task_req::Trigger::ScheduleTick(tick) => EventCtx::Schedule {
+                        tick_ms: tick.tick_ms,
+                    },
+                    distribute_task_req::Trigger::EventExpire(expire) => {
+                        EventCtx::KvExpire { key: expire.key }
+                    }
                 },
             );
 
@@ -832,7 +1091,55 @@ impl Executor {
         }
     }
 
+    /// Whether the schedule tick is new, the ticks of a function only grow.
+    fn take_schedule_tick(&self, app: &str, func: &str, tick_ms: i64) -> bool {
+        let mut last = self
+            .schedule_ticks
+            .entry((app.to_owned(), func.to_owned()))
+            .or_insert(i64::MIN);
+        if *last >= tick_ms {
+            return false;
+        }
+        *last = tick_ms;
+        true
+    }
+
+    /// Name of the app a call of `app` runs as, see [`version`](crate::general::app::version).
+    /// Calls not pinned to a version run the active one, or the canary by its share.
+    async fn route_call(&self, app: &str) -> WSResult<String> {
+        let appmeta_manager = self.view.appmeta_manager();
+        if version::split_versioned_app(app).1.is_some()
+            || appmeta_manager.native_apps.contains_key(app)
+        {
+            return Ok(app.to_owned());
+        }
+        let Some(versions) = appmeta_manager.get_routing_versions(app).await? else {
+            // not found by the caller
+            return Ok(app.to_owned());
+        };
+        self.view
+            .instance_manager()
+            .drain_unrouted_versions(app, &versions);
+        let version = versions.route(rand::thread_rng().gen_range(0..100));
+        if version == versions.active {
+            // the meta of the active version is in the dataset of the plain app name
+            Ok(app.to_owned())
+        } else {
+            Ok(version::versioned_app(app, version))
+        }
+    }
+
+    /// Per version call metrics, a canary is judged by them before it's promoted.
+    fn record_call(&self, app: &str, begin: Instant, success: bool) {
+        if let (app, Some(version)) = version::split_versioned_app(app) {
+            self.view
+                .metric_publisher()
+                .record_app_call(app, version, success, begin.elapsed());
+        }
+    }
+
     /// before call this, verify app and func exist
+    /// `appname` may be pinned to a version as `<app>@v<version>`
     pub async fn handle_http_task(
         &self,
         appname: &str,
@@ -843,10 +1150,15 @@ impl Executor {
         //     .next_req_id
         //     .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
+        let Some(_running) = self.begin_task(appname, funcname, None) else {
+            return Err(WsFuncError::NodeDraining.into());
+        };
+
         // check app exist
-        tracing::debug!("calling get_app_meta to check app exist, app: {}", appname);
+        let routed = self.route_call(appname).await?;
+        tracing::debug!("calling get_app_meta to check app exist, app: {}", routed);
         let Some((appmeta, datameta_opt)) =
-            self.view.appmeta_manager().get_app_meta(appname).await?
+            self.view.appmeta_manager().get_app_meta(&routed).await?
         else {
             tracing::warn!("app {} not found", appname);
             return Err(WsFuncError::AppNotFound {
@@ -864,11 +1176,18 @@ impl Executor {
             .into());
         };
 
+        let versioned = appmeta.versioned_name(&routed);
+        self.view
+            .instance_manager()
+            .update_memory_cap(&versioned, appmeta.max_memory_mb());
+
         // get app file and extract to execute dir
         if let Some(datameta) = datameta_opt {
+            // the files are in the dataset of the version, not the one of the plain app name
+            let datameta = (versioned == routed).then_some(datameta);
             self.view
                 .appmeta_manager()
-                .load_app_file(appname, datameta)
+                .load_app_file(&versioned, datameta)
                 .await
                 .map_err(|e| {
                     tracing::error!("load app file failed with err: {}", e);
@@ -904,7 +1223,7 @@ impl Executor {
         let res = if func.sync_async.asyncable() {
             let ctx = FnExeCtxAsync::new(
                 FnExeCtxAsyncAllowedType::try_from(appmeta.app_type.clone()).unwrap(),
-                appname.to_owned(),
+                versioned.clone(),
                 funcname.to_owned(),
                 func.clone(),
                 task_id.clone(),
@@ -914,7 +1233,7 @@ impl Executor {
         } else {
             let ctx = FnExeCtxSync::new(
                 FnExeCtxAsyncAllowedType::try_from(appmeta.app_type.clone()).unwrap(),
-                appname.to_owned(),
+                versioned.clone(),
                 funcname.to_owned(),
                 func.clone(),
                 task_id.clone(),
@@ -961,21 +1280,18 @@ impl Executor {
     // }
 
     fn execute_sync(&self, mut ctx: FnExeCtxSync) -> WSResult<Option<String>> {
-        let instance = self
+        let call_begin = Instant::now();
+        let instance = match self
             .view
             .instance_manager()
-            .load_instance_sync(&ctx.inner.app_type, &ctx.inner.app)?;
-
-        let _ = self
-            .view
-            .instance_manager()
-            .instance_running_function
-            .insert(
-                instance.instance_name().to_owned(),
-                UnsafeFunctionCtx::Sync(
-                    NonNull::new(&ctx as *const FnExeCtxSync as *mut FnExeCtxSync).unwrap(),
-                ),
-            );
+            .load_instance_sync(&ctx.inner.app_type, &ctx.inner.app)
+        {
+            Ok(instance) => instance,
+            Err(err) => {
+                self.record_call(&ctx.inner.app, call_begin, false);
+                return Err(err);
+            }
+        };
 
         tracing::debug!(
             "start run sync instance {} app {} fn {}",
@@ -990,40 +1306,72 @@ impl Executor {
             .as_millis() as u64;
 
         tracing::debug!("start execute sync");
-        let res = instance.execute_sync(self.view.instance_manager(), &mut ctx)?;
-
-        let res = res.map(|v| {
-            let mut res: serde_json::Value = serde_json::from_str(&*v).unwrap();
-            let _ = res.as_object_mut().unwrap().insert(
-                "bf_exec_time".to_owned(),
-                serde_json::Value::from(bf_exec_time),
+        let limits = ctx.func_meta().limits.clone();
+        let app = ctx.inner.app.clone();
+        let func = ctx.inner.func.clone();
+        let taskid = ctx.inner.task_id.clone();
+        let view = self.view.clone();
+        // sync functions block, they run on their own thread to be stopped once over the limits
+        let call = move |stop| {
+            ctx.stop = stop;
+            let instman = view.instance_manager();
+            let _ = instman.instance_running_function.insert(
+                instance.instance_name().to_owned(),
+                UnsafeFunctionCtx::Sync(
+                    NonNull::new(&ctx as *const FnExeCtxSync as *mut FnExeCtxSync).unwrap(),
+                ),
             );
-            serde_json::to_string(&res).unwrap()
+            let res = instance.execute_sync(instman, &mut ctx);
+            let _ = instman
+                .instance_running_function
+                .remove(&instance.instance_name());
+            (res, instance)
+        };
+        let res = match limits::run_sync_limited(&limits, call) {
+            Ok((res, instance)) => {
+                tracing::debug!(
+                    "finish run sync instance {} fn {}, res:{:?}",
+                    instance.instance_name(),
+                    func,
+                    res
+                );
+                self.view
+                    .instance_manager()
+                    .finish_using(&app, instance, true);
+                res
+            }
+            Err(violation) => {
+                tracing::warn!(
+                    "app {} fn {} went over its limits: {:?}",
+                    app,
+                    func,
+                    violation
+                );
+                Err(limits.violated(&app, &func, violation).into())
+            }
+        };
+        self.record_call(&app, call_begin, res.is_ok());
+        let view = self.view.clone();
+        let _ = tokio::spawn(async move {
+            view.dist_lock().release_task_locks(&taskid).await;
         });
 
-        let _ = self
-            .view
-            .instance_manager()
-            .instance_running_function
-            .remove(&instance.instance_name());
-
-        tracing::debug!(
-            "finish run sync instance {} fn {}, res:{:?}",
-            instance.instance_name(),
-            ctx.inner.func,
-            res
-        );
-
-        self.view
-            .instance_manager()
-            .finish_using(&ctx.inner.app, instance);
-
-        Ok(res)
+        res.map(|res| {
+            res.map(|v| {
+                let mut res: serde_json::Value = serde_json::from_str(&*v).unwrap();
+                let _ = res.as_object_mut().unwrap().insert(
+                    "bf_exec_time".to_owned(),
+                    serde_json::Value::from(bf_exec_time),
+                );
+                serde_json::to_string(&res).unwrap()
+            })
+        })
     }
 
     /// prepare app and func before call execute
     async fn execute(&self, mut fn_ctx: FnExeCtxAsync) -> WSResult<Option<String>> {
-        let instance = self
+        let call_begin = Instant::now();
+        let mut instance = self
             .view
             .instance_manager()
             .load_instance(&fn_ctx.inner.app_type, &fn_ctx.inner.app)
@@ -1053,9 +1401,23 @@ impl Executor {
             .as_millis() as u64;
 
         tracing::debug!("start execute");
-        let res = instance
-            .execute(self.view.instance_manager(), &mut fn_ctx)
-            .await;
+        let limits = fn_ctx.func_meta().limits.clone();
+        if let Instance::Owned(OwnedInstance::WasmInstance(vm)) = &mut instance {
+            wasm::set_instr_budget(vm, limits.wasm_instr_budget().map(|(budget, _)| budget));
+        }
+        let (res, violation) = match self.execute_limited(&instance, &mut fn_ctx, &limits).await {
+            Ok(res) => (res, None),
+            Err(violation) => {
+                tracing::warn!(
+                    "app {} fn {} went over its limits: {:?}",
+                    fn_ctx.inner.app,
+                    fn_ctx.inner.func,
+                    violation
+                );
+                let err = limits.violated(&fn_ctx.inner.app, &fn_ctx.inner.func, violation);
+                (Err(err.into()), Some(violation))
+            }
+        };
 
         let res = res.map(|v| {
             v.map(|v| {
@@ -1073,6 +1435,13 @@ impl Executor {
             .instance_manager()
             .instance_running_function
             .remove(&instance.instance_name());
+        if violation.is_some() && matches!(instance, Instance::Shared(_)) {
+            // the call keeps running inside the app process until the process is gone
+            self.view
+                .instance_manager()
+                .kill_process_instance(&fn_ctx.inner.app)
+                .await;
+        }
 
         tracing::debug!(
             "finish run instance {} fn {}, res:{:?}",
@@ -1087,8 +1456,109 @@ impl Executor {
 
         self.view
             .instance_manager()
-            .finish_using(&fn_ctx.inner.app, instance);
+            .finish_using(&fn_ctx.inner.app, instance, violation.is_none());
+        self.record_call(&fn_ctx.inner.app, call_begin, res.is_ok());
+        // a crashed or killed function doesn't unlock, don't let its locks wait for the lease
+        self.view
+            .dist_lock()
+            .release_task_locks(&fn_ctx.inner.task_id)
+            .await;
 
         res
     }
+
+    /// Run the function and stop it once over its limits
+    async fn execute_limited(
+        &self,
+        instance: &Instance,
+        fn_ctx: &mut FnExeCtxAsync,
+        limits: &FnLimits,
+    ) -> Result<WSResult<Option<String>>, LimitViolation> {
+        let process_pid = match instance {
+            Instance::Shared(shared) => shared.0.pid(),
+            _ => None,
+        };
+        let watch_process = async {
+            match process_pid {
+                Some(pid) if limits.cpu_time_ms.or(limits.max_memory_mb).is_some() => {
+                    limits::watch_process(pid, limits).await
+                }
+                _ => std::future::pending().await,
+            }
+        };
+        let timeout = async {
+            match limits.timeout_ms {
+                Some(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                None => std::future::pending().await,
+            }
+        };
+        // the cpu time of in process functions, the process ones are watched
+        let exec = CpuTimed::new(
+            instance.execute(self.view.instance_manager(), fn_ctx),
+            limits.cpu_time_ms,
+        );
+        let res = tokio::select! {
+            res = exec => res.ok_or(LimitViolation::CpuTime)?,
+            violation = watch_process => return Err(violation),
+            _ = timeout => return Err(LimitViolation::Timeout),
+        };
+
+        if let Instance::Owned(OwnedInstance::WasmInstance(vm)) = instance {
+            if let Err(WSError::WsFuncError(WsFuncError::WasmError(err))) = &res {
+                if wasm::is_instr_budget_exceeded(err) {
+                    return Err(limits
+                        .wasm_instr_budget()
+                        .map_or(LimitViolation::CpuTime, |(_, limit)| limit));
+                }
+            }
+            if let (Some(limit), Some(used)) = (limits.max_memory_mb, wasm::memory_mb(vm)) {
+                // growing memory fails at the cap of the instance
+                if used > limit || (res.is_err() && used >= limit) {
+                    return Err(LimitViolation::Memory);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExecutorView;
+    use crate::general::{network::proto, test_utils};
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = ExecutorView::new(sys2);
+        let executor = view.executor();
+
+        let task_id = proto::FnTaskId {
+            call_node_id: 0,
+            task_id: u32::MAX,
+        };
+        let running = executor
+            .begin_task("app", "fn", Some(task_id.clone()))
+            .unwrap();
+        let mut done = executor
+            .task_subwait_by
+            .entry(task_id)
+            .or_insert_with(|| broadcast::channel(16).0)
+            .subscribe();
+
+        // new tasks are rejected, the unfinished one is reported failed to its caller
+        executor.drain(Duration::from_millis(100)).await;
+        assert!(executor.begin_task("app", "fn", None).is_none());
+        let res = tokio::time::timeout(Duration::from_secs(1), done.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(res.starts_with("err:"), "{}", res);
+
+        drop(running);
+        assert_eq!(executor.running_task_cnt(), 0);
+    }
 }
@@ -1,19 +1,30 @@
 pub mod app_native;
 pub mod app_owned;
 pub mod app_shared;
+pub mod condition;
 mod http;
 pub mod instance;
+mod key_pattern;
+pub mod limits;
 pub mod m_executor;
+pub mod schedule;
 pub mod v_os;
+pub mod version;
 
-use super::data::m_data_general::{DataSetMetaV2, GetOrDelDataArg, GetOrDelDataArgType};
+use super::data::m_data_general::{
+    DataSetMetaV2, DataWriteCond, GetOrDelDataArg, GetOrDelDataArgType,
+};
 use super::data::m_kv_user_client::KvUserClient;
 use super::m_os::APPS_REL_DIR;
 use crate::general::app::app_native::native_apps;
+use crate::general::app::condition::TriggerCondition;
 use crate::general::app::instance::m_instance_manager::InstanceManager;
+use crate::general::app::limits::FnLimits;
 use crate::general::app::m_executor::Executor;
 use crate::general::app::m_executor::FnExeCtxAsyncAllowedType;
+use crate::general::app::schedule::FnSchedule;
 use crate::general::app::v_os::AppMetaVisitOs;
+use crate::general::app::version::AppVersions;
 use crate::general::data::m_data_general::dataitem::DataItemArgWrapper;
 use crate::general::network::proto_ext::ProtoExtDataItem;
 use crate::util::VecExt;
@@ -34,20 +45,22 @@ use crate::{
     result::{WSError, WsDataError},
 };
 use crate::{
+    config::NodeConfig,
     logical_module_view_impl,
     master::m_master::Master,
-    result::{WSResult, WsFuncError},
+    result::{WSResult, WsFormatErr, WsFuncError},
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
     util::{self, JoinHandleWrapper},
 };
 use async_trait::async_trait;
 use axum::body::Bytes;
+use bincode::Options;
 use enum_as_inner::EnumAsInner;
+pub use key_pattern::KeyPattern;
 use m_executor::FnExeCtxSyncAllowedType;
 use parking_lot::Mutex;
 use serde::{de::Error, Deserialize, Deserializer, Serialize};
-use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
     borrow::Borrow,
     collections::{BTreeMap, HashMap},
@@ -143,95 +156,173 @@ pub struct FnMetaYaml {
     pub calls: Vec<FnCallMeta>,
     pub kvs: Option<BTreeMap<String, Vec<serde_yaml::Value>>>,
     pub affinity: Option<AffinityYaml>,
+    pub anti_affinity: Option<AntiAffinityYaml>,
+    pub schedule: Option<FnSchedule>,
+    pub limits: FnLimits,
 }
 
-impl<'de> Deserialize<'de> for FnMetaYaml {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let mut map = serde_yaml::Value::deserialize(deserializer)?;
-        let map = map
-            .as_mapping_mut()
-            .ok_or_else(|| D::Error::custom("not a map"))?;
+/// A problem found when validating app.yaml, `path` locates the field like `fns.handle.kvs.user_{}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppMetaProblem {
+    pub path: String,
+    pub msg: String,
+}
 
-        let mut calls = vec![];
+impl AppMetaProblem {
+    fn new(path: impl Into<String>, msg: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            msg: msg.into(),
+        }
+    }
 
-        // Helper block for parsing HTTP call configuration from YAML
-        // This block encapsulates the logic for extracting and validating HTTP call parameters
-        let parse_http_call = |v: &serde_yaml::Value| -> Result<HttpCall, D::Error> {
-            let map = v
-                .as_mapping()
-                .ok_or_else(|| D::Error::custom("not a map"))?;
-            let call = map
-                .get("call")
-                .ok_or_else(|| D::Error::missing_field("call"))?;
-            let call = call
-                .as_str()
-                .ok_or_else(|| D::Error::custom("not a string"))?;
-            match call {
-                "direct" => Ok(HttpCall::Direct),
-                "indirect" => Ok(HttpCall::Indirect),
-                _ => Err(D::Error::custom("invalid call type")),
-            }
+    /// Make the path relative to `parent`
+    fn under(mut self, parent: &str) -> Self {
+        self.path = if self.path.is_empty() {
+            parent.to_owned()
+        } else {
+            format!("{}.{}", parent, self.path)
         };
+        self
+    }
+}
 
-        if let Some(v) = map.get("http.get") {
-            let call = parse_http_call(v)?;
-            calls.push(FnCallMeta::Http {
-                method: HttpMethod::Get,
-                call,
-            });
-        }
-        if let Some(v) = map.get("http.post") {
-            let call = parse_http_call(v)?;
-            calls.push(FnCallMeta::Http {
-                method: HttpMethod::Post,
-                call,
-            });
+impl std::fmt::Display for AppMetaProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.msg)
+    }
+}
+
+impl FnMetaYaml {
+    /// Parse a function of app.yaml, problems have paths relative to the function.
+    fn parse(v: serde_yaml::Value) -> Result<Self, Vec<AppMetaProblem>> {
+        let serde_yaml::Value::Mapping(mut map) = v else {
+            return Err(vec![AppMetaProblem::new("", "function must be a map")]);
+        };
+        let mut problems = vec![];
+
+        let mut calls = vec![];
+        for (key, method) in [
+            ("http.get", HttpMethod::Get),
+            ("http.post", HttpMethod::Post),
+        ] {
+            let Some(v) = map.remove(key) else {
+                continue;
+            };
+            let call = match v.get("call").and_then(|call| call.as_str()) {
+                Some("direct") => HttpCall::Direct,
+                Some("indirect") => HttpCall::Indirect,
+                _ => {
+                    problems.push(AppMetaProblem::new(
+                        format!("{}.call", key),
+                        "must be 'direct' or 'indirect'",
+                    ));
+                    continue;
+                }
+            };
+            calls.push(FnCallMeta::Http { method, call });
         }
-        if let Some(_v) = map.get("rpc") {
+        if map.remove("rpc").is_some() {
             calls.push(FnCallMeta::Rpc);
         }
 
-        let kvs = map.remove("kvs");
-        let kvs = if let Some(kvs) = kvs {
-            serde_yaml::from_value(kvs).map_err(|e| D::Error::custom(e.to_string()))?
-        } else {
-            None
+        let kvs = match map.remove("kvs").map(serde_yaml::from_value) {
+            Some(Ok(kvs)) => kvs,
+            Some(Err(e)) => {
+                problems.push(AppMetaProblem::new(
+                    "kvs",
+                    format!("must be a map from key pattern to ops, {}", e),
+                ));
+                None
+            }
+            None => None,
+        };
+
+        let sync = match map.remove("sync") {
+            Some(sync) => match sync.as_str() {
+                Some(sync @ ("sync" | "async")) => Some(sync.to_owned()),
+                _ => {
+                    problems.push(AppMetaProblem::new("sync", "must be 'sync' or 'async'"));
+                    None
+                }
+            },
+            None => None,
         };
 
-        let sync = if let Some(sync) = map.get("sync") {
-            let sync = sync
-                .as_str()
-                .ok_or_else(|| D::Error::custom("sync value must be a string"))?;
-            match sync {
-                "sync" | "async" => Some(sync.to_string()),
-                _ => return Err(D::Error::custom("sync value must be 'sync' or 'async'")),
+        let affinity = match map.remove("affinity").map(serde_yaml::from_value) {
+            Some(Ok(affinity)) => affinity,
+            Some(Err(e)) => {
+                problems.push(AppMetaProblem::new("affinity", e.to_string()));
+                None
             }
-        } else {
-            None
+            None => None,
         };
 
-        let affinity = map.remove("affinity");
-        let affinity = if let Some(affinity) = affinity {
-            serde_yaml::from_value(affinity).map_err(|e| D::Error::custom(e.to_string()))?
-        } else {
-            None
+        let anti_affinity = match map.remove("anti_affinity").map(serde_yaml::from_value) {
+            Some(Ok(anti_affinity)) => anti_affinity,
+            Some(Err(e)) => {
+                problems.push(AppMetaProblem::new("anti_affinity", e.to_string()));
+                None
+            }
+            None => None,
+        };
+
+        let schedule = match map.remove("schedule") {
+            Some(schedule) => match FnSchedule::from_yaml(&schedule) {
+                Ok(schedule) => Some(schedule),
+                Err(e) => {
+                    problems.push(AppMetaProblem::new("schedule", e));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let limits = match map.remove("limits") {
+            Some(limits) => FnLimits::from_yaml(&limits).unwrap_or_else(|e| {
+                problems.push(AppMetaProblem::new("limits", e));
+                FnLimits::default()
+            }),
+            None => FnLimits::default(),
         };
 
+        for (key, _) in map {
+            tracing::warn!("unknown function field {:?} in app.yaml is ignored", key);
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
         tracing::debug!("FnMetaYaml constructed, calls:{:?}", calls);
         Ok(Self {
             calls,
             kvs,
             sync,
             affinity,
+            anti_affinity,
+            schedule,
+            limits,
         })
     }
 }
 
-#[derive(Hash, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub struct KeyPattern(pub String);
+impl<'de> Deserialize<'de> for FnMetaYaml {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = serde_yaml::Value::deserialize(deserializer)?;
+        Self::parse(v).map_err(|problems| {
+            D::Error::custom(
+                problems
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )
+        })
+    }
+}
 
 // #[derive(Debug, Clone, Serialize, Deserialize)]
 // pub struct KvMeta {
@@ -245,8 +336,26 @@ pub struct KeyPattern(pub String);
 pub enum DataEventTrigger {
     Write,
     New,
-    WriteWithCondition { condition: String },
-    NewWithCondition { condition: String },
+    WriteWithCondition { condition: TriggerCondition },
+    NewWithCondition { condition: TriggerCondition },
+    // the data outlived its ttl and was reclaimed
+    Expire,
+}
+
+impl DataEventTrigger {
+    pub fn condition(&self) -> Option<&TriggerCondition> {
+        match self {
+            DataEventTrigger::WriteWithCondition { condition }
+            | DataEventTrigger::NewWithCondition { condition } => Some(condition),
+            DataEventTrigger::Write | DataEventTrigger::New | DataEventTrigger::Expire => None,
+        }
+    }
+    pub fn only_new(&self) -> bool {
+        matches!(
+            self,
+            DataEventTrigger::New | DataEventTrigger::NewWithCondition { .. }
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -255,6 +364,10 @@ pub struct DataAccess {
     get: bool,
     delete: bool,
     pub event: Option<DataEventTrigger>,
+    /// keys of the pattern put by the function without a ttl expire this long after the put
+    pub ttl_ms: Option<u64>,
+    /// copies kept of each split of the keys of the pattern, whoever writes them
+    pub replicas: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -287,11 +400,17 @@ pub struct FnMeta {
     // pub args: Vec<FnArg>,
     pub data_accesses: Option<HashMap<KeyPattern, DataAccess>>,
     pub affinity: Option<AffinityRule>,
+    pub anti_affinity: Option<AntiAffinityRule>,
+    /// dispatched by master on each tick
+    pub schedule: Option<FnSchedule>,
+    pub limits: FnLimits,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AppMetaYaml {
     pub fns: HashMap<String, FnMetaYaml>,
+    /// `canary`, see [`AppMeta::canary_percent`]
+    pub canary_percent: Option<u8>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -304,38 +423,163 @@ pub enum AppType {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AppMeta {
     pub app_type: AppType,
+    /// 0 for native apps, which are not versioned
+    pub version: u64,
+    /// share of the calls the version gets as a canary when it's uploaded while another version
+    /// is active, see [`version`]
+    pub canary_percent: Option<u8>,
     pub fns: HashMap<String, FnMeta>,
     cache_contains_http_fn: Option<bool>,
 }
 
+/// `AppMeta` as stored before apps were versioned
+#[derive(Deserialize)]
+struct AppMetaNoVersion {
+    app_type: AppType,
+    fns: HashMap<String, FnMetaNoVersion>,
+    cache_contains_http_fn: Option<bool>,
+}
+
+/// `FnMeta` as stored before apps were versioned
+#[derive(Deserialize)]
+struct FnMetaNoVersion {
+    sync_async: FnSyncAsyncSupport,
+    calls: Vec<FnCallMeta>,
+    data_accesses: Option<HashMap<KeyPattern, DataAccessNoVersion>>,
+    affinity: Option<AffinityRule>,
+}
+
+/// `DataAccess` as stored before apps were versioned
+#[derive(Deserialize)]
+struct DataAccessNoVersion {
+    set: bool,
+    get: bool,
+    delete: bool,
+    event: Option<DataEventTrigger>,
+}
+
+impl From<AppMetaNoVersion> for AppMeta {
+    fn from(m: AppMetaNoVersion) -> Self {
+        let fns = m
+            .fns
+            .into_iter()
+            .map(|(name, f)| {
+                let data_accesses = f.data_accesses.map(|accesses| {
+                    accesses
+                        .into_iter()
+                        .map(|(pattern, access)| {
+                            let access = DataAccess {
+                                set: access.set,
+                                get: access.get,
+                                delete: access.delete,
+                                event: access.event,
+                                ttl_ms: None,
+                                replicas: None,
+                            };
+                            (pattern, access)
+                        })
+                        .collect()
+                });
+                let f = FnMeta {
+                    sync_async: f.sync_async,
+                    calls: f.calls,
+                    data_accesses,
+                    affinity: f.affinity,
+                    anti_affinity: None,
+                    schedule: None,
+                    limits: FnLimits::default(),
+                };
+                (name, f)
+            })
+            .collect();
+        Self {
+            app_type: m.app_type,
+            version: 0,
+            canary_percent: None,
+            fns,
+            cache_contains_http_fn: m.cache_contains_http_fn,
+        }
+    }
+}
+
 impl AppMeta {
+    /// Decode a stored meta, including the ones stored before apps were versioned.
+    pub fn decode(bytes: &[u8]) -> bincode::Result<Self> {
+        // the layout of `bincode::serialize`, with all the bytes taken so that an old meta
+        //  isn't misread as a new one
+        let options = bincode::DefaultOptions::new().with_fixint_encoding();
+        options.deserialize::<AppMeta>(bytes).or_else(|err| {
+            options
+                .deserialize::<AppMetaNoVersion>(bytes)
+                .map(AppMeta::from)
+                .map_err(|_| err)
+        })
+    }
+
     pub fn new(app_type: AppType, fns: HashMap<String, FnMeta>) -> Self {
         Self {
             app_type,
+            version: 0,
+            canary_percent: None,
             fns,
             cache_contains_http_fn: None,
         }
     }
 
+    /// Name of the instances and the app dir of this version,
+    /// `app` is the plain app name or the versioned one.
+    pub fn versioned_name(&self, app: &str) -> String {
+        if self.version == 0 {
+            return app.to_owned();
+        }
+        version::versioned_app(version::split_versioned_app(app).0, self.version)
+    }
+
     pub async fn new_from_yaml(
         metayaml: AppMetaYaml,
         app_name: &str,
         meta_fs: &AppMetaVisitOs,
     ) -> WSResult<Self> {
         let app_type = meta_fs.get_app_type(app_name).await?;
-        let fns = metayaml
-            .fns
-            .into_iter()
-            .map(|(fnname, fnmeta)| {
-                let fnmeta = (app_type, fnmeta).into();
-                (fnname, fnmeta)
-            })
-            .collect();
-        Ok(Self {
+        let mut problems = vec![];
+        let mut fns = HashMap::new();
+        for (fnname, fnmeta) in metayaml.fns {
+            match FnMeta::try_from((app_type, fnmeta)) {
+                Ok(fnmeta) => {
+                    let _ = fns.insert(fnname, fnmeta);
+                }
+                Err(fn_problems) => problems.extend(
+                    fn_problems
+                        .into_iter()
+                        .map(|p| p.under(&format!("fns.{}", fnname))),
+                ),
+            }
+        }
+        let meta = Self {
             app_type,
+            version: version::split_versioned_app(app_name).1.unwrap_or(0),
+            canary_percent: metayaml.canary_percent,
             fns,
             cache_contains_http_fn: None,
-        })
+        };
+        if !problems.is_empty() {
+            return Err(WsFuncError::InvalidAppMeta {
+                app: app_name.to_owned(),
+                problems,
+            }
+            .into());
+        }
+        Ok(meta)
+    }
+    /// Memory cap of the instances shared by all functions of the app,
+    /// None when any of them is unlimited.
+    pub fn max_memory_mb(&self) -> Option<u64> {
+        self.fns
+            .values()
+            .map(|fnmeta| fnmeta.limits.max_memory_mb)
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .max()
     }
     pub fn fns(&self) -> Vec<String> {
         self.fns.iter().map(|(fnname, _)| fnname.clone()).collect()
@@ -386,19 +630,74 @@ pub struct AppMetas {
 // }
 
 impl AppMetaYaml {
-    pub fn read(apps_dir: impl AsRef<Path>, appname: &str) -> AppMetaYaml {
-        let file_path = apps_dir.as_ref().join(format!("{}/app.yaml", appname));
-        let file = std::fs::File::open(file_path).unwrap_or_else(|err| {
-            tracing::debug!("open config file failed, err: {:?}", err);
+    /// Parse app.yaml, all problems of it are reported at once by `WsFuncError::InvalidAppMeta`.
+    pub fn parse(app: &str, content: &str) -> WSResult<AppMetaYaml> {
+        let yaml: serde_yaml::Value =
+            serde_yaml::from_str(content).map_err(WsFuncError::AppPackConfDecodeErr)?;
+        let mut problems = vec![];
+        let mut fns = HashMap::new();
+        match yaml.get("fns").map(|fns| fns.as_mapping()) {
+            None => problems.push(AppMetaProblem::new("fns", "missing")),
+            Some(None) => problems.push(AppMetaProblem::new("fns", "must be a map of functions")),
+            Some(Some(fns_yaml)) => {
+                for (fnname, fnyaml) in fns_yaml {
+                    let Some(fnname) = fnname.as_str() else {
+                        problems.push(AppMetaProblem::new(
+                            "fns",
+                            format!("function name {:?} is not a string", fnname),
+                        ));
+                        continue;
+                    };
+                    match FnMetaYaml::parse(fnyaml.clone()) {
+                        Ok(fnmeta) => {
+                            let _ = fns.insert(fnname.to_owned(), fnmeta);
+                        }
+                        Err(fn_problems) => problems.extend(
+                            fn_problems
+                                .into_iter()
+                                .map(|p| p.under(&format!("fns.{}", fnname))),
+                        ),
+                    }
+                }
+            }
+        }
+        let canary_percent = match yaml.get("canary") {
+            None => None,
+            Some(canary) => match canary.as_u64() {
+                Some(percent @ 1..=99) => Some(percent as u8),
+                _ => {
+                    problems.push(AppMetaProblem::new(
+                        "canary",
+                        "must be a percent between 1 and 99",
+                    ));
+                    None
+                }
+            },
+        };
+        if !problems.is_empty() {
+            return Err(WsFuncError::InvalidAppMeta {
+                app: app.to_owned(),
+                problems,
+            }
+            .into());
+        }
+        Ok(AppMetaYaml {
+            fns,
+            canary_percent,
+        })
+    }
 
-            let file_path = apps_dir.as_ref().join(format!("{}/app.yml", appname));
-            std::fs::File::open(file_path).unwrap_or_else(|err| {
-                panic!("open config file failed, err: {:?}", err);
-            })
+    pub fn read(apps_dir: impl AsRef<Path>, appname: &str) -> WSResult<AppMetaYaml> {
+        let app_dir = apps_dir.as_ref().join(appname);
+        let content = fs::read_to_string(app_dir.join("app.yaml")).or_else(|err| {
+            tracing::debug!("open config file failed, err: {:?}", err);
+            fs::read_to_string(app_dir.join("app.yml"))
         });
-        serde_yaml::from_reader(file).unwrap_or_else(|e| {
-            panic!("parse yaml config file failed, err: {:?}", e);
-        })
+        let content = content.map_err(|err| {
+            tracing::warn!("app pack conf read invalid, {:?}", err);
+            WsFuncError::AppPackConfReadInvalid(err)
+        })?;
+        Self::parse(appname, &content)
     }
     // // return true if key set is valid
     // pub fn check_key_set(&self, key: &str) -> bool {
@@ -426,52 +725,60 @@ impl FnMeta {
             _ => None,
         })
     }
-}
 
-impl KeyPattern {
-    pub fn new(input: String) -> Self {
-        Self(input)
+    /// The pattern and event by which writing the key triggers the function, the most specific pattern wins.
+    pub fn triggered_by(&self, key: &str) -> Option<(&KeyPattern, &DataEventTrigger)> {
+        self.most_specific_access(key, |access| {
+            access
+                .event
+                .as_ref()
+                .filter(|event| !matches!(event, DataEventTrigger::Expire))
+        })
     }
-    // match {} for any words
-    // "xxxx_{}_{}" matches "xxxx_abc_123"
-    // "xxxx{}{}" matches "xxxxabc123"
-    pub fn match_key(&self, key: &str) -> bool {
-        let re = self.0.replace("{}", "[a-zA-Z0-9]+");
-        // let pattern_len = re.len();
-        // tracing::info!("len:{}", re.len());
-        let re = regex::Regex::new(&re).unwrap();
-        if let Some(len) = re.find(key) {
-            tracing::info!(
-                "match key: {} with pattern: {} with len {} {} ",
-                key,
-                self.0,
-                len.len(),
-                key.len()
-            );
-            len.len() == key.len()
-        } else {
-            tracing::info!("not match key: {} with pattern: {}", key, self.0);
-            false
-        }
+
+    /// The pattern by which the key expiring triggers the function, the most specific pattern wins.
+    pub fn expiry_triggered_by(&self, key: &str) -> Option<&KeyPattern> {
+        self.most_specific_access(key, |access| {
+            access
+                .event
+                .as_ref()
+                .filter(|event| matches!(event, DataEventTrigger::Expire))
+        })
+        .map(|(pattern, _)| pattern)
     }
-    // pub fn matcher(&self) -> String {
 
-    //     // let re = Regex::new(r"(.+)\{\}").unwrap();
+    /// Ttl of the key when the function puts it without one, from the most specific pattern having one.
+    pub fn default_ttl_ms(&self, key: &str) -> Option<u64> {
+        self.most_specific_access(key, |access| access.ttl_ms.as_ref())
+            .map(|(_, ttl_ms)| *ttl_ms)
+    }
 
-    //     // if let Some(captured) = re.captures(&*self.0) {
-    //     //     if let Some(capture_group) = captured.get(1) {
-    //     //         let result = capture_group.as_str();
-    //     //         // println!("Result: {}", result);
-    //     //         return result.to_owned();
-    //     //     }
-    //     // }
+    /// Copies kept of the key, from the most specific pattern having a replica count.
+    pub fn replicas(&self, key: &str) -> Option<u32> {
+        self.most_specific_access(key, |access| access.replicas.as_ref())
+            .map(|(_, replicas)| *replicas)
+    }
 
-    //     // self.0.clone()
-    // }
+    fn most_specific_access<'a, T>(
+        &'a self,
+        key: &str,
+        pick: impl Fn(&'a DataAccess) -> Option<&'a T>,
+    ) -> Option<(&'a KeyPattern, &'a T)> {
+        self.data_accesses
+            .as_ref()?
+            .iter()
+            .filter_map(|(pattern, access)| Some((pattern, pick(access)?)))
+            .filter(|(pattern, _)| pattern.match_key(key))
+            .max_by_key(|(pattern, _)| pattern.literal_prefix().len())
+    }
 }
 
-impl From<(AppType, FnMetaYaml)> for FnMeta {
-    fn from((app_type, yaml): (AppType, FnMetaYaml)) -> Self {
+impl TryFrom<(AppType, FnMetaYaml)> for FnMeta {
+    /// problems with paths relative to the function
+    type Error = Vec<AppMetaProblem>;
+    fn try_from((app_type, yaml): (AppType, FnMetaYaml)) -> Result<Self, Self::Error> {
+        let mut problems = vec![];
+
         let sync_or_async = yaml.sync.as_deref().map(|s| s == "sync").unwrap_or(true);
 
         // if sync but not allowed, set sync_or_async to false
@@ -501,12 +808,8 @@ impl From<(AppType, FnMetaYaml)> for FnMeta {
             let tags = affinity_yaml
                 .tags
                 .unwrap_or_else(|| vec!["worker".to_string()])
-                .into_iter()
-                .map(|tag| match tag.as_str() {
-                    "worker" => NodeTag::Worker,
-                    "master" => NodeTag::Master,
-                    custom => NodeTag::Custom(custom.to_string()),
-                })
+                .iter()
+                .map(|tag| NodeTag::parse(tag))
                 .collect();
 
             let nodes = match affinity_yaml.nodes {
@@ -514,11 +817,28 @@ impl From<(AppType, FnMetaYaml)> for FnMeta {
                     if nodes_str == "*" {
                         AffinityPattern::All
                     } else if let Ok(count) = nodes_str.parse::<usize>() {
+                        if count == 0 {
+                            problems.push(AppMetaProblem::new(
+                                "affinity.nodes",
+                                "node count must be at least 1",
+                            ));
+                        }
                         AffinityPattern::NodeCount(count)
                     } else {
-                        AffinityPattern::List(
-                            nodes_str.split(',').map(|s| s.parse().unwrap()).collect(),
-                        )
+                        let mut nodes = vec![];
+                        for node in nodes_str.split(',') {
+                            match node.trim().parse() {
+                                Ok(node) => nodes.push(node),
+                                Err(_) => problems.push(AppMetaProblem::new(
+                                    "affinity.nodes",
+                                    format!(
+                                        "{:?} is not '*', a node count or a list of node ids",
+                                        nodes_str
+                                    ),
+                                )),
+                            }
+                        }
+                        AffinityPattern::List(nodes)
                     }
                 }
                 None => AffinityPattern::All,
@@ -527,83 +847,146 @@ impl From<(AppType, FnMetaYaml)> for FnMeta {
             AffinityRule { tags, nodes }
         });
 
-        Self {
-            sync_async,
-            calls: yaml.calls,
-            data_accesses: if let Some(kvs) = yaml.kvs {
-                Some(
-                    kvs.into_iter()
-                        .map(|(key, ops)| {
-                            let mut set = false;
-                            let mut get = false;
-                            let mut delete = false;
-                            let mut event = None;
-                            for op in ops {
-                                #[derive(Serialize, Deserialize)]
-                                struct TriggerWithCondition {
-                                    condition: String,
-                                }
-                                if let Some(opstr) = op.as_str() {
-                                    match opstr {
-                                        "write" | "set" => set = true,
-                                        "read" | "get" => get = true,
-                                        "delete" => delete = true,
-                                        "trigger_by_write" => {
-                                            event = Some(DataEventTrigger::Write);
-                                        }
-                                        "trigger_by_new" => {
-                                            event = Some(DataEventTrigger::New);
-                                        }
-                                        _ => {
-                                            panic!("invalid op: {:?}", op);
-                                        }
-                                    }
-                                } else if let Ok(trigger_with_condition) =
-                                    serde_yaml::from_value::<HashMap<String, TriggerWithCondition>>(
-                                        op.clone(),
-                                    )
-                                {
-                                    if trigger_with_condition.len() == 1 {
-                                        if let Some(t) =
-                                            trigger_with_condition.get("trigger_by_write")
-                                        {
-                                            event = Some(DataEventTrigger::WriteWithCondition {
-                                                condition: t.condition.clone(),
-                                            });
-                                        } else if let Some(t) =
-                                            trigger_with_condition.get("trigger_by_new")
-                                        {
-                                            event = Some(DataEventTrigger::NewWithCondition {
-                                                condition: t.condition.clone(),
-                                            });
-                                        } else {
-                                            panic!("invalid op: {:?}", op);
-                                        }
-                                    } else {
-                                        panic!("invalid op: {:?}", op);
-                                    }
+        let anti_affinity = yaml.anti_affinity.map(|anti_affinity_yaml| AntiAffinityRule {
+            tags: anti_affinity_yaml
+                .tags
+                .unwrap_or_default()
+                .iter()
+                .map(|tag| NodeTag::parse(tag))
+                .collect(),
+            instances: anti_affinity_yaml.instances.unwrap_or(false),
+        });
+
+        let data_accesses = yaml.kvs.map(|kvs| {
+            let mut data_accesses = HashMap::new();
+            for (key, ops) in kvs {
+                let path = format!("kvs.{}", key);
+                let mut set = false;
+                let mut get = false;
+                let mut delete = false;
+                let mut event = None;
+                let mut ttl_ms = None;
+                let mut replicas = None;
+                for (idx, op) in ops.into_iter().enumerate() {
+                    #[derive(Serialize, Deserialize)]
+                    struct TriggerWithCondition {
+                        condition: String,
+                    }
+                    if let Some(opstr) = op.as_str() {
+                        match opstr {
+                            "write" | "set" => set = true,
+                            "read" | "get" => get = true,
+                            "delete" => delete = true,
+                            "trigger_by_write" => {
+                                event = Some(DataEventTrigger::Write);
+                            }
+                            "trigger_by_new" => {
+                                event = Some(DataEventTrigger::New);
+                            }
+                            "trigger_by_expire" => {
+                                event = Some(DataEventTrigger::Expire);
+                            }
+                            _ => problems.push(AppMetaProblem::new(
+                                format!("{}[{}]", path, idx),
+                                format!("invalid op {:?}", opstr),
+                            )),
+                        }
+                        continue;
+                    }
+                    if let Some(ttl) = op.get("ttl") {
+                        let secs = match ttl {
+                            serde_yaml::Value::Number(n) => n.as_u64(),
+                            serde_yaml::Value::String(s) => schedule::parse_interval_secs(s),
+                            _ => None,
+                        };
+                        match secs {
+                            Some(secs) if secs > 0 => ttl_ms = Some(secs * 1000),
+                            _ => problems.push(AppMetaProblem::new(
+                                format!("{}[{}]", path, idx),
+                                format!("invalid ttl {:?}", ttl),
+                            )),
+                        }
+                        continue;
+                    }
+                    if let Some(count) = op.get("replicas") {
+                        match count.as_u64() {
+                            Some(count) if count > 0 && count <= u32::MAX as u64 => {
+                                replicas = Some(count as u32)
+                            }
+                            _ => problems.push(AppMetaProblem::new(
+                                format!("{}[{}]", path, idx),
+                                format!("invalid replicas {:?}", count),
+                            )),
+                        }
+                        continue;
+                    }
+                    let trigger_with_condition =
+                        serde_yaml::from_value::<HashMap<String, TriggerWithCondition>>(op)
+                            .ok()
+                            .filter(|t| t.len() == 1)
+                            .and_then(|mut t| {
+                                if let Some(t) = t.remove("trigger_by_write") {
+                                    Some((true, t.condition))
                                 } else {
-                                    panic!("invalid op: {:?}", op);
+                                    Some((false, t.remove("trigger_by_new")?.condition))
                                 }
+                            });
+                    match trigger_with_condition {
+                        // parsed once here, the master evaluates it on each write
+                        Some((on_write, condition)) => match TriggerCondition::parse(&condition) {
+                            Ok(condition) if on_write => {
+                                event = Some(DataEventTrigger::WriteWithCondition { condition })
+                            }
+                            Ok(condition) => {
+                                event = Some(DataEventTrigger::NewWithCondition { condition })
                             }
+                            Err(err) => problems.push(AppMetaProblem::new(
+                                format!("{}[{}]", path, idx),
+                                format!("invalid trigger condition {:?}, {}", condition, err),
+                            )),
+                        },
+                        None => problems.push(AppMetaProblem::new(
+                            format!("{}[{}]", path, idx),
+                            "must be an op, a ttl, replicas or trigger_by_write/trigger_by_new with a condition",
+                        )),
+                    }
+                }
 
-                            (
-                                KeyPattern::new(key),
-                                DataAccess {
-                                    delete,
-                                    set,
-                                    get,
-                                    event,
-                                },
-                            )
-                        })
-                        .collect(),
-                )
-            } else {
-                None
-            },
-            affinity,
+                match KeyPattern::new(key) {
+                    Ok(pattern) => {
+                        let _ = data_accesses.insert(
+                            pattern,
+                            DataAccess {
+                                delete,
+                                set,
+                                get,
+                                event,
+                                ttl_ms,
+                                replicas,
+                            },
+                        );
+                    }
+                    Err(WsFormatErr::KeyPatternFormatErr { reason, .. }) => {
+                        problems.push(AppMetaProblem::new(path, reason))
+                    }
+                    Err(err) => problems.push(AppMetaProblem::new(path, err.to_string())),
+                }
+            }
+            data_accesses
+        });
+
+        if !problems.is_empty() {
+            return Err(problems);
         }
+        Ok(Self {
+            sync_async,
+            calls: yaml.calls,
+            data_accesses,
+            affinity,
+            anti_affinity,
+            schedule: yaml.schedule,
+            limits: yaml.limits,
+        })
     }
 }
 
@@ -634,6 +1017,10 @@ pub struct AppMetaManager {
     pub fs_layer: AppMetaVisitOs,
     view: View,
     pub native_apps: HashMap<String, AppMeta>,
+    /// uploads on this node, one at a time as they unzip into the dirs of the versions they take
+    uploading: tokio::sync::Mutex<()>,
+    /// app -> versions calls are routed by, see [`AppMetaManager::get_routing_versions`]
+    routing_versions: moka::sync::Cache<String, AppVersions>,
     // app_meta_list_lock: Mutex<()>,
     #[cfg(test)]
     pub test_http_app_uploaded: Mutex<Bytes>,
@@ -662,6 +1049,10 @@ impl LogicalModule for AppMetaManager {
             view,
             fs_layer,
             native_apps: native_apps(),
+            uploading: tokio::sync::Mutex::new(()),
+            routing_versions: moka::sync::CacheBuilder::new(10000)
+                .time_to_live(version::ROUTING_VERSIONS_TTL)
+                .build(),
             #[cfg(test)]
             test_http_app_uploaded: Mutex::new(Bytes::new()), // app_meta_list_lock: Mutex::new(()),
         }
@@ -845,8 +1236,9 @@ impl AppMetaManager {
     //     }
     // }
 
-    /// get app by idx 1
-    pub async fn load_app_file(&self, app: &str, datameta: DataSetMetaV2) -> WSResult<()> {
+    /// get app by idx 1, `app` is the versioned app name
+    /// None DataSetMetaV2 means the meta is fetched from master
+    pub async fn load_app_file(&self, app: &str, datameta: Option<DataSetMetaV2>) -> WSResult<()> {
         tracing::debug!(
             "calling get_or_del_data to load app file, app: {}, datameta: {:?}",
             app,
@@ -860,7 +1252,7 @@ impl AppMetaManager {
                 .view
                 .data_general()
                 .get_or_del_datas(GetOrDelDataArg {
-                    meta: Some(datameta.clone()),
+                    meta: datameta.clone(),
                     unique_id: format!("{}{}", DATA_UID_PREFIX_APP_META, app).into(),
                     ty: GetOrDelDataArgType::PartialOne { idx: 1 },
                 })
@@ -949,7 +1341,7 @@ impl AppMetaManager {
     //     Ok()
     // }
 
-    /// get app meta by idx 0
+    /// get app meta by idx 0, of the active version for a plain app name
     /// None DataSetMetaV2 means temp app prepared
     /// Some DataSetMetaV2 means app from inner storage
     pub async fn get_app_meta(
@@ -1030,7 +1422,7 @@ impl AppMetaManager {
             .into());
         };
 
-        let meta = bincode::deserialize_from::<_, AppMeta>(Cursor::new(&metabytes));
+        let meta = AppMeta::decode(&metabytes);
         let meta = match meta {
             Err(e) => {
                 tracing::warn!(
@@ -1048,33 +1440,250 @@ impl AppMetaManager {
         Ok(Some((meta, Some(datameta))))
     }
 
-    pub async fn app_uploaded(&self, appname: String, data: Bytes) -> WSResult<()> {
-        // 1. tmpapp name & dir
-        // TODO: fobidden tmpapp public access
-        // let tmpapp = format!("tmp{}", Uuid::new_v4()); //appname.clone();
-        let tmpapp = format!("{}", appname);
-        let tmpappdir = self.fs_layer.concat_app_dir(&tmpapp);
-        let tmpapp = tmpapp.clone();
-
-        // 2. unzip app pack
-        let tmpappdir2 = tmpappdir.clone();
-        // remove old dir&app
-        if let Some(_) = self.meta.write().await.tmp_app_metas.remove(&tmpapp) {
-            tracing::debug!("remove old app meta {}", tmpapp);
+    /// Versions of the app, None if the app is not uploaded yet
+    pub async fn get_app_versions(&self, app: &str) -> WSResult<Option<AppVersions>> {
+        Ok(self
+            .get_app_versions_at(app)
+            .await?
+            .map(|(versions, _at)| versions))
+    }
+
+    /// Versions calls not pinned to a version are routed by, cached so calls don't fetch them.
+    /// Refreshed when this node switches the version or gets the updated meta of the app.
+    pub async fn get_routing_versions(&self, app: &str) -> WSResult<Option<AppVersions>> {
+        if let Some(versions) = self.routing_versions.get(app) {
+            return Ok(Some(versions));
         }
-        let ins = self.view.instance_manager().app_instances.remove(&tmpapp);
-        if let Some(ins) = ins {
-            ins.value().kill().await;
-            tracing::debug!("remove old app instance {}", tmpapp);
+        let versions = self.get_app_versions(app).await?;
+        if let Some(versions) = &versions {
+            self.routing_versions
+                .insert(app.to_owned(), versions.clone());
         }
+        Ok(versions)
+    }
 
-        if tmpappdir2.exists() {
-            // remove old app
-            fs::remove_dir_all(&tmpappdir2).unwrap();
+    pub fn invalidate_routing_versions(&self, app: &str) {
+        self.routing_versions.invalidate(app);
+    }
+
+    /// Versions of the app and the data version of the record holding them,
+    /// the record is written back only while it's still at that data version.
+    async fn get_app_versions_at(&self, app: &str) -> WSResult<Option<(AppVersions, u64)>> {
+        let res = self
+            .view
+            .data_general()
+            .get_or_del_datas(GetOrDelDataArg {
+                meta: None,
+                unique_id: format!("{}{}", DATA_UID_PREFIX_APP_META, app).into(),
+                ty: GetOrDelDataArgType::PartialOne { idx: 1 },
+            })
+            .await;
+        let datas = match res {
+            Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => return Ok(None),
+            Err(err) => return Err(err),
+            Ok((datameta, datas)) => (datameta.version, datas),
+        };
+        let (at, mut datas) = datas;
+        let bytes = match datas.remove(&1) {
+            Some(proto::DataItem {
+                data_item_dispatch: Some(proto::data_item::DataItemDispatch::RawBytes(bytes)),
+            }) => bytes,
+            // uploaded before apps were versioned, the item holds the app files, fetched by now
+            Some(proto::DataItem {
+                data_item_dispatch: Some(proto::data_item::DataItemDispatch::File(_)),
+            }) => {
+                self.store_unversioned(app).await?;
+                return Ok(Some((AppVersions::unversioned(), at)));
+            }
+            _ => {
+                return Err(WsFuncError::InvalidAppMetaDataItem {
+                    app: app.to_owned(),
+                }
+                .into())
+            }
+        };
+        bincode::deserialize(&bytes)
+            .map(|versions| Some((versions, at)))
+            .map_err(|err| {
+                tracing::warn!("app versions of {} decode failed, err: {:?}", app, err);
+                WsFuncError::InvalidAppMetaDataItem {
+                    app: app.to_owned(),
+                }
+                .into()
+            })
+    }
+
+    /// Copy an app uploaded before apps were versioned to the dataset of its version 1, so it's
+    /// kept when the dataset of the plain app name is written by a switch. Done once, by the
+    /// first node reading its versions.
+    async fn store_unversioned(&self, app: &str) -> WSResult<()> {
+        let versioned = version::versioned_app(app, 1);
+        if self
+            .view
+            .data_general()
+            .get_or_del_datameta_from_master(
+                format!("{}{}", DATA_UID_PREFIX_APP_META, versioned).as_bytes(),
+                false,
+            )
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        let appdir = self.fs_layer.concat_app_dir(app);
+        let pack = tokio::task::spawn_blocking(move || {
+            util::zip::zip_dir_2_mem(&appdir, zip::CompressionMethod::Stored)
+        })
+        .await
+        .unwrap()?;
+        let task = self.view.executor().register_sub_task();
+        match self
+            .store_app_version(app, 1, pack.into(), task.clone())
+            .await
+        {
+            Ok(_) => {
+                tracing::info!("app {} uploaded before versioning stored as version 1", app);
+            }
+            // stored by another node meanwhile
+            Err(WSError::WsDataError(WsDataError::VersionMismatch { .. })) => {}
+            Err(err) => return Err(err),
+        }
+        let _ = self.view.executor().wait_for_subtasks(&task.task_id).await;
+        Ok(())
+    }
+
+    /// Store the app pack as a new version and activate it, returns the version.
+    pub async fn app_uploaded(&self, appname: String, data: Bytes) -> WSResult<u64> {
+        // the versioned name must still be a valid app dir and data key
+        if appname.is_empty()
+            || !appname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(WsFuncError::InvalidAppName {
+                app: appname,
+                reason: "only letters, digits, '_' and '-' are allowed".to_owned(),
+            }
+            .into());
+        }
+
+        let uploaded_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as i64;
+        let task = self.view.executor().register_sub_task();
+        let uploading = self.uploading.lock().await;
+        // uploads on other nodes race for the same version, the loser takes the next one
+        let mut min_version = 0;
+        let mut retries = 0;
+        let (version, appmeta) = loop {
+            let versions = self.get_app_versions(&appname).await?.unwrap_or_default();
+            let version = (versions.latest() + 1).max(min_version);
+            match self
+                .store_app_version(&appname, version, data.clone(), task.clone())
+                .await
+            {
+                Ok(appmeta) => break (version, appmeta),
+                Err(WSError::WsDataError(WsDataError::VersionMismatch { .. }))
+                    if retries < version::UPLOAD_VERSION_RETRIES =>
+                {
+                    tracing::debug!("version {} of app {} taken, retry", version, appname);
+                    min_version = version + 1;
+                    retries += 1;
+                }
+                Err(WSError::WsDataError(WsDataError::VersionMismatch { .. })) => {
+                    return Err(WsFuncError::AppVersionExists {
+                        app: appname,
+                        version,
+                    }
+                    .into())
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        /////
+        // activate the version, or start it as a canary of the active one,
+        // the versions record is written back only if no other upload or switch changed it
+        loop {
+            let (mut versions, at) = self
+                .get_app_versions_at(&appname)
+                .await?
+                .unwrap_or_default();
+            let _ = versions.versions.insert(version, uploaded_at_ms);
+            let active_meta = match appmeta.canary_percent {
+                Some(percent) if versions.active != 0 && versions.active != version => {
+                    versions
+                        .set_canary(version, percent)
+                        .expect("a new version is a valid canary");
+                    self.version_meta(&appname, versions.active).await?
+                }
+                _ => {
+                    let _ = versions.activate(version);
+                    appmeta.clone()
+                }
+            };
+            match self
+                .write_active_version(&appname, &active_meta, &versions, at, task.clone())
+                .await
+            {
+                Ok(()) => break,
+                Err(WSError::WsDataError(WsDataError::VersionMismatch { .. })) => {
+                    tracing::debug!("versions of app {} changed meanwhile, retry", appname);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        drop(uploading);
+
+        // wait for sub task done(checkpoint)
+        let _ = self.view.executor().wait_for_subtasks(&task.task_id).await;
+        tracing::debug!("app uploaded, wait for sub task done");
+        Ok(version)
+    }
+
+    /// Unzip the app pack as `version` of the app and write its dataset, which must not exist yet.
+    /// Fails with `WsDataError::VersionMismatch` if the version is taken.
+    async fn store_app_version(
+        &self,
+        appname: &str,
+        version: u64,
+        data: Bytes,
+        task: proto::FnTaskId,
+    ) -> WSResult<AppMeta> {
+        let versioned = version::versioned_app(appname, version);
+        let write_data_id = format!("{}{}", DATA_UID_PREFIX_APP_META, versioned);
+        match self
+            .view
+            .data_general()
+            .get_or_del_datameta_from_master(write_data_id.as_bytes(), false)
+            .await
+        {
+            Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => {}
+            Ok((datameta, _)) => {
+                return Err(WsDataError::VersionMismatch {
+                    expected: 0,
+                    actual: datameta.version,
+                }
+                .into())
+            }
+            Err(err) => return Err(err),
+        }
+
+        // 1. unzip app pack to the dir of the version
+        if let Some(_) = self.meta.write().await.tmp_app_metas.remove(appname) {
+            tracing::debug!("remove old app meta {}", appname);
+        }
+        let appdir = self.fs_layer.concat_app_dir(&versioned);
+        let appdir2 = appdir.clone();
+        if appdir2.exists() {
+            // left by a failed upload
+            fs::remove_dir_all(&appdir2).map_err(WsFuncError::AppPackRemoveFailed)?;
         }
         let res = tokio::task::spawn_blocking(move || {
             let data = data.to_vec();
-            zip_extract::extract(Cursor::new(data), &tmpappdir2, false)
+            zip_extract::extract(Cursor::new(data), &appdir2, false)
         })
         .await
         .unwrap();
@@ -1083,17 +1692,17 @@ impl AppMetaManager {
             Ok(res) => res,
             Err(err) => {
                 tracing::warn!("unzip failed, err: {:?}", err);
-                let _ = fs::remove_dir_all(&tmpappdir);
+                let _ = fs::remove_dir_all(&appdir);
                 return Err(WsFuncError::AppPackFailedZip(err).into());
             }
         };
 
         /////
-        // check meta by tmp app dir
-        let res = self.fs_layer.read_app_meta(&tmpapp).await; //self.construct_tmp_app(&tmpapp).await;
+        // check meta by app dir
+        let res = self.fs_layer.read_app_meta(&versioned).await;
         let appmeta = match res {
             Err(e) => {
-                let _ = fs::remove_dir_all(&tmpappdir);
+                let _ = fs::remove_dir_all(&appdir);
                 tracing::warn!("construct app failed, err {:?}", e);
                 return Err(e);
             }
@@ -1101,20 +1710,9 @@ impl AppMetaManager {
         };
 
         /////
-        // mv temp app to formal app dir
-        let rel_app_dir = format!("{}/{}", APPS_REL_DIR, appname);
-        let formal_app_dir = self.view.os().file_path.join(rel_app_dir.clone());
-        let _ = fs::rename(&tmpappdir, &formal_app_dir).map_err(|e| {
-            WSError::from(WsDataError::FileOpenErr {
-                path: PathBuf::from(formal_app_dir.clone()),
-                err: e,
-            })
-        })?;
-
-        /////
-        // write data to whole system
+        // 2. write the version to whole system, it's never written again
+        let rel_app_dir = format!("{}/{}", APPS_REL_DIR, versioned);
         let appmeta_encoded = bincode::serialize(&appmeta).unwrap();
-        let write_data_id = format!("{}{}", DATA_UID_PREFIX_APP_META, appname);
         let write_datas = vec![
             DataItemArgWrapper::from_bytes(appmeta_encoded.clone()),
             //DataItemArgWrapper::from_file(rel_app_dir),
@@ -1134,26 +1732,166 @@ impl AppMetaManager {
                 // 修改前：.map(|v| v.to_string())   去掉了这一行，为结构体派生了debug特征  曾俊
                 .collect::<Vec<_>>()
         );
-        let task = self.view.executor().register_sub_task();
-        self.view
+        let res = self
+            .view
             .data_general()
-            .write_data(
+            .write_data_if(
                 write_data_id,
                 write_datas,
                 Some((
                     self.view.p2p().nodes_config.this_node(),
                     proto::DataOpeType::Write,
                     OpeRole::UploadApp(DataOpeRoleUploadApp {
-                        app: appname.clone(),
+                        app: versioned.clone(),
                         app_meta_encoded: appmeta_encoded,
                     }),
-                    task.clone(),
+                    task,
                 )),
+                // checked by master under the meta lock, so a concurrent upload can't overwrite it
+                DataWriteCond {
+                    expected_version: Some(0),
+                    ..Default::default()
+                },
+            )
+            .await;
+        if let Err(err) = res {
+            // the version is taken by an upload on another node, its files are fetched from there
+            let _ = fs::remove_dir_all(&appdir);
+            return Err(err);
+        }
+        Ok(appmeta)
+    }
+
+    /// Switch the active version of the app, calls not pinned to a version run it from now on.
+    pub async fn activate_app_version(&self, app: &str, version: u64) -> WSResult<()> {
+        let (mut versions, at) =
+            self.get_app_versions_at(app)
+                .await?
+                .ok_or_else(|| WsFuncError::AppNotFound {
+                    app: app.to_owned(),
+                })?;
+        if !versions.activate(version) {
+            return Err(WsFuncError::AppVersionNotFound {
+                app: app.to_owned(),
+                version,
+            }
+            .into());
+        }
+        self.switch_version(app, versions, at).await
+    }
+
+    /// Switch back to the version active before the last switch, returns it.
+    pub async fn rollback_app(&self, app: &str) -> WSResult<u64> {
+        let (mut versions, at) =
+            self.get_app_versions_at(app)
+                .await?
+                .ok_or_else(|| WsFuncError::AppNotFound {
+                    app: app.to_owned(),
+                })?;
+        let version = versions
+            .rollback()
+            .ok_or_else(|| WsFuncError::AppNoPreviousVersion {
+                app: app.to_owned(),
+            })?;
+        self.switch_version(app, versions, at).await?;
+        Ok(version)
+    }
+
+    /// Route `percent` of the calls not pinned to a version to `version`, 0 stops the canary.
+    pub async fn set_app_canary(&self, app: &str, version: u64, percent: u8) -> WSResult<()> {
+        let (mut versions, at) =
+            self.get_app_versions_at(app)
+                .await?
+                .ok_or_else(|| WsFuncError::AppNotFound {
+                    app: app.to_owned(),
+                })?;
+        if percent > 0 && !versions.versions.contains_key(&version) {
+            return Err(WsFuncError::AppVersionNotFound {
+                app: app.to_owned(),
+                version,
+            }
+            .into());
+        }
+        versions
+            .set_canary(version, percent)
+            .map_err(|reason| WsFuncError::InvalidAppCanary {
+                app: app.to_owned(),
+                reason,
+            })?;
+        self.switch_version(app, versions, at).await
+    }
+
+    async fn version_meta(&self, app: &str, version: u64) -> WSResult<AppMeta> {
+        let versioned = version::versioned_app(app, version);
+        let Some((appmeta, _)) = self.get_app_meta(&versioned).await? else {
+            return Err(WsFuncError::AppVersionNotFound {
+                app: app.to_owned(),
+                version,
+            }
+            .into());
+        };
+        Ok(appmeta)
+    }
+
+    /// Fails with `WsDataError::VersionMismatch` if the versions changed since read at data
+    /// version `at`.
+    async fn switch_version(&self, app: &str, versions: AppVersions, at: u64) -> WSResult<()> {
+        let appmeta = self.version_meta(app, versions.active).await?;
+        let task = self.view.executor().register_sub_task();
+        self.write_active_version(app, &appmeta, &versions, at, task)
+            .await
+    }
+
+    /// The dataset of the plain app name holds the meta of the active version and the versions,
+    /// the master binds the triggers of the app to the active version when it's written.
+    ///
+    /// Written only while the dataset is still at data version `at`, 0 when the app has no
+    /// version yet, so concurrent uploads and switches don't lose each other's versions.
+    async fn write_active_version(
+        &self,
+        app: &str,
+        appmeta: &AppMeta,
+        versions: &AppVersions,
+        at: u64,
+        task: proto::FnTaskId,
+    ) -> WSResult<()> {
+        let appmeta_encoded = bincode::serialize(appmeta).unwrap();
+        let _ = self
+            .view
+            .data_general()
+            .write_data_if(
+                format!("{}{}", DATA_UID_PREFIX_APP_META, app),
+                vec![
+                    DataItemArgWrapper::from_bytes(appmeta_encoded.clone()),
+                    DataItemArgWrapper::from_bytes(bincode::serialize(versions).unwrap()),
+                ],
+                Some((
+                    self.view.p2p().nodes_config.this_node(),
+                    proto::DataOpeType::Write,
+                    OpeRole::UploadApp(DataOpeRoleUploadApp {
+                        app: app.to_owned(),
+                        app_meta_encoded: appmeta_encoded,
+                    }),
+                    task,
+                )),
+                DataWriteCond {
+                    expected_version: Some(at),
+                    ..Default::default()
+                },
             )
             .await?;
-        // wait for sub task done(checkpoint)
-        let _ = self.view.executor().wait_for_subtasks(&task.task_id).await;
-        tracing::debug!("app uploaded, wait for sub task done");
+        tracing::info!(
+            "app {} switched to version {}, canary {:?}",
+            app,
+            versions.active,
+            versions.canary
+        );
+        self.routing_versions
+            .insert(app.to_owned(), versions.clone());
+        // other nodes drain when their calls see the switch
+        self.view
+            .instance_manager()
+            .drain_unrouted_versions(app, &versions);
         Ok(())
     }
 
@@ -1383,6 +2121,25 @@ pub enum NodeTag {
     Custom(String),
 }
 
+impl NodeTag {
+    fn parse(tag: &str) -> Self {
+        match tag {
+            "worker" => NodeTag::Worker,
+            "master" => NodeTag::Master,
+            custom => NodeTag::Custom(custom.to_string()),
+        }
+    }
+
+    /// Whether the node has the tag in its `spec` of node_config.yaml, like `ssd` or `zone-a`.
+    pub fn matches(&self, node: &NodeConfig) -> bool {
+        match self {
+            NodeTag::Worker => node.is_worker(),
+            NodeTag::Master => node.is_master(),
+            NodeTag::Custom(tag) => node.spec.contains(tag),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AffinityRule {
     // 节点必须具有的标签列表,默认包含 worker
@@ -1401,6 +2158,14 @@ pub enum AffinityPattern {
     NodeCount(usize),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AntiAffinityRule {
+    // 节点不能具有的标签
+    pub tags: Vec<NodeTag>,
+    // 尽量避开已有该应用实例的节点,都有实例时不限制
+    pub instances: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AffinityYaml {
     // 标签列表,使用字符串表示
@@ -1409,15 +2174,187 @@ pub struct AffinityYaml {
     pub nodes: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct AntiAffinityYaml {
+    // 要避开的标签列表
+    pub tags: Option<Vec<String>>,
+    // 是否避开已有该应用实例的节点
+    pub instances: Option<bool>,
+}
+
 #[cfg(test)]
 mod test {
     use crate::util;
+    use std::collections::HashSet;
 
     use super::*;
     #[test]
     fn test_key_pattern() {
         util::test_tracing_start();
-        let pattern = KeyPattern::new("xxxx_{}_{}".to_owned());
+        let pattern = KeyPattern::new("xxxx_{}_{}".to_owned()).unwrap();
         assert!(pattern.match_key("xxxx_abc_123"));
     }
+
+    #[test]
+    fn test_app_meta_yaml_problems() {
+        let problems = |res: WSResult<AppMetaYaml>| match res {
+            Err(WSError::WsFuncError(WsFuncError::InvalidAppMeta { problems, .. })) => {
+                problems.into_iter().map(|p| p.path).collect::<HashSet<_>>()
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        let yaml = r#"
+canary: 100
+fns:
+  a:
+    sync: maybe
+    http.get: {call: nowhere}
+  b:
+    schedule: {interval: 0}
+  c:
+    http.post: {call: direct}
+  d:
+    anti_affinity: {tags: [ssd], instances: maybe}
+"#;
+        let expected = [
+            "canary",
+            "fns.a.sync",
+            "fns.a.http.get.call",
+            "fns.b.schedule",
+            "fns.d.anti_affinity",
+        ];
+        assert_eq!(
+            problems(AppMetaYaml::parse("app", yaml)),
+            expected.iter().map(|p| p.to_string()).collect()
+        );
+        assert!(matches!(
+            AppMetaYaml::parse("app", "fns: ["),
+            Err(WSError::WsFuncError(WsFuncError::AppPackConfDecodeErr(_)))
+        ));
+        assert_eq!(
+            problems(AppMetaYaml::parse("app", "name: app")),
+            ["fns".to_owned()].into_iter().collect()
+        );
+
+        let yaml = r#"
+fns:
+  f:
+    affinity: {nodes: "1,x"}
+    kvs:
+      "k_{}": [set, fly, {trigger_by_write: {condition: "size > 1"}}, {trigger_by_any: {condition: "1"}}]
+      "c_{}": [{trigger_by_new: {condition: "size >"}}, {trigger_by_write: {condition: "checkpointable"}}]
+      "k_{": [get]
+      "t_{}": [set, {ttl: 0}]
+      "r_{}": [set, {replicas: 0}]
+"#;
+        let mut yaml = AppMetaYaml::parse("app", yaml).unwrap();
+        let f = yaml.fns.remove("f").unwrap();
+        let paths: HashSet<_> = FnMeta::try_from((AppType::Wasm, f))
+            .unwrap_err()
+            .into_iter()
+            .map(|p| p.path)
+            .collect();
+        let expected = [
+            "affinity.nodes",
+            "kvs.k_{}[1]",
+            "kvs.k_{}[3]",
+            "kvs.c_{}[0]",
+            "kvs.c_{}[1]",
+            "kvs.k_{",
+            "kvs.t_{}[1]",
+            "kvs.r_{}[1]",
+        ];
+        assert_eq!(paths, expected.iter().map(|p| p.to_string()).collect());
+    }
+
+    #[test]
+    fn test_fn_meta_kv_ttl() {
+        let yaml = r#"
+fns:
+  f:
+    kvs:
+      "session_{}": [set, {ttl: 10m}, trigger_by_expire]
+      "session_admin_{}": [set, {ttl: 3600}]
+      "session_{}_log": [set, trigger_by_write]
+"#;
+        let mut yaml = AppMetaYaml::parse("app", yaml).unwrap();
+        let f = FnMeta::try_from((AppType::Wasm, yaml.fns.remove("f").unwrap())).unwrap();
+        assert_eq!(f.default_ttl_ms("session_42"), Some(600_000));
+        assert_eq!(f.default_ttl_ms("session_admin_1"), Some(3_600_000));
+        assert_eq!(f.default_ttl_ms("user_1"), None);
+
+        assert_eq!(
+            f.expiry_triggered_by("session_42"),
+            Some(&KeyPattern::new("session_{}".to_owned()).unwrap())
+        );
+        // the expire trigger doesn't fire on writes
+        assert!(f.triggered_by("session_42").is_none());
+        assert!(f.triggered_by("session_42_log").is_some());
+    }
+
+    #[test]
+    fn test_fn_meta_kv_replicas() {
+        let yaml = r#"
+fns:
+  f:
+    kvs:
+      "model_{}": [set, {replicas: 3}]
+      "model_tmp_{}": [set, {replicas: 1}]
+      "log_{}": [set]
+"#;
+        let mut yaml = AppMetaYaml::parse("app", yaml).unwrap();
+        let f = FnMeta::try_from((AppType::Wasm, yaml.fns.remove("f").unwrap())).unwrap();
+        assert_eq!(f.replicas("model_resnet"), Some(3));
+        assert_eq!(f.replicas("model_tmp_1"), Some(1));
+        assert_eq!(f.replicas("log_1"), None);
+    }
+    #[test]
+    fn test_app_meta_old_layout() {
+        // stored before apps were versioned, `bincode::serialize` of
+        //  AppMeta { app_type: Wasm, fns: {"f": FnMeta { sync_async: Sync,
+        //  calls: [Http { method: Post, call: Direct }],
+        //  data_accesses: Some({"log_{}": DataAccess { set: true, get: false, delete: false,
+        //  event: Some(Write) }}), affinity: None }}, cache_contains_http_fn: None }
+        let old: &[u8] = &[
+            1, 0, 0, 0, // app_type: Wasm
+            1, 0, 0, 0, 0, 0, 0, 0, // fns: 1 entry
+            1, 0, 0, 0, 0, 0, 0, 0, b'f', // "f"
+            0, 0, 0, 0, // sync_async: Sync
+            1, 0, 0, 0, 0, 0, 0, 0, // calls: 1 entry
+            0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, // Http { method: Post, call: Direct }
+            1, // data_accesses: Some
+            1, 0, 0, 0, 0, 0, 0, 0, // 1 entry
+            6, 0, 0, 0, 0, 0, 0, 0, b'l', b'o', b'g', b'_', b'{', b'}', // "log_{}"
+            1, 0, 0, // set, get, delete
+            1, 0, 0, 0, 0, // event: Some(Write)
+            0, // affinity: None
+            0, // cache_contains_http_fn: None
+        ];
+        let meta = AppMeta::decode(old).unwrap();
+        assert_eq!(meta.app_type, AppType::Wasm);
+        assert_eq!(meta.version, 0);
+        assert_eq!(meta.canary_percent, None);
+        let f = meta.get_fn_meta("f").unwrap();
+        assert!(f.sync_async.syncable() && !f.sync_async.asyncable());
+        assert!(matches!(
+            f.calls[..],
+            [FnCallMeta::Http {
+                method: HttpMethod::Post,
+                call: HttpCall::Direct
+            }]
+        ));
+        let (pattern, trigger) = f.triggered_by("log_1").unwrap();
+        assert_eq!(pattern, &KeyPattern::new("log_{}".to_owned()).unwrap());
+        assert!(matches!(trigger, DataEventTrigger::Write));
+        assert_eq!(f.default_ttl_ms("log_1"), None);
+        assert_eq!(f.limits, FnLimits::default());
+        // a cut one is no meta of either layout
+        assert!(AppMeta::decode(&old[..old.len() - 1]).is_err());
+
+        let mut meta = AppMeta::new(AppType::Wasm, meta.fns);
+        meta.version = 3;
+        meta.canary_percent = Some(10);
+        let decoded = AppMeta::decode(&bincode::serialize(&meta).unwrap()).unwrap();
+        assert_eq!((decoded.version, decoded.canary_percent), (3, Some(10)));
+    }
 }
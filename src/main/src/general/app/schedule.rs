@@ -0,0 +1,282 @@
+//! Time based triggers of functions.
+//!
+//! ```yaml
+//! fns:
+//!   daily_report:
+//!     schedule:
+//!       cron: "0 2 * * *"    # minute hour day-of-month month day-of-week, in UTC
+//!   flush:
+//!     schedule:
+//!       interval: 30s        # s, m, h, d, or plain seconds
+//! ```
+//!
+//! Ticks are aligned to wall clock (intervals to multiples of themselves since the unix epoch),
+//! so a new master continues with the same ticks as the old one.
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FnSchedule {
+    Cron(CronExpr),
+    Interval { secs: u64 },
+}
+
+impl FnSchedule {
+    pub fn from_yaml(v: &serde_yaml::Value) -> Result<Self, String> {
+        let map = v
+            .as_mapping()
+            .ok_or_else(|| "schedule must be a map with cron or interval".to_owned())?;
+        if map.len() != 1 {
+            return Err("schedule must have exactly one of cron or interval".to_owned());
+        }
+        if let Some(cron) = map.get("cron") {
+            let cron = cron
+                .as_str()
+                .ok_or_else(|| "schedule cron must be a string".to_owned())?;
+            Ok(FnSchedule::Cron(CronExpr::parse(cron)?))
+        } else if let Some(interval) = map.get("interval") {
+            let secs = match interval {
+                serde_yaml::Value::Number(n) => n.as_u64(),
+                serde_yaml::Value::String(s) => parse_interval_secs(s),
+                _ => None,
+            };
+            match secs {
+                Some(secs) if secs > 0 => Ok(FnSchedule::Interval { secs }),
+                _ => Err(format!("invalid schedule interval {:?}", interval)),
+            }
+        } else {
+            Err("schedule must have exactly one of cron or interval".to_owned())
+        }
+    }
+
+    /// The first tick later than `after_ms`, in unix milliseconds.
+    pub fn next_tick_after(&self, after_ms: i64) -> Option<i64> {
+        match self {
+            FnSchedule::Cron(cron) => cron.next_after(after_ms),
+            FnSchedule::Interval { secs } => {
+                let interval_ms = (*secs as i64).checked_mul(1000)?;
+                (after_ms.div_euclid(interval_ms) + 1).checked_mul(interval_ms)
+            }
+        }
+    }
+
+    /// The latest tick not later than `now_ms` but later than `after_ms`,
+    /// ticks missed in between are coalesced into it.
+    pub fn due_tick(&self, after_ms: i64, now_ms: i64) -> Option<i64> {
+        let mut due = self.next_tick_after(after_ms).filter(|t| *t <= now_ms)?;
+        if let FnSchedule::Interval { secs } = self {
+            let interval_ms = *secs as i64 * 1000;
+            return Some(now_ms.div_euclid(interval_ms) * interval_ms);
+        }
+        while let Some(next) = self.next_tick_after(due).filter(|t| *t <= now_ms) {
+            due = next;
+        }
+        Some(due)
+    }
+}
+
+/// `30s`, `5m`, `2h`, `1d` or plain seconds.
+pub fn parse_interval_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+    let num: u64 = num.parse().ok()?;
+    let unit = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    num.checked_mul(unit)
+}
+
+/// A 5 fields cron expression, each field accepts `*`, `a`, `a-b`, lists by `,`
+/// and steps by `/n`. Like classic cron, when both day-of-month and day-of-week
+/// are restricted, a day matching either of them fires.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CronExpr {
+    src: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    dom_any: bool,
+    dow_any: bool,
+}
+
+impl CronExpr {
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = src.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron {:?} must have 5 fields", src));
+        }
+        let field = |idx: usize, min: u32, max: u32| {
+            parse_cron_field(fields[idx], min, max).map_err(|e| format!("cron {:?}: {}", src, e))
+        };
+        let mut days_of_week = field(4, 0, 7)?;
+        // both 0 and 7 are sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        let expr = Self {
+            src: src.to_owned(),
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days_of_month: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            days_of_week,
+            dom_any: fields[2] == "*",
+            dow_any: fields[4] == "*",
+        };
+        if expr.next_after(0).is_none() {
+            return Err(format!("cron {:?} never fires", src));
+        }
+        Ok(expr)
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    fn day_matches(&self, date: &NaiveDateTime) -> bool {
+        let dom = self.days_of_month & (1 << date.day()) != 0;
+        let dow = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.dom_any, self.dow_any) {
+            (true, true) => true,
+            (true, false) => dow,
+            (false, true) => dom,
+            (false, false) => dom || dow,
+        }
+    }
+
+    fn next_after(&self, after_ms: i64) -> Option<i64> {
+        // enough for the rarest expressions like "0 0 29 2 *"
+        const MAX_STEPS: usize = 100_000;
+        let after = DateTime::from_timestamp_millis(after_ms)?.naive_utc();
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        for _ in 0..MAX_STEPS {
+            if self.months & (1 << t.month()) == 0 {
+                let (y, m) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(y, m, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(&t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t.and_utc().timestamp_millis());
+            }
+        }
+        None
+    }
+}
+
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid step {:?}", step))?,
+            ),
+            None => (part, 1),
+        };
+        let num = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| format!("{:?} is not in {}-{}", s, min, max))
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            (num(from)?, num(to)?)
+        } else if part.contains('/') {
+            // "5/15" means from 5 to the end
+            (num(range)?, max)
+        } else {
+            let n = num(range)?;
+            (n, n)
+        };
+        if from > to {
+            return Err(format!("invalid range {:?}", range));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CronExpr, FnSchedule};
+
+    // 2024-01-01T00:00:00Z, a monday
+    const BASE: i64 = 1_704_067_200_000;
+    const MIN: i64 = 60_000;
+
+    #[test]
+    fn test_fn_schedule() {
+        let every_5m = FnSchedule::Cron(CronExpr::parse("*/5 * * * *").unwrap());
+        assert_eq!(every_5m.next_tick_after(BASE), Some(BASE + 5 * MIN));
+        assert_eq!(every_5m.next_tick_after(BASE + 1), Some(BASE + 5 * MIN));
+        assert_eq!(every_5m.due_tick(BASE, BASE + 4 * MIN), None);
+        assert_eq!(
+            every_5m.due_tick(BASE, BASE + 12 * MIN),
+            Some(BASE + 10 * MIN)
+        );
+
+        // 02:30 on sundays
+        let weekly = FnSchedule::Cron(CronExpr::parse("30 2 * * 7").unwrap());
+        assert_eq!(
+            weekly.next_tick_after(BASE),
+            Some(BASE + 6 * 24 * 60 * MIN + 150 * MIN)
+        );
+        // leap day
+        let leap = CronExpr::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            FnSchedule::Cron(leap).next_tick_after(BASE),
+            Some(BASE + (31 + 28) * 24 * 60 * MIN)
+        );
+
+        let interval = FnSchedule::from_yaml(&serde_yaml::from_str("interval: 90s").unwrap());
+        let interval = interval.unwrap();
+        assert_eq!(interval, FnSchedule::Interval { secs: 90 });
+        assert_eq!(interval.next_tick_after(BASE), Some(BASE + 90_000));
+        // a day of down time fires once, at the latest tick
+        assert_eq!(
+            interval.due_tick(BASE, BASE + 24 * 60 * MIN + 1),
+            Some(BASE + 24 * 60 * MIN)
+        );
+
+        for bad in [
+            "cron: '* * * *'",
+            "cron: '60 * * * *'",
+            "cron: '0 0 30 2 *'",
+            "cron: '*/0 * * * *'",
+            "interval: 0",
+            "interval: 5x",
+            "{interval: 5, cron: '* * * * *'}",
+        ] {
+            let v = serde_yaml::from_str(bad).unwrap();
+            assert!(
+                FnSchedule::from_yaml(&v).is_err(),
+                "{} should be rejected",
+                bad
+            );
+        }
+    }
+}
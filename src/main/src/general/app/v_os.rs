@@ -32,8 +32,10 @@ impl AppMetaVisitOs {
 
     pub async fn read_app_meta(&self, app: &str) -> WSResult<AppMeta> {
         let app_dir = self.concat_app_dir(app);
-        let yml_dir = app_dir.join("app.yml");
-        let ymlcontent = fs::read_to_string(yml_dir).await;
+        let mut ymlcontent = fs::read_to_string(app_dir.join("app.yml")).await;
+        if ymlcontent.is_err() {
+            ymlcontent = fs::read_to_string(app_dir.join("app.yaml")).await;
+        }
         let ymlcontent = match ymlcontent {
             Err(e) => {
                 tracing::warn!("app pack conf read invalid, {:?}", e);
@@ -41,11 +43,10 @@ impl AppMetaVisitOs {
             }
             Ok(ok) => ok,
         };
-        let yml = serde_yaml::from_str::<AppMetaYaml>(&ymlcontent);
-        let yml = match yml {
+        let yml = match AppMetaYaml::parse(app, &ymlcontent) {
             Err(e) => {
-                tracing::warn!("app pack conf decode invalid, {:?}", e);
-                return Err(WsFuncError::AppPackConfDecodeErr(e).into());
+                tracing::warn!("app pack conf invalid, {:?}", e);
+                return Err(e);
             }
             Ok(ok) => ok,
         };
@@ -0,0 +1,213 @@
+//! Versions of apps.
+//!
+//! Every upload of an app is kept as an immutable version named `<app>@v<version>`, with its own
+//! dataset, app dir and instances. The dataset of the plain app name holds the meta of the active
+//! version and the [`AppVersions`] of the app, so calls to `<app>` run the active version while
+//! calls to `<app>@v<version>` are pinned to one.
+//!
+//! - `POST /appmgmt/upload_app` uploads a new version and activates it
+//! - `GET /appmgmt/versions/:app` lists the versions
+//! - `POST /appmgmt/activate/:app/:version` switches the active version
+//! - `POST /appmgmt/rollback/:app` switches back to the version active before
+//! - `POST /appmgmt/canary/:app/:version/:percent` routes a share of the calls not pinned to a
+//!   version to a canary, a share of 0 stops it
+//!
+//! A version whose app.yaml has `canary: <percent>` starts as the canary when it's uploaded
+//! while another version is active. Switching the active version stops the canary.
+//!
+//! An app uploaded before apps were versioned has the implicit version 1, stored in the dataset
+//! of the plain app name. It's copied to the dataset of `<app>@v1` when its versions are first
+//! read, so switching away from it keeps it.
+//!
+//! Nodes cache the versions calls are routed by, a switch made on another node reaches them once
+//! they get the updated meta of the app or within [`ROUTING_VERSIONS_TTL`].
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Separator of the app name and the version in a versioned app name.
+pub const VERSION_SEP: &str = "@v";
+
+/// Versions active before, kept for rolling back.
+pub const MAX_ROLLBACK_HISTORY: usize = 32;
+
+/// Longest a node routes calls by versions cached before a switch on another node.
+pub const ROUTING_VERSIONS_TTL: Duration = Duration::from_secs(2);
+
+/// Versions an upload tries before giving up, when uploads on other nodes take them first.
+pub const UPLOAD_VERSION_RETRIES: usize = 8;
+
+/// A version getting a share of the calls not pinned to a version, the active one gets the rest.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Canary {
+    pub version: u64,
+    /// 1..=99
+    pub percent: u8,
+}
+
+pub fn versioned_app(app: &str, version: u64) -> String {
+    format!("{}{}{}", app, VERSION_SEP, version)
+}
+
+/// Split `<app>@v<version>` into the app and the version, a plain app name has no version.
+pub fn split_versioned_app(name: &str) -> (&str, Option<u64>) {
+    match name.rsplit_once(VERSION_SEP) {
+        // the canonical form only, so each version has one name
+        Some((app, version)) if !app.is_empty() => match version.parse::<u64>() {
+            Ok(v) if v > 0 && v.to_string() == version => (app, Some(v)),
+            _ => (name, None),
+        },
+        _ => (name, None),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AppVersions {
+    /// 0 before the first version is activated
+    pub active: u64,
+    /// version -> upload time in unix milliseconds
+    pub versions: BTreeMap<u64, i64>,
+    /// versions active before, the last one is restored by a rollback
+    pub history: Vec<u64>,
+    pub canary: Option<Canary>,
+}
+
+impl AppVersions {
+    /// Versions of an app uploaded before apps were versioned.
+    pub fn unversioned() -> Self {
+        Self {
+            active: 1,
+            versions: BTreeMap::from([(1, 0)]),
+            ..Default::default()
+        }
+    }
+
+    pub fn latest(&self) -> u64 {
+        self.versions.keys().next_back().copied().unwrap_or(0)
+    }
+
+    /// Record a new version, it's activated separately.
+    pub fn add(&mut self, uploaded_at_ms: i64) -> u64 {
+        let version = self.latest() + 1;
+        let _ = self.versions.insert(version, uploaded_at_ms);
+        version
+    }
+
+    /// Returns false if the version doesn't exist.
+    pub fn activate(&mut self, version: u64) -> bool {
+        if !self.versions.contains_key(&version) {
+            return false;
+        }
+        if self.active != version {
+            if self.active != 0 {
+                self.history.push(self.active);
+                if self.history.len() > MAX_ROLLBACK_HISTORY {
+                    let _ = self.history.remove(0);
+                }
+            }
+            self.active = version;
+        }
+        self.canary = None;
+        true
+    }
+
+    /// Switch back to the version active before, None if there is none.
+    pub fn rollback(&mut self) -> Option<u64> {
+        // activating a version from the history leaves it there
+        while let Some(version) = self.history.pop() {
+            if version != self.active {
+                self.active = version;
+                self.canary = None;
+                return Some(version);
+            }
+        }
+        None
+    }
+
+    /// Route `percent` of the calls not pinned to a version to `version`, 0 stops the canary.
+    /// Returns an error message if the canary isn't valid.
+    pub fn set_canary(&mut self, version: u64, percent: u8) -> Result<(), String> {
+        if percent == 0 {
+            self.canary = None;
+            return Ok(());
+        }
+        if percent >= 100 {
+            return Err("percent must be below 100, activate the version instead".to_owned());
+        }
+        if !self.versions.contains_key(&version) {
+            return Err(format!("version {} doesn't exist", version));
+        }
+        if version == self.active {
+            return Err(format!("version {} is already active", version));
+        }
+        self.canary = Some(Canary { version, percent });
+        Ok(())
+    }
+
+    /// Versions the calls not pinned to a version may run.
+    pub fn routed(&self) -> impl Iterator<Item = u64> + '_ {
+        std::iter::once(self.active).chain(self.canary.map(|c| c.version))
+    }
+
+    /// Version a call not pinned to a version runs, `roll` is uniform in 0..100.
+    pub fn route(&self, roll: u8) -> u64 {
+        match self.canary {
+            Some(canary) if roll < canary.percent => canary.version,
+            _ => self.active,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_versioned_app, versioned_app, AppVersions};
+
+    #[test]
+    fn test_app_versions() {
+        assert_eq!(
+            split_versioned_app(&versioned_app("img", 12)),
+            ("img", Some(12))
+        );
+        for plain in ["img", "img@v", "img@v0", "img@v01", "img@vx", "@v1"] {
+            assert_eq!(split_versioned_app(plain), (plain, None));
+        }
+
+        let mut versions = AppVersions::default();
+        for v in 1..=3 {
+            assert_eq!(versions.add(v as i64), v);
+            assert!(versions.activate(v));
+        }
+        assert!(!versions.activate(4));
+        assert!(versions.activate(1));
+        assert_eq!(versions.history, vec![1, 2, 3]);
+        // rolling back walks the history instead of toggling
+        assert_eq!(versions.rollback(), Some(3));
+        assert_eq!(versions.rollback(), Some(2));
+        assert_eq!(versions.rollback(), Some(1));
+        assert_eq!(versions.rollback(), None);
+        assert_eq!(versions.active, 1);
+        // entries of the active version are skipped
+        versions.history = vec![2, 1, 1];
+        assert_eq!(versions.rollback(), Some(2));
+        assert!(versions.history.is_empty());
+        versions.history = vec![2];
+        assert_eq!(versions.rollback(), None);
+        assert_eq!(versions.active, 2);
+        assert!(versions.activate(1));
+        assert_eq!(versions.add(0), 4);
+
+        assert!(versions.set_canary(4, 100).is_err());
+        assert!(versions.set_canary(1, 5).is_err());
+        assert!(versions.set_canary(5, 5).is_err());
+        versions.set_canary(4, 5).unwrap();
+        assert_eq!(versions.routed().collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(versions.route(0), 4);
+        assert_eq!(versions.route(4), 4);
+        assert_eq!(versions.route(5), 1);
+        assert_eq!(versions.route(99), 1);
+        // promoting the canary stops it
+        assert!(versions.activate(4));
+        assert_eq!(versions.canary, None);
+        assert_eq!(versions.route(0), 4);
+    }
+}
@@ -104,11 +104,13 @@ impl DataGeneral {
                     //原代码block_type: data.as_ref(),  类型不匹配，使用自定义的to_data_item函数转化     曾俊
                     block_type: Some(data.to_data_item()),
                     block_index: block_idx as u32,
+                    checksum: super::checksum::content_digest(&block_data),
                     data: block_data,
                     operation: proto::DataOpeType::Write as i32,
                     unique_id: unique_id.clone(),
                     version,
                     total_size: total_size as u64,
+                    replica_copy: false,
                 };
 
                 // 发送请求
@@ -1,4 +1,8 @@
-use super::{DataGeneral, DataSetMetaV2};
+use super::{
+    checksum,
+    erasure::{self, ErasureLayout},
+    DataGeneral, DataGeneralView, DataSetMetaV2, DataSplit, EachNodeSplit,
+};
 use crate::{
     general::{
         data::m_data_general::dataitem::WriteSplitDataTaskGroup,
@@ -129,6 +133,46 @@ impl BatchDoneResponsor for RPCResponsor<BatchDataRequest> {
     //     }
     // }
 }
+/// Stores the received replica copy before answering the sender,
+/// see `DataGeneral::repair_data_item`.
+struct ReplicaCopyResponsor {
+    responsor: RPCResponsor<BatchDataRequest>,
+    view: DataGeneralView,
+    unique_id: Vec<u8>,
+    idx: u8,
+}
+
+#[async_trait]
+impl BatchDoneResponsor for ReplicaCopyResponsor {
+    async fn done(&self, msg: BatchDoneMsg) {
+        let msg = match msg {
+            BatchDoneMsg::Done {
+                version,
+                request_id,
+                required_result: Some(item),
+            } => match self.view.data_general().store_replica_copy(
+                &self.unique_id,
+                self.idx,
+                version,
+                item.into_data_bytes(),
+            ) {
+                Ok(()) => BatchDoneMsg::Done {
+                    version,
+                    request_id,
+                    required_result: None,
+                },
+                Err(err) => BatchDoneMsg::Error {
+                    version,
+                    error_message: format!("store replica copy failed: {}", err),
+                    request_id,
+                },
+            },
+            msg => msg,
+        };
+        self.responsor.done(msg).await;
+    }
+}
+
 /// 共享状态,用于记录最新的请求响应器
 /// 当收到新的请求时,会更新响应器并自动处理旧的请求
 #[derive(Clone)]
@@ -234,23 +278,57 @@ impl DataGeneral {
         responsor: RPCResponsor<proto::BatchDataRequest>,
         req: proto::BatchDataRequest,
     ) -> WSResult<()> {
-        tracing::debug!(
-            "rpc_handle_batch_data with batchid({:?})",
-            req.request_id.clone().unwrap()
-        );
+        tracing::debug!("rpc_handle_batch_data with batchid({:?})", req.request_id);
+        let (Some(request_id), Some(block_type)) = (req.request_id.clone(), req.block_type.clone())
+        else {
+            let error_message = "batch data without request id or block type".to_owned();
+            tracing::warn!("rpc_handle_batch_data rejected block: {}", error_message);
+            responsor
+                .send_resp(BatchDataResponse {
+                    request_id: req.request_id,
+                    version: req.version,
+                    success: false,
+                    error_message,
+                })
+                .await?;
+            return Ok(());
+        };
         // 预先克隆闭包外需要的字段
         // let block_index = req.block_index;
         // let data = req.data.clone();
         // let request_id = req.request_id.clone().unwrap();
 
+        let responsor: Box<dyn BatchDoneResponsor> = if req.replica_copy {
+            Box::new(ReplicaCopyResponsor {
+                responsor,
+                view: self.view.clone(),
+                unique_id: req.unique_id.clone(),
+                idx: req.data_item_idx as u8,
+            })
+        } else {
+            Box::new(responsor)
+        };
+        if let Err(err) = checksum::verify_block(&req) {
+            tracing::warn!("rpc_handle_batch_data rejected block: {}", err);
+            responsor
+                .done(BatchDoneMsg::Error {
+                    version: req.version,
+                    error_message: err.to_string(),
+                    request_id,
+                })
+                .await;
+            return Ok(());
+        }
+        // block_type only tells the item type, the block content is in data
+        let partial_block = erasure::shard_item(&block_type.get_data_type(), req.data);
         self.handle_batch_data_one(
             req.unique_id,
-            req.request_id.unwrap(),
+            request_id,
             req.total_size as usize,
-            req.block_type.unwrap(),
+            partial_block,
             req.version,
             req.block_index as usize,
-            Box::new(responsor),
+            responsor,
             req.data_item_idx as u8,
         )
         .await?;
@@ -406,7 +484,175 @@ impl DataGeneral {
         Ok(())
     }
 
-    fn next_batch_id(&self, nodeid: NodeID) -> proto::BatchRequestId {
+    /// Read the range held by `replicas` from the primary, failing over to the next replica
+    /// when one is unreachable or lost the data. Deleting goes to every replica.
+    async fn get_or_del_split(
+        &self,
+        unique_id: &[u8],
+        idx: u8,
+        replicas: &[EachNodeSplit],
+        opetype: &GetOrDelType,
+    ) -> Result<proto::DataItem, String> {
+        let display_id = String::from_utf8_lossy(unique_id);
+        let call = |node_id: NodeID| {
+            self.rpc_call_get_data.call(
+                self.view.p2p(),
+                node_id,
+                proto::GetOneDataRequest {
+                    unique_id: unique_id.to_vec(),
+                    idxs: vec![idx as u32],
+                    delete: opetype.delete(),
+                    return_data: opetype.return_data(),
+                },
+                Some(Duration::from_secs(60)),
+            )
+        };
+        let check = |split: &EachNodeSplit, res: WSResult<proto::GetOneDataResponse>| {
+            let reason = match res {
+                Ok(mut resp) if resp.success && resp.data.len() == 1 => {
+                    return Ok(resp.data.pop().unwrap());
+                }
+                Ok(resp) => format!("count({}), {}", resp.data.len(), resp.message),
+                Err(err) => err.to_string(),
+            };
+            Err(format!(
+                "batch one fetch key({}), idx({}), split range({}-{}) from node({}) failed: {}",
+                display_id,
+                idx,
+                split.data_offset,
+                split.data_offset + split.data_size,
+                split.node_id,
+                reason
+            ))
+        };
+
+        let mut last_err = format!(
+            "batch one fetch key({}), idx({}) has no replica",
+            display_id, idx
+        );
+        if opetype.delete() {
+            let results =
+                futures::future::join_all(replicas.iter().map(|split| call(split.node_id))).await;
+            let mut fetched = None;
+            for (split, res) in replicas.iter().zip(results) {
+                match check(split, res) {
+                    Ok(item) => {
+                        if fetched.is_none() {
+                            fetched = Some(item);
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!("{}", err);
+                        last_err = err;
+                    }
+                }
+            }
+            return fetched.ok_or(last_err);
+        }
+        // a replica on this node is read without crossing the network
+        let this_node = self.view.p2p().nodes_config.this_node();
+        let local_first = replicas
+            .iter()
+            .filter(|split| split.node_id == this_node)
+            .chain(replicas.iter().filter(|split| split.node_id != this_node));
+        for split in local_first {
+            match check(split, call(split.node_id).await) {
+                Ok(item) => return Ok(item),
+                Err(err) => {
+                    tracing::warn!("{}, trying next replica", err);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Read an erasure coded item from its data shards, decoding with the parity shards
+    /// when some are lost. Deleting goes to every shard.
+    pub(super) async fn get_or_del_erasure_coded(
+        &self,
+        unique_id: &[u8],
+        idx: u8,
+        splits: &DataSplit,
+        layout: &ErasureLayout,
+        opetype: &GetOrDelType,
+    ) -> Result<Option<proto::DataItem>, String> {
+        let mut shard_replicas: Vec<Vec<EachNodeSplit>> = vec![vec![]; layout.shard_cnt()];
+        for split in &splits.splits {
+            shard_replicas[layout.shard_index(split)].push(split.clone());
+        }
+        let fetch = |wanted: std::ops::Range<usize>| {
+            futures::future::join_all(wanted.map(|i| {
+                let replicas = &shard_replicas[i];
+                async move {
+                    let res = self
+                        .get_or_del_split(unique_id, idx, replicas, opetype)
+                        .await;
+                    (i, res)
+                }
+            }))
+        };
+
+        let first = if opetype.delete() {
+            layout.shard_cnt()
+        } else {
+            layout.data_shards
+        };
+        let mut fetched = fetch(0..first).await;
+        if !opetype.return_data() {
+            return Ok(None);
+        }
+        if fetched.iter().any(|(_, res)| res.is_err()) && first < layout.shard_cnt() {
+            fetched.extend(fetch(first..layout.shard_cnt()).await);
+        }
+        let mut shards: Vec<Option<proto::DataItem>> = vec![None; layout.shard_cnt()];
+        let mut errs = vec![];
+        for (i, res) in fetched {
+            match res {
+                Ok(item) => shards[i] = Some(item),
+                Err(err) => errs.push(err),
+            }
+        }
+        let have = shards.iter().filter(|shard| shard.is_some()).count();
+        if have < layout.data_shards {
+            return Err(format!(
+                "only {} of {} shards needed by key({}) idx({}) are left: {}",
+                have,
+                layout.data_shards,
+                String::from_utf8_lossy(unique_id),
+                idx,
+                errs.join(", ")
+            ));
+        }
+        if !errs.is_empty() {
+            tracing::warn!("decoding lost shards: {}", errs.join(", "));
+        }
+
+        let data_type = shards
+            .iter()
+            .flatten()
+            .next()
+            .map(|item| item.get_data_type())
+            .unwrap();
+        let data = layout
+            .decode(
+                shards
+                    .into_iter()
+                    .map(|shard| shard.map(|item| item.into_data_bytes()))
+                    .collect(),
+            )
+            .map_err(|err| {
+                format!(
+                    "decode key({}) idx({}) failed: {}",
+                    String::from_utf8_lossy(unique_id),
+                    idx,
+                    err
+                )
+            })?;
+        Ok(Some(erasure::shard_item(&data_type, data)))
+    }
+
+    pub(super) fn next_batch_id(&self, nodeid: NodeID) -> proto::BatchRequestId {
         static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(1); // 从1开始,保留0作为特殊值
         proto::BatchRequestId {
             node_id: nodeid,
@@ -500,8 +746,51 @@ impl DataGeneral {
                 total_size
             );
 
+            // 纠删码数据项解码后整体交给 WriteSplitDataTaskGroup
+            if let Some(layout) = ErasureLayout::of(splits) {
+                let view = self.view.clone();
+                let unique_id = unique_id.clone();
+                let opetype = opetype.clone();
+                let splits = splits.clone();
+                let version = dataset_meta.version;
+                let _ = tokio::spawn(async move {
+                    match view
+                        .data_general()
+                        .get_or_del_erasure_coded(&unique_id, idx, &splits, &layout, &opetype)
+                        .await
+                    {
+                        Ok(Some(item)) => view
+                            .data_general()
+                            .handle_batch_data_one(
+                                unique_id.clone(),
+                                request_id,
+                                layout.data_size,
+                                item,
+                                version,
+                                0,
+                                Box::new(responsor),
+                                idx,
+                            )
+                            .await
+                            .unwrap(),
+                        Ok(None) => {}
+                        Err(err_msg) => {
+                            tracing::warn!("{}", err_msg);
+                            responsor
+                                .done(BatchDoneMsg::Error {
+                                    version,
+                                    error_message: err_msg,
+                                    request_id,
+                                })
+                                .await;
+                        }
+                    }
+                });
+                continue;
+            }
+
             // 发起多个batch read, 拿到返回结果后并行调用 rpc_handle_batch_data
-            for (split_idx, split) in splits.splits.iter().enumerate() {
+            for (split_idx, replicas) in splits.replica_groups().into_iter().enumerate() {
                 // get one data request
                 let view = self.view.clone();
                 let unique_id = unique_id.clone();
@@ -509,73 +798,43 @@ impl DataGeneral {
                 let opetype = opetype.clone();
                 let responsor = responsor.clone();
                 let version = dataset_meta.version;
-                // let node_id = split.node_id as NodeID;
-                let split = split.clone();
-                // let data_offset = split.data_offset;
+                let replicas: Vec<EachNodeSplit> = replicas.into_iter().cloned().collect();
                 let _ = tokio::spawn(async move {
                     // first read the partial block from target node
-                    let mut partial_block = view
+                    let partial_block = match view
                         .data_general()
-                        .rpc_call_get_data
-                        .call(
-                            view.p2p(),
-                            split.node_id,
-                            proto::GetOneDataRequest {
-                                unique_id: unique_id.clone(),
-                                idxs: vec![idx as u32],
-                                delete: opetype.delete(),
-                                return_data: opetype.return_data(),
-                            },
-                            Some(Duration::from_secs(60)),
-                        )
+                        .get_or_del_split(&unique_id, idx, &replicas, &opetype)
                         .await
-                        .unwrap_or_else(|err| {
-                            panic!("batch one fetch {:?} error: {}", request_id, err);
-                        });
-
-                    if partial_block.data.len() != 1 {
-                        let err_msg = format!(
-                            "batch one fetch partial_block wrong count, key({:?}), idx({}), count({}), supposed split range({}-{})",
-                            std::str::from_utf8(&unique_id).map(|v|v.to_string()).unwrap_or(format!("{:?}", unique_id)),
-                            idx,
-                            partial_block.data.len(),
-                            split.data_offset,
-                            split.data_offset + split.data_size
-                        );
-                        tracing::warn!("{}", err_msg);
-                        responsor
-                            .done(BatchDoneMsg::Error {
-                                version: version,
-                                error_message: err_msg,
-                                request_id: request_id.clone(),
-                                // required_result: None,
-                            })
-                            .await;
-                        return;
-                    }
+                    {
+                        Ok(partial_block) => partial_block,
+                        Err(err_msg) => {
+                            tracing::warn!("{}", err_msg);
+                            responsor
+                                .done(BatchDoneMsg::Error {
+                                    version: version,
+                                    error_message: err_msg,
+                                    request_id: request_id.clone(),
+                                    // required_result: None,
+                                })
+                                .await;
+                            return;
+                        }
+                    };
 
                     tracing::debug!(
                         "batch one recev partial_block, idx({}), type({:?}), size({})",
                         idx,
-                        partial_block.data[0].get_data_type(),
-                        partial_block.data[0].inmem_size()
+                        partial_block.get_data_type(),
+                        partial_block.inmem_size()
                     );
 
-                    if !partial_block.success {
-                        tracing::error!(
-                            "batch one recev {:?} error: {}",
-                            request_id,
-                            partial_block.message
-                        );
-                    }
-
                     if opetype.return_data() {
                         view.data_general()
                             .handle_batch_data_one(
                                 unique_id.clone(),
                                 request_id.clone(),
                                 total_size,
-                                partial_block.data.pop().unwrap(),
+                                partial_block,
                                 version,
                                 split_idx,
                                 Box::new(responsor),
@@ -601,7 +860,10 @@ impl DataGeneral {
                         msg: format!("Failed to submit task: submit_split count to the end"),
                     }));
                 };
-                results.push(res.unwrap());
+                let item = res.unwrap();
+                self.verify_fetched_item(dataset_meta, &unique_id, idxs[i], &item)
+                    .await?;
+                results.push(item);
             }
             Some(results)
         } else {
@@ -0,0 +1,281 @@
+//! End-to-end checksums of data items.
+//!
+//! The writer records the md5 of each item's content in `DataSetMetaV2::digests` and every
+//! batch transfer block carries the md5 of its bytes. Splits holding a whole item are checked
+//! when written and when read, erasure coded items once decoded, and the scrub re-checks
+//! everything a node stores.
+use super::{DataGeneral, DataItemIdx, DataSetMetaV2};
+use crate::{
+    general::{
+        data::m_kv_store_engine::{
+            KeyType, KeyTypeDataSetItem, KeyTypeDataSetMeta, KvAdditionalConf,
+        },
+        network::{proto, proto_ext::DataItemExt},
+    },
+    result::{WSResult, WsDataError},
+};
+use md5::{Digest, Md5};
+use serde::Serialize;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Digest of an item's content, also used for transfer blocks.
+pub fn content_digest(content: &[u8]) -> Vec<u8> {
+    Md5::digest(content).to_vec()
+}
+
+/// Digest of a file's content, read in chunks.
+pub async fn file_digest(path: &Path) -> WSResult<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|err| WsDataError::FileOpenErr {
+            path: path.to_path_buf(),
+            err,
+        })?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|err| WsDataError::FileReadErr {
+                path: path.to_path_buf(),
+                err,
+            })?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Digest of the content of a held split, comparable to the recorded one for whole splits.
+pub fn item_digest(item: &proto::DataItem) -> Vec<u8> {
+    content_digest(item_content(item))
+}
+
+fn item_content(item: &proto::DataItem) -> &[u8] {
+    match item.data_item_dispatch.as_ref() {
+        Some(proto::data_item::DataItemDispatch::RawBytes(bytes)) => bytes,
+        Some(proto::data_item::DataItemDispatch::File(file_data)) => &file_data.file_content,
+        None => &[],
+    }
+}
+
+/// The digest a split of `content_len` bytes can be checked against, `None` for partial
+/// splits, erasure coded items and data written before digests were recorded.
+pub fn recorded_digest(
+    meta: &DataSetMetaV2,
+    idx: DataItemIdx,
+    content_len: usize,
+) -> Option<&[u8]> {
+    let digest = meta.digests.get(idx as usize).filter(|d| !d.is_empty())?;
+    if meta.cache_mode_visitor(idx).is_redundancy_erasure()
+        || content_len != meta.datas_splits[idx as usize].total_size()
+    {
+        return None;
+    }
+    Some(digest)
+}
+
+fn check(
+    unique_id: &[u8],
+    idx: DataItemIdx,
+    expected: &[u8],
+    actual: &[u8],
+    context: impl FnOnce() -> String,
+) -> WSResult<()> {
+    if expected == actual {
+        return Ok(());
+    }
+    Err(WsDataError::ChecksumMismatch {
+        unique_id: unique_id.to_vec(),
+        idx,
+        context: format!(
+            "{}, expected {}, got {}",
+            context(),
+            hex::encode(expected),
+            hex::encode(actual)
+        ),
+    }
+    .into())
+}
+
+/// Check a split holding a whole item against the digest the writer recorded.
+pub fn verify_split(
+    meta: &DataSetMetaV2,
+    unique_id: &[u8],
+    idx: DataItemIdx,
+    item: &proto::DataItem,
+) -> WSResult<()> {
+    let content = item_content(item);
+    let Some(expected) = recorded_digest(meta, idx, content.len()) else {
+        return Ok(());
+    };
+    check(unique_id, idx, expected, &content_digest(content), || {
+        format!("split of version {}", meta.version)
+    })
+}
+
+/// Check a transfer block against the checksum it was sent with.
+pub fn verify_block(req: &proto::BatchDataRequest) -> WSResult<()> {
+    if req.checksum.is_empty() {
+        return Ok(());
+    }
+    check(
+        &req.unique_id,
+        req.data_item_idx as DataItemIdx,
+        &req.checksum,
+        &content_digest(&req.data),
+        || format!("block {} of batch {:?}", req.block_index, req.request_id),
+    )
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScrubbedItem {
+    pub unique_id: String,
+    pub idx: DataItemIdx,
+    pub version: u64,
+    pub reason: String,
+}
+
+/// Result of re-checking the items stored on a node.
+#[derive(Debug, Default, Serialize)]
+pub struct ScrubReport {
+    pub checked: usize,
+    /// partial splits, erasure coded shards and items without a recorded digest
+    pub skipped: usize,
+    pub corrupt: Vec<ScrubbedItem>,
+}
+
+impl DataGeneral {
+    /// Check a fetched item against the digest the writer recorded.
+    /// Directories are skipped, they are unzipped once received.
+    pub(super) async fn verify_fetched_item(
+        &self,
+        meta: &DataSetMetaV2,
+        unique_id: &[u8],
+        idx: DataItemIdx,
+        item: &proto::DataItem,
+    ) -> WSResult<()> {
+        let Some(expected) = meta.digests.get(idx as usize).filter(|d| !d.is_empty()) else {
+            return Ok(());
+        };
+        let actual = match item.data_item_dispatch.as_ref() {
+            Some(proto::data_item::DataItemDispatch::File(file_data))
+                if file_data.file_content.is_empty() =>
+            {
+                if file_data.is_dir_opt {
+                    return Ok(());
+                }
+                file_digest(&self.view.os().file_path.join(&file_data.file_name_opt)).await?
+            }
+            _ => content_digest(item_content(item)),
+        };
+        check(unique_id, idx, expected, &actual, || {
+            format!("read of version {}", meta.version)
+        })
+    }
+
+    /// Re-check every item stored on this node against the digests in its meta.
+    pub fn scrub_items(&self) -> ScrubReport {
+        let kv_store_engine = self.view.kv_store_engine();
+        let start = [KeyTypeDataSetItem { uid: &[], idx: 0 }.id()];
+        let end = super::super::m_kv_store_engine::prefix_end(&start);
+        let mut report = ScrubReport::default();
+        for (key, value) in kv_store_engine.scan_raw_range(&start, end.as_deref(), usize::MAX) {
+            let Ok((unique_id, idx)) = bincode::deserialize::<(Vec<u8>, DataItemIdx)>(&key[1..])
+            else {
+                continue;
+            };
+            let key = KeyTypeDataSetItem {
+                uid: &unique_id,
+                idx,
+            };
+            let Some(persisted) = key.deserialize_from(&value) else {
+                continue;
+            };
+            let corrupt = |version, reason: String| ScrubbedItem {
+                unique_id: String::from_utf8_lossy(&unique_id).into_owned(),
+                idx,
+                version,
+                reason,
+            };
+            let Some((_, meta)) =
+                kv_store_engine.get(&KeyTypeDataSetMeta(&unique_id), false, KvAdditionalConf {})
+            else {
+                report.skipped += 1;
+                continue;
+            };
+            let decoded = if persisted.is_empty() {
+                Err("empty item".to_owned())
+            } else {
+                proto::DataItem::decode_persist(persisted).map_err(|err| err.to_string())
+            };
+            let item = match decoded {
+                Ok(item) => item,
+                Err(err) => {
+                    report.checked += 1;
+                    report.corrupt.push(corrupt(meta.version, err));
+                    continue;
+                }
+            };
+            if recorded_digest(&meta, idx, item_content(&item).len()).is_none() {
+                report.skipped += 1;
+                continue;
+            }
+            report.checked += 1;
+            if let Err(err) = verify_split(&meta, &unique_id, idx, &item) {
+                tracing::warn!("scrub found corrupt item: {}", err);
+                report.corrupt.push(corrupt(meta.version, err.to_string()));
+            }
+        }
+        tracing::info!(
+            "scrub checked {} items, skipped {}, {} corrupt",
+            report.checked,
+            report.skipped,
+            report.corrupt.len()
+        );
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{content_digest, verify_split};
+    use crate::general::{
+        data::m_data_general::{DataSetMetaBuilder, DataSplit, EachNodeSplit},
+        network::{proto, proto_ext::ProtoExtDataItem},
+    };
+
+    #[test]
+    fn test_verify_split_against_recorded_digest() {
+        let content = b"hello waverless".to_vec();
+        let whole = |size: usize| DataSplit {
+            splits: vec![EachNodeSplit {
+                node_id: 1,
+                data_offset: 0,
+                data_size: size as u32,
+                cache_mode: 0,
+            }],
+        };
+        let mut builder = DataSetMetaBuilder::new(vec![None, None]);
+        let _ = builder
+            .set_data_splits(vec![whole(content.len()), whole(content.len())])
+            .set_digests(vec![content_digest(&content), vec![]]);
+        let meta = builder.build();
+
+        let item = proto::DataItem::new_mem_data(content.clone());
+        assert!(verify_split(&meta, b"uid", 0, &item).is_ok());
+        let mut corrupt = content.clone();
+        corrupt[0] ^= 1;
+        let corrupt = proto::DataItem::new_mem_data(corrupt);
+        assert!(verify_split(&meta, b"uid", 0, &corrupt).is_err());
+        // no recorded digest
+        assert!(verify_split(&meta, b"uid", 1, &corrupt).is_ok());
+        // partial split of the item
+        let partial = proto::DataItem::new_mem_data(content[..4].to_vec());
+        assert!(verify_split(&meta, b"uid", 0, &partial).is_ok());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::general::data::m_data_general::checksum;
 use crate::general::data::m_data_general::UniqueId;
 use crate::general::data::m_data_general::{DataItemIdx, DataSplitIdx, GetOrDelDataArgType};
 use crate::general::m_os::OperatingSystem;
@@ -1174,6 +1175,22 @@ impl DataItemArgWrapper {
         }
     }
 
+    /// md5 of the content as it is transferred, directories by their zipped file
+    pub async fn digest(&mut self, filepath: &PathBuf) -> WSResult<Vec<u8>> {
+        let file_data = match &self.dataitem.data_item_dispatch {
+            Some(proto::data_item::DataItemDispatch::RawBytes(bytes)) => {
+                return Ok(checksum::content_digest(bytes))
+            }
+            Some(proto::data_item::DataItemDispatch::File(file_data)) => file_data.clone(),
+            None => return Ok(checksum::content_digest(&[])),
+        };
+        if let Some(tmp_path) = self.get_tmpzipfile(filepath).await? {
+            checksum::file_digest(tmp_path).await
+        } else {
+            checksum::file_digest(&filepath.join(&file_data.file_name_opt)).await
+        }
+    }
+
     pub async fn clone_split_range(
         &mut self,
         filepath: &PathBuf,
@@ -0,0 +1,350 @@
+//! Reed–Solomon coding of one data item, chosen by the master for large files and marked
+//! with `CACHE_MODE_REDUNDANCY_ERASURE_MASK`.
+//!
+//! The item is cut into k data shards covering its bytes like plain splits, plus m parity
+//! shards of the same shard size. Parity splits are marked `CACHE_MODE_REDUNDANCY_PARITY_MASK`
+//! and cover ranges after the item end, so each shard keeps its own replica group.
+//! Any k shards recover the item.
+//!
+//! Shards are coded a stripe at a time: byte `j` of every parity shard only depends on byte `j`
+//! of each data shard, so the writer reads the same stripe of each data shard from the file
+//! instead of the whole item.
+use super::{
+    dataitem::DataItemArgWrapper, DataGeneral, DataSplit, EachNodeSplit,
+    CACHE_MODE_REDUNDANCY_ERASURE_MASK, CACHE_MODE_REDUNDANCY_PARITY_MASK,
+};
+use crate::{
+    general::network::{proto, proto_ext::DataItemExt},
+    result::{WSResult, WsDataError},
+    sys::NodeID,
+};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::ops::Range;
+
+/// Bytes of each shard coded at once.
+pub const ERASURE_STRIPE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErasureLayout {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    pub shard_size: usize,
+    /// size of the item, the last data shard may be shorter than `shard_size`
+    pub data_size: usize,
+}
+
+impl ErasureLayout {
+    /// Cut `data_size` bytes into at most `data_shards` shards, fewer when the tail ones would be empty.
+    pub fn new(data_size: usize, data_shards: usize, parity_shards: usize) -> Self {
+        let shard_size = ((data_size + data_shards - 1) / data_shards).max(1);
+        Self {
+            data_shards: ((data_size + shard_size - 1) / shard_size).max(1),
+            parity_shards,
+            shard_size,
+            data_size,
+        }
+    }
+
+    /// Layout of an erasure coded item from its splits, `None` for replicated ones.
+    pub fn of(split: &DataSplit) -> Option<Self> {
+        let groups = split.replica_groups();
+        let parity: Vec<&EachNodeSplit> = groups
+            .iter()
+            .map(|group| group[0])
+            .filter(|split| split.cache_mode_visitor().is_redundancy_parity())
+            .collect();
+        let shard_size = parity.first()?.data_size as usize;
+        Some(Self {
+            data_shards: groups.len() - parity.len(),
+            parity_shards: parity.len(),
+            shard_size,
+            data_size: split.total_size(),
+        })
+    }
+
+    pub fn shard_cnt(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// Range covered by shard `i`, parity shards follow the item end.
+    pub fn shard_range(&self, i: usize) -> Range<usize> {
+        if i < self.data_shards {
+            let start = i * self.shard_size;
+            start..(start + self.shard_size).min(self.data_size)
+        } else {
+            let start = self.data_size + (i - self.data_shards) * self.shard_size;
+            start..start + self.shard_size
+        }
+    }
+
+    /// Index of the shard a split holds.
+    pub fn shard_index(&self, split: &EachNodeSplit) -> usize {
+        let offset = split.data_offset as usize;
+        if split.cache_mode_visitor().is_redundancy_parity() {
+            self.data_shards + (offset - self.data_size) / self.shard_size
+        } else {
+            offset / self.shard_size
+        }
+    }
+
+    /// One shard on each of `nodes`, data shards first.
+    /// Fails when the parity ranges following the item end don't fit the split offsets.
+    pub fn plan_split(&self, nodes: &[NodeID]) -> WSResult<DataSplit> {
+        assert_eq!(nodes.len(), self.shard_cnt(), "one node per shard");
+        let mut splits = Vec::with_capacity(nodes.len());
+        for (i, &node_id) in nodes.iter().enumerate() {
+            let range = self.shard_range(i);
+            let (Ok(data_offset), Ok(data_size)) =
+                (u32::try_from(range.start), u32::try_from(range.len()))
+            else {
+                return Err(WsDataError::ErasureCodingFailed {
+                    reason: format!("shard {} range {:?} out of u32 offsets", i, range),
+                }
+                .into());
+            };
+            splits.push(EachNodeSplit {
+                node_id,
+                data_offset,
+                data_size,
+                cache_mode: if i < self.data_shards {
+                    CACHE_MODE_REDUNDANCY_ERASURE_MASK
+                } else {
+                    CACHE_MODE_REDUNDANCY_PARITY_MASK
+                } as u32,
+            });
+        }
+        Ok(DataSplit { splits })
+    }
+
+    /// Stripes of a shard, in order.
+    pub fn stripes(&self) -> impl Iterator<Item = Range<usize>> {
+        let shard_size = self.shard_size;
+        (0..shard_size)
+            .step_by(ERASURE_STRIPE_SIZE)
+            .map(move |start| start..(start + ERASURE_STRIPE_SIZE).min(shard_size))
+    }
+
+    /// Item ranges of `stripe` in each data shard, shorter or empty past the item end.
+    pub fn stripe_ranges(&self, stripe: &Range<usize>) -> Vec<Range<usize>> {
+        (0..self.data_shards)
+            .map(|i| {
+                let shard = self.shard_range(i);
+                (shard.start + stripe.start).min(shard.end)
+                    ..(shard.start + stripe.end).min(shard.end)
+            })
+            .collect()
+    }
+
+    /// Parity of one stripe of the data shards, read from `stripe_ranges`.
+    pub fn encode_stripe(&self, data: Vec<Vec<u8>>, stripe_len: usize) -> WSResult<Vec<Vec<u8>>> {
+        let mut shards: Vec<Vec<u8>> = data
+            .into_iter()
+            .chain((0..self.parity_shards).map(|_| vec![]))
+            .map(|mut shard| {
+                shard.resize(stripe_len, 0);
+                shard
+            })
+            .collect();
+        self.codec()?
+            .encode(&mut shards)
+            .map_err(|err| WsDataError::ErasureCodingFailed {
+                reason: format!("encode: {:?}", err),
+            })?;
+        Ok(shards.split_off(self.data_shards))
+    }
+
+    fn codec(&self) -> WSResult<ReedSolomon> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|err| {
+            WsDataError::ErasureCodingFailed {
+                reason: format!("{:?} with layout {:?}", err, self),
+            }
+            .into()
+        })
+    }
+
+    /// All shards of `data`, indexed like `shard_range`.
+    pub fn encode(&self, data: &[u8]) -> WSResult<Vec<Vec<u8>>> {
+        if data.len() != self.data_size {
+            return Err(WsDataError::SizeMismatch {
+                expected: self.data_size,
+                actual: data.len(),
+            }
+            .into());
+        }
+        // data shards are stored as plain splits
+        let mut shards: Vec<Vec<u8>> = (0..self.data_shards)
+            .map(|i| data[self.shard_range(i)].to_vec())
+            .collect();
+        let mut parity = vec![Vec::with_capacity(self.shard_size); self.parity_shards];
+        for stripe in self.stripes() {
+            let stripe_data = self
+                .stripe_ranges(&stripe)
+                .into_iter()
+                .map(|range| data[range].to_vec())
+                .collect();
+            for (shard, part) in parity
+                .iter_mut()
+                .zip(self.encode_stripe(stripe_data, stripe.len())?)
+            {
+                shard.extend(part);
+            }
+        }
+        shards.extend(parity);
+        Ok(shards)
+    }
+
+    /// The item from at least `data_shards` of its shards, indexed like `shard_range`.
+    pub fn decode(&self, mut shards: Vec<Option<Vec<u8>>>) -> WSResult<Vec<u8>> {
+        for (i, shard) in shards.iter_mut().enumerate() {
+            let Some(shard) = shard else {
+                continue;
+            };
+            if shard.len() != self.shard_range(i).len() {
+                return Err(WsDataError::SizeMismatch {
+                    expected: self.shard_range(i).len(),
+                    actual: shard.len(),
+                }
+                .into());
+            }
+            shard.resize(self.shard_size, 0);
+        }
+        self.codec()?.reconstruct_data(&mut shards).map_err(|err| {
+            WsDataError::ErasureCodingFailed {
+                reason: format!("decode: {:?}", err),
+            }
+        })?;
+        let mut data = Vec::with_capacity(self.data_size);
+        for shard in shards.into_iter().take(self.data_shards) {
+            data.extend_from_slice(&shard.unwrap());
+        }
+        data.truncate(self.data_size);
+        Ok(data)
+    }
+}
+
+impl DataGeneral {
+    /// Parity shards of an item being written, reading one stripe of each data shard
+    /// at a time, indexed from the first parity shard.
+    pub(super) async fn encode_parity(
+        &self,
+        item: &mut DataItemArgWrapper,
+        layout: &ErasureLayout,
+    ) -> WSResult<Vec<proto::DataItem>> {
+        let file_path = &self.view.os().file_path;
+        let data_type = item.dataitem.get_data_type();
+        let mut parity = vec![Vec::with_capacity(layout.shard_size); layout.parity_shards];
+        for stripe in layout.stripes() {
+            let mut stripe_data = Vec::with_capacity(layout.data_shards);
+            for range in layout.stripe_ranges(&stripe) {
+                if range.is_empty() {
+                    stripe_data.push(vec![]);
+                    continue;
+                }
+                stripe_data.push(
+                    item.clone_split_range(file_path, range)
+                        .await?
+                        .into_data_bytes(),
+                );
+            }
+            for (shard, part) in parity
+                .iter_mut()
+                .zip(layout.encode_stripe(stripe_data, stripe.len())?)
+            {
+                shard.extend(part);
+            }
+        }
+        Ok(parity
+            .into_iter()
+            .map(|shard| shard_item(&data_type, shard))
+            .collect())
+    }
+}
+
+/// A shard or a decoded item carried the same way as the item it belongs to.
+pub fn shard_item(
+    data_type: &proto::data_item::DataItemDispatch,
+    bytes: Vec<u8>,
+) -> proto::DataItem {
+    let dispatch = match data_type {
+        proto::data_item::DataItemDispatch::File(file_data) => {
+            proto::data_item::DataItemDispatch::File(proto::FileData {
+                file_content: bytes,
+                ..file_data.clone()
+            })
+        }
+        proto::data_item::DataItemDispatch::RawBytes(_) => {
+            proto::data_item::DataItemDispatch::RawBytes(bytes)
+        }
+    };
+    proto::DataItem {
+        data_item_dispatch: Some(dispatch),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErasureLayout, ERASURE_STRIPE_SIZE};
+
+    #[test]
+    fn test_erasure_layout_plan() {
+        let layout = ErasureLayout::new(10, 4, 2);
+        assert_eq!(layout.shard_size, 3);
+        assert_eq!(layout.data_shards, 4);
+        let split = layout.plan_split(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(split.total_size(), 10);
+        assert_eq!(ErasureLayout::of(&split), Some(layout.clone()));
+        let indexes: Vec<usize> = split.splits.iter().map(|s| layout.shard_index(s)).collect();
+        assert_eq!(indexes, vec![0, 1, 2, 3, 4, 5]);
+
+        // the empty tail shard is dropped
+        let layout = ErasureLayout::new(9, 4, 2);
+        assert_eq!((layout.data_shards, layout.shard_size), (3, 3));
+
+        // parity past the u32 offsets
+        let layout = ErasureLayout::new(u32::MAX as usize, 4, 2);
+        assert!(layout.plan_split(&[1, 2, 3, 4, 5, 6]).is_err());
+    }
+
+    #[test]
+    fn test_erasure_encode_in_stripes() {
+        let data: Vec<u8> = (0..3 * ERASURE_STRIPE_SIZE + 7)
+            .map(|i| (i * 31 % 251) as u8)
+            .collect();
+        let layout = ErasureLayout::new(data.len(), 2, 1);
+        assert_eq!(layout.stripes().count(), 2);
+        let shards = layout.encode(&data).unwrap();
+        // the same parity as coding the shards at once
+        let whole = 0..layout.shard_size;
+        let whole_data = layout
+            .stripe_ranges(&whole)
+            .into_iter()
+            .map(|range| data[range].to_vec())
+            .collect();
+        let whole_parity = layout.encode_stripe(whole_data, whole.len()).unwrap();
+        assert_eq!(shards[2..], whole_parity[..]);
+
+        let mut lost: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        lost[1] = None;
+        assert_eq!(layout.decode(lost).unwrap(), data);
+    }
+
+    #[test]
+    fn test_erasure_decode_from_any_k_shards() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let layout = ErasureLayout::new(data.len(), 4, 2);
+        let shards = layout.encode(&data).unwrap();
+        assert_eq!(shards.len(), 6);
+        assert_eq!(shards[0], data[layout.shard_range(0)]);
+
+        let mut lost: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+        lost[0] = None;
+        lost[3] = None;
+        assert_eq!(layout.decode(lost).unwrap(), data);
+
+        let mut too_few: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        too_few[0] = None;
+        too_few[1] = None;
+        too_few[4] = None;
+        assert!(layout.decode(too_few).is_err());
+    }
+}
@@ -1,22 +1,29 @@
-use super::{DataGeneral, DataGeneralView};
+use super::{checksum::ScrubReport, DataGeneral, DataGeneralView, DataItemIdx, DataSetMetaV2};
 use crate::general::data::m_data_general::dataitem::DataItemArgWrapper;
-use crate::general::data::m_data_general::new_data_unique_id_fn_kv;
+use crate::general::data::m_data_general::{
+    new_data_unique_id_fn_kv, DataWriteCond, GetOrDelDataArg, GetOrDelDataArgType,
+};
 use crate::general::network::proto;
 use crate::general::network::proto_ext::data_ope_role::ProtoExtDataOpeRole;
-use crate::general::network::proto_ext::ProtoExtDataItem;
-use crate::result::WSResultExt;
+use crate::general::network::proto_ext::{DataItemExt, ProtoExtDataItem};
+use crate::result::{WSError, WSResultExt, WsDataError};
 use crate::with_option;
 use crate::{result::WSResult, util::syntactic_discipline::with_option};
 use async_raft::State as RaftState;
-use axum::extract::{Multipart, State};
-use axum::http::StatusCode;
+use axum::body::StreamBody;
+use axum::extract::{Multipart, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::routing::post;
-use axum::Router;
-use serde::Serialize;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 impl DataGeneral {
     pub fn register_http(&self) -> WSResult<()> {
@@ -24,7 +31,17 @@ impl DataGeneral {
 
         with_option!(router_holder.option_mut(), router => {
             // router.route("/upload_data", post(handle_write_data))
-            router.merge(Router::new().route("/upload_data", post(handle_upload_data).with_state(self.view.clone())))
+            router.merge(
+                Router::new()
+                    .route("/upload_data", post(handle_upload_data))
+                    .route(
+                        "/data/:unique_id",
+                        get(handle_download_data).delete(handle_delete_data),
+                    )
+                    .route("/data/:unique_id/meta", get(handle_data_meta))
+                    .route("/admin/scrub", post(handle_scrub))
+                    .with_state(self.view.clone()),
+            )
         });
         Ok(())
     }
@@ -51,8 +68,15 @@ struct UploadDataResponse {
 //     }
 // }
 
+#[derive(Debug, Deserialize)]
+struct UploadDataParams {
+    /// copies kept of each split, the key pattern's count or the cluster default when absent
+    replicas: Option<u32>,
+}
+
 async fn handle_upload_data(
     State(view): State<DataGeneralView>,
+    Query(params): Query<UploadDataParams>,
     mut multipart: Multipart,
 ) -> Response {
     // let mut responses = UploadDataResponses {
@@ -131,7 +155,7 @@ async fn handle_upload_data(
     let taskid_value = taskid.task_id;
     let _ = view
         .data_general()
-        .write_data(
+        .write_data_if(
             new_data_unique_id_fn_kv(unique_id.as_bytes()),
             // vec![DataItemArgWrapper::new(proto::DataItem::new_mem_data(
             //     data.to_vec(),
@@ -143,6 +167,10 @@ async fn handle_upload_data(
                 proto::data_schedule_context::OpeRole::new_upload_data(),
                 taskid,
             )),
+            DataWriteCond {
+                replicas: params.replicas.unwrap_or(0),
+                ..Default::default()
+            },
         )
         .await
         .todo_handle("write data failed when upload data");
@@ -176,3 +204,355 @@ async fn handle_upload_data(
 
     (StatusCode::OK, res_str).into_response()
 }
+
+/// Re-check the items stored on this node against their recorded digests.
+async fn handle_scrub(
+    State(view): State<DataGeneralView>,
+) -> Result<Json<ScrubReport>, StatusCode> {
+    tokio::task::spawn_blocking(move || view.data_general().scrub_items())
+        .await
+        .map(Json)
+        .map_err(|err| {
+            tracing::error!("scrub task failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Chunk size of streamed file items.
+const DOWNLOAD_CHUNK_SIZE: u64 = 1024 * 1024;
+
+const HEADER_DATA_VERSION: &str = "x-data-version";
+
+fn data_err_response(err: WSError) -> Response {
+    let status = match &err {
+        WSError::WsDataError(WsDataError::DataSetNotFound { .. }) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    tracing::warn!("data http request failed: {}", err);
+    (status, err.to_string()).into_response()
+}
+
+fn version_headers(meta: &DataSetMetaV2) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let _ = headers.insert(HEADER_DATA_VERSION, HeaderValue::from(meta.version));
+    headers
+}
+
+/// Byte range asked by a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// no header, or one we don't serve such as multiple ranges, the whole item is sent
+    Whole,
+    Part(Range<u64>),
+    Unsatisfiable,
+}
+
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Whole;
+    };
+    if spec.contains(',') {
+        return ByteRange::Whole;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Unsatisfiable;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // suffix of the item
+        match end.parse::<u64>() {
+            Ok(suffix) if suffix > 0 => len.saturating_sub(suffix)..len,
+            _ => return ByteRange::Unsatisfiable,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return ByteRange::Unsatisfiable;
+        };
+        let end = if end.is_empty() {
+            len
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => (end + 1).min(len),
+                _ => return ByteRange::Unsatisfiable,
+            }
+        };
+        start..end
+    };
+    if range.start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(range)
+}
+
+/// Content of a fetched item, files are streamed from disk.
+enum ItemBody {
+    Bytes(Vec<u8>),
+    File {
+        path: PathBuf,
+        len: u64,
+        /// directories are sent as a zip, removed once sent
+        _zipped: Option<tempfile::TempPath>,
+    },
+}
+
+impl ItemBody {
+    async fn new(view: &DataGeneralView, item: proto::DataItem) -> WSResult<Self> {
+        let file_name = match &item.data_item_dispatch {
+            Some(proto::data_item::DataItemDispatch::File(file_data))
+                if file_data.file_content.is_empty() =>
+            {
+                file_data.file_name_opt.clone()
+            }
+            _ => return Ok(Self::Bytes(item.into_data_bytes())),
+        };
+        let path = view.os().file_path.join(file_name);
+        let zipped = if tokio::fs::metadata(&path).await?.is_dir() {
+            let (mut file, zipped) = tempfile::NamedTempFile::new()?.into_parts();
+            crate::util::zip::zip_dir_2_file(&path, zip::CompressionMethod::Stored, &mut file)
+                .await?;
+            Some(zipped)
+        } else {
+            None
+        };
+        let path = zipped.as_ref().map_or(path, |zipped| zipped.to_path_buf());
+        Ok(Self::File {
+            len: tokio::fs::metadata(&path).await?.len(),
+            path,
+            _zipped: zipped,
+        })
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes.len() as u64,
+            Self::File { len, .. } => *len,
+        }
+    }
+
+    fn stream(self, range: Range<u64>) -> BoxStream<'static, io::Result<Vec<u8>>> {
+        match self {
+            Self::Bytes(bytes) => {
+                stream::once(
+                    async move { Ok(bytes[range.start as usize..range.end as usize].to_vec()) },
+                )
+                .boxed()
+            }
+            Self::File { path, _zipped, .. } => stream::try_unfold(
+                (None, path, range, _zipped),
+                |(file, path, range, zipped)| async move {
+                    if range.is_empty() {
+                        return Ok::<_, io::Error>(None);
+                    }
+                    let mut file = match file {
+                        Some(file) => file,
+                        None => {
+                            let mut file = tokio::fs::File::open(&path).await?;
+                            let _ = file.seek(io::SeekFrom::Start(range.start)).await?;
+                            file
+                        }
+                    };
+                    let mut chunk =
+                        vec![0; DOWNLOAD_CHUNK_SIZE.min(range.end - range.start) as usize];
+                    let _ = file.read_exact(&mut chunk).await?;
+                    let range = range.start + chunk.len() as u64..range.end;
+                    Ok(Some((chunk, (Some(file), path, range, zipped))))
+                },
+            )
+            .boxed(),
+        }
+    }
+}
+
+fn item_file_name(meta: &DataSetMetaV2, idx: DataItemIdx, body: &ItemBody) -> Option<String> {
+    let filepath = meta.filepath.get(idx as usize)?.as_ref()?;
+    let name = std::path::Path::new(filepath)
+        .file_name()?
+        .to_string_lossy();
+    Some(match body {
+        ItemBody::File {
+            _zipped: Some(_), ..
+        } => format!("{}.zip", name),
+        _ => name.into_owned(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct DownloadDataParams {
+    /// only this item, all of them as `multipart/mixed` when absent and there are several
+    idx: Option<DataItemIdx>,
+}
+
+/// Items of data uploaded by `/upload_data`. A single item honours a `Range` header.
+async fn handle_download_data(
+    State(view): State<DataGeneralView>,
+    Path(unique_id): Path<String>,
+    Query(params): Query<DownloadDataParams>,
+    req_headers: HeaderMap,
+) -> Response {
+    let unique_id = new_data_unique_id_fn_kv(unique_id.as_bytes());
+    let meta = match view
+        .data_general()
+        .get_or_del_datameta_from_master(&unique_id, false)
+        .await
+    {
+        Ok(meta) => meta,
+        Err(err) => return data_err_response(err),
+    };
+    let ty = match params.idx {
+        Some(idx) if idx as usize >= meta.data_item_cnt() => {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("data has {} items, no idx {}", meta.data_item_cnt(), idx),
+            )
+                .into_response();
+        }
+        Some(idx) => GetOrDelDataArgType::PartialOne { idx },
+        None if meta.data_item_cnt() == 1 => GetOrDelDataArgType::PartialOne { idx: 0 },
+        None => GetOrDelDataArgType::All,
+    };
+    let (meta, mut items) = match view
+        .data_general()
+        .get_or_del_datas(GetOrDelDataArg {
+            meta: Some(meta),
+            unique_id,
+            ty,
+        })
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => return data_err_response(err),
+    };
+    let mut idxs: Vec<DataItemIdx> = items.keys().copied().collect();
+    idxs.sort();
+    let mut bodies = Vec::with_capacity(idxs.len());
+    for idx in idxs {
+        match ItemBody::new(&view, items.remove(&idx).unwrap()).await {
+            Ok(body) => bodies.push((idx, body)),
+            Err(err) => return data_err_response(err),
+        }
+    }
+
+    let mut headers = version_headers(&meta);
+    if bodies.len() == 1 {
+        let (idx, body) = bodies.pop().unwrap();
+        let len = body.len();
+        let _ = headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        let _ = headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        if let Some(name) = item_file_name(&meta, idx, &body) {
+            if let Ok(value) = format!("attachment; filename=\"{}\"", name).parse() {
+                let _ = headers.insert(header::CONTENT_DISPOSITION, value);
+            }
+        }
+        let range = req_headers
+            .get(header::RANGE)
+            .and_then(|range| range.to_str().ok())
+            .map_or(ByteRange::Whole, |range| parse_range(range, len));
+        let (status, range) = match range {
+            ByteRange::Whole => (StatusCode::OK, 0..len),
+            ByteRange::Part(range) => {
+                let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+                let _ = headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+                (StatusCode::PARTIAL_CONTENT, range)
+            }
+            ByteRange::Unsatisfiable => {
+                let content_range = format!("bytes */{}", len);
+                let _ = headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+                return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+            }
+        };
+        let _ = headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from(range.end - range.start),
+        );
+        return (status, headers, StreamBody::new(body.stream(range))).into_response();
+    }
+
+    // one part for each item, in idx order
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let _ = headers.insert(
+        header::CONTENT_TYPE,
+        format!("multipart/mixed; boundary={}", boundary)
+            .parse()
+            .unwrap(),
+    );
+    let mut parts = Vec::with_capacity(bodies.len() * 2 + 1);
+    for (idx, body) in bodies {
+        let disposition = match item_file_name(&meta, idx, &body) {
+            Some(name) => format!("attachment; name=\"{}\"; filename=\"{}\"", idx, name),
+            None => format!("attachment; name=\"{}\"", idx),
+        };
+        let part_header = format!(
+            "--{}\r\nContent-Type: application/octet-stream\r\nContent-Disposition: {}\r\nContent-Length: {}\r\n\r\n",
+            boundary,
+            disposition,
+            body.len()
+        );
+        let len = body.len();
+        parts.push(stream::once(async move { Ok(part_header.into_bytes()) }).boxed());
+        parts.push(body.stream(0..len));
+        parts.push(stream::once(async { Ok(b"\r\n".to_vec()) }).boxed());
+    }
+    let closing = format!("--{}--\r\n", boundary);
+    parts.push(stream::once(async move { Ok(closing.into_bytes()) }).boxed());
+    (
+        StatusCode::OK,
+        headers,
+        StreamBody::new(stream::iter(parts).flatten()),
+    )
+        .into_response()
+}
+
+/// Meta of data uploaded by `/upload_data` as json, its version also in `x-data-version`
+/// so `HEAD` can check for a newer one.
+async fn handle_data_meta(
+    State(view): State<DataGeneralView>,
+    Path(unique_id): Path<String>,
+) -> Response {
+    match view
+        .data_general()
+        .get_or_del_datameta_from_master(&new_data_unique_id_fn_kv(unique_id.as_bytes()), false)
+        .await
+    {
+        Ok(meta) => (version_headers(&meta), Json(meta)).into_response(),
+        Err(err) => data_err_response(err),
+    }
+}
+
+/// Delete data uploaded by `/upload_data` from the nodes holding it.
+async fn handle_delete_data(
+    State(view): State<DataGeneralView>,
+    Path(unique_id): Path<String>,
+) -> Response {
+    match view
+        .data_general()
+        .get_or_del_datas(GetOrDelDataArg {
+            meta: None,
+            unique_id: new_data_unique_id_fn_kv(unique_id.as_bytes()),
+            ty: GetOrDelDataArgType::Delete,
+        })
+        .await
+    {
+        Ok((meta, _)) => (StatusCode::NO_CONTENT, version_headers(&meta)).into_response(),
+        Err(err) => data_err_response(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_range, ByteRange};
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), ByteRange::Part(0..10));
+        assert_eq!(parse_range("bytes=90-", 100), ByteRange::Part(90..100));
+        assert_eq!(parse_range("bytes=-10", 100), ByteRange::Part(90..100));
+        assert_eq!(parse_range("bytes=50-200", 100), ByteRange::Part(50..100));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=9-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), ByteRange::Whole);
+        assert_eq!(parse_range("items=0-1", 100), ByteRange::Whole);
+    }
+}
@@ -1,12 +1,16 @@
 pub mod batch;
 pub mod batch_handler;
+pub mod checksum;
 pub mod dataitem;
+pub mod erasure;
 pub mod http;
+pub mod repair;
 
 /// 缓存模式类型
 pub type CacheMode = u16;
 
 use crate::general::app::m_executor::Executor;
+use crate::general::app::AppMetaManager;
 use crate::general::data::m_data_general::batch_handler::{
     BatchReceiveState, SharedWithBatchHandler,
 };
@@ -17,12 +21,14 @@ use crate::general::network::http_handler::HttpHandler;
 use crate::general::network::proto::{DataItem, FnTaskId};
 use batch_handler::GetOrDelType;
 use dataitem::{DataItemArgWrapper, WriteSplitTaskResult};
+use erasure::ErasureLayout;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use crate::general::{
     data::m_kv_store_engine::{
-        KeyTypeDataSetItem, KeyTypeDataSetMeta, KvAdditionalConf, KvStoreEngine, KvVersion,
+        KeyTypeDataExpiry, KeyTypeDataSetItem, KeyTypeDataSetMeta, KvAdditionalConf, KvStoreEngine,
+        KvVersion,
     },
     m_os::OperatingSystem,
     network::{
@@ -51,7 +57,7 @@ use std::{
     collections::{BTreeSet, HashMap, HashSet},
     sync::atomic::{AtomicU32, Ordering},
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::Semaphore;
 use tokio::task::JoinError;
@@ -74,12 +80,15 @@ logical_module_view_impl!(DataGeneralView, kv_store_engine, KvStoreEngine);
 logical_module_view_impl!(DataGeneralView, os, OperatingSystem);
 logical_module_view_impl!(DataGeneralView, http_handler, Box<dyn HttpHandler>);
 logical_module_view_impl!(DataGeneralView, executor, Executor);
+logical_module_view_impl!(DataGeneralView, appmeta_manager, AppMetaManager);
 
 pub type DataVersion = u64;
 pub type DataItemIdx = u8;
 
 pub const DATA_UID_PREFIX_APP_META: &str = "app";
 pub const DATA_UID_PREFIX_FN_KV: &str = "fkv";
+/// values up to this size are also sent to the master to evaluate trigger conditions
+pub const TRIGGER_VALUE_MAX_BYTES: usize = 64 * 1024;
 
 pub const CACHE_MODE_TIME_MASK: u16 = 0xf000;
 pub const CACHE_MODE_TIME_FOREVER_MASK: u16 = 0x0fff;
@@ -93,6 +102,13 @@ pub const CACHE_MODE_POS_AUTO_MASK: u16 = 0xf2ff;
 pub const CACHE_MODE_MAP_MASK: u16 = 0x00f0;
 pub const CACHE_MODE_MAP_COMMON_KV_MASK: u16 = 0xff0f;
 pub const CACHE_MODE_MAP_FILE_MASK: u16 = 0xff1f;
+
+pub const CACHE_MODE_REDUNDANCY_MASK: u16 = 0x000f;
+pub const CACHE_MODE_REDUNDANCY_REPLICA_MASK: u16 = 0xfff0;
+/// reed-solomon coded item, see `erasure`
+pub const CACHE_MODE_REDUNDANCY_ERASURE_MASK: u16 = 0xfff1;
+/// only on the parity splits of an erasure coded item
+pub const CACHE_MODE_REDUNDANCY_PARITY_MASK: u16 = 0xfff2;
 // const DATA_UID_PREFIX_OBJ: &str = "obj";
 
 pub fn parse_appname_from_data_uid(data_uid: &[u8]) -> Option<String> {
@@ -111,6 +127,50 @@ pub fn new_data_unique_id_app(app_name: &str) -> String {
     format!("{}{}", DATA_UID_PREFIX_APP_META, app_name)
 }
 
+/// The function kv key of a data unique id made by [`new_data_unique_id_fn_kv`].
+pub fn fn_kv_key_of_unique_id(unique_id: &[u8]) -> Option<&[u8]> {
+    unique_id.strip_prefix(DATA_UID_PREFIX_FN_KV.as_bytes())
+}
+
+/// Conditions a write is applied under, checked by master under the data's meta lock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DataWriteCond {
+    /// write only when the data is still at this version, 0 means it must not exist
+    pub expected_version: Option<u64>,
+    /// refused once a write with a newer token was applied, 0 means unfenced
+    pub fencing_token: u64,
+    /// the data expires this long after the write, 0 takes the default of the writing function
+    pub ttl_ms: u64,
+    /// copies kept of each split, 0 takes the key pattern's count or the cluster default
+    pub replicas: u32,
+}
+
+/// Unix milliseconds, the clock data ttls are measured by.
+pub fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Unix milliseconds the data expires at, 0 when it never expires.
+/// Only master and the master candidates keep it.
+pub fn data_expire_at(kv_store_engine: &KvStoreEngine, unique_id: &[u8]) -> u64 {
+    kv_store_engine
+        .get(
+            &KeyTypeDataExpiry(unique_id),
+            false,
+            KvAdditionalConf::default(),
+        )
+        .map_or(0, |(_, expire_at)| expire_at)
+}
+
+/// Expired data is invisible until it's reclaimed.
+pub fn data_expired(kv_store_engine: &KvStoreEngine, unique_id: &[u8]) -> bool {
+    let expire_at = data_expire_at(kv_store_engine, unique_id);
+    expire_at != 0 && expire_at <= unix_now_ms()
+}
+
 pub fn new_data_unique_id_fn_kv(key: &[u8]) -> Vec<u8> {
     let mut temp = DATA_UID_PREFIX_FN_KV.as_bytes().to_owned();
     temp.extend(key);
@@ -129,6 +189,7 @@ pub struct DataGeneral {
     rpc_call_write_once_data: RPCCaller<proto::WriteOneDataRequest>,
     rpc_call_batch_data: RPCCaller<proto::BatchDataRequest>,
     rpc_call_get_data_meta: RPCCaller<proto::DataMetaGetRequest>,
+    rpc_call_delete_data_meta: RPCCaller<proto::DataMetaDeleteRequest>,
     rpc_call_get_data: RPCCaller<proto::GetOneDataRequest>,
 
     //费新文
@@ -138,6 +199,8 @@ pub struct DataGeneral {
     rpc_handler_data_meta_update: RPCHandler<proto::DataMetaUpdateRequest>,
     rpc_handler_get_data_meta: RPCHandler<proto::DataMetaGetRequest>,
     rpc_handler_get_data: RPCHandler<proto::GetOneDataRequest>,
+    rpc_handler_data_digest: RPCHandler<proto::DataItemDigestRequest>,
+    rpc_handler_data_repair: RPCHandler<proto::DataRepairRequest>,
 
     //费新文
     // rpc_handler_distribute_task: RPCHandler<DistributeTaskReq>,
@@ -157,6 +220,7 @@ impl DataGeneral {
         data: proto::DataItem,
         data_item_idx: DataItemIdx,
         node_id: NodeID,
+        replica_copy: bool,
     ) -> WSResult<()> {
         // 调用 batch_transfer 函数处理数据传输
         async fn batch_transfer(
@@ -166,9 +230,14 @@ impl DataGeneral {
             target_node: NodeID,
             data: Arc<DataItemSource>,
             view: DataGeneralView,
+            replica_copy: bool,
         ) -> WSResult<()> {
             let (tx, mut rx) = tokio::sync::mpsc::channel(32);
             let mut handles = Vec::new();
+            // 所有数据块属于同一个批量请求, 接收端据此汇总
+            let request_id = view
+                .data_general()
+                .next_batch_id(view.p2p().nodes_config.this_node());
 
             let data_size = data.size().await?;
             let splits = calculate_splits(data_size);
@@ -234,10 +303,7 @@ impl DataGeneral {
                 };
 
                 let request = proto::BatchDataRequest {
-                    request_id: Some(proto::BatchRequestId {
-                        node_id: target_node as u32,
-                        sequence: block_idx as u64,
-                    }),
+                    request_id: Some(request_id.clone()),
                     dataset_unique_id: unique_id.clone(),
                     data_item_idx: data_item_idx as u32,
                     // 用空的 DataItem 代替
@@ -258,11 +324,13 @@ impl DataGeneral {
                         }),
                     },
                     block_index: block_idx as u32,
+                    checksum: checksum::content_digest(&block_data),
                     data: block_data,
                     operation: proto::DataOpeType::Write as i32,
                     unique_id: unique_id.clone(),
                     version,
                     total_size: data_size as u64,
+                    replica_copy,
                 };
 
                 let tx = tx.clone();
@@ -337,6 +405,7 @@ impl DataGeneral {
             node_id,
             data,
             self.view.clone(),
+            replica_copy,
         )
         .await
     }
@@ -352,13 +421,24 @@ impl DataGeneral {
             delete,
             self.view.p2p().nodes_config.this.0
         );
+        if delete {
+            return self
+                .del_datameta_from_master(unique_id, None)
+                .await?
+                .ok_or_else(|| {
+                    WsDataError::DataSetNotFound {
+                        uniqueid: unique_id.to_vec(),
+                    }
+                    .into()
+                });
+        }
         let p2p = self.view.p2p();
         // get meta from master
         let meta = self
             .rpc_call_get_data_meta
             .call(
                 p2p,
-                p2p.nodes_config.get_master_node(),
+                p2p.nodes_config.get_master_node()?,
                 proto::DataMetaGetRequest {
                     unique_id: unique_id.to_vec(),
                     delete,
@@ -383,6 +463,49 @@ impl DataGeneral {
         })
     }
 
+    /// Delete the meta on master, when given only if the data is at `expected_version`, 0 for
+    /// data that doesn't exist. Returns the deleted meta, none when there was none.
+    /// Fails with `WsDataError::VersionMismatch` otherwise.
+    pub async fn del_datameta_from_master(
+        &self,
+        unique_id: &[u8],
+        expected_version: Option<u64>,
+    ) -> WSResult<Option<DataSetMetaV2>> {
+        let p2p = self.view.p2p();
+        let resp = self
+            .rpc_call_delete_data_meta
+            .call(
+                p2p,
+                p2p.nodes_config.get_master_node()?,
+                proto::DataMetaDeleteRequest {
+                    unique_id: unique_id.to_vec(),
+                    expected_version: expected_version
+                        .map(|version| proto::DataVersionCond { version }),
+                },
+                Some(Duration::from_secs(60)),
+            )
+            .await?;
+        if let Some(current) = resp.current_version {
+            return Err(WsDataError::VersionMismatch {
+                expected: expected_version.unwrap_or_default(),
+                actual: current.version,
+            }
+            .into());
+        }
+        if resp.serialized_meta.is_empty() {
+            return Ok(None);
+        }
+        bincode::deserialize(&resp.serialized_meta)
+            .map(Some)
+            .map_err(|err| {
+                WsSerialErr::BincodeErr {
+                    err,
+                    context: "del_datameta_from_master".to_owned(),
+                }
+                .into()
+            })
+    }
+
     pub async fn get_or_del_datas(
         &self,
         GetOrDelDataArg {
@@ -571,7 +694,7 @@ impl DataGeneral {
     pub async fn write_data(
         &self,
         unique_id: impl Into<Vec<u8>>,
-        mut datas: Vec<DataItemArgWrapper>,
+        datas: Vec<DataItemArgWrapper>,
         context_openode_opetype_operole_src: Option<(
             NodeID,
             proto::DataOpeType,
@@ -579,12 +702,37 @@ impl DataGeneral {
             proto::FnTaskId,
         )>,
     ) -> WSResult<()> {
+        self.write_data_if(
+            unique_id,
+            datas,
+            context_openode_opetype_operole_src,
+            DataWriteCond::default(),
+        )
+        .await
+        .map(|_version| ())
+    }
+
+    /// Write only when `cond` holds, returns the written version.
+    /// Fails with `WsDataError::VersionMismatch` or `WsDataError::StaleFencingToken` otherwise.
+    pub async fn write_data_if(
+        &self,
+        unique_id: impl Into<Vec<u8>>,
+        mut datas: Vec<DataItemArgWrapper>,
+        context_openode_opetype_operole_src: Option<(
+            NodeID,
+            proto::DataOpeType,
+            proto::data_schedule_context::OpeRole,
+            proto::FnTaskId,
+        )>,
+        cond: DataWriteCond,
+    ) -> WSResult<u64> {
         let unique_id = unique_id.into();
         let log_tag = format!("[write_data({})]", String::from_utf8_lossy(&unique_id));
         tracing::debug!("{} start write data", log_tag);
 
         let mut data_transfer_sizes = Vec::new();
         data_transfer_sizes.reserve(datas.len());
+        let mut digests = Vec::with_capacity(datas.len());
         for d in datas.iter_mut() {
             data_transfer_sizes.push(d.get_data_size(&self.view.os().file_path).await.map_err(
                 |err| {
@@ -592,13 +740,17 @@ impl DataGeneral {
                     err
                 },
             )?);
+            digests.push(d.digest(&self.view.os().file_path).await.map_err(|err| {
+                tracing::error!("{} digest error: {}", log_tag, err);
+                err
+            })?);
         }
         // 获取数据调度计划
         let version_schedule_resp = self
             .rpc_call_data_version_schedule
             .call(
                 self.view.p2p(),
-                self.view.p2p().nodes_config.get_master_node(),
+                self.view.p2p().nodes_config.get_master_node()?,
                 proto::DataVersionScheduleRequest {
                     unique_id: unique_id.clone(),
                     context: context_openode_opetype_operole_src.map(
@@ -617,10 +769,28 @@ impl DataGeneral {
                                     .iter()
                                     .map(|d| d.filepath().unwrap_or_default())
                                     .collect(),
+                                trigger_value: match datas
+                                    .first()
+                                    .and_then(|d| d.dataitem.data_item_dispatch.as_ref())
+                                {
+                                    Some(proto::data_item::DataItemDispatch::RawBytes(bytes))
+                                        if bytes.len() <= TRIGGER_VALUE_MAX_BYTES =>
+                                    {
+                                        bytes.clone()
+                                    }
+                                    _ => vec![],
+                                },
+                                digests,
                             }
                         },
                     ),
                     version: 0,
+                    expected_version: cond
+                        .expected_version
+                        .map(|version| proto::DataVersionCond { version }),
+                    fencing_token: cond.fencing_token,
+                    ttl_ms: cond.ttl_ms,
+                    replicas: cond.replicas,
                 },
                 Some(Duration::from_secs(60)),
             )
@@ -633,6 +803,31 @@ impl DataGeneral {
                 );
                 err
             })?;
+        if let Some(current) = version_schedule_resp.current_version {
+            tracing::debug!(
+                "{} data is at version {}, not written",
+                log_tag,
+                current.version
+            );
+            return Err(WsDataError::VersionMismatch {
+                expected: cond.expected_version.unwrap_or_default(),
+                actual: current.version,
+            }
+            .into());
+        }
+        if version_schedule_resp.fenced_by != 0 {
+            tracing::debug!(
+                "{} fencing token {} is older than {}, not written",
+                log_tag,
+                cond.fencing_token,
+                version_schedule_resp.fenced_by
+            );
+            return Err(WsDataError::StaleFencingToken {
+                token: cond.fencing_token,
+                fenced_by: version_schedule_resp.fenced_by,
+            }
+            .into());
+        }
 
         // Clone the response to extend its lifetime
         let version = version_schedule_resp.version;
@@ -643,11 +838,20 @@ impl DataGeneral {
         // 处理每个数据项
         let mut iter = WantIdxIter::new(&GetOrDelDataArgType::All, datas.len() as u8);
         while let Some(data_item_idx) = iter.next() {
-            let data_item: &DataItemArgWrapper = &mut datas[data_item_idx as usize];
+            let data_item: &mut DataItemArgWrapper = &mut datas[data_item_idx as usize];
             let split = &splits[data_item_idx as usize];
             let mut primary_tasks = Vec::new();
 
-            // 1. 并行写入所有主数据分片
+            // 纠删码数据项按条带编码出校验分片, 数据分片和普通分片一样按范围读取
+            let erasure = match ErasureLayout::of(&DataSplit::from(split.clone())) {
+                Some(layout) => {
+                    let parity = self.encode_parity(data_item, &layout).await?;
+                    Some((layout, parity))
+                }
+                None => None,
+            };
+
+            // 1. 并行写入所有分片及其副本
             let mut split_iter =
                 WantIdxIter::new(&GetOrDelDataArgType::All, split.splits.len() as u8);
             while let Some(split_idx) = split_iter.next() {
@@ -665,15 +869,23 @@ impl DataGeneral {
                 let unique_id_clone = unique_id.clone();
                 // let data_item_primary = data_item.clone_split_range(split_info.data_offset..split_info.data_offset+split_info.data_size);        类型不匹配     曾俊
                 // 生成一个复制的可变数据项
-                let mut data_item_clone = (*data_item).clone();
-                let data_item_primary = data_item_clone
-                    .clone_split_range(
-                        &self.view.os().file_path,
-                        split_info.data_offset as usize
-                            ..(split_info.data_offset + split_info.data_size) as usize,
-                    )
-                    .await
-                    .todo_handle("clone_split_range for write data err")?;
+                let parity_shard = erasure.as_ref().and_then(|(layout, parity)| {
+                    let shard = layout.shard_index(&split_info.clone().into());
+                    parity.get(shard.checked_sub(layout.data_shards)?)
+                });
+                let data_item_primary = if let Some(parity_shard) = parity_shard {
+                    parity_shard.clone()
+                } else {
+                    let mut data_item_clone = (*data_item).clone();
+                    data_item_clone
+                        .clone_split_range(
+                            &self.view.os().file_path,
+                            split_info.data_offset as usize
+                                ..(split_info.data_offset + split_info.data_size) as usize,
+                        )
+                        .await
+                        .todo_handle("clone_split_range for write data err")?
+                };
 
                 #[cfg(test)]
                 {
@@ -704,7 +916,7 @@ impl DataGeneral {
                         )
                         .await
                 });
-                primary_tasks.push(task);
+                primary_tasks.push((split_info.node_id, task));
             }
 
             // 2. 并行写入缓存数据（完整数据）
@@ -765,15 +977,36 @@ impl DataGeneral {
             //     }
             // }
 
+            let (primary_nodes, primary_tasks): (Vec<_>, Vec<_>) =
+                primary_tasks.into_iter().unzip();
             let primary_results = futures::future::join_all(primary_tasks).await;
             tracing::debug!("{} primary_results: {:?}", log_tag, primary_results);
             // let cache_results = futures::future::join_all(cache_tasks).await;
             // tracing::debug!("{} cache_results: {:?}", log_tag, cache_results);
 
-            if primary_results.iter().any(|res| res.is_err())
+            // a quorum of the replicas is enough, repair copies the data to the rest
+            let written: Vec<bool> = primary_results
+                .iter()
+                .map(|res| matches!(res, Ok(Ok(resp)) if resp.success))
+                .collect();
+            let failed_nodes: Vec<NodeID> = primary_nodes
+                .into_iter()
+                .zip(written.iter())
+                .filter(|(_, written)| !**written)
+                .map(|(node, _)| node)
+                .collect();
+            if !failed_nodes.is_empty() {
+                tracing::warn!(
+                    "{} data item {} not written to nodes {:?}, left to repair",
+                    log_tag,
+                    data_item_idx,
+                    failed_nodes
+                );
+            }
+            if !DataSplit::from(split.clone()).write_quorum_met(&written)
             // || cache_results.iter().any(|res| res.is_err())
             {
-                let error_msg = format!("主节点或缓存节点数据写入失败");
+                let error_msg = format!("主节点或副本节点数据写入失败: {:?}", failed_nodes);
                 tracing::error!("{}", error_msg);
                 return Err(WSError::WsDataError(WsDataError::WriteDataFailed {
                     unique_id: unique_id.clone(),
@@ -782,7 +1015,7 @@ impl DataGeneral {
             }
         }
 
-        Ok(())
+        Ok(version)
     }
 
     async fn rpc_handle_write_one_data(
@@ -945,6 +1178,29 @@ impl DataGeneral {
             // .todo_handle("3 err_comment waitting to fill");
             return;
         }
+        let meta = &required_meta.as_ref().unwrap().1;
+        for data_with_idx in req.data.iter() {
+            let Some(data) = data_with_idx.data.as_ref() else {
+                continue;
+            };
+            if let Err(err) =
+                checksum::verify_split(meta, &req.unique_id, data_with_idx.idx as DataItemIdx, data)
+            {
+                drop(guard);
+                tracing::warn!("reject corrupt data partial: {}", err);
+                if let Err(e) = responsor
+                    .send_resp(WriteOneDataResponse {
+                        remote_version: req.version,
+                        success: false,
+                        message: err.to_string(),
+                    })
+                    .await
+                {
+                    tracing::error!("Failed to send write one data response 5: {}", e);
+                }
+                return;
+            }
+        }
 
         for data_with_idx in req.data.into_iter() {
             let proto::DataItemWithIdx { idx, data } = data_with_idx;
@@ -1021,6 +1277,13 @@ impl DataGeneral {
             node: self.view.p2p().nodes_config.this_node(),
         };
 
+        // the versions of an app are in the dataset of its meta
+        if let Some(app) = parse_appname_from_data_uid(&req.unique_id) {
+            self.view
+                .appmeta_manager()
+                .invalidate_routing_versions(&app);
+        }
+
         let key = KeyTypeDataSetMeta(&req.unique_id);
         let keybytes = key.make_key();
 
@@ -1155,19 +1418,20 @@ impl DataGeneral {
         tracing::debug!("starting rpc_handle_get_one_data {:?}", req);
 
         let kv_store_engine = self.view.kv_store_engine();
-        let _ = self
-            .view
-            .get_metadata(&req.unique_id, req.delete)
-            .await
-            .map_err(|err| {
+        let meta = match self.view.get_metadata(&req.unique_id, req.delete).await {
+            Ok(meta) => Some(meta),
+            // the deleter may have dropped the meta on master first, the items go all the same
+            Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) if req.delete => None,
+            Err(err) => {
                 tracing::warn!("rpc_handle_get_one_data get_metadata failed: {:?}", err);
-                err
-            })?;
+                return Err(err);
+            }
+        };
 
         let mut got_or_deleted = vec![];
         let mut kv_ope_err = vec![];
 
-        for idx in req.idxs {
+        for &idx in req.idxs.iter() {
             let value = if req.delete {
                 tracing::debug!("deleting data item at idx: {}", idx);
                 match kv_store_engine.del(
@@ -1233,7 +1497,7 @@ impl DataGeneral {
 
         let mut got_or_deleted_checked: Vec<proto::DataItem> = vec![];
         if success {
-            for v in got_or_deleted {
+            for (&idx, v) in req.idxs.iter().zip(got_or_deleted) {
                 #[cfg(test)]
                 {
                     tracing::debug!(
@@ -1257,6 +1521,20 @@ impl DataGeneral {
                                 &item.clone().into_data_bytes()[0..30]
                             );
                         }
+                        // a corrupt replica fails the read, so the reader turns to another one
+                        if let Some(meta) = meta.as_ref().filter(|_| !req.delete) {
+                            if let Err(err) = checksum::verify_split(
+                                meta,
+                                &req.unique_id,
+                                idx as DataItemIdx,
+                                &item,
+                            ) {
+                                tracing::error!("{}", err);
+                                success = false;
+                                message = err.to_string();
+                                break;
+                            }
+                        }
                         got_or_deleted_checked.push(item);
                     }
                     Err(e) => {
@@ -1394,6 +1672,35 @@ pub struct DataSetMetaV2 {
     pub synced_nodes: HashSet<NodeID>,
     pub cache_mode: Vec<CacheMode>,
     pub filepath: Vec<Option<String>>,
+    /// md5 of each item's content, empty when the writer didn't record one
+    pub digests: Vec<Vec<u8>>,
+}
+
+/// `DataSetMetaV2` as stored before item digests were recorded
+#[derive(Serialize, Deserialize)]
+pub struct DataSetMetaV2NoDigest {
+    api_version: u8,
+    pub version: u64,
+    pub datas_splits: Vec<DataSplit>,
+    pub data_metas: Vec<DataMetaSys>,
+    pub synced_nodes: HashSet<NodeID>,
+    pub cache_mode: Vec<CacheMode>,
+    pub filepath: Vec<Option<String>>,
+}
+
+impl From<DataSetMetaV2NoDigest> for DataSetMetaV2 {
+    fn from(d: DataSetMetaV2NoDigest) -> Self {
+        Self {
+            api_version: d.api_version,
+            version: d.version,
+            digests: vec![vec![]; d.datas_splits.len()],
+            datas_splits: d.datas_splits,
+            data_metas: d.data_metas,
+            synced_nodes: d.synced_nodes,
+            cache_mode: d.cache_mode,
+            filepath: d.filepath,
+        }
+    }
 }
 
 impl DataSetMetaV2 {
@@ -1435,6 +1742,7 @@ impl EachNodeSplit {
 
 /// 数据项的分片信息
 /// 我们需要知道每个数据项的分片大小
+/// 覆盖相同数据范围的分片互为副本, 先出现的为主分片
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DataSplit {
     pub splits: Vec<EachNodeSplit>,
@@ -1442,7 +1750,64 @@ pub struct DataSplit {
 
 impl DataSplit {
     pub fn total_size(&self) -> usize {
-        self.splits.iter().map(|s| s.data_size as usize).sum()
+        self.splits
+            .iter()
+            .filter(|s| !s.cache_mode_visitor().is_redundancy_parity())
+            .map(|s| (s.data_offset + s.data_size) as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the splits `written` (indexed like `splits`) keep the item readable through
+    /// failures: a majority of each replica group, or enough erasure shards to decode with
+    /// half of the parity shards to spare.
+    pub fn write_quorum_met(&self, written: &[bool]) -> bool {
+        // (range, replicas, replicas written) of each group
+        let mut groups: Vec<((u32, u32), usize, usize)> = Vec::new();
+        for (i, split) in self.splits.iter().enumerate() {
+            let range = (split.data_offset, split.data_size);
+            let idx = match groups.iter().position(|(r, _, _)| *r == range) {
+                Some(idx) => idx,
+                None => {
+                    groups.push((range, 0, 0));
+                    groups.len() - 1
+                }
+            };
+            groups[idx].1 += 1;
+            if written.get(i).copied().unwrap_or(false) {
+                groups[idx].2 += 1;
+            }
+        }
+        if let Some(layout) = ErasureLayout::of(self) {
+            let shards_written = groups.iter().filter(|(_, _, w)| *w > 0).count();
+            return shards_written >= layout.data_shards + (layout.parity_shards + 1) / 2;
+        }
+        groups.iter().all(|(_, total, w)| *w > *total / 2)
+    }
+
+    /// Splits grouped by the range they cover, the primary first in each group.
+    pub fn replica_groups(&self) -> Vec<Vec<&EachNodeSplit>> {
+        let mut groups: Vec<Vec<&EachNodeSplit>> = Vec::new();
+        for split in &self.splits {
+            match groups.iter_mut().find(|group| {
+                group[0].data_offset == split.data_offset && group[0].data_size == split.data_size
+            }) {
+                Some(group) => group.push(split),
+                None => groups.push(vec![split]),
+            }
+        }
+        groups
+    }
+
+    /// Whether `node` holds a split covering the whole item, so reading it there is local.
+    pub fn held_whole_by(&self, node: NodeID) -> bool {
+        let total_size = self.total_size();
+        self.splits.iter().any(|split| {
+            split.node_id == node
+                && split.data_offset == 0
+                && split.data_size as usize == total_size
+                && !split.cache_mode_visitor().is_redundancy_parity()
+        })
     }
 }
 
@@ -1514,6 +1879,18 @@ impl Into<proto::EachNodeSplit> for EachNodeSplit {
             node_id: self.node_id,
             data_offset: self.data_offset,
             data_size: self.data_size,
+            cache_mode: self.cache_mode,
+        }
+    }
+}
+
+impl From<proto::EachNodeSplit> for EachNodeSplit {
+    fn from(s: proto::EachNodeSplit) -> Self {
+        Self {
+            node_id: s.node_id,
+            data_offset: s.data_offset,
+            data_size: s.data_size,
+            cache_mode: s.cache_mode,
         }
     }
 }
@@ -1525,6 +1902,14 @@ impl Into<proto::DataSplit> for DataSplit {
         }
     }
 }
+
+impl From<proto::DataSplit> for DataSplit {
+    fn from(s: proto::DataSplit) -> Self {
+        Self {
+            splits: s.splits.into_iter().map(|s| s.into()).collect(),
+        }
+    }
+}
 //     uint32 split_size = 1;
 //   repeated uint32 node_ids = 2;
 
@@ -1561,7 +1946,10 @@ generate_cache_mode_methods!(
     (pos, specnode),
     (pos, auto),
     (map, common_kv),
-    (map, file)
+    (map, file),
+    (redundancy, replica),
+    (redundancy, erasure),
+    (redundancy, parity)
 );
 
 #[test]
@@ -1579,6 +1967,12 @@ fn test_cache_mode_visitor() {
     assert!(cache_mode_visitor.is_map_file());
     assert!(!cache_mode_visitor.is_map_common_kv());
 
+    let cache_mode_visitor = CacheModeVisitor(0);
+    assert!(cache_mode_visitor.is_redundancy_replica());
+    let cache_mode_visitor = CacheModeVisitor(CACHE_MODE_REDUNDANCY_PARITY_MASK);
+    assert!(cache_mode_visitor.is_redundancy_parity());
+    assert!(!cache_mode_visitor.is_redundancy_erasure());
+
     // test builder
 
     let meta = DataSetMetaBuilder::new(vec![None])
@@ -1601,6 +1995,130 @@ fn test_cache_mode_visitor() {
     assert!(!meta.cache_mode_visitor(0).is_time_auto());
 }
 
+#[test]
+fn test_data_split_replica_groups() {
+    let split = |node_id, data_offset, data_size| EachNodeSplit {
+        node_id,
+        data_offset,
+        data_size,
+        cache_mode: 0,
+    };
+    let data_split = DataSplit {
+        splits: vec![
+            split(1, 0, 10),
+            split(2, 10, 5),
+            split(3, 0, 10),
+            split(4, 10, 5),
+        ],
+    };
+    // replicas don't count in the item size
+    assert_eq!(data_split.total_size(), 15);
+    let groups: Vec<Vec<NodeID>> = data_split
+        .replica_groups()
+        .into_iter()
+        .map(|group| group.into_iter().map(|s| s.node_id).collect())
+        .collect();
+    assert_eq!(groups, vec![vec![1, 3], vec![2, 4]]);
+    // partial splits don't make a node hold the item
+    assert!(!data_split.held_whole_by(1));
+    let whole = DataSplit {
+        splits: vec![split(1, 0, 15), split(2, 0, 15)],
+    };
+    assert!(whole.held_whole_by(2) && !whole.held_whole_by(3));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_trigger_locality() {
+    use crate::general::app::{limits::FnLimits, FnMeta, FnSyncAsyncSupport};
+    use crate::general::test_utils;
+    use crate::master::m_master::MasterView;
+    use prometheus_client::encoding::text::encode;
+
+    // sys1 is the master, sys2 the only worker
+    let (_hold, sys1, _sys2) = test_utils::get_test_sys().await;
+    let view = MasterView::new(sys1);
+    let app = "test_trigger_locality_app";
+    let fnmeta = FnMeta {
+        sync_async: FnSyncAsyncSupport::Async,
+        calls: vec![],
+        data_accesses: None,
+        affinity: None,
+        anti_affinity: None,
+        schedule: None,
+        limits: FnLimits::default(),
+    };
+    let held_by = |node_id| DataSplit {
+        splits: vec![EachNodeSplit {
+            node_id,
+            data_offset: 0,
+            data_size: 10,
+            cache_mode: 0,
+        }],
+    };
+
+    // data on the worker runs there, data only on the master can't
+    for (holder, local) in [(2, true), (2, true), (1, false)] {
+        let data_split = held_by(holder);
+        let near: HashSet<NodeID> = [1, 2]
+            .into_iter()
+            .filter(|&node| data_split.held_whole_by(node))
+            .collect();
+        let nodes = view
+            .master()
+            .select_nodes_by_affinity(app, "handle", &fnmeta, &near)
+            .unwrap();
+        assert_eq!(nodes, vec![2]);
+        assert_eq!(near.contains(&nodes[0]), local);
+        view.metric_observor()
+            .record_trigger_locality(app, near.contains(&nodes[0]));
+    }
+
+    let mut text = String::new();
+    encode(&mut text, &view.metric_observor().registry).unwrap();
+    let count = |locality: &str| {
+        text.lines()
+            .find(|line| {
+                line.starts_with("trigger_locality")
+                    && line.contains(app)
+                    && line.contains(locality)
+            })
+            .and_then(|line| line.rsplit(' ').next())
+            .map(|count| count.to_owned())
+    };
+    assert_eq!(count("Local").as_deref(), Some("2"));
+    assert_eq!(count("Remote").as_deref(), Some("1"));
+}
+
+#[test]
+fn test_data_split_write_quorum() {
+    let split = |node_id, data_offset, data_size| EachNodeSplit {
+        node_id,
+        data_offset,
+        data_size,
+        cache_mode: 0,
+    };
+    let data_split = DataSplit {
+        splits: vec![
+            split(1, 0, 10),
+            split(2, 10, 5),
+            split(3, 0, 10),
+            split(4, 10, 5),
+            split(5, 0, 10),
+        ],
+    };
+    // a majority of each range
+    assert!(data_split.write_quorum_met(&[true, true, true, false, false]));
+    assert!(!data_split.write_quorum_met(&[true, false, true, true, false]));
+    assert!(!data_split.write_quorum_met(&[true, true, false, true, false]));
+
+    // 4 data and 2 parity shards decode from any 4, writing 5 leaves one to lose
+    let erasure = ErasureLayout::new(40, 4, 2)
+        .plan_split(&[1, 2, 3, 4, 5, 6])
+        .unwrap();
+    assert!(erasure.write_quorum_met(&[true, true, false, true, true, true]));
+    assert!(!erasure.write_quorum_met(&[true, true, false, true, false, true]));
+}
+
 pub struct DataSetMetaBuilder {
     building: Option<DataSetMetaV2>,
 }
@@ -1620,6 +2138,7 @@ impl DataSetMetaBuilder {
                 synced_nodes: HashSet::new(),
                 cache_mode: vec![],
                 filepath: filepath,
+                digests: vec![],
             }),
         }
     }
@@ -1649,6 +2168,18 @@ impl DataSetMetaBuilder {
         let building = self.building.as_mut().unwrap();
         building.datas_splits = splits;
         building.cache_mode = vec![0; building.datas_splits.len()];
+        building.digests = vec![vec![]; building.datas_splits.len()];
+        self
+    }
+
+    pub fn set_digests(&mut self, digests: Vec<Vec<u8>>) -> &mut Self {
+        let building = self.building.as_mut().unwrap();
+        assert_eq!(
+            digests.len(),
+            building.datas_splits.len(),
+            "digests len must be equal to data splits len"
+        );
+        building.digests = digests;
         self
     }
 
@@ -1692,6 +2223,7 @@ impl GetOrDelDataArgType {
 }
 
 impl DataGeneralView {
+    /// Deleting on master leaves the meta to [`DataGeneral::del_datameta_from_master`].
     fn get_data_meta_local(
         &self,
         unique_id: &[u8],
@@ -1708,7 +2240,14 @@ impl DataGeneralView {
         let _guard = write_lock.write();
 
         let meta_opt = if delete {
-            kv_store_engine.del(key, true)?
+            if self.p2p().nodes_config.this_is_master() {
+                // dropped with what master keeps along, replicated to the candidates
+                None
+            } else {
+                kv_store_engine.del(key, true)?
+            }
+        } else if data_expired(kv_store_engine, unique_id) {
+            None
         } else {
             kv_store_engine.get(&key, true, KvAdditionalConf {})
         };
@@ -1748,6 +2287,7 @@ impl LogicalModule for DataGeneral {
             rpc_call_write_once_data: RPCCaller::new(),
             rpc_call_batch_data: RPCCaller::new(),
             rpc_call_get_data_meta: RPCCaller::new(),
+            rpc_call_delete_data_meta: RPCCaller::new(),
             rpc_call_get_data: RPCCaller::new(),
 
             // //费新文
@@ -1758,6 +2298,8 @@ impl LogicalModule for DataGeneral {
             rpc_handler_data_meta_update: RPCHandler::new(),
             rpc_handler_get_data_meta: RPCHandler::new(),
             rpc_handler_get_data: RPCHandler::new(),
+            rpc_handler_data_digest: RPCHandler::new(),
+            rpc_handler_data_repair: RPCHandler::new(),
 
             // 批量数据接收状态管理
             batch_receive_states: AsyncInitMap::new(),
@@ -1786,6 +2328,7 @@ impl LogicalModule for DataGeneral {
             self.rpc_call_write_once_data.regist(p2p);
             self.rpc_call_batch_data.regist(p2p);
             self.rpc_call_get_data_meta.regist(p2p);
+            self.rpc_call_delete_data_meta.regist(p2p);
             self.rpc_call_get_data.regist(p2p);
 
             //费新文
@@ -1882,10 +2425,48 @@ impl LogicalModule for DataGeneral {
                     Ok(())
                 },
             );
+
+            let view = self.view.clone();
+            self.rpc_handler_data_digest
+                .regist(p2p, move |responsor, req| {
+                    let view = view.clone();
+                    let _ = tokio::spawn(async move {
+                        let resp = view.data_general().data_item_digests(&req);
+                        if let Err(err) = responsor.send_resp(resp).await {
+                            tracing::error!("send data digest resp failed with err: {}", err);
+                        }
+                    });
+                    Ok(())
+                });
+
+            let view = self.view.clone();
+            self.rpc_handler_data_repair
+                .regist(p2p, move |responsor, req| {
+                    let view = view.clone();
+                    let _ = tokio::spawn(async move {
+                        let resp = view.data_general().repair_data_item(req).await;
+                        if let Err(err) = responsor.send_resp(resp).await {
+                            tracing::error!("send data repair resp failed with err: {}", err);
+                        }
+                    });
+                    Ok(())
+                });
         }
 
         Ok(vec![])
     }
+    async fn shutdown(&self) -> WSResult<()> {
+        // the node is drained, peers read and write the other replicas from now on
+        let p2p = self.view.p2p();
+        self.rpc_handler_write_once_data.unregist(p2p);
+        self.rpc_handler_batch_data.unregist(p2p);
+        self.rpc_handler_data_meta_update.unregist(p2p);
+        self.rpc_handler_get_data_meta.unregist(p2p);
+        self.rpc_handler_get_data.unregist(p2p);
+        self.rpc_handler_data_digest.unregist(p2p);
+        self.rpc_handler_data_repair.unregist(p2p);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
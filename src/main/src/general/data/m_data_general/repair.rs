@@ -0,0 +1,222 @@
+//! Data node side of the master's replica repair, see `DataMaster::repair_data`.
+//!
+//! Master compares the content digests replicas report for an item with the one the writer
+//! recorded in the meta, then asks a healthy replica to copy its persisted item to the broken
+//! or new nodes over the batch transfer path, the receiver stores it as is. A lost shard of an
+//! erasure coded item is rebuilt by a node holding another shard, decoding the item from the
+//! shards left.
+use super::{
+    batch_handler::GetOrDelType,
+    checksum,
+    erasure::{self, ErasureLayout},
+    DataGeneral, DataItemIdx, DataSetMetaV2,
+};
+use crate::{
+    general::{
+        data::m_kv_store_engine::{
+            KeyLockGuard, KeyType, KeyTypeDataSetItem, KeyTypeDataSetMeta, KvAdditionalConf,
+        },
+        network::{
+            proto,
+            proto_ext::{DataItemExt, ProtoExtDataItem},
+        },
+    },
+    result::{WSResult, WsDataError},
+    sys::NodeID,
+};
+
+impl DataGeneral {
+    /// Version of the local meta and digests of the requested items held by this node.
+    pub(super) fn data_item_digests(
+        &self,
+        req: &proto::DataItemDigestRequest,
+    ) -> proto::DataItemDigestResponse {
+        let kv_store_engine = self.view.kv_store_engine();
+        let version = kv_store_engine
+            .get(
+                &KeyTypeDataSetMeta(&req.unique_id),
+                false,
+                KvAdditionalConf {},
+            )
+            .map_or(0, |(_, meta)| meta.version);
+        let digests = req
+            .idxs
+            .iter()
+            .map(|&idx| {
+                kv_store_engine
+                    .get(
+                        &KeyTypeDataSetItem {
+                            uid: &req.unique_id,
+                            idx: idx as u8,
+                        },
+                        false,
+                        KvAdditionalConf {},
+                    )
+                    .filter(|(_, persisted)| !persisted.is_empty())
+                    .and_then(|(_, persisted)| proto::DataItem::decode_persist(persisted).ok())
+                    .map_or(vec![], |item| checksum::item_digest(&item))
+            })
+            .collect();
+        proto::DataItemDigestResponse { version, digests }
+    }
+
+    /// Copy the local item, or the erasure shard rebuilt from the other shards, to the targets,
+    /// only when this node holds the requested version.
+    pub(super) async fn repair_data_item(
+        &self,
+        req: proto::DataRepairRequest,
+    ) -> proto::DataRepairResponse {
+        let kv_store_engine = self.view.kv_store_engine();
+        let this_node = self.view.p2p().nodes_config.this_node();
+        let failed = |message: String| proto::DataRepairResponse {
+            copied: vec![],
+            message,
+        };
+        let meta = kv_store_engine
+            .get(
+                &KeyTypeDataSetMeta(&req.unique_id),
+                false,
+                KvAdditionalConf {},
+            )
+            .map(|(_, meta)| meta);
+        let version = meta.as_ref().map_or(0, |meta| meta.version);
+        if version != req.version {
+            return failed(format!(
+                "data is at version {} on node {}, not {}",
+                version, this_node, req.version
+            ));
+        }
+        let persisted = match (&req.erasure_shard, &meta) {
+            (Some(shard), Some(meta)) => {
+                match self
+                    .rebuild_erasure_shard(&req.unique_id, req.idx as u8, meta, shard.data_offset)
+                    .await
+                {
+                    Ok(persisted) => persisted,
+                    Err(err) => {
+                        return failed(format!(
+                            "rebuild shard at {} on node {} failed: {}",
+                            shard.data_offset, this_node, err
+                        ))
+                    }
+                }
+            }
+            _ => {
+                let Some((_, persisted)) = kv_store_engine.get(
+                    &KeyTypeDataSetItem {
+                        uid: &req.unique_id,
+                        idx: req.idx as u8,
+                    },
+                    false,
+                    KvAdditionalConf {},
+                ) else {
+                    return failed(format!(
+                        "item {} is not held by node {}",
+                        req.idx, this_node
+                    ));
+                };
+                persisted
+            }
+        };
+
+        let copies = req.targets.iter().map(|&target| {
+            self.write_data_batch(
+                req.unique_id.clone(),
+                version,
+                proto::DataItem::new_mem_data(persisted.clone()),
+                req.idx as DataItemIdx,
+                target as NodeID,
+                true,
+            )
+        });
+        let results = futures::future::join_all(copies).await;
+        let mut copied = vec![];
+        let mut errs = vec![];
+        for (&target, res) in req.targets.iter().zip(results) {
+            match res {
+                Ok(()) => copied.push(target),
+                Err(err) => errs.push(format!("node {}: {}", target, err)),
+            }
+        }
+        proto::DataRepairResponse {
+            copied,
+            message: errs.join(", "),
+        }
+    }
+
+    /// The shard of the item starting at `data_offset` as persisted, decoded from the other shards.
+    async fn rebuild_erasure_shard(
+        &self,
+        unique_id: &[u8],
+        idx: DataItemIdx,
+        meta: &DataSetMetaV2,
+        data_offset: u32,
+    ) -> WSResult<Vec<u8>> {
+        let splits = meta
+            .datas_splits
+            .get(idx as usize)
+            .ok_or(WsDataError::ItemIdxOutOfRange {
+                wanted: idx,
+                len: meta.datas_splits.len() as u8,
+            })?;
+        let not_erasure = || WsDataError::ErasureCodingFailed {
+            reason: format!("no shard of item {} at {}", idx, data_offset),
+        };
+        let layout = ErasureLayout::of(splits).ok_or_else(not_erasure)?;
+        let shard = splits
+            .splits
+            .iter()
+            .find(|split| split.data_offset == data_offset)
+            .map(|split| layout.shard_index(split))
+            .ok_or_else(not_erasure)?;
+        let decoded = self
+            .get_or_del_erasure_coded(unique_id, idx, splits, &layout, &GetOrDelType::Get)
+            .await
+            .map_err(|reason| WsDataError::ErasureCodingFailed { reason })?
+            .ok_or_else(not_erasure)?;
+        let data_type = decoded.get_data_type();
+        let mut shards = layout.encode(&decoded.into_data_bytes())?;
+        Ok(erasure::shard_item(&data_type, shards.swap_remove(shard)).encode_persist())
+    }
+
+    /// Store the item copied from another replica as is,
+    /// unless the data was written again meanwhile.
+    pub(super) fn store_replica_copy(
+        &self,
+        unique_id: &[u8],
+        idx: DataItemIdx,
+        version: u64,
+        persisted: Vec<u8>,
+    ) -> WSResult<()> {
+        let kv_store_engine = self.view.kv_store_engine();
+        let lock = kv_store_engine.with_rwlock(&KeyTypeDataSetMeta(unique_id).make_key());
+        let _guard = KeyLockGuard::Write(lock.write());
+        if let Some((_, meta)) =
+            kv_store_engine.get(&KeyTypeDataSetMeta(unique_id), true, KvAdditionalConf {})
+        {
+            if meta.version > version {
+                return Err(WsDataError::VersionMismatch {
+                    expected: version,
+                    actual: meta.version,
+                }
+                .into());
+            }
+        }
+        let _ = kv_store_engine.set(
+            KeyTypeDataSetItem {
+                uid: unique_id,
+                idx,
+            },
+            &persisted,
+            true,
+        )?;
+        kv_store_engine.flush();
+        tracing::debug!(
+            "stored replica copy of data({:?}) idx({}) version({})",
+            String::from_utf8_lossy(unique_id),
+            idx,
+            version
+        );
+        Ok(())
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::hash::Hash;
-use std::hash::Hasher;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use crate::general::data::m_kv_store_engine::{
+    KeyType, KeyTypeFencingToken, KvAdditionalConf, KvStoreEngine,
+};
+use crate::general::network::m_master_election::MasterElection;
+use crate::general::network::proto::FnTaskId;
+use crate::result::WsDataError;
 use crate::sys::LogicalModule;
 use crate::sys::LogicalModulesRef;
+use crate::sys::NodeID;
 use crate::util::DropDebug;
 use crate::{
     logical_module_view_impl, result::WSResult, sys::LogicalModuleNewArgs, util::JoinHandleWrapper,
@@ -19,6 +26,7 @@ use enum_as_inner::EnumAsInner;
 use parking_lot::Mutex;
 use rand::thread_rng;
 use rand::Rng;
+use serde::Serialize;
 use tokio::sync::Notify;
 use tokio::sync::RwLock;
 use ws_derive::LogicalModule;
@@ -31,9 +39,38 @@ use crate::general::network::{
 logical_module_view_impl!(View);
 logical_module_view_impl!(View, p2p, P2PModule);
 logical_module_view_impl!(View, dist_lock, DistLock);
+logical_module_view_impl!(View, kv_store_engine, KvStoreEngine);
+logical_module_view_impl!(View, master_election, MasterElection);
 
 type LockReleaseId = u32;
 
+/// Lease of a grant when the request doesn't name one.
+pub const DEFAULT_LOCK_LEASE: Duration = Duration::from_secs(30);
+/// How often expired leases are released.
+const LEASE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const FENCING_TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One holder of a lock, released once `expires_at` passes without a renew.
+struct LockLease {
+    holder: NodeID,
+    fencing_token: u64,
+    ttl: Duration,
+    expires_at: Instant,
+}
+
+type LockLeases = HashMap<LockReleaseId, LockLease>;
+
+/// A lock held on the node serving it, for admin views.
+#[derive(Serialize)]
+pub struct HeldLock {
+    pub key: String,
+    pub write: bool,
+    pub holder: NodeID,
+    pub release_id: LockReleaseId,
+    pub fencing_token: u64,
+    pub expires_in_ms: u64,
+}
+
 #[derive(EnumAsInner)]
 pub enum DistLockOpe {
     Read,
@@ -103,7 +140,13 @@ pub struct DistLock {
     rpc_handler_kv_lock: RPCHandler<proto::kv::KvLockRequest>,
     // rpc_handler_kv_lock_wait_acquire_notify_request:
     //     RPCHandler<proto::kv::KvLockWaitAcquireNotifyRequest>,
-    locks: RwLock<HashMap<Vec<u8>, LockStateShared<HashSet<LockReleaseId>>>>,
+    rpc_caller_fencing_token: RPCCaller<proto::kv::KvFencingTokenRequest>,
+    rpc_handler_fencing_token: RPCHandler<proto::kv::KvFencingTokenRequest>,
+    locks: RwLock<HashMap<Vec<u8>, LockStateShared<LockLeases>>>,
+    /// held by master while issuing a fencing token
+    issuing_fencing_token: tokio::sync::Mutex<()>,
+    /// locks taken by the function tasks of this node, released when the task ends
+    task_locks: Mutex<HashMap<FnTaskId, HashSet<(Vec<u8>, LockReleaseId)>>>,
 }
 
 #[async_trait]
@@ -116,10 +159,14 @@ impl LogicalModule for DistLock {
         Self {
             rpc_caller_kv_lock: RPCCaller::new(),
             rpc_handler_kv_lock: RPCHandler::new(),
+            rpc_caller_fencing_token: RPCCaller::new(),
+            rpc_handler_fencing_token: RPCHandler::new(),
 
             // rpc_caller_kv_lock_wait_acquire_notify_request: RPCCaller::new(),
             // rpc_handler_kv_lock_wait_acquire_notify_request: RPCHandler::new(),
             locks: RwLock::new(HashMap::new()), //dashmap::DashMap::new(),
+            issuing_fencing_token: tokio::sync::Mutex::new(()),
+            task_locks: Mutex::new(HashMap::new()),
 
             view,
         }
@@ -128,6 +175,7 @@ impl LogicalModule for DistLock {
         // register rpc caller
         {
             self.rpc_caller_kv_lock.regist(self.view.p2p());
+            self.rpc_caller_fencing_token.regist(self.view.p2p());
             // self.rpc_caller_kv_lock_wait_acquire_notify_request
             //     .regist(self.view.p2p());
         }
@@ -149,6 +197,30 @@ impl LogicalModule for DistLock {
                     Ok(())
                 },
             );
+            let view = self.view.clone();
+            self.rpc_handler_fencing_token.regist(
+                self.view.p2p(),
+                move |responser: RPCResponsor<proto::kv::KvFencingTokenRequest>,
+                      _req: proto::kv::KvFencingTokenRequest| {
+                    let view = view.clone();
+                    let _ = tokio::spawn(async move {
+                        let resp = match view.dist_lock().issue_fencing_token().await {
+                            Ok(fencing_token) => proto::kv::KvFencingTokenResponse {
+                                fencing_token,
+                                err_msg: String::new(),
+                            },
+                            Err(err) => proto::kv::KvFencingTokenResponse {
+                                fencing_token: 0,
+                                err_msg: format!("{:?}", err),
+                            },
+                        };
+                        if let Err(err) = responser.send_resp(resp).await {
+                            tracing::warn!("send fencing token failed: {:?}", err);
+                        }
+                    });
+                    Ok(())
+                },
+            );
             // let view = self.view.clone();
             // self.rpc_handler_kv_lock_wait_acquire_notify_request.regist(
             //     self.view.p2p(),
@@ -164,7 +236,19 @@ impl LogicalModule for DistLock {
             // );
         }
 
-        Ok(vec![])
+        let view = self.view.clone();
+        let sweeper = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(LEASE_SWEEP_INTERVAL).await;
+                view.release_expired_leases().await;
+            }
+        });
+        Ok(vec![sweeper.into()])
+    }
+    async fn shutdown(&self) -> WSResult<()> {
+        self.rpc_handler_kv_lock.unregist(self.view.p2p());
+        self.rpc_handler_fencing_token.unregist(self.view.p2p());
+        Ok(())
     }
 }
 
@@ -173,22 +257,22 @@ impl View {
         &self,
         req: &proto::kv::KvLockRequest,
         read_or_write_tag: &str,
-    ) -> LockStateShared<HashSet<LockReleaseId>> {
+    ) -> LockStateShared<LockLeases> {
         loop {
             let mut insert_new = false;
-            let lock: LockStateShared<HashSet<LockReleaseId>> = {
+            let lock: LockStateShared<LockLeases> = {
                 let mut distlock_wr = DropDebug::new(
                     format!("prepare stage {} lock map", read_or_write_tag),
                     self.dist_lock().locks.write().await,
                 );
                 let entry = distlock_wr._t.entry(req.key.clone()).or_insert_with(|| {
                     insert_new = true;
-                    LockStateShared::new(req.read_0_write_1_unlock_2 == 0, HashSet::new())
+                    LockStateShared::new(req.read_0_write_1_unlock_2 == 0, HashMap::new())
                 });
                 entry.clone()
             };
 
-            async fn wait_for_next(lock: &LockStateShared<HashSet<LockReleaseId>>) {
+            async fn wait_for_next(lock: &LockStateShared<LockLeases>) {
                 let adding_wait_opt = lock.0.wait_for_delete.lock().as_ref().map(|v| v.clone());
                 if let Some(notify) = adding_wait_opt {
                     notify.notified().await;
@@ -228,6 +312,23 @@ impl View {
         responser: RPCResponsor<proto::kv::KvLockRequest>,
         req: proto::kv::KvLockRequest,
     ) -> WSResult<()> {
+        let nodes_config = &self.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            // sent before a master takeover, the holders lost their leases with the old master
+            responser
+                .send_resp(proto::kv::KvLockResponse {
+                    success: false,
+                    context: format!(
+                        "node {} doesn't serve locks, master is {:?}",
+                        nodes_config.this_node(),
+                        nodes_config.master_node()
+                    ),
+                    release_id: 0,
+                    fencing_token: 0,
+                })
+                .await?;
+            return Ok(());
+        }
         match req.read_0_write_1_unlock_2 {
             // 0 => {
             //     tracing::debug!(
@@ -299,104 +400,110 @@ impl View {
 
                 let lock = self.wait_for_lock(&req, read_or_write_tag).await;
 
-                // Here we have got the lock, just regist for a new release id
-
-                fn insert_release_id(
-                    lock: &LockStateShared<HashSet<LockReleaseId>>,
-                ) -> LockReleaseId {
-                    // tracing::debug!("handle_kv_lock_request - inserting release id");
-                    loop {
-                        let release_id = thread_rng().gen_range(1..u32::MAX);
-                        let mut locked_release_ids = lock.0.payload.lock();
-                        if locked_release_ids.contains(&release_id) {
-                            // tracing::debug!("handle_kv_lock_request - inserting release id retry");
-                            continue;
+                // Here we have got the lock, just regist for a new release id with its lease
+                let ttl = lease_ttl(req.ttl_ms);
+                let release_id = loop {
+                    let release_id = thread_rng().gen_range(1..u32::MAX);
+                    let mut leases = lock.0.payload.lock();
+                    if leases.contains_key(&release_id) {
+                        continue;
+                    }
+                    let _ = leases.insert(
+                        release_id,
+                        LockLease {
+                            holder: responser.node_id(),
+                            // set below
+                            fencing_token: 0,
+                            ttl,
+                            expires_at: Instant::now() + ttl,
+                        },
+                    );
+                    break release_id;
+                };
+                // taken after the grant, so the tokens grow in the order the lock is held
+                let fencing_token = match self.dist_lock().next_fencing_token().await {
+                    Ok(fencing_token) => fencing_token,
+                    Err(err) => {
+                        tracing::warn!("no fencing token for lock grant, releasing: {:?}", err);
+                        if let Err(reason) = self.release(&req.key, release_id).await {
+                            tracing::warn!("release lock without fencing token failed: {}", reason);
                         }
-                        let _ = locked_release_ids.insert(release_id);
-                        // tracing::debug!("handle_kv_lock_request - inserted release id");
-                        return release_id;
+                        responser
+                            .send_resp(proto::kv::KvLockResponse {
+                                success: false,
+                                context: format!("no fencing token: {:?}", err),
+                                release_id: 0,
+                                fencing_token: 0,
+                            })
+                            .await?;
+                        return Ok(());
                     }
+                };
+                if let Some(lease) = lock.0.payload.lock().get_mut(&release_id) {
+                    lease.fencing_token = fencing_token;
                 }
-                let release_id = insert_release_id(&lock);
 
                 responser
                     .send_resp(proto::kv::KvLockResponse {
                         success: true,
                         context: "locked".to_owned(),
                         release_id,
+                        fencing_token,
                     })
                     .await?;
-                tracing::debug!("handle_kv_lock_request write lock request returned");
+                tracing::debug!(
+                    "handle_kv_lock_request {} lock request returned",
+                    read_or_write_tag
+                );
             }
             2 => {
                 tracing::debug!("handle_kv_lock_request unlocking");
-                let mut fail_reason = None;
-                {
-                    let distlock_rd = DropDebug::new(
-                        "prepare stage unlock map".to_owned(),
-                        self.dist_lock().locks.read().await,
-                    );
-                    if let Some(lock_state) = distlock_rd._t.get(&req.key).cloned() {
-                        let mut locked_release_ids = lock_state.0.payload.lock();
-                        if locked_release_ids.contains(&req.release_id) {
-                            assert!(locked_release_ids.remove(&req.release_id));
-                        } else {
-                            fail_reason =
-                                Some("invalid release id or unlock multiple times".to_owned());
-                        }
-                    } else {
-                        fail_reason = Some("not locked".to_owned());
-                    }
-                }
-
-                if let Some(fail_reason) = fail_reason {
-                    responser
-                        .send_resp(proto::kv::KvLockResponse {
-                            success: false,
-                            context: fail_reason,
-                            release_id: 0,
-                        })
-                        .await?;
-                    tracing::debug!("handle_kv_lock_request unlocking returned");
-                } else {
-                    // 这里进行fetch sub
-                    let mut need_delete = false;
-                    {
-                        let distlock_rd = DropDebug::new(
-                            "fetch_sub stage unlock map".to_owned(),
-                            self.dist_lock().locks.read().await,
-                        );
-
-                        if let Some(lock_state) = distlock_rd._t.get(&req.key).cloned() {
-                            need_delete = !lock_state.fetch_sub();
+                let resp = match self.release(&req.key, req.release_id).await {
+                    Ok(()) => proto::kv::KvLockResponse {
+                        success: true,
+                        context: "unlocked".to_owned(),
+                        release_id: 0,
+                        fencing_token: 0,
+                    },
+                    Err(fail_reason) => proto::kv::KvLockResponse {
+                        success: false,
+                        context: fail_reason,
+                        release_id: 0,
+                        fencing_token: 0,
+                    },
+                };
+                responser.send_resp(resp).await?;
+                tracing::debug!("handle_kv_lock_request unlocking returned");
+            }
+            3 => {
+                tracing::debug!("handle_kv_lock_request renewing");
+                let renewed = {
+                    let distlock_rd = self.dist_lock().locks.read().await;
+                    distlock_rd.get(&req.key).and_then(|lock_state| {
+                        let mut leases = lock_state.0.payload.lock();
+                        let lease = leases.get_mut(&req.release_id)?;
+                        if req.ttl_ms != 0 {
+                            lease.ttl = lease_ttl(req.ttl_ms);
                         }
-                    }
-
-                    if need_delete {
-                        let mut removed = self.dist_lock().locks.write().await.remove(&req.key);
-                        assert!(removed.is_some());
-
-                        let wait_for_delete_took = removed
-                            .as_mut()
-                            .unwrap()
-                            .0
-                            .wait_for_delete
-                            .lock()
-                            .take()
-                            .unwrap(); // only one can unlock and take it
-                        wait_for_delete_took.notify_waiters();
-                    }
-                    responser
-                        .send_resp(proto::kv::KvLockResponse {
-                            success: true,
-                            context: "unlocked".to_owned(),
-                            release_id: 0,
-                        })
-                        .await?;
-                    tracing::debug!("handle_kv_lock_request unlocking returned");
-                }
-
-                // let mut lock = self.dist_lock().locks.remove(&req.key);
+                        lease.expires_at = Instant::now() + lease.ttl;
+                        Some(lease.fencing_token)
+                    })
+                };
+                let resp = match renewed {
+                    Some(fencing_token) => proto::kv::KvLockResponse {
+                        success: true,
+                        context: "renewed".to_owned(),
+                        release_id: req.release_id,
+                        fencing_token,
+                    },
+                    None => proto::kv::KvLockResponse {
+                        success: false,
+                        context: "lease expired or not locked".to_owned(),
+                        release_id: 0,
+                        fencing_token: 0,
+                    },
+                };
+                responser.send_resp(resp).await?;
             }
             _ => {
                 let errmsg = "invalid request type, should be verified in msg pack precheck";
@@ -405,6 +512,7 @@ impl View {
                         success: false,
                         context: errmsg.to_owned(),
                         release_id: 0,
+                        fencing_token: 0,
                     })
                     .await?;
                 panic!("{}", errmsg);
@@ -412,6 +520,84 @@ impl View {
         }
         Ok(())
     }
+
+    /// Drops the grant `release_id` of `key`, the lock is deleted with its last grant.
+    async fn release(&self, key: &[u8], release_id: LockReleaseId) -> Result<(), String> {
+        {
+            let distlock_rd = DropDebug::new(
+                "prepare stage unlock map".to_owned(),
+                self.dist_lock().locks.read().await,
+            );
+            let Some(lock_state) = distlock_rd._t.get(key).cloned() else {
+                return Err("not locked".to_owned());
+            };
+            if lock_state.0.payload.lock().remove(&release_id).is_none() {
+                return Err("invalid release id or unlock multiple times".to_owned());
+            }
+        }
+
+        // 这里进行fetch sub
+        let mut need_delete = false;
+        {
+            let distlock_rd = DropDebug::new(
+                "fetch_sub stage unlock map".to_owned(),
+                self.dist_lock().locks.read().await,
+            );
+
+            if let Some(lock_state) = distlock_rd._t.get(key).cloned() {
+                need_delete = !lock_state.fetch_sub();
+            }
+        }
+
+        if need_delete {
+            let mut removed = self.dist_lock().locks.write().await.remove(key);
+            assert!(removed.is_some());
+
+            let wait_for_delete_took = removed
+                .as_mut()
+                .unwrap()
+                .0
+                .wait_for_delete
+                .lock()
+                .take()
+                .unwrap(); // only one can unlock and take it
+            wait_for_delete_took.notify_waiters();
+        }
+        Ok(())
+    }
+
+    /// Holders that stopped renewing, a crashed function or a lost node, give up their locks here.
+    /// A node no longer master gives up all of them, the new master serves the locks.
+    async fn release_expired_leases(&self) {
+        let now = Instant::now();
+        let demoted = !self.p2p().nodes_config.this_is_master();
+        let expired: Vec<(Vec<u8>, LockReleaseId, NodeID)> = {
+            let distlock_rd = self.dist_lock().locks.read().await;
+            distlock_rd
+                .iter()
+                .flat_map(|(key, lock_state)| {
+                    lock_state
+                        .0
+                        .payload
+                        .lock()
+                        .iter()
+                        .filter(|(_, lease)| demoted || lease.expires_at <= now)
+                        .map(|(release_id, lease)| (key.clone(), *release_id, lease.holder))
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        };
+        for (key, release_id, holder) in expired {
+            tracing::warn!(
+                "lock lease of key {:?} held by node {} expired or no longer served, releasing",
+                String::from_utf8_lossy(&key),
+                holder
+            );
+            // an unlock may have raced with us
+            let _ = self.release(&key, release_id).await;
+        }
+    }
+
     // pub async fn handle_kv_lock_wait_acquire_notify(
     //     &self,
     //     responser: RPCResponsor<proto::kv::KvLockWaitAcquireNotifyRequest>,
@@ -420,21 +606,165 @@ impl View {
     // }
 }
 
+fn lease_ttl(ttl_ms: u32) -> Duration {
+    if ttl_ms == 0 {
+        DEFAULT_LOCK_LEASE
+    } else {
+        Duration::from_millis(ttl_ms as u64)
+    }
+}
+
 impl DistLock {
+    /// Fencing token for a new grant, issued by master.
+    async fn next_fencing_token(&self) -> WSResult<u64> {
+        let p2p = self.view.p2p();
+        let resp = self
+            .rpc_caller_fencing_token
+            .call(
+                p2p,
+                p2p.nodes_config.get_master_node()?,
+                proto::kv::KvFencingTokenRequest {},
+                Some(FENCING_TOKEN_TIMEOUT),
+            )
+            .await?;
+        if resp.fencing_token == 0 {
+            return Err(WsDataError::FencingTokenFailed {
+                reason: resp.err_msg,
+            }
+            .into());
+        }
+        Ok(resp.fencing_token)
+    }
+
+    /// Next of the counter replicated to the master candidates, so the tokens keep growing
+    /// across lock servers, restarts and master takeovers. Master only.
+    async fn issue_fencing_token(&self) -> WSResult<u64> {
+        let _issuing = self.issuing_fencing_token.lock().await;
+        // the counter starts from the wall clock, above the tokens lock servers took from their
+        //  own clocks before
+        let last = self
+            .view
+            .kv_store_engine()
+            .get(&KeyTypeFencingToken, false, KvAdditionalConf::default())
+            .map_or_else(
+                || {
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("Time went backwards")
+                        .as_micros() as u64
+                },
+                |(_, last)| last,
+            );
+        let next = last + 1;
+        // refused unless this node is master
+        self.view
+            .master_election()
+            .replicate(vec![(
+                KeyTypeFencingToken.make_key(),
+                Some(bincode::serialize(&next).unwrap()),
+            )])
+            .await?;
+        Ok(next)
+    }
+
+    /// Locks served by this node with their holders.
+    pub async fn held_locks(&self) -> Vec<HeldLock> {
+        let now = Instant::now();
+        let locks = self.locks.read().await;
+        let mut held = vec![];
+        for (key, lock_state) in locks.iter() {
+            for (release_id, lease) in lock_state.0.payload.lock().iter() {
+                held.push(HeldLock {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    write: !lock_state.0.read_or_write,
+                    holder: lease.holder,
+                    release_id: *release_id,
+                    fencing_token: lease.fencing_token,
+                    expires_in_ms: lease.expires_at.saturating_duration_since(now).as_millis()
+                        as u64,
+                });
+            }
+        }
+        held
+    }
+
+    /// Same as [`DistLock::lock`], the locks still held when `taskid` ends are released by
+    /// [`DistLock::release_task_locks`].
+    pub async fn lock_for_task(
+        &self,
+        taskid: &FnTaskId,
+        lock: proto::kv::KvLockRequest,
+    ) -> WSResult<proto::kv::KvLockResponse> {
+        let key = lock.key.clone();
+        let ope = lock.read_0_write_1_unlock_2;
+        let unlocked_release_id = lock.release_id;
+        let resp = self.lock(lock).await?;
+        if resp.success {
+            let mut task_locks = self.task_locks.lock();
+            match ope {
+                0 | 1 => {
+                    let _ = task_locks
+                        .entry(taskid.clone())
+                        .or_default()
+                        .insert((key, resp.release_id));
+                }
+                2 => {
+                    if let Some(held) = task_locks.get_mut(taskid) {
+                        let _ = held.remove(&(key, unlocked_release_id));
+                        if held.is_empty() {
+                            let _ = task_locks.remove(taskid);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Releases the locks `taskid` didn't unlock before it ended.
+    pub async fn release_task_locks(&self, taskid: &FnTaskId) {
+        let Some(held) = self.task_locks.lock().remove(taskid) else {
+            return;
+        };
+        for (key, release_id) in held {
+            tracing::debug!(
+                "task {:?} ended holding lock {:?}, releasing",
+                taskid,
+                String::from_utf8_lossy(&key)
+            );
+            let res = self
+                .lock(proto::kv::KvLockRequest {
+                    key,
+                    read_0_write_1_unlock_2: 2,
+                    release_id,
+                    ttl_ms: 0,
+                })
+                .await;
+            match res {
+                // the lease may have expired already
+                Ok(resp) if !resp.success => {
+                    tracing::debug!("release lock of ended task failed: {}", resp.context)
+                }
+                Err(err) => tracing::warn!("release lock of ended task err: {:?}", err),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Locks are served by the elected master, so a key has one server whatever the membership.
+    /// A master takeover drops the leases, renews fail then and the fencing tokens of new grants
+    /// are above the old ones.
     pub async fn lock(
         &self,
         lock: proto::kv::KvLockRequest,
     ) -> WSResult<proto::kv::KvLockResponse> {
-        // hash target node
-        let node_cnt = self.view.p2p().nodes_config.node_cnt();
-        let mut hasher = DefaultHasher::new();
-        lock.key.hash(&mut hasher);
-        let node_id = hasher.finish() as usize % node_cnt;
+        let node_id = self.view.p2p().nodes_config.get_master_node()?;
         tracing::debug!("requested to node {}", node_id);
         self.rpc_caller_kv_lock
             .call(
                 self.view.p2p(),
-                node_id as u32,
+                node_id,
                 lock,
                 Some(Duration::from_secs(10000)),
             )
@@ -473,6 +803,7 @@ mod test {
                         key: key.as_bytes().to_owned(),
                         read_0_write_1_unlock_2: 0,
                         release_id: 0,
+                        ttl_ms: 0,
                     })
                     .await
                     .unwrap();
@@ -483,6 +814,7 @@ mod test {
                             key: key.as_bytes().to_owned(),
                             read_0_write_1_unlock_2: 2,
                             release_id: resp.release_id,
+                            ttl_ms: 0,
                         })
                         .await
                         .unwrap()
@@ -503,6 +835,7 @@ mod test {
                     key: "key".as_bytes().to_owned(),
                     read_0_write_1_unlock_2: 1, // outter get the write lock, inner will wait
                     release_id: 0,
+                    ttl_ms: 0,
                 })
                 .await
                 .unwrap();
@@ -518,6 +851,7 @@ mod test {
                         key: "key".as_bytes().to_owned(),
                         read_0_write_1_unlock_2: 0,
                         release_id: 0,
+                        ttl_ms: 0,
                     })
                     .await
                     .unwrap();
@@ -533,6 +867,7 @@ mod test {
                             key: "key".as_bytes().to_owned(),
                             read_0_write_1_unlock_2: 2,
                             release_id: resp.release_id,
+                            ttl_ms: 0,
                         })
                         .await
                         .unwrap()
@@ -548,6 +883,7 @@ mod test {
                         key: "key".as_bytes().to_owned(),
                         read_0_write_1_unlock_2: 2,
                         release_id: resp.release_id,
+                        ttl_ms: 0,
                     })
                     .await
                     .unwrap()
@@ -556,5 +892,49 @@ mod test {
             tracing::debug!("outter unlocked");
             let _ = _t.await.unwrap();
         }
+
+        {
+            //test lease renew, expiry and fencing token
+            let lock_req = |ope, release_id, ttl_ms| proto::kv::KvLockRequest {
+                key: "lease".as_bytes().to_owned(),
+                read_0_write_1_unlock_2: ope,
+                release_id,
+                ttl_ms,
+            };
+            let first = view1.dist_lock().lock(lock_req(1, 0, 2000)).await.unwrap();
+            assert!(first.success);
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            let renewed = view1
+                .dist_lock()
+                .lock(lock_req(3, first.release_id, 0))
+                .await
+                .unwrap();
+            assert!(renewed.success);
+            assert_eq!(renewed.fencing_token, first.fencing_token);
+
+            // holder stops renewing, view2 gets the lock once the lease expires
+            let begin = std::time::Instant::now();
+            let second = view2.dist_lock().lock(lock_req(1, 0, 0)).await.unwrap();
+            assert!(second.success);
+            assert!(begin.elapsed().as_millis() >= 1000);
+            assert!(second.fencing_token > first.fencing_token);
+
+            assert!(
+                !view1
+                    .dist_lock()
+                    .lock(lock_req(3, first.release_id, 0))
+                    .await
+                    .unwrap()
+                    .success
+            );
+            assert!(
+                view2
+                    .dist_lock()
+                    .lock(lock_req(2, second.release_id, 0))
+                    .await
+                    .unwrap()
+                    .success
+            );
+        }
     }
 }
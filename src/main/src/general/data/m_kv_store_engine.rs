@@ -15,17 +15,21 @@ use sled::IVec;
 use tokio::sync::oneshot;
 
 use std::io::Cursor;
+use std::ops::Bound;
 use std::sync::Arc;
 use std::sync::OnceLock;
 use std::time::Duration;
 
 use crate::general::{
-    data::m_data_general::DataSetMetaV2, m_os::OperatingSystem, network::m_p2p::P2PModule,
+    data::m_data_general::{DataSetMetaV2, DataSetMetaV2NoDigest},
+    m_os::OperatingSystem,
+    network::m_p2p::P2PModule,
 };
 
 use crate::{
     logical_module_view_impl,
-    result::WSResult,
+    master::app::fn_schedule::FnScheduleTick,
+    result::{WSResult, WsDataError},
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
     util::JoinHandleWrapper,
 };
@@ -111,6 +115,18 @@ impl LogicalModule for KvStoreEngine {
         });
         Ok(vec![])
     }
+    async fn shutdown(&self) -> WSResult<()> {
+        if let Some(db) = self.db.get() {
+            let _ = db
+                .flush_async()
+                .await
+                .map_err(|err| WsDataError::KvEngineInnerError {
+                    context: "flush on shutdown".to_owned(),
+                    inner: err,
+                })?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -267,13 +283,18 @@ impl KvStoreEngine {
         let _hold_lock_guard = hold_lock.as_ref().map(|lock| lock.read());
 
         let res = self.db.get().unwrap().get(key).unwrap();
-        res.map(|v| {
-            let kvversion = bincode::deserialize::<u64>(&v.as_ref()[0..8]).unwrap() as usize;
-            // let value: K::Value = key
-            //     .deserialize_from(&v.as_ref()[8..])
-            //     .unwrap_or_else(|| panic!("deserialize failed"));
-            (kvversion, v.to_vec().drain(0..8).collect())
-        })
+        res.and_then(|v| Self::split_version(key, &v))
+    }
+
+    /// Stored value to its kv version and payload, `None` for a value too short to hold the
+    ///  version.
+    fn split_version(key: &[u8], v: &[u8]) -> Option<(KvVersion, Vec<u8>)> {
+        if v.len() < 8 {
+            tracing::error!("kv value of key {:?} is shorter than its version", key);
+            return None;
+        }
+        let kvversion = bincode::deserialize::<u64>(&v[0..8]).ok()? as usize;
+        Some((kvversion, v[8..].to_vec()))
     }
 
     pub fn decode_kv<K>(key_: &K, data: &IVec) -> (KvVersion, K::Value)
@@ -325,13 +346,7 @@ impl KvStoreEngine {
         let _hold_lock_guard = hold_lock.as_ref().map(|lock| lock.write());
 
         let res = self.db.get().unwrap().remove(key).unwrap();
-        Ok(res.map(|v| {
-            let kvversion = bincode::deserialize::<u64>(&v.as_ref()[0..8]).unwrap() as usize;
-            // let value: K::Value = key
-            //     .deserialize_from(&v.as_ref()[8..])
-            //     .unwrap_or_else(|| panic!("deserialize failed"));
-            (kvversion, v.to_vec().drain(0..8).collect())
-        }))
+        Ok(res.and_then(|v| Self::split_version(key, &v)))
     }
 
     pub fn del<K>(&self, key: K, locked: bool) -> WSResult<Option<(KvVersion, K::Value)>>
@@ -353,6 +368,62 @@ impl KvStoreEngine {
     pub fn flush(&self) {
         let _ = self.db.get().unwrap().flush().unwrap();
     }
+
+    /// All entries whose key starts with `prefix`, values without the kv version,
+    ///  in the same form `set_raw` takes them.
+    pub fn scan_raw_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.db
+            .get()
+            .unwrap()
+            .scan_prefix(prefix)
+            .filter_map(|res| match res {
+                Ok((k, v)) => Self::split_version(&k, &v).map(|(_, v)| (k.to_vec(), v)),
+                Err(e) => {
+                    tracing::error!("scan kv error: {:?}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Entries with keys in `[start, end)` ordered by key, at most `limit` of them,
+    ///  values without the kv version. `end` of None is unbounded.
+    pub fn scan_raw_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let range = (
+            Bound::Included(start),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        self.db
+            .get()
+            .unwrap()
+            .range::<&[u8], _>(range)
+            .filter_map(|res| match res {
+                Ok((k, v)) => Self::split_version(&k, &v).map(|(_, v)| (k.to_vec(), v)),
+                Err(e) => {
+                    tracing::error!("scan kv error: {:?}", e);
+                    None
+                }
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+/// The smallest key greater than all keys starting with `prefix`, None if there is none.
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
 }
 
 pub trait KeyType: Serialize {
@@ -463,7 +534,7 @@ pub struct KeyTypeServiceList;
 generate_key_struct!([KeyTypeServiceList], 3, Vec<u8>);
 
 pub struct KeyTypeDataSetMeta<'a>(pub &'a [u8]);
-generate_key_struct!([KeyTypeDataSetMeta,'_], 4, DataSetMetaV2);
+generate_key_struct!([KeyTypeDataSetMeta,'_], 4, DataSetMetaV2, [DataSetMetaV2NoDigest]);
 
 pub struct KeyTypeDataSetItem<'a> {
     pub uid: &'a [u8],
@@ -471,6 +542,43 @@ pub struct KeyTypeDataSetItem<'a> {
 }
 generate_key_struct!([KeyTypeDataSetItem,'_], 5, Vec<u8>);
 
+/// app name -> bincode encoded `AppMeta`, kept by master for rebuilding triggers
+pub struct KeyTypeAppMeta<'a>(pub &'a [u8]);
+generate_key_struct!([KeyTypeAppMeta,'_], 6, Vec<u8>);
+
+/// (app, fn) -> the latest schedule tick, kept by master
+pub struct KeyTypeFnScheduleTick<'a> {
+    pub app: &'a [u8],
+    pub func: &'a [u8],
+}
+generate_key_struct!([KeyTypeFnScheduleTick,'_], 7, FnScheduleTick, [i64]);
+
+/// function kv key -> (), kept by master for range scans.
+/// The key is stored as is, so the index is ordered by the function kv key.
+pub struct KeyTypeFnKvIndex<'a>(pub &'a [u8]);
+generate_key_struct!([KeyTypeFnKvIndex,'_], 8, ());
+
+/// data unique id -> newest fencing token a write of it carried, kept by master
+pub struct KeyTypeDataFence<'a>(pub &'a [u8]);
+generate_key_struct!([KeyTypeDataFence,'_], 9, u64);
+
+/// data unique id -> unix milliseconds the data expires at, 0 for never, kept by master.
+/// The id is stored as is, so the expiring data can be scanned.
+pub struct KeyTypeDataExpiry<'a>(pub &'a [u8]);
+generate_key_struct!([KeyTypeDataExpiry,'_], 10, u64);
+
+/// (unix milliseconds the data expires at, data unique id) -> (), kept by master.
+/// The time is stored big endian, so the expiring data can be scanned in expiry order.
+pub struct KeyTypeDataExpiryQueue<'a> {
+    pub expire_at: u64,
+    pub uid: &'a [u8],
+}
+generate_key_struct!([KeyTypeDataExpiryQueue,'_], 12, ());
+
+/// -> last fencing token issued for a lock grant, kept by master
+pub struct KeyTypeFencingToken;
+generate_key_struct!([KeyTypeFencingToken], 11, u64);
+
 // impl KeyType for KeyTypeKvPosition<'_> {
 //     type Value = NodeID;
 //     fn id(&self) -> u8 {
@@ -540,6 +648,60 @@ impl Serialize for KeyTypeDataSetMeta<'_> {
     }
 }
 
+impl Serialize for KeyTypeFencingToken {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit()
+    }
+}
+
+impl Serialize for KeyTypeDataFence<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl Serialize for KeyTypeAppMeta<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl Serialize for KeyTypeFnScheduleTick<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(2)?;
+        tup.serialize_element(self.app)?;
+        tup.serialize_element(self.func)?;
+        tup.end()
+    }
+}
+
+impl Serialize for KeyTypeFnKvIndex<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // a tuple has no length prefix, unlike a slice
+        let mut tup = serializer.serialize_tuple(self.0.len())?;
+        for b in self.0 {
+            tup.serialize_element(b)?;
+        }
+        tup.end()
+    }
+}
+
+impl Serialize for KeyTypeDataExpiry<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        KeyTypeFnKvIndex(self.0).serialize(serializer)
+    }
+}
+
+impl Serialize for KeyTypeDataExpiryQueue<'_> {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tup = serializer.serialize_tuple(8 + self.uid.len())?;
+        for b in self.expire_at.to_be_bytes().iter().chain(self.uid) {
+            tup.serialize_element(b)?;
+        }
+        tup.end()
+    }
+}
+
 impl Serialize for KeyTypeDataSetItem<'_> {
     fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         let mut tup = serializer.serialize_tuple(2)?;
@@ -555,15 +717,45 @@ mod test {
         general::{
             data::{
                 m_data_general::DataSetMetaBuilder,
-                m_kv_store_engine::{KeyTypeDataSetMeta, KvAdditionalConf},
+                m_kv_store_engine::{
+                    prefix_end, KeyType, KeyTypeDataExpiryQueue, KeyTypeDataSetMeta,
+                    KeyTypeFnKvIndex, KeyTypeFnScheduleTick, KvAdditionalConf,
+                },
             },
             test_utils,
         },
+        master::app::fn_schedule::FnScheduleTick,
         result::WSResultExt,
     };
 
     use super::View;
 
+    #[test]
+    fn test_schedule_tick_old_layout() {
+        let key = KeyTypeFnScheduleTick {
+            app: b"app",
+            func: b"f",
+        };
+        let pending = FnScheduleTick {
+            tick_ms: 2000,
+            node: 3,
+            dispatched: false,
+        };
+        assert_eq!(
+            key.deserialize_from(&bincode::serialize(&pending).unwrap()),
+            Some(pending)
+        );
+        // recorded as the bare tick before pending ticks were tracked
+        assert_eq!(
+            key.deserialize_from(&bincode::serialize(&1000i64).unwrap()),
+            Some(FnScheduleTick {
+                tick_ms: 1000,
+                node: 0,
+                dispatched: true,
+            })
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_kv_store_engine() {
         let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
@@ -614,4 +806,57 @@ mod test {
             .unwrap()
             .is_none());
     }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"user_42_"), Some(b"user_42`".to_vec()));
+        assert_eq!(prefix_end(&[1, 0xff, 0xff]), Some(vec![2]));
+        assert_eq!(prefix_end(&[0xff]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+
+    #[test]
+    fn test_fn_kv_index_key_order() {
+        let key = |k: &[u8]| KeyTypeFnKvIndex(k).make_key();
+        assert_eq!(key(b"ab"), vec![KeyTypeFnKvIndex(&[]).id(), b'a', b'b']);
+        // ordered by the function kv key, not by its length
+        assert!(key(b"b") > key(b"abc"));
+        assert!(key(b"user_42_") < key(b"user_42_a"));
+        assert!(key(b"user_42_z") < prefix_end(&key(b"user_42_")).unwrap());
+    }
+
+    #[test]
+    fn test_data_expiry_queue_key_order() {
+        let key = |expire_at: u64, uid: &'static [u8]| {
+            KeyTypeDataExpiryQueue { expire_at, uid }.make_key()
+        };
+        assert_eq!(key(1, b"a")[1..], [0, 0, 0, 0, 0, 0, 0, 1, b'a']);
+        // ordered by the time first, a scan up to a time passes only the earlier ones
+        assert!(key(0x100, b"a") > key(0xff, b"b"));
+        assert!(key(5, b"zzz") < key(6, b""));
+        assert!(key(5, b"a") < key(5, b"b"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_store_engine_scan_range() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        let view = View::new(sys2);
+        let engine = view.kv_store_engine();
+        for k in ["scan_a", "scan_b1", "scan_b2", "scan_c"] {
+            let _ = engine
+                .set(KeyTypeFnKvIndex(k.as_bytes()), &(), false)
+                .todo_handle("set index");
+        }
+        let start = KeyTypeFnKvIndex(b"scan_b").make_key();
+        let end = prefix_end(&start).unwrap();
+        let keys: Vec<_> = engine
+            .scan_raw_range(&start, Some(&end), 10)
+            .into_iter()
+            .map(|(k, _)| k[1..].to_vec())
+            .collect();
+        assert_eq!(keys, vec![b"scan_b1".to_vec(), b"scan_b2".to_vec()]);
+        let limited = engine.scan_raw_range(&KeyTypeFnKvIndex(b"scan_").make_key(), None, 3);
+        assert_eq!(limited.len(), 3);
+        assert_eq!(&limited[0].0[1..], b"scan_a");
+    }
 }
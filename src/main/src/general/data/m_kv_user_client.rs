@@ -6,26 +6,41 @@ use crate::{
         data::{
             m_data_general::{
                 dataitem::DataItemArgWrapper, new_data_unique_id_fn_kv, DataGeneral, DataItemIdx,
-                DataSetMetaV2, GetOrDelDataArg, GetOrDelDataArgType,
+                DataSetMetaV2, DataWriteCond, GetOrDelDataArg, GetOrDelDataArgType,
             },
-            m_dist_lock::DistLock,
+            m_dist_lock::{DistLock, DEFAULT_LOCK_LEASE},
         },
         network::{
+            http_handler::HttpHandler,
             m_p2p::{P2PModule, RPCCaller},
             proto::{
                 self,
-                kv::{KvRequests, KvResponse, KvResponses},
+                kv::{
+                    KvRequests, KvResponse, KvResponses, KvScanKeysRequest, KvWatchRequest,
+                    KvWatchResponse,
+                },
             },
-            proto_ext::ProtoExtKvResponse,
+            proto_ext::{ProtoExtKeyRange, ProtoExtKvResponse},
         },
     },
     logical_module_view_impl,
-    result::{WSError, WSResult, WSResultExt, WsDataError},
-    sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef},
+    master::data::kv_watch::KV_WATCH_MAX_TIMEOUT,
+    result::{WSError, WSResult, WSResultExt, WsDataError, WsSerialErr},
+    sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
     util::JoinHandleWrapper,
+    with_option,
 };
 use async_trait::async_trait;
-use std::collections::{BTreeSet, HashMap};
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::get;
+use axum::Router;
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::time::Duration;
 use ws_derive::LogicalModule;
 
 logical_module_view_impl!(KvUserClientView);
@@ -33,12 +48,15 @@ logical_module_view_impl!(KvUserClientView, p2p, P2PModule);
 logical_module_view_impl!(KvUserClientView, data_general, DataGeneral);
 logical_module_view_impl!(KvUserClientView, dist_lock, DistLock);
 logical_module_view_impl!(KvUserClientView, kv_user_client, KvUserClient);
+logical_module_view_impl!(KvUserClientView, http_handler, Box<dyn HttpHandler>);
 
 #[derive(LogicalModule)]
 pub struct KvUserClient {
     // testmap: SkipMap<Vec<u8>, Vec<u8>>,
     view: KvUserClientView,
     rpc_caller_kv: RPCCaller<KvRequests>,
+    rpc_caller_scan_keys: RPCCaller<KvScanKeysRequest>,
+    rpc_caller_watch: RPCCaller<KvWatchRequest>,
 }
 
 #[async_trait]
@@ -56,14 +74,33 @@ impl LogicalModule for KvUserClient {
             // testmap: SkipMap::new(),
             view: KvUserClientView::new(args.logical_modules_ref.clone()),
             rpc_caller_kv: RPCCaller::default(),
+            rpc_caller_scan_keys: RPCCaller::default(),
+            rpc_caller_watch: RPCCaller::default(),
         }
     }
+    async fn init(&self) -> WSResult<()> {
+        let mut router_holder = self.view.http_handler().building_router();
+        with_option!(router_holder.option_mut(), router => {
+            router.merge(Router::new().route("/kv/watch", get(handle_http_watch).with_state(self.view.clone())))
+        });
+        Ok(())
+    }
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
         self.rpc_caller_kv.regist(self.view.p2p());
+        self.rpc_caller_scan_keys.regist(self.view.p2p());
+        self.rpc_caller_watch.regist(self.view.p2p());
 
-        let all = vec![];
+        let view = self.view.clone();
+        let intent_sweeper = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(KV_TXN_INTENT_SWEEP_INTERVAL).await;
+                if view.p2p().nodes_config.this_is_master() {
+                    view.kv_user_client().recover_txn_intents().await;
+                }
+            }
+        });
 
-        Ok(all)
+        Ok(vec![intent_sweeper.into()])
     }
 }
 
@@ -121,6 +158,118 @@ impl LogicalModule for KvUserClient {
 //     }
 // }
 
+/// Prefixes the lock keys of function kv txns and cas, so they never meet the locks functions
+/// take on their own keys.
+const KV_TXN_LOCK_PREFIX: &[u8] = b"__kv_txn/";
+
+/// Prefixes the function kv keys the intents of committed txns are stored at.
+const KV_TXN_INTENT_PREFIX: &[u8] = b"__kv_txn_intent/";
+
+/// How often master rolls forward the txns interrupted after committing.
+const KV_TXN_INTENT_SWEEP_INTERVAL: Duration = DEFAULT_LOCK_LEASE;
+
+/// Keys of a range get whose values are fetched at the same time.
+const KV_RANGE_GET_CONCURRENCY: usize = 16;
+
+fn txn_aborted(reason: String) -> KvResponses {
+    KvResponses {
+        responses: vec![],
+        txn_abort_reason: reason,
+    }
+}
+
+/// The writes of a committed txn, kept until all of them are applied.
+#[derive(Serialize, Deserialize)]
+struct TxnIntent {
+    app: String,
+    func: String,
+    /// call node and task id of the function running the txn
+    src_task: (NodeID, u32),
+    writes: Vec<TxnWrite>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TxnWrite {
+    key: Vec<u8>,
+    /// none deletes the key
+    values: Option<Vec<Vec<u8>>>,
+    ttl_ms: u64,
+}
+
+/// An op of a txn on its single key.
+enum TxnOp {
+    Get {
+        idxs: Vec<u8>,
+    },
+    Set {
+        values: Vec<Vec<u8>>,
+        ttl_ms: u64,
+    },
+    Delete,
+    Cas {
+        values: Vec<Vec<u8>>,
+        expected_version: u64,
+        delete: bool,
+    },
+}
+
+impl TxnOp {
+    /// The key and op of a request allowed in a txn, why it isn't otherwise.
+    fn parse(req: proto::kv::KvRequest) -> Result<(Vec<u8>, TxnOp), String> {
+        let single_key = |range: Option<proto::kv::KeyRange>| match range {
+            Some(range) if !range.is_scan() => Ok(range.start),
+            Some(range) => Err(format!("range {:?} can't run in a txn", range)),
+            None => Err("txn request without a key range".to_owned()),
+        };
+        let with_kv = |kv: Option<proto::kv::KvPair>| {
+            kv.ok_or_else(|| "txn request without a key".to_owned())
+        };
+        match req.op {
+            Some(proto::kv::kv_request::Op::Get(get)) => Ok((
+                single_key(get.range)?,
+                TxnOp::Get {
+                    idxs: get.idxs.into_iter().map(|i| i as u8).collect(),
+                },
+            )),
+            Some(proto::kv::kv_request::Op::Set(set)) => {
+                let kv = with_kv(set.kv)?;
+                // rolled forward after its lock is long gone, when a newer token may be in
+                if set.fencing_token != 0 {
+                    return Err(format!(
+                        "fenced set of key {:?} can't run in a txn",
+                        String::from_utf8_lossy(&kv.key)
+                    ));
+                }
+                Ok((
+                    kv.key,
+                    TxnOp::Set {
+                        values: kv.values,
+                        ttl_ms: set.ttl_ms,
+                    },
+                ))
+            }
+            Some(proto::kv::kv_request::Op::Delete(delete)) => {
+                Ok((single_key(delete.range)?, TxnOp::Delete))
+            }
+            Some(proto::kv::kv_request::Op::Cas(cas)) => {
+                let kv = with_kv(cas.kv)?;
+                Ok((
+                    kv.key,
+                    TxnOp::Cas {
+                        values: kv.values,
+                        expected_version: cas.expected_version,
+                        delete: cas.delete,
+                    },
+                ))
+            }
+            op => Err(format!(
+                "only single key get, set, delete and cas run in a txn, got {:?}",
+                op
+            )),
+        }
+    }
+}
+
 impl KvUserClient {
     pub async fn kv_requests(
         &self,
@@ -129,11 +278,20 @@ impl KvUserClient {
             app: app_name,
             func: func_name,
             requests,
+            txn,
             ..
         }: proto::kv::KvRequests,
         // responsor: RPCResponsor<KvRequests>,
     ) -> WSResult<proto::kv::KvResponses> {
-        let mut kv_responses = KvResponses { responses: vec![] };
+        if txn {
+            return self
+                .kv_txn(src_taskid, &app_name, &func_name, requests)
+                .await;
+        }
+        let mut kv_responses = KvResponses {
+            responses: vec![],
+            txn_abort_reason: String::new(),
+        };
         // pre-collect each operation's event trigger info
 
         // let mut kv_opeid = None;
@@ -148,22 +306,30 @@ impl KvUserClient {
                 proto::kv::kv_request::Op::Delete(delete) => {
                     Some(self.handle_kv_delete(delete).await)
                 }
+                proto::kv::kv_request::Op::Cas(cas) => Some(
+                    self.handle_kv_cas(src_taskid.clone(), &app_name, &func_name, cas)
+                        .await,
+                ),
+                proto::kv::kv_request::Op::Watch(watch) => Some(self.handle_kv_watch(watch).await),
                 proto::kv::kv_request::Op::Lock(lock) => {
+                    let key = lock.range.unwrap().start;
                     let req = if lock.release_id.len() > 0 {
                         proto::kv::KvLockRequest {
-                            key: lock.range.unwrap().start,
-                            read_0_write_1_unlock_2: 2,
+                            key,
+                            read_0_write_1_unlock_2: if lock.renew { 3 } else { 2 },
                             release_id: lock.release_id[0],
+                            ttl_ms: lock.ttl_ms,
                         }
                     } else {
                         proto::kv::KvLockRequest {
-                            key: lock.range.unwrap().start,
+                            key,
                             read_0_write_1_unlock_2: if lock.read_or_write { 0 } else { 1 },
                             release_id: 0,
+                            ttl_ms: lock.ttl_ms,
                         }
                     };
 
-                    let ok = match self.view.dist_lock().lock(req).await {
+                    let ok = match self.view.dist_lock().lock_for_task(&src_taskid, req).await {
                         Ok(ok) => ok,
                         Err(err) => {
                             tracing::warn!("kv_requests lock err:{:?}", err);
@@ -171,7 +337,7 @@ impl KvUserClient {
                         }
                     };
 
-                    if lock.release_id.len() > 0 {
+                    if lock.release_id.len() > 0 && !lock.renew {
                         //unlocked
                         Some(proto::kv::KvResponse {
                             resp: Some(proto::kv::kv_response::Resp::LockId(if ok.success {
@@ -181,10 +347,8 @@ impl KvUserClient {
                             })),
                         })
                     } else {
-                        // locked
-                        Some(proto::kv::KvResponse {
-                            resp: Some(proto::kv::kv_response::Resp::LockId(ok.release_id)),
-                        })
+                        // locked or renewed, lock id 0 when the lease was lost
+                        Some(KvResponse::new_lock_grant(ok.release_id, ok.fencing_token))
                     }
 
                     // Some(ok)
@@ -205,6 +369,54 @@ impl KvUserClient {
         Ok(kv_responses)
     }
 
+    fn fn_write_context(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+    ) -> (
+        NodeID,
+        proto::DataOpeType,
+        proto::data_schedule_context::OpeRole,
+        FnTaskId,
+    ) {
+        let this_node = self.view.p2p().nodes_config.this_node();
+        (
+            this_node,
+            proto::DataOpeType::Write,
+            proto::data_schedule_context::OpeRole::new_fn_call(app_name, func_name, this_node),
+            src_taskid,
+        )
+    }
+
+    fn values_to_items(values: Vec<Vec<u8>>) -> Vec<DataItemArgWrapper> {
+        values
+            .into_iter()
+            .map(|v| DataItemArgWrapper::new(proto::DataItem::new_mem_data(v)))
+            .collect()
+    }
+
+    async fn set_values(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+        key: &[u8],
+        values: Vec<Vec<u8>>,
+        cond: DataWriteCond,
+    ) -> WSResult<()> {
+        self.view
+            .data_general()
+            .write_data_if(
+                new_data_unique_id_fn_kv(key),
+                Self::values_to_items(values),
+                Some(self.fn_write_context(src_taskid, app_name, func_name)),
+                cond,
+            )
+            .await
+            .map(|_version| ())
+    }
+
     async fn handle_kv_set(
         &self,
         src_taskid: FnTaskId,
@@ -214,27 +426,20 @@ impl KvUserClient {
     ) -> KvResponse {
         let kv = set.kv.unwrap();
         let key = kv.key;
-        let values = kv.values;
 
         let res = self
-            .view
-            .data_general()
-            .write_data(
-                new_data_unique_id_fn_kv(&key),
-                values
-                    .into_iter()
-                    .map(|v| DataItemArgWrapper::new(proto::DataItem::new_mem_data(v)))
-                    .collect(),
-                Some((
-                    self.view.p2p().nodes_config.this_node(),
-                    proto::DataOpeType::Write,
-                    proto::data_schedule_context::OpeRole::new_fn_call(
-                        app_name,
-                        func_name,
-                        self.view.p2p().nodes_config.this_node(),
-                    ),
-                    src_taskid,
-                )),
+            .set_values(
+                src_taskid,
+                app_name,
+                func_name,
+                &key,
+                kv.values,
+                DataWriteCond {
+                    expected_version: None,
+                    fencing_token: set.fencing_token,
+                    ttl_ms: set.ttl_ms,
+                    ..Default::default()
+                },
             )
             .await;
 
@@ -253,68 +458,744 @@ impl KvUserClient {
         }
     }
 
-    async fn handle_kv_get(&self, get: proto::kv::kv_request::KvGetRequest) -> KvResponse {
-        tracing::debug!("handle_kv_get:{:?}", get);
-        let idxs: Vec<u8> = get.idxs.into_iter().map(|i| i as u8).collect();
-        let range = get.range.unwrap();
-        let key = range.start;
+    /// Keys in `range` ordered by key from master, with the continuation of the next page.
+    async fn scan_keys(
+        &self,
+        range: proto::kv::KeyRange,
+        limit: u32,
+        continuation: Vec<u8>,
+    ) -> WSResult<(Vec<Vec<u8>>, Vec<u8>)> {
+        let p2p = self.view.p2p();
+        let resp = self
+            .rpc_caller_scan_keys
+            .call(
+                p2p,
+                p2p.nodes_config.get_master_node()?,
+                KvScanKeysRequest {
+                    range: Some(range),
+                    limit,
+                    continuation,
+                },
+                Some(Duration::from_secs(60)),
+            )
+            .await?;
+        if !resp.err_msg.is_empty() {
+            return Err(WsDataError::KvScanFailed {
+                reason: resp.err_msg,
+            }
+            .into());
+        }
+        Ok((resp.keys, resp.continuation))
+    }
 
-        let data_general = self.view.data_general();
-        let uid = new_data_unique_id_fn_kv(&key);
-        let got = data_general
+    /// Puts and deletes of the watched keys from master, waits for the first one up to the timeout.
+    pub async fn watch(&self, req: KvWatchRequest) -> WSResult<KvWatchResponse> {
+        let p2p = self.view.p2p();
+        let resp = self
+            .rpc_caller_watch
+            .call(
+                p2p,
+                p2p.nodes_config.get_master_node()?,
+                req,
+                Some(KV_WATCH_MAX_TIMEOUT + Duration::from_secs(10)),
+            )
+            .await?;
+        if !resp.err_msg.is_empty() {
+            return Err(WsDataError::KvWatchFailed {
+                reason: resp.err_msg,
+            }
+            .into());
+        }
+        Ok(resp)
+    }
+
+    async fn handle_kv_watch(&self, watch: KvWatchRequest) -> KvResponse {
+        let from_revision = watch.from_revision;
+        match self.watch(watch).await {
+            Ok(resp) => KvResponse::new_watch(resp),
+            Err(err) => {
+                tracing::warn!("kv watch err: {:?}", err);
+                KvResponse::new_watch(KvWatchResponse {
+                    revision: from_revision,
+                    err_msg: format!("{:?}", err),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    /// Values of the wanted idxs present in `key`, in the order of `idxs`, and the key's version.
+    async fn get_values(&self, key: &[u8], idxs: &[u8]) -> WSResult<(Vec<Vec<u8>>, u64)> {
+        let (meta, mut idx_2_items) = self
+            .view
+            .data_general()
             .get_or_del_datas(GetOrDelDataArg {
                 meta: None,
-                unique_id: uid.clone(),
+                unique_id: new_data_unique_id_fn_kv(key),
                 ty: GetOrDelDataArgType::PartialMany {
                     idxs: idxs.iter().map(|i| *i).collect(),
                 },
             })
+            .await?;
+        let mut values = vec![];
+        for idx in idxs.iter() {
+            if let Some(mut item) = idx_2_items.remove(idx) {
+                values.push(item.take_mem_data());
+            }
+        }
+        Ok((values, meta.version))
+    }
+
+    /// All values of `key` ordered by idx and its version, none when the key doesn't exist.
+    async fn all_values(&self, key: &[u8]) -> WSResult<Option<(Vec<Vec<u8>>, u64)>> {
+        let res = self
+            .view
+            .data_general()
+            .get_or_del_datas(GetOrDelDataArg {
+                meta: None,
+                unique_id: new_data_unique_id_fn_kv(key),
+                ty: GetOrDelDataArgType::All,
+            })
             .await;
+        let (meta, mut idx_2_items) = match res {
+            Ok(got) => got,
+            Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let ordered_idxs: BTreeSet<u8> = idx_2_items.iter().map(|(i, _)| *i).collect();
+        Ok(Some((
+            ordered_idxs
+                .iter()
+                .map(|idx| idx_2_items.remove(idx).unwrap().take_mem_data())
+                .collect(),
+            meta.version,
+        )))
+    }
 
-        match got {
-            Ok((_meta, mut idx_2_items)) => {
-                let mut values = vec![];
-                for idx in idxs.iter() {
-                    if let Some(mut item) = idx_2_items.remove(idx) {
-                        values.push(item.take_mem_data());
-                    }
-                }
-                KvResponse::new_get(idxs, values)
-            }
+    /// Deleted values of `key`, ordered by idx.
+    async fn delete_values(&self, key: &[u8]) -> WSResult<Vec<Vec<u8>>> {
+        let (_meta, mut idx_2_items) = self
+            .view
+            .data_general()
+            .get_or_del_datas(GetOrDelDataArg {
+                meta: None,
+                unique_id: new_data_unique_id_fn_kv(key),
+                ty: GetOrDelDataArgType::Delete,
+            })
+            .await?;
+        let ordered_idxs: BTreeSet<u8> = idx_2_items.iter().map(|(i, _)| *i).collect();
+        Ok(ordered_idxs
+            .iter()
+            .map(|idx| idx_2_items.remove(idx).unwrap().take_mem_data())
+            .collect())
+    }
+
+    async fn handle_kv_get(&self, get: proto::kv::kv_request::KvGetRequest) -> KvResponse {
+        tracing::debug!("handle_kv_get:{:?}", get);
+        let idxs: Vec<u8> = get.idxs.into_iter().map(|i| i as u8).collect();
+        let Some(range) = get.range else {
+            tracing::error!("kv get without key range");
+            return KvResponse::new_get(vec![], vec![], 0);
+        };
+        if range.is_scan() {
+            return self
+                .handle_kv_get_range(range, &idxs, get.limit, get.continuation)
+                .await;
+        }
+        let key = range.start;
+
+        match self.get_values(&key, &idxs).await {
+            Ok((values, version)) => KvResponse::new_get(idxs, values, version),
             Err(err) => {
                 tracing::error!("kv get err: {:?}", err);
-                KvResponse::new_get(vec![], vec![])
+                KvResponse::new_get(vec![], vec![], 0)
             }
         }
     }
+
+    async fn handle_kv_get_range(
+        &self,
+        range: proto::kv::KeyRange,
+        idxs: &[u8],
+        limit: u32,
+        continuation: Vec<u8>,
+    ) -> KvResponse {
+        let (keys, continuation) = match self.scan_keys(range, limit, continuation).await {
+            Ok(scanned) => scanned,
+            Err(err) => {
+                tracing::error!("kv scan err: {:?}", err);
+                return KvResponse::new_get_range(vec![], vec![]);
+            }
+        };
+        // fetched together, kept in key order
+        let got: Vec<_> = stream::iter(keys)
+            .map(|key| async move {
+                let res = self.get_values(&key, idxs).await;
+                (key, res)
+            })
+            .buffered(KV_RANGE_GET_CONCURRENCY)
+            .collect()
+            .await;
+        let mut kvs = Vec::with_capacity(got.len());
+        for (key, res) in got {
+            match res {
+                Ok((values, _version)) => kvs.push(proto::kv::KvPair { key, values }),
+                // deleted after the scan
+                Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => {}
+                Err(err) => {
+                    tracing::error!("kv get err in range scan: {:?}", err);
+                }
+            }
+        }
+        KvResponse::new_get_range(kvs, continuation)
+    }
+
     async fn handle_kv_delete(&self, delete: proto::kv::kv_request::KvDeleteRequest) -> KvResponse {
         tracing::debug!("handle_kv_delete:{:?}", delete);
-        let range = delete.range.unwrap();
+        let Some(range) = delete.range else {
+            tracing::error!("kv delete without key range");
+            return KvResponse::new_get(vec![], vec![], 0);
+        };
+        if range.is_scan() {
+            return self
+                .handle_kv_delete_range(range, delete.limit, delete.continuation)
+                .await;
+        }
         let key = range.start;
 
-        let data_general = self.view.data_general();
-        let uid = new_data_unique_id_fn_kv(&key);
-        let deleted = data_general
+        match self.delete_values(&key).await {
+            Ok(values) => KvResponse::new_put_or_del(proto::kv::KvPair { key, values }),
+            Err(err) => {
+                tracing::error!("kv get err: {:?}", err);
+                KvResponse::new_get(vec![], vec![], 0)
+            }
+        }
+    }
+
+    async fn handle_kv_delete_range(
+        &self,
+        range: proto::kv::KeyRange,
+        limit: u32,
+        continuation: Vec<u8>,
+    ) -> KvResponse {
+        let (keys, continuation) = match self.scan_keys(range, limit, continuation).await {
+            Ok(scanned) => scanned,
+            Err(err) => {
+                tracing::error!("kv scan err: {:?}", err);
+                return KvResponse::new_del_range(vec![], vec![]);
+            }
+        };
+        let mut kvs = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.delete_values(&key).await {
+                Ok(values) => kvs.push(proto::kv::KvPair { key, values }),
+                Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => {}
+                Err(err) => {
+                    tracing::error!("kv delete err in range scan: {:?}", err);
+                }
+            }
+        }
+        KvResponse::new_del_range(kvs, continuation)
+    }
+
+    /// Write locks the txn lock of each key in the given order, releasing what's held on failure.
+    async fn lock_txn_keys(&self, keys: Vec<Vec<u8>>) -> WSResult<Vec<(Vec<u8>, u32)>> {
+        let mut held = Vec::with_capacity(keys.len());
+        for key in keys {
+            let lock_key = [KV_TXN_LOCK_PREFIX, &key].concat();
+            let res = self
+                .view
+                .dist_lock()
+                .lock(proto::kv::KvLockRequest {
+                    key: lock_key.clone(),
+                    read_0_write_1_unlock_2: 1,
+                    release_id: 0,
+                    ttl_ms: 0,
+                })
+                .await;
+            let err = match res {
+                Ok(resp) if resp.success => {
+                    held.push((lock_key, resp.release_id));
+                    continue;
+                }
+                Ok(resp) => WsDataError::KvTxnLockFailed {
+                    key,
+                    context: resp.context,
+                }
+                .into(),
+                Err(err) => err,
+            };
+            self.unlock_txn_keys(held).await;
+            return Err(err);
+        }
+        Ok(held)
+    }
+
+    async fn unlock_txn_keys(&self, held: Vec<(Vec<u8>, u32)>) {
+        for (lock_key, release_id) in held.into_iter().rev() {
+            let res = self
+                .view
+                .dist_lock()
+                .lock(proto::kv::KvLockRequest {
+                    key: lock_key,
+                    read_0_write_1_unlock_2: 2,
+                    release_id,
+                    ttl_ms: 0,
+                })
+                .await;
+            match res {
+                Ok(resp) if resp.success => {}
+                Ok(resp) => tracing::warn!("kv txn unlock failed: {}", resp.context),
+                Err(err) => tracing::warn!("kv txn unlock err: {:?}", err),
+            }
+        }
+    }
+
+    /// Applies a cas of `kv` with its key's txn lock held, the version written on success,
+    /// or `WsDataError::VersionMismatch` with the key's current version.
+    async fn apply_cas(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+        kv: proto::kv::KvPair,
+        expected_version: u64,
+        delete: bool,
+    ) -> WSResult<u64> {
+        let unique_id = new_data_unique_id_fn_kv(&kv.key);
+        // master checks the version under its meta lock
+        if !delete {
+            return self
+                .view
+                .data_general()
+                .write_data_if(
+                    unique_id,
+                    Self::values_to_items(kv.values),
+                    Some(self.fn_write_context(src_taskid, app_name, func_name)),
+                    DataWriteCond {
+                        expected_version: Some(expected_version),
+                        ..Default::default()
+                    },
+                )
+                .await;
+        }
+        let Some(meta) = self
+            .view
+            .data_general()
+            .del_datameta_from_master(&unique_id, Some(expected_version))
+            .await?
+        else {
+            return Ok(0);
+        };
+        let _ = self
+            .view
+            .data_general()
             .get_or_del_datas(GetOrDelDataArg {
-                meta: None,
-                unique_id: uid.clone(),
+                meta: Some(meta),
+                unique_id,
                 ty: GetOrDelDataArgType::Delete,
             })
+            .await?;
+        Ok(0)
+    }
+
+    async fn handle_kv_cas(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+        cas: proto::kv::kv_request::KvCasRequest,
+    ) -> KvResponse {
+        tracing::debug!("handle_kv_cas:{:?}", cas);
+        let Some(kv) = cas.kv else {
+            tracing::warn!("kv cas without a key");
+            return KvResponse::new_cas(false, 0);
+        };
+        let held = match self.lock_settled_keys(vec![kv.key.clone()]).await {
+            Ok(held) => held,
+            Err(err) => {
+                tracing::warn!("kv cas lock err: {:?}", err);
+                return KvResponse::new_cas(false, 0);
+            }
+        };
+        let res = self
+            .apply_cas(
+                src_taskid,
+                app_name,
+                func_name,
+                kv,
+                cas.expected_version,
+                cas.delete,
+            )
+            .await;
+        self.unlock_txn_keys(held).await;
+
+        match res {
+            Ok(version) => KvResponse::new_cas(true, version),
+            Err(WSError::WsDataError(WsDataError::VersionMismatch { actual, .. })) => {
+                KvResponse::new_cas(false, actual)
+            }
+            Err(err) => {
+                tracing::warn!("kv cas err: {:?}", err);
+                KvResponse::new_cas(false, 0)
+            }
+        }
+    }
+
+    /// Applies all `requests` or none of them.
+    ///
+    /// The touched keys are locked in key order so txns never deadlock each other, and every
+    /// condition is checked before anything is written. The txn commits by creating its intent,
+    /// which lists all its writes, then applies them and drops the intent. A txn interrupted
+    /// after committing, by a crash of this node or a failed write, is rolled forward from its
+    /// intent by the next txn or cas locking one of its keys, or by the sweep on master.
+    /// Plain sets, deletes and gets don't take the locks, so they aren't isolated from a running
+    /// txn and may see it half applied until it's rolled forward.
+    async fn kv_txn(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+        requests: Vec<proto::kv::KvRequest>,
+    ) -> WSResult<KvResponses> {
+        let mut ops = Vec::with_capacity(requests.len());
+        for req in requests {
+            match TxnOp::parse(req) {
+                Ok(op) => ops.push(op),
+                Err(reason) => return Ok(txn_aborted(reason)),
+            }
+        }
+        let keys: BTreeSet<Vec<u8>> = ops.iter().map(|(key, _)| key.clone()).collect();
+
+        let held = match self.lock_settled_keys(keys.into_iter().collect()).await {
+            Ok(held) => held,
+            Err(err) => return Ok(txn_aborted(format!("{:?}", err))),
+        };
+        let res = self
+            .kv_txn_locked(src_taskid, app_name, func_name, ops)
             .await;
+        self.unlock_txn_keys(held).await;
+        res
+    }
 
-        match deleted {
-            Ok((_meta, mut idx_2_items)) => {
-                let mut values = vec![];
+    async fn kv_txn_locked(
+        &self,
+        src_taskid: FnTaskId,
+        app_name: &str,
+        func_name: &str,
+        ops: Vec<(Vec<u8>, TxnOp)>,
+    ) -> WSResult<KvResponses> {
+        // the keys can't change through other txns while locked, so what's read here still holds
+        //  when the writes are applied
+        // values and version of the written keys as the txn leaves them so far
+        let mut written: HashMap<Vec<u8>, (Option<Vec<Vec<u8>>>, u64)> = HashMap::new();
+        let mut writes = vec![];
+        let mut responses = Vec::with_capacity(ops.len());
+        // cas responses by their write, the version is known once applied
+        let mut cas_responses = vec![];
+        for (key, op) in ops {
+            if let TxnOp::Get { idxs } = op {
+                let response = match written.get(&key) {
+                    Some((Some(values), version)) => {
+                        let (idxs, values): (Vec<u8>, Vec<Vec<u8>>) = idxs
+                            .into_iter()
+                            .filter_map(|idx| Some((idx, values.get(idx as usize)?.clone())))
+                            .unzip();
+                        KvResponse::new_get(idxs, values, *version)
+                    }
+                    Some((None, _)) => KvResponse::new_get(vec![], vec![], 0),
+                    None => match self.get_values(&key, &idxs).await {
+                        Ok((values, version)) => KvResponse::new_get(idxs, values, version),
+                        Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => {
+                            KvResponse::new_get(vec![], vec![], 0)
+                        }
+                        Err(err) => return Ok(txn_aborted(format!("{:?}", err))),
+                    },
+                };
+                responses.push(response);
+                continue;
+            }
 
-                let ordered_idxs: BTreeSet<u8> = idx_2_items.iter().map(|(i, _)| *i).collect();
-                for idx in ordered_idxs.iter() {
-                    values.push(idx_2_items.remove(idx).unwrap().take_mem_data());
+            let (values_before, version_before) = match written.remove(&key) {
+                Some(state) => state,
+                None => match self.all_values(&key).await {
+                    Ok(Some((values, version))) => (Some(values), version),
+                    Ok(None) => (None, 0),
+                    Err(err) => return Ok(txn_aborted(format!("{:?}", err))),
+                },
+            };
+            let (values, ttl_ms, response) = match op {
+                TxnOp::Get { .. } => unreachable!(),
+                TxnOp::Set { values, ttl_ms } => (
+                    Some(values),
+                    ttl_ms,
+                    KvResponse::new_put_or_del(proto::kv::KvPair {
+                        key: key.clone(),
+                        values: vec![],
+                    }),
+                ),
+                TxnOp::Delete => (
+                    None,
+                    0,
+                    KvResponse::new_put_or_del(proto::kv::KvPair {
+                        key: key.clone(),
+                        values: values_before.unwrap_or_default(),
+                    }),
+                ),
+                TxnOp::Cas {
+                    values,
+                    expected_version,
+                    delete,
+                } => {
+                    if version_before != expected_version {
+                        return Ok(txn_aborted(format!(
+                            "key {:?} is at version {}, expected {}",
+                            String::from_utf8_lossy(&key),
+                            version_before,
+                            expected_version
+                        )));
+                    }
+                    cas_responses.push((writes.len(), responses.len()));
+                    let values = if delete { None } else { Some(values) };
+                    (values, 0, KvResponse::new_cas(true, 0))
+                }
+            };
+            // a write makes a new version, a delete leaves the key absent
+            let version_after = if values.is_some() {
+                version_before + 1
+            } else {
+                0
+            };
+            let _ = written.insert(key.clone(), (values.clone(), version_after));
+            writes.push(TxnWrite {
+                key,
+                values,
+                ttl_ms,
+            });
+            responses.push(response);
+        }
+        if writes.is_empty() {
+            return Ok(KvResponses {
+                responses,
+                txn_abort_reason: String::new(),
+            });
+        }
+
+        let intent = TxnIntent {
+            app: app_name.to_owned(),
+            func: func_name.to_owned(),
+            src_task: (src_taskid.call_node_id, src_taskid.task_id),
+            writes,
+        };
+        let intent_key = [
+            KV_TXN_INTENT_PREFIX,
+            format!(
+                "{}-{:016x}",
+                self.view.p2p().nodes_config.this_node(),
+                rand::random::<u64>()
+            )
+            .as_bytes(),
+        ]
+        .concat();
+        // creating the intent is the commit point
+        let committed = self
+            .view
+            .data_general()
+            .write_data_if(
+                new_data_unique_id_fn_kv(&intent_key),
+                Self::values_to_items(vec![bincode::serialize(&intent).unwrap()]),
+                None,
+                DataWriteCond {
+                    expected_version: Some(0),
+                    ..Default::default()
+                },
+            )
+            .await;
+        if let Err(err) = committed {
+            // the write may have reached master before failing
+            match self.all_values(&intent_key).await {
+                Ok(None) => return Ok(txn_aborted(format!("{:?}", err))),
+                Ok(Some(_)) => {}
+                // unknown whether it committed, left to the recovery
+                Err(_) => return Err(err),
+            }
+        }
+
+        match self.apply_txn_intent(&intent).await {
+            Ok(versions) => {
+                for (write_idx, response_idx) in cas_responses {
+                    responses[response_idx] = KvResponse::new_cas(true, versions[write_idx]);
+                }
+                if let Err(err) = self.drop_txn_intent(&intent_key).await {
+                    tracing::warn!("kv txn intent left for the recovery: {:?}", err);
                 }
-                KvResponse::new_put_or_del(proto::kv::KvPair { key, values })
             }
             Err(err) => {
-                tracing::error!("kv get err: {:?}", err);
-                KvResponse::new_get(vec![], vec![])
+                tracing::warn!(
+                    "kv txn committed, the rest is left for the recovery: {:?}",
+                    err
+                )
+            }
+        }
+        Ok(KvResponses {
+            responses,
+            txn_abort_reason: String::new(),
+        })
+    }
+
+    /// Applies the writes of a committed txn in order, the version each one wrote.
+    /// Applying them again leaves the keys the same, so an interrupted txn just starts over.
+    async fn apply_txn_intent(&self, intent: &TxnIntent) -> WSResult<Vec<u64>> {
+        let src_taskid = FnTaskId {
+            call_node_id: intent.src_task.0,
+            task_id: intent.src_task.1,
+        };
+        let mut versions = Vec::with_capacity(intent.writes.len());
+        for write in &intent.writes {
+            let version = match &write.values {
+                // unfenced, the txn holds the keys' locks
+                Some(values) => {
+                    self.view
+                        .data_general()
+                        .write_data_if(
+                            new_data_unique_id_fn_kv(&write.key),
+                            Self::values_to_items(values.clone()),
+                            Some(self.fn_write_context(
+                                src_taskid.clone(),
+                                &intent.app,
+                                &intent.func,
+                            )),
+                            DataWriteCond {
+                                ttl_ms: write.ttl_ms,
+                                ..Default::default()
+                            },
+                        )
+                        .await?
+                }
+                None => match self.delete_values(&write.key).await {
+                    Ok(_) | Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => 0,
+                    Err(err) => return Err(err),
+                },
+            };
+            versions.push(version);
+        }
+        Ok(versions)
+    }
+
+    async fn drop_txn_intent(&self, intent_key: &[u8]) -> WSResult<()> {
+        match self.delete_values(intent_key).await {
+            Ok(_) | Err(WSError::WsDataError(WsDataError::DataSetNotFound { .. })) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The intent stored at `intent_key`, none once it's dropped.
+    async fn read_txn_intent(&self, intent_key: &[u8]) -> WSResult<Option<TxnIntent>> {
+        let Some((values, _version)) = self.all_values(intent_key).await? else {
+            return Ok(None);
+        };
+        let intent = values
+            .first()
+            .map(|value| bincode::deserialize(value))
+            .transpose()
+            .map_err(|err| WsSerialErr::BincodeErr {
+                err,
+                context: "read_txn_intent".to_owned(),
+            })?;
+        Ok(intent)
+    }
+
+    /// Keys of the intents not dropped yet, the committed txns still running or interrupted.
+    async fn txn_intent_keys(&self) -> WSResult<Vec<Vec<u8>>> {
+        let mut intent_keys = vec![];
+        let mut continuation = vec![];
+        loop {
+            let (keys, next) = self
+                .scan_keys(
+                    proto::kv::KeyRange::new_prefix(KV_TXN_INTENT_PREFIX.to_vec()),
+                    0,
+                    continuation,
+                )
+                .await?;
+            intent_keys.extend(keys);
+            if next.is_empty() {
+                return Ok(intent_keys);
+            }
+            continuation = next;
+        }
+    }
+
+    /// Rolls the txn of `intent_key` forward and drops its intent, nothing to do once it's gone.
+    async fn recover_txn_intent(&self, intent_key: &[u8]) -> WSResult<()> {
+        let Some(intent) = self.read_txn_intent(intent_key).await? else {
+            return Ok(());
+        };
+        let keys: BTreeSet<Vec<u8>> = intent.writes.iter().map(|w| w.key.clone()).collect();
+        // the txn keeps its keys locked until its intent is dropped, so once they're held here
+        //  it has either finished or died
+        let held = self.lock_txn_keys(keys.into_iter().collect()).await?;
+        let res: WSResult<()> = async {
+            let Some(intent) = self.read_txn_intent(intent_key).await? else {
+                return Ok(());
+            };
+            tracing::warn!(
+                "rolling forward the interrupted kv txn {}",
+                String::from_utf8_lossy(intent_key)
+            );
+            let _ = self.apply_txn_intent(&intent).await?;
+            self.drop_txn_intent(intent_key).await
+        }
+        .await;
+        self.unlock_txn_keys(held).await;
+        res
+    }
+
+    /// Rolls forward every txn whose intent is left, run by master from time to time.
+    async fn recover_txn_intents(&self) {
+        let intent_keys = match self.txn_intent_keys().await {
+            Ok(intent_keys) => intent_keys,
+            Err(err) => {
+                tracing::warn!("kv txn intents scan err: {:?}", err);
+                return;
+            }
+        };
+        for intent_key in intent_keys {
+            if let Err(err) = self.recover_txn_intent(&intent_key).await {
+                tracing::warn!(
+                    "kv txn {} recovery err: {:?}",
+                    String::from_utf8_lossy(&intent_key),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Locks `keys` like `lock_txn_keys`, once the committed txns left on any of them are rolled
+    /// forward, so conditions checked with them held see every committed txn.
+    async fn lock_settled_keys(&self, keys: Vec<Vec<u8>>) -> WSResult<Vec<(Vec<u8>, u32)>> {
+        loop {
+            let held = self.lock_txn_keys(keys.clone()).await?;
+            let mut left = vec![];
+            let res: WSResult<()> = async {
+                for intent_key in self.txn_intent_keys().await? {
+                    let Some(intent) = self.read_txn_intent(&intent_key).await? else {
+                        continue;
+                    };
+                    if intent.writes.iter().any(|write| keys.contains(&write.key)) {
+                        left.push(intent_key);
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            if res.is_ok() && left.is_empty() {
+                return Ok(held);
+            }
+            // the recovery locks the intent's keys, some of them held here
+            self.unlock_txn_keys(held).await;
+            res?;
+            for intent_key in left {
+                self.recover_txn_intent(&intent_key).await?;
             }
         }
     }
@@ -385,12 +1266,105 @@ impl KvUserClient {
     // }
 }
 
+/// How long one poll of an http watch waits on master.
+const HTTP_WATCH_POLL_TIMEOUT_MS: u32 = 30_000;
+
+#[derive(Deserialize)]
+struct HttpWatchParams {
+    key: String,
+    /// watch all the keys starting with `key`
+    #[serde(default)]
+    prefix: bool,
+    /// resume after this revision, a `Last-Event-ID` header overrides it
+    #[serde(default)]
+    from_revision: u64,
+}
+
+#[derive(Serialize)]
+struct HttpWatchEvent {
+    key: String,
+    version: u64,
+    revision: u64,
+}
+
+/// Server-sent `put` and `delete` events of the watched keys. Event ids are the revisions,
+///  so a reconnecting client resumes where it stopped, after `compacted` it should read the keys again.
+async fn handle_http_watch(
+    State(view): State<KvUserClientView>,
+    Query(params): Query<HttpWatchParams>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let from_revision = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(params.from_revision);
+    let key = params.key.into_bytes();
+    let range = if params.prefix {
+        proto::kv::KeyRange::new_prefix(key)
+    } else {
+        proto::kv::KeyRange::new_range(key, vec![])
+    };
+    let events = futures::stream::unfold(
+        (view, range, from_revision, VecDeque::new()),
+        |(view, range, mut revision, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (view, range, revision, pending)));
+                }
+                let resp = match view
+                    .kv_user_client()
+                    .watch(KvWatchRequest {
+                        range: Some(range.clone()),
+                        from_revision: revision,
+                        timeout_ms: HTTP_WATCH_POLL_TIMEOUT_MS,
+                        limit: 0,
+                    })
+                    .await
+                {
+                    Ok(resp) => resp,
+                    Err(err) => {
+                        tracing::warn!("http kv watch failed, will retry: {:?}", err);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                if resp.compacted {
+                    pending.push_back(
+                        Event::default()
+                            .event("compacted")
+                            .id(resp.revision.to_string())
+                            .data(""),
+                    );
+                }
+                for event in resp.events {
+                    let data = serde_json::to_string(&HttpWatchEvent {
+                        key: String::from_utf8_lossy(&event.key).into_owned(),
+                        version: event.version,
+                        revision: event.revision,
+                    })
+                    .unwrap();
+                    pending.push_back(
+                        Event::default()
+                            .event(if event.delete { "delete" } else { "put" })
+                            .id(event.revision.to_string())
+                            .data(data),
+                    );
+                }
+                revision = resp.revision;
+            }
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
 #[cfg(test)]
 mod test {
 
     use std::time::Duration;
 
-    use super::KvUserClientView;
+    use super::{KvUserClientView, TxnIntent, TxnWrite, KV_TXN_INTENT_PREFIX};
+    use crate::general::data::m_data_general::DataWriteCond;
     use crate::general::{
         network::{
             proto::{
@@ -398,7 +1372,7 @@ mod test {
                 kv::{KvRequest, KvRequests},
                 FnTaskId,
             },
-            proto_ext::KvRequestExt,
+            proto_ext::{KvRequestExt, ProtoExtKeyRange, ProtoExtKvResponse},
         },
         test_utils,
     };
@@ -428,6 +1402,7 @@ mod test {
                         app: app.to_owned(),
                         func: func.to_owned(),
                         prev_kv_opeid: -1,
+                        txn: false,
                         requests: vec![KvRequest::new_get(test_key.as_bytes().to_owned(), vec![0])],
                     },
                 )
@@ -454,6 +1429,7 @@ mod test {
                         app: app.to_owned(),
                         func: func.to_owned(),
                         prev_kv_opeid: -1,
+                        txn: false,
                         requests: vec![KvRequest::new_set(proto::kv::KvPair {
                             key: test_key.as_bytes().to_owned(),
                             values: vec![test_value.as_bytes().to_owned()],
@@ -485,6 +1461,7 @@ mod test {
                         app: app.to_owned(),
                         func: func.to_owned(),
                         prev_kv_opeid: -1,
+                        txn: false,
                         requests: vec![KvRequest::new_get(test_key.as_bytes().to_owned(), vec![0])],
                     },
                 )
@@ -511,6 +1488,7 @@ mod test {
                         app: app.to_owned(),
                         func: func.to_owned(),
                         prev_kv_opeid: -1,
+                        txn: false,
                         requests: vec![KvRequest::new_delete(test_key.as_bytes().to_owned())],
                     },
                 )
@@ -536,6 +1514,7 @@ mod test {
                         app: app.to_owned(),
                         func: func.to_owned(),
                         prev_kv_opeid: -1,
+                        txn: false,
                         requests: vec![KvRequest::new_delete(test_key.as_bytes().to_owned())],
                     },
                 )
@@ -551,4 +1530,462 @@ mod test {
             tracing::debug!("delete again is none");
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_user_client_range() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = KvUserClientView::new(sys2);
+        let test_taskid = FnTaskId {
+            task_id: 0,
+            call_node_id: view.p2p().nodes_config.this_node(),
+        };
+        let call = |requests: Vec<KvRequest>| {
+            view.kv_user_client().kv_requests(
+                test_taskid.clone(),
+                KvRequests {
+                    app: "test_app".to_owned(),
+                    func: "test_func".to_owned(),
+                    prev_kv_opeid: -1,
+                    txn: false,
+                    requests,
+                },
+            )
+        };
+
+        let keys = ["user_42_b", "user_42_a", "user_43_a", "user_42_c"];
+        let _ = call(
+            keys.iter()
+                .map(|k| {
+                    KvRequest::new_set(proto::kv::KvPair {
+                        key: k.as_bytes().to_owned(),
+                        values: vec![k.as_bytes().to_owned()],
+                    })
+                })
+                .collect(),
+        )
+        .await
+        .unwrap();
+
+        // two pages of the prefix, ordered by key
+        let prefix = proto::kv::KeyRange::new_prefix(b"user_42_".to_vec());
+        let res = call(vec![KvRequest::new_get_range(
+            prefix.clone(),
+            vec![0],
+            2,
+            vec![],
+        )])
+        .await
+        .unwrap();
+        let proto::kv::kv_response::Resp::Get(page) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        let got: Vec<_> = page.kvs.iter().map(|kv| kv.key.clone()).collect();
+        assert_eq!(got, vec![b"user_42_a".to_vec(), b"user_42_b".to_vec()]);
+        assert_eq!(page.kvs[0].values[0], b"user_42_a".to_vec());
+        assert!(!page.continuation.is_empty());
+
+        let res = call(vec![KvRequest::new_get_range(
+            prefix.clone(),
+            vec![0],
+            2,
+            page.continuation,
+        )])
+        .await
+        .unwrap();
+        let proto::kv::kv_response::Resp::Get(page) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        assert_eq!(page.kvs.len(), 1);
+        assert_eq!(page.kvs[0].key, b"user_42_c".to_vec());
+        assert!(page.continuation.is_empty());
+
+        // [start, end)
+        let range = proto::kv::KeyRange::new_range(b"user_42_b".to_vec(), b"user_43_a".to_vec());
+        let res = call(vec![KvRequest::new_get_range(range, vec![0], 0, vec![])])
+            .await
+            .unwrap();
+        let proto::kv::kv_response::Resp::Get(page) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        let got: Vec<_> = page.kvs.iter().map(|kv| kv.key.clone()).collect();
+        assert_eq!(got, vec![b"user_42_b".to_vec(), b"user_42_c".to_vec()]);
+
+        // delete the prefix, the other key stays
+        let res = call(vec![KvRequest::new_delete_range(prefix.clone(), 0, vec![])])
+            .await
+            .unwrap();
+        let proto::kv::kv_response::Resp::PutOrDel(deleted) =
+            res.responses[0].resp.clone().unwrap()
+        else {
+            panic!("require delete resp");
+        };
+        assert_eq!(deleted.kvs.len(), 3);
+        let res = call(vec![KvRequest::new_get_range(
+            proto::kv::KeyRange::new_prefix(b"user_4".to_vec()),
+            vec![0],
+            0,
+            vec![],
+        )])
+        .await
+        .unwrap();
+        let proto::kv::kv_response::Resp::Get(page) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        let got: Vec<_> = page.kvs.iter().map(|kv| kv.key.clone()).collect();
+        assert_eq!(got, vec![b"user_43_a".to_vec()]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_user_client_cas_txn() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = KvUserClientView::new(sys2);
+        let test_taskid = FnTaskId {
+            task_id: 0,
+            call_node_id: view.p2p().nodes_config.this_node(),
+        };
+        let call = |requests: Vec<KvRequest>, txn: bool| {
+            view.kv_user_client().kv_requests(
+                test_taskid.clone(),
+                KvRequests {
+                    app: "test_app".to_owned(),
+                    func: "test_func".to_owned(),
+                    prev_kv_opeid: -1,
+                    requests,
+                    txn,
+                },
+            )
+        };
+        let kv = |key: &str, value: &str| proto::kv::KvPair {
+            key: key.as_bytes().to_owned(),
+            values: vec![value.as_bytes().to_owned()],
+        };
+        let cas_resp = |res: &proto::kv::KvResponses| {
+            let proto::kv::kv_response::Resp::Cas(cas) = res.responses[0].resp.clone().unwrap()
+            else {
+                panic!("require cas resp");
+            };
+            cas
+        };
+
+        // create only if missing, the second create loses
+        let res = call(vec![KvRequest::new_cas(kv("cas_a", "1"), 0)], false)
+            .await
+            .unwrap();
+        let created = cas_resp(&res);
+        assert!(created.success);
+        let res = call(vec![KvRequest::new_cas(kv("cas_a", "2"), 0)], false)
+            .await
+            .unwrap();
+        let lost = cas_resp(&res);
+        assert!(!lost.success);
+        assert_eq!(lost.version, created.version);
+
+        // the version read by get is the one to compare against
+        let res = call(vec![KvRequest::new_get(b"cas_a".to_vec(), vec![0])], false)
+            .await
+            .unwrap();
+        let Some(get) = res.responses[0].get_kvs() else {
+            panic!("require get resp");
+        };
+        assert_eq!(get.version, created.version);
+        let res = call(
+            vec![KvRequest::new_cas(kv("cas_a", "3"), get.version)],
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(cas_resp(&res).success);
+
+        // a failed condition aborts the whole txn, nothing written
+        let res = call(
+            vec![
+                KvRequest::new_set(kv("txn_b", "1")),
+                KvRequest::new_cas(kv("cas_a", "4"), created.version),
+            ],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(!res.txn_abort_reason.is_empty());
+        assert!(res.responses.is_empty());
+        let res = call(vec![KvRequest::new_get(b"txn_b".to_vec(), vec![0])], false)
+            .await
+            .unwrap();
+        assert!(res.responses[0].get_kvs().unwrap().values.is_empty());
+
+        // move cas_a to txn_b atomically
+        let res = call(vec![KvRequest::new_get(b"cas_a".to_vec(), vec![0])], false)
+            .await
+            .unwrap();
+        let version = res.responses[0].get_kvs().unwrap().version;
+        let res = call(
+            vec![
+                KvRequest::new_cas_delete(b"cas_a".to_vec(), version),
+                KvRequest::new_set(kv("txn_b", "3")),
+            ],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(res.txn_abort_reason.is_empty());
+        assert_eq!(res.responses.len(), 2);
+        let res = call(
+            vec![
+                KvRequest::new_get(b"cas_a".to_vec(), vec![0]),
+                KvRequest::new_get(b"txn_b".to_vec(), vec![0]),
+            ],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(res.responses[0].get_kvs().unwrap().values.is_empty());
+        assert_eq!(
+            res.responses[1].get_kvs().unwrap().values,
+            vec![b"3".to_vec()]
+        );
+
+        // range requests can't join a txn
+        let res = call(
+            vec![KvRequest::new_get_range(
+                proto::kv::KeyRange::new_prefix(b"txn_".to_vec()),
+                vec![0],
+                0,
+                vec![],
+            )],
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(!res.txn_abort_reason.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_user_client_txn_recovery() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = KvUserClientView::new(sys2);
+        let client = view.kv_user_client();
+        let test_taskid = FnTaskId {
+            task_id: 0,
+            call_node_id: view.p2p().nodes_config.this_node(),
+        };
+        let (app, func) = ("test_app", "test_func");
+        client
+            .set_values(
+                test_taskid.clone(),
+                app,
+                func,
+                b"rec_b",
+                vec![b"1".to_vec()],
+                DataWriteCond::default(),
+            )
+            .await
+            .unwrap();
+
+        // a txn that died right after committing, none of its writes applied
+        let intent_key = [KV_TXN_INTENT_PREFIX, b"crashed"].concat();
+        let intent = TxnIntent {
+            app: app.to_owned(),
+            func: func.to_owned(),
+            src_task: (test_taskid.call_node_id, test_taskid.task_id),
+            writes: vec![
+                TxnWrite {
+                    key: b"rec_a".to_vec(),
+                    values: Some(vec![b"2".to_vec()]),
+                    ttl_ms: 0,
+                },
+                TxnWrite {
+                    key: b"rec_b".to_vec(),
+                    values: None,
+                    ttl_ms: 0,
+                },
+            ],
+        };
+        client
+            .set_values(
+                test_taskid.clone(),
+                app,
+                func,
+                &intent_key,
+                vec![bincode::serialize(&intent).unwrap()],
+                DataWriteCond::default(),
+            )
+            .await
+            .unwrap();
+
+        // a cas on one of its keys rolls the whole txn forward first
+        let res = client
+            .kv_requests(
+                test_taskid.clone(),
+                KvRequests {
+                    app: app.to_owned(),
+                    func: func.to_owned(),
+                    prev_kv_opeid: -1,
+                    requests: vec![KvRequest::new_cas(
+                        proto::kv::KvPair {
+                            key: b"rec_a".to_vec(),
+                            values: vec![b"3".to_vec()],
+                        },
+                        0,
+                    )],
+                    txn: false,
+                },
+            )
+            .await
+            .unwrap();
+        let proto::kv::kv_response::Resp::Cas(cas) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require cas resp");
+        };
+        assert!(!cas.success);
+        assert_eq!(cas.version, 1);
+
+        assert_eq!(
+            client.all_values(b"rec_a").await.unwrap().unwrap().0,
+            vec![b"2".to_vec()]
+        );
+        assert!(client.all_values(b"rec_b").await.unwrap().is_none());
+        assert!(client.all_values(&intent_key).await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_user_client_ttl() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = KvUserClientView::new(sys2);
+        let test_taskid = FnTaskId {
+            task_id: 0,
+            call_node_id: view.p2p().nodes_config.this_node(),
+        };
+        let call = |requests: Vec<KvRequest>| {
+            view.kv_user_client().kv_requests(
+                test_taskid.clone(),
+                KvRequests {
+                    app: "test_app".to_owned(),
+                    func: "test_func".to_owned(),
+                    prev_kv_opeid: -1,
+                    txn: false,
+                    requests,
+                },
+            )
+        };
+        let kv = |key: &[u8]| proto::kv::KvPair {
+            key: key.to_vec(),
+            values: vec![key.to_vec()],
+        };
+
+        let _ = call(vec![
+            KvRequest::new_set_with_ttl(kv(b"session_short"), 1000),
+            KvRequest::new_set(kv(b"session_long")),
+        ])
+        .await
+        .unwrap();
+        let res = call(vec![KvRequest::new_get(b"session_short".to_vec(), vec![0])])
+            .await
+            .unwrap();
+        let proto::kv::kv_response::Resp::Get(got) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        assert_eq!(got.values.len(), 1);
+
+        // gone once the ttl passed, even before the sweeper ran
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let res = call(vec![KvRequest::new_get(b"session_short".to_vec(), vec![0])])
+            .await
+            .unwrap();
+        let proto::kv::kv_response::Resp::Get(got) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        assert!(got.values.is_empty());
+        let res = call(vec![KvRequest::new_get_range(
+            proto::kv::KeyRange::new_prefix(b"session_".to_vec()),
+            vec![0],
+            0,
+            vec![],
+        )])
+        .await
+        .unwrap();
+        let proto::kv::kv_response::Resp::Get(page) = res.responses[0].resp.clone().unwrap() else {
+            panic!("require get resp");
+        };
+        let got: Vec<_> = page.kvs.iter().map(|kv| kv.key.clone()).collect();
+        assert_eq!(got, vec![b"session_long".to_vec()]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_kv_user_client_watch() {
+        let (_hold, _sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view = KvUserClientView::new(sys2);
+        let test_taskid = FnTaskId {
+            task_id: 0,
+            call_node_id: view.p2p().nodes_config.this_node(),
+        };
+        let call = |requests: Vec<KvRequest>| {
+            view.kv_user_client().kv_requests(
+                test_taskid.clone(),
+                KvRequests {
+                    app: "test_app".to_owned(),
+                    func: "test_func".to_owned(),
+                    prev_kv_opeid: -1,
+                    txn: false,
+                    requests,
+                },
+            )
+        };
+        let watch = |res: proto::kv::KvResponses| {
+            let proto::kv::kv_response::Resp::Watch(watch) = res.responses[0].resp.clone().unwrap()
+            else {
+                panic!("require watch resp");
+            };
+            watch
+        };
+
+        // nothing happens, the revision to resume from comes back
+        let prefix = proto::kv::KeyRange::new_prefix(b"watched_".to_vec());
+        let start = watch(
+            call(vec![KvRequest::new_watch(prefix.clone(), 0, 100)])
+                .await
+                .unwrap(),
+        );
+        assert!(start.err_msg.is_empty());
+        assert!(start.events.is_empty());
+
+        let _ = call(vec![
+            KvRequest::new_set(proto::kv::KvPair {
+                key: b"watched_a".to_vec(),
+                values: vec![b"a".to_vec()],
+            }),
+            KvRequest::new_set(proto::kv::KvPair {
+                key: b"unwatched_a".to_vec(),
+                values: vec![b"a".to_vec()],
+            }),
+            KvRequest::new_delete(b"watched_a".to_vec()),
+        ])
+        .await
+        .unwrap();
+
+        // resumed after the disconnect
+        let events = watch(
+            call(vec![KvRequest::new_watch(prefix, start.revision, 1000)])
+                .await
+                .unwrap(),
+        );
+        let got: Vec<_> = events
+            .events
+            .iter()
+            .map(|event| (event.key.clone(), event.delete))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (b"watched_a".to_vec(), false),
+                (b"watched_a".to_vec(), true)
+            ]
+        );
+        assert_eq!(events.events[0].version, events.events[1].version);
+        assert!(events.events[0].revision < events.events[1].revision);
+        assert_eq!(events.revision, events.events[1].revision);
+    }
 }
@@ -1,8 +1,12 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
+use dashmap::DashMap;
 use sysinfo::{CpuExt, CpuRefreshKind, RefreshKind, System, SystemExt};
 use ws_derive::LogicalModule;
 
 use crate::{
+    general::app::{instance::m_instance_manager::InstanceManager, m_executor::Executor},
     logical_module_view_impl,
     result::WSResult,
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef},
@@ -18,10 +22,23 @@ logical_module_view_impl!(MetricPublisherView);
 logical_module_view_impl!(MetricPublisherView, p2p, P2PModule);
 // logical_module_view_impl!(MetricPublisherView, metric_observor, Option<MetricObservor>);
 logical_module_view_impl!(MetricPublisherView, metric_publisher, MetricPublisher);
+logical_module_view_impl!(MetricPublisherView, executor, Executor);
+logical_module_view_impl!(MetricPublisherView, instance_manager, InstanceManager);
+
+/// Calls of an app version since the last report
+#[derive(Default)]
+struct AppCallStat {
+    success: u64,
+    failure: u64,
+    latency_ms_sum: u64,
+}
 
 #[derive(LogicalModule)]
 pub struct MetricPublisher {
     msg_sender: MsgSender<proto::metric::RscMetric>,
+    app_call_sender: MsgSender<proto::metric::AppCallMetrics>,
+    /// (app, version) 2 calls since the last report
+    app_calls: DashMap<(String, u64), AppCallStat>,
     view: MetricPublisherView,
 }
 
@@ -34,6 +51,8 @@ impl LogicalModule for MetricPublisher {
         Self {
             view: MetricPublisherView::new(args.logical_modules_ref.clone()),
             msg_sender: MsgSender::default(),
+            app_call_sender: MsgSender::default(),
+            app_calls: DashMap::new(),
         }
     }
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
@@ -46,6 +65,32 @@ impl LogicalModule for MetricPublisher {
     }
 }
 
+impl MetricPublisher {
+    pub fn record_app_call(&self, app: &str, version: u64, success: bool, latency: Duration) {
+        let mut stat = self.app_calls.entry((app.to_owned(), version)).or_default();
+        if success {
+            stat.success += 1;
+        } else {
+            stat.failure += 1;
+        }
+        stat.latency_ms_sum += latency.as_millis() as u64;
+    }
+
+    fn take_app_calls(&self) -> Vec<proto::metric::AppCallMetric> {
+        let keys: Vec<_> = self.app_calls.iter().map(|e| e.key().clone()).collect();
+        keys.into_iter()
+            .filter_map(|key| self.app_calls.remove(&key))
+            .map(|((app, version), stat)| proto::metric::AppCallMetric {
+                app,
+                version,
+                success: stat.success,
+                failure: stat.failure,
+                latency_ms_sum: stat.latency_ms_sum,
+            })
+            .collect()
+    }
+}
+
 async fn report_metric_task(view: MetricPublisherView) {
     // let mut m = machine_info::Machine::new();
     // let info = m.system_info();
@@ -72,6 +117,8 @@ async fn report_metric_task(view: MetricPublisherView) {
             mem_used: sys.used_memory() as f32,
             cpu_all: cpu_all as f32,
             mem_all: sys.total_memory() as f32,
+            running_tasks: view.executor().running_task_cnt() as u32,
+            warm_apps: view.instance_manager().warm_apps(),
         };
         // println!("send metrics to master");
         // let node_config = view.p2p().nodes_config;
@@ -81,16 +128,25 @@ async fn report_metric_task(view: MetricPublisherView) {
         //         .insert_node_rsc_metric(view.p2p().nodes_config.this.0, metric);
         // } else {
 
+        // no master known during an election
+        let Some(master) = view.p2p().nodes_config.master_node() else {
+            continue;
+        };
         let _res = view
             .metric_publisher()
             .msg_sender
-            .send(
-                view.p2p(),
-                view.p2p().nodes_config.get_master_node(),
-                metric,
-            )
+            .send(view.p2p(), master, metric)
             .await;
 
+        let calls = view.metric_publisher().take_app_calls();
+        if !calls.is_empty() {
+            let _res = view
+                .metric_publisher()
+                .app_call_sender
+                .send(view.p2p(), master, proto::metric::AppCallMetrics { calls })
+                .await;
+        }
+
         // .send_resp(1, 0, metric).await;
         // }
     }
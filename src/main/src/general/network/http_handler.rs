@@ -4,6 +4,7 @@ use crate::{
         self, AddServiceReq, AddServiceResp, ApiHandler, DeleteServiceReq, DeleteServiceResp,
         GetServiceListResp, RunServiceActionReq, RunServiceActionResp,
     },
+    general::data::m_dist_lock::{DistLock, HeldLock},
     logical_module_view_impl,
     master::m_http_handler::MasterHttpHandler,
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef},
@@ -12,10 +13,12 @@ use crate::{
 };
 use async_trait::async_trait;
 use axum::{
+    body::Body,
     extract::Path,
+    http::{Request, StatusCode},
     response::{IntoResponse, Response},
-    routing::post,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
 use std::{net::SocketAddr, sync::OnceLock};
 use std::{ops::Deref, sync::atomic::AtomicUsize};
@@ -40,7 +43,8 @@ impl LocalReqIdAllocator {
 pub trait HttpHandler: LogicalModule {
     fn building_router<'a>(&'a self) -> WithBind<'a, Router>;
     // fn alloc_local_req_id(&self) -> ReqId;
-    async fn handle_request(&self, req_fn: &str, http_text: String) -> Response;
+    /// `req` is the client's, relayed as it is in `master_proxy` mode
+    async fn handle_request(&self, req_fn: &str, req: Request<Body>) -> Response;
     // async fn select_node(
     //     &self,
     //     req: proto::sche::FnEventScheRequest,
@@ -50,6 +54,7 @@ pub trait HttpHandler: LogicalModule {
 logical_module_view_impl!(HttpHandlerView);
 logical_module_view_impl!(HttpHandlerView, p2p, P2PModule);
 logical_module_view_impl!(HttpHandlerView, http_handler, Box<dyn HttpHandler>);
+logical_module_view_impl!(HttpHandlerView, dist_lock, DistLock);
 // logical_module_view_impl!(HttpHandlerView, appmeta_manager, AppMetaManager);
 
 pub struct ApiHandlerImpl;
@@ -133,7 +138,8 @@ pub async fn start_http_handler(modsref: LogicalModulesRef) {
     // prometheus metrics
     // .route("metrics")
     //
-    let app = if view.p2p().nodes_config.this_node() == view.p2p().nodes_config.get_master_node() {
+    let nodes_config = &view.p2p().nodes_config;
+    let app = if nodes_config.master_node() == Some(nodes_config.this_node()) {
         apis::add_routers(app)
     } else {
         app
@@ -141,6 +147,8 @@ pub async fn start_http_handler(modsref: LogicalModulesRef) {
 
     let app = app
         // .route("/:app/:fn", post(handler2))
+        .route("/admin/drain", post(drain))
+        .route("/admin/locks", get(held_locks))
         .route("/:route", post(handler))
         .layer(CorsLayer::permissive());
 
@@ -159,10 +167,22 @@ pub async fn start_http_handler(modsref: LogicalModulesRef) {
 //         .await
 // }
 
-async fn handler(route: Path<String>, body: String) -> impl IntoResponse {
+/// Drain and shut down this node, running tasks finish first.
+async fn drain() -> impl IntoResponse {
+    tracing::info!("drain requested over http");
+    http_handler_view().copy_module_ref().request_drain();
+    StatusCode::ACCEPTED
+}
+
+/// Locks served by this node with their holders and remaining lease.
+async fn held_locks() -> Json<Vec<HeldLock>> {
+    Json(http_handler_view().dist_lock().held_locks().await)
+}
+
+async fn handler(route: Path<String>, req: Request<Body>) -> impl IntoResponse {
     http_handler_view()
         .http_handler()
-        .handle_request(route.as_str(), body)
+        .handle_request(route.as_str(), req)
         .await
 }
 
@@ -177,8 +197,9 @@ impl Deref for HttpHandlerDispatch {
 }
 
 impl HttpHandlerDispatch {
+    /// Master candidates get the master handler, it serves while the node is the elected master.
     pub fn new(arg: LogicalModuleNewArgs) -> Self {
-        if arg.nodes_config.this.1.is_master() {
+        if arg.nodes_config.this.1.can_be_master() {
             Self(Box::new(MasterHttpHandler::new(arg)))
         } else {
             Self(Box::new(WorkerHttpHandler::new(arg)))
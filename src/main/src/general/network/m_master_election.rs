@@ -0,0 +1,1060 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::sync::{Notify, OwnedMutexGuard};
+use ws_derive::LogicalModule;
+
+use super::{
+    m_p2p::{P2PModule, RPCCaller, RPCHandler, RPCResponsor},
+    proto::{self, cluster::MasterMetaEntry},
+};
+use crate::{
+    general::data::m_kv_store_engine::{
+        KeyType, KeyTypeAppMeta, KeyTypeDataExpiry, KeyTypeDataExpiryQueue, KeyTypeDataFence,
+        KeyTypeDataSetMeta, KeyTypeFencingToken, KeyTypeFnKvIndex, KeyTypeFnScheduleTick,
+        KvStoreEngine,
+    },
+    logical_module_view_impl,
+    master::app::m_app_master::MasterAppMgmt,
+    result::{WSResult, WSResultExt, WsDataError, WsNetworkLogicErr},
+    sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
+    util::JoinHandleWrapper,
+};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// A candidate starts an election after not hearing from master for this long,
+/// plus a random jitter so that candidates don't split the votes.
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const ELECTION_TIMEOUT_JITTER_MS: u64 = 3000;
+const REPLICATE_TIMEOUT: Duration = Duration::from_secs(3);
+/// A candidate missing an earlier write waits this long for it before it answers.
+const REPLICATE_GAP_WAIT: Duration = Duration::from_millis(1500);
+
+/// One master metadata write, a `None` value deletes the key.
+pub type MetaWrite = (Vec<u8>, Option<Vec<u8>>);
+
+/// Master metadata to write once replicated, see [`MasterElection::stage`].
+pub struct StagedMetaWrite {
+    term: u64,
+    seq: u64,
+    entries: Vec<MasterMetaEntry>,
+    _keys: MetaKeysGuard,
+}
+
+type MetaKeyLocks = Arc<Mutex<HashMap<Vec<u8>, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Held from reading master metadata for a write until the write is committed, so the writes
+/// of a key are replicated and applied one at a time, see [`MasterElection::lock_keys`].
+pub struct MetaKeysGuard {
+    locks: MetaKeyLocks,
+    held: Vec<(Vec<u8>, OwnedMutexGuard<()>)>,
+}
+
+impl Drop for MetaKeysGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock();
+        for (key, guard) in self.held.drain(..) {
+            drop(guard);
+            // nobody else holds or waits for it
+            if locks
+                .get(&key)
+                .map_or(false, |lock| Arc::strong_count(lock) == 1)
+            {
+                let _ = locks.remove(&key);
+            }
+        }
+    }
+}
+
+/// Seqs of the master metadata writes, applied in order.
+#[derive(Default)]
+struct MetaSeqs {
+    /// (term, seq) of the last write applied here, all the earlier ones are applied too
+    applied: (u64, u64),
+    /// master only, the last seq given to a staged write
+    staged: u64,
+    /// writes settled while an earlier one is missing, with their term: received by a candidate
+    ///  with the entries to apply, or committed or given up by master
+    ahead: BTreeMap<u64, (u64, Option<Vec<MasterMetaEntry>>)>,
+}
+
+impl MetaSeqs {
+    fn next_staged(&mut self) -> u64 {
+        self.staged = self.staged.max(self.applied.1) + 1;
+        self.staged
+    }
+
+    /// Whether `seq` of `term` is in place of a write applied here, which master never
+    /// committed then, only a snapshot repairs it.
+    fn diverged(&self, term: u64, seq: u64) -> bool {
+        seq <= self.applied.1 && term > self.applied.0
+    }
+
+    /// Records the write `seq` of `term`, returns the entries to apply now, in order.
+    fn settle(
+        &mut self,
+        term: u64,
+        seq: u64,
+        entries: Option<Vec<MasterMetaEntry>>,
+    ) -> Vec<MasterMetaEntry> {
+        if seq <= self.applied.1 {
+            return vec![];
+        }
+        // a newer master reused the seq
+        if self.ahead.get(&seq).map_or(true, |(prev, _)| *prev <= term) {
+            let _ = self.ahead.insert(seq, (term, entries));
+        }
+        self.drain()
+    }
+
+    /// A snapshot of master up to `(term, seq)` was applied.
+    fn synced(&mut self, term: u64, seq: u64) -> Vec<MasterMetaEntry> {
+        self.applied = self.applied.max((term, seq));
+        self.ahead = self.ahead.split_off(&(self.applied.1 + 1));
+        self.drain()
+    }
+
+    fn drain(&mut self) -> Vec<MasterMetaEntry> {
+        let mut ready = vec![];
+        while let Some(next) = self.ahead.first_entry() {
+            if *next.key() != self.applied.1 + 1 {
+                break;
+            }
+            let (term, entries) = next.remove();
+            // left by a deposed master, the write of the new one comes with a snapshot
+            if term < self.applied.0 {
+                break;
+            }
+            self.applied = (term, self.applied.1 + 1);
+            ready.extend(entries.into_iter().flatten());
+        }
+        ready
+    }
+}
+
+logical_module_view_impl!(View);
+logical_module_view_impl!(View, p2p, P2PModule);
+logical_module_view_impl!(View, kv_store_engine, KvStoreEngine);
+logical_module_view_impl!(View, app_master, Option<MasterAppMgmt>);
+logical_module_view_impl!(View, master_election, MasterElection);
+
+/// Kv key types written by master, replicated to all master candidates.
+fn master_meta_key_ids() -> [u8; 8] {
+    [
+        KeyTypeDataSetMeta(&[]).id(),
+        KeyTypeAppMeta(&[]).id(),
+        KeyTypeFnScheduleTick {
+            app: &[],
+            func: &[],
+        }
+        .id(),
+        KeyTypeFnKvIndex(&[]).id(),
+        KeyTypeDataFence(&[]).id(),
+        KeyTypeDataExpiry(&[]).id(),
+        KeyTypeDataExpiryQueue {
+            expire_at: 0,
+            uid: &[],
+        }
+        .id(),
+        KeyTypeFencingToken.id(),
+    ]
+}
+
+fn random_election_timeout() -> Duration {
+    ELECTION_TIMEOUT
+        + Duration::from_millis(rand::thread_rng().gen_range(0..ELECTION_TIMEOUT_JITTER_MS))
+}
+
+struct ElectionState {
+    /// at most one vote per term
+    voted_term: u64,
+    /// last heartbeat from a master
+    last_master_contact: Option<Instant>,
+    /// master meta seq reported by the last heartbeat when this node was behind it
+    lagging_seq: Option<u64>,
+    /// start an election when passed, pushed back by heartbeats and votes
+    election_deadline: Instant,
+    /// master only, last time a majority of candidates answered the heartbeat
+    last_majority_ack: Instant,
+}
+
+/// Master election among the `meta` nodes.
+///
+/// The configured master leads term 0. Master candidates (see `NodeConfig::can_be_master`)
+/// run the master modules as standby; when one of them misses the master heartbeat for
+/// the election timeout, it asks the others for votes with the raft `VoteRequest` and takes
+/// over once a majority of candidates agreed. A candidate only votes for peers whose master
+/// metadata is at least as new as its own, and the master replicates every metadata write to
+/// a majority of candidates before applying and answering it, so the new master has it intact.
+/// The writes are numbered by seq, candidates apply them in that order and count only the
+/// writes without gaps before them.
+/// Tolerating one failed master therefore takes at least three `meta` nodes.
+#[derive(LogicalModule)]
+pub struct MasterElection {
+    rpc_caller_vote: RPCCaller<proto::raft::VoteRequest>,
+    rpc_handler_vote: RPCHandler<proto::raft::VoteRequest>,
+    rpc_caller_heartbeat: RPCCaller<proto::raft::AppendEntriesRequest>,
+    rpc_handler_heartbeat: RPCHandler<proto::raft::AppendEntriesRequest>,
+    rpc_caller_replicate: RPCCaller<proto::cluster::ReplicateMasterMetaReq>,
+    rpc_handler_replicate: RPCHandler<proto::cluster::ReplicateMasterMetaReq>,
+    rpc_caller_sync: RPCCaller<proto::cluster::SyncMasterMetaReq>,
+    rpc_handler_sync: RPCHandler<proto::cluster::SyncMasterMetaReq>,
+    state: Mutex<ElectionState>,
+    seqs: Mutex<MetaSeqs>,
+    /// notified when `seqs.applied` moves
+    applied_changed: Notify,
+    /// master only, keys with a write being staged or replicated
+    key_locks: MetaKeyLocks,
+    /// serialize applying replicated writes and snapshots
+    apply_lock: tokio::sync::Mutex<()>,
+    /// keys replicated while a snapshot is being fetched, the older snapshot values are skipped
+    syncing: Mutex<Option<HashSet<Vec<u8>>>>,
+    view: View,
+}
+
+#[async_trait]
+impl LogicalModule for MasterElection {
+    fn inner_new(args: LogicalModuleNewArgs) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            rpc_caller_vote: RPCCaller::new(),
+            rpc_handler_vote: RPCHandler::new(),
+            rpc_caller_heartbeat: RPCCaller::new(),
+            rpc_handler_heartbeat: RPCHandler::new(),
+            rpc_caller_replicate: RPCCaller::new(),
+            rpc_handler_replicate: RPCHandler::new(),
+            rpc_caller_sync: RPCCaller::new(),
+            rpc_handler_sync: RPCHandler::new(),
+            state: Mutex::new(ElectionState {
+                voted_term: 0,
+                last_master_contact: None,
+                lagging_seq: None,
+                election_deadline: Instant::now() + random_election_timeout(),
+                last_majority_ack: Instant::now(),
+            }),
+            seqs: Mutex::new(MetaSeqs::default()),
+            applied_changed: Notify::new(),
+            key_locks: Arc::new(Mutex::new(HashMap::new())),
+            apply_lock: tokio::sync::Mutex::new(()),
+            syncing: Mutex::new(None),
+            view: View::new(args.logical_modules_ref.clone()),
+        }
+    }
+    async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
+        self.rpc_caller_vote.regist(self.view.p2p());
+        self.rpc_caller_heartbeat.regist(self.view.p2p());
+        self.rpc_caller_replicate.regist(self.view.p2p());
+        self.rpc_caller_sync.regist(self.view.p2p());
+
+        let view = self.view.clone();
+        self.rpc_handler_vote
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let resp = view.master_election().handle_vote(&req);
+                    let _ = responsor
+                        .send_resp(resp)
+                        .await
+                        .todo_handle("send vote response failed");
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        self.rpc_handler_heartbeat
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let resp = view
+                        .master_election()
+                        .handle_heartbeat(responsor.node_id(), &req);
+                    let _ = responsor
+                        .send_resp(resp)
+                        .await
+                        .todo_handle("send heartbeat response failed");
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        self.rpc_handler_replicate
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let _ = view
+                        .master_election()
+                        .handle_replicate(responsor, req)
+                        .await
+                        .todo_handle("handle replicate master meta failed");
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        self.rpc_handler_sync
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let _ = view
+                        .master_election()
+                        .handle_sync(responsor, req)
+                        .await
+                        .todo_handle("handle sync master meta failed");
+                });
+                Ok(())
+            });
+
+        let view = self.view.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                view.master_election().tick().await;
+            }
+        });
+        Ok(vec![task.into()])
+    }
+}
+
+impl MasterElection {
+    fn majority(candidates: &[NodeID]) -> usize {
+        candidates.len() / 2 + 1
+    }
+
+    fn entries_proto(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<MasterMetaEntry> {
+        entries
+            .into_iter()
+            .map(|(key, value)| MasterMetaEntry {
+                key,
+                value,
+                deleted: false,
+            })
+            .collect()
+    }
+
+    async fn tick(&self) {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if nodes_config.this_is_master() {
+            self.send_heartbeats().await;
+            return;
+        }
+        if !nodes_config.this.1.can_be_master() {
+            return;
+        }
+        let timeout = Instant::now() > self.state.lock().election_deadline;
+        if timeout {
+            self.run_election().await;
+        }
+    }
+
+    async fn send_heartbeats(&self) {
+        let p2p = self.view.p2p();
+        let term = p2p.nodes_config.master_term();
+        let (applied_term, applied_seq) = self.seqs.lock().applied;
+        let candidates = p2p.nodes_config.get_master_candidates();
+        let peers: Vec<NodeID> = p2p.nodes_config.peers().into_keys().collect();
+
+        let calls = peers.iter().map(|peer| {
+            self.rpc_caller_heartbeat.call(
+                p2p,
+                *peer,
+                proto::raft::AppendEntriesRequest {
+                    term,
+                    leader_id: p2p.nodes_config.this_node() as u64,
+                    prev_log_index: applied_seq,
+                    prev_log_term: applied_term,
+                    entries: vec![],
+                    leader_commit: applied_seq,
+                },
+                Some(HEARTBEAT_INTERVAL),
+            )
+        });
+        let results = futures::future::join_all(calls).await;
+
+        let mut acks = 1;
+        for (peer, res) in peers.iter().zip(results) {
+            match res {
+                Ok(resp) if resp.term > term => {
+                    tracing::info!(
+                        "node {} is in newer term {}, step down from master of term {}",
+                        peer,
+                        resp.term,
+                        term
+                    );
+                    let _ = p2p.nodes_config.observe_term(resp.term);
+                    return;
+                }
+                Ok(resp) if resp.success && candidates.contains(peer) => acks += 1,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::debug!("heartbeat to {} failed: {:?}", peer, err);
+                }
+            }
+        }
+
+        let mut state = self.state.lock();
+        if acks >= Self::majority(&candidates) {
+            state.last_majority_ack = Instant::now();
+        } else if state.last_majority_ack.elapsed() > ELECTION_TIMEOUT {
+            // isolated from most candidates, they are electing a new master
+            tracing::warn!(
+                "only {} of {} master candidates reachable, step down from master of term {}",
+                acks,
+                candidates.len(),
+                term
+            );
+            p2p.nodes_config.step_down();
+            state.election_deadline = Instant::now() + random_election_timeout();
+        }
+    }
+
+    async fn run_election(&self) {
+        let p2p = self.view.p2p();
+        let this = p2p.nodes_config.this_node();
+        // the local term is only moved once the votes are in, so a failed
+        // election of a partitioned candidate doesn't depose a live master
+        let term = {
+            let mut state = self.state.lock();
+            let term = p2p.nodes_config.master_term().max(state.voted_term) + 1;
+            state.voted_term = term;
+            state.election_deadline = Instant::now() + random_election_timeout();
+            term
+        };
+        let (applied_term, applied_seq) = self.seqs.lock().applied;
+        let candidates = p2p.nodes_config.get_master_candidates();
+        tracing::info!(
+            "no heartbeat from master {:?}, start election for term {} among {:?}",
+            p2p.nodes_config.master_node(),
+            term,
+            candidates
+        );
+
+        let others: Vec<NodeID> = candidates.iter().copied().filter(|n| *n != this).collect();
+        let calls = others.iter().map(|peer| {
+            self.rpc_caller_vote.call(
+                p2p,
+                *peer,
+                proto::raft::VoteRequest {
+                    term,
+                    candidate_id: this as u64,
+                    last_log_index: applied_seq,
+                    last_log_term: applied_term,
+                },
+                Some(HEARTBEAT_INTERVAL * 2),
+            )
+        });
+        let results = futures::future::join_all(calls).await;
+
+        let mut votes = 1;
+        for (peer, res) in others.iter().zip(results) {
+            match res {
+                Ok(resp) if resp.vote_granted => votes += 1,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::debug!("request vote from {} failed: {:?}", peer, err);
+                }
+            }
+        }
+
+        let need = Self::majority(&candidates);
+        if votes < need {
+            tracing::info!("election for term {} got {}/{} votes", term, votes, need);
+            return;
+        }
+        if !p2p.nodes_config.set_master(term, this) {
+            // another node won the term meanwhile
+            return;
+        }
+        tracing::info!("elected as master of term {} with {} votes", term, votes);
+        self.state.lock().last_majority_ack = Instant::now();
+        // the candidates learn the new master before it writes any metadata
+        self.send_heartbeats().await;
+        match self.view.copy_module_ref().start_master_modules().await {
+            // started now, the app master loaded the apps on start
+            Ok(true) => {}
+            // master before, reload the apps uploaded under the other master
+            Ok(false) => {
+                let _ = self
+                    .view
+                    .app_master()
+                    .load_persisted_apps()
+                    .await
+                    .todo_handle("reload apps after master takeover failed");
+            }
+            Err(err) => {
+                tracing::error!("start master modules after takeover failed: {:?}", err);
+            }
+        }
+    }
+
+    fn handle_vote(&self, req: &proto::raft::VoteRequest) -> proto::raft::VoteResponse {
+        let nodes_config = &self.view.p2p().nodes_config;
+        let candidate = req.candidate_id as NodeID;
+        let cur_term = nodes_config.master_term();
+        let deny = |term| proto::raft::VoteResponse {
+            term,
+            vote_granted: false,
+        };
+        if req.term < cur_term {
+            return deny(cur_term);
+        }
+        if !nodes_config
+            .get_nodeconfig(candidate)
+            .map_or(false, |conf| conf.can_be_master())
+        {
+            return deny(cur_term);
+        }
+        let mut state = self.state.lock();
+        // a live master keeps its lease, a partitioned candidate can't depose it
+        let master_alive = nodes_config.this_is_master()
+            || state
+                .last_master_contact
+                .map_or(false, |t| t.elapsed() < ELECTION_TIMEOUT);
+        if master_alive || state.voted_term >= req.term {
+            return deny(cur_term);
+        }
+        // only vote for a candidate holding all the master metadata we have
+        if (req.last_log_term, req.last_log_index) < self.seqs.lock().applied {
+            return deny(cur_term);
+        }
+        tracing::info!("vote for node {} in term {}", candidate, req.term);
+        let _ = nodes_config.observe_term(req.term);
+        state.voted_term = req.term;
+        state.election_deadline = Instant::now() + random_election_timeout();
+        proto::raft::VoteResponse {
+            term: req.term,
+            vote_granted: true,
+        }
+    }
+
+    fn handle_heartbeat(
+        &self,
+        from: NodeID,
+        req: &proto::raft::AppendEntriesRequest,
+    ) -> proto::raft::AppendEntriesResponse {
+        let nodes_config = &self.view.p2p().nodes_config;
+        let cur_term = nodes_config.master_term();
+        let reject = |term| proto::raft::AppendEntriesResponse {
+            term,
+            success: false,
+            conflict_index: 0,
+            conflict_term: 0,
+        };
+        // only a candidate can have been elected
+        let from_candidate = nodes_config
+            .get_nodeconfig(from)
+            .map_or(false, |conf| conf.can_be_master());
+        let old_master = nodes_config.master_node();
+        if !from_candidate || req.term < cur_term || !nodes_config.set_master(req.term, from) {
+            return reject(cur_term);
+        }
+        if old_master != Some(from) {
+            tracing::info!(
+                "master changed from {:?} to {} in term {}",
+                old_master,
+                from,
+                req.term
+            );
+        }
+        let need_sync = {
+            let mut state = self.state.lock();
+            state.last_master_contact = Some(Instant::now());
+            state.election_deadline = Instant::now() + random_election_timeout();
+            // writes in flight show up as a gap for one heartbeat,
+            //  only a gap still there at the next heartbeat needs a sync
+            let applied = self.seqs.lock().applied;
+            let still_behind = state.lagging_seq.map_or(false, |seq| applied.1 < seq);
+            state.lagging_seq = if (req.prev_log_term, req.prev_log_index) > applied {
+                Some(req.prev_log_index)
+            } else {
+                None
+            };
+            still_behind
+        };
+        if need_sync && nodes_config.this.1.can_be_master() {
+            let view = self.view.clone();
+            let _ = tokio::spawn(async move {
+                view.master_election().sync_from_master(from).await;
+            });
+        }
+        proto::raft::AppendEntriesResponse {
+            term: req.term,
+            success: true,
+            conflict_index: 0,
+            conflict_term: 0,
+        }
+    }
+
+    /// Locks `keys` for a write of master metadata, held by the staged write until it is
+    /// committed. Taken before reading the metadata the write is based on, so the read sees
+    /// the earlier writes of the keys applied.
+    pub async fn lock_keys(&self, keys: impl IntoIterator<Item = Vec<u8>>) -> MetaKeysGuard {
+        let mut keys: Vec<Vec<u8>> = keys.into_iter().collect();
+        // in order, so two writes locking the same keys don't wait for each other
+        keys.sort();
+        keys.dedup();
+        let mut held = Vec::with_capacity(keys.len());
+        for key in keys {
+            let lock = self
+                .key_locks
+                .lock()
+                .entry(key.clone())
+                .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+                .clone();
+            held.push((key, lock.lock_owned().await));
+        }
+        MetaKeysGuard {
+            locks: self.key_locks.clone(),
+            held,
+        }
+    }
+
+    /// Number a write of master metadata, [`MasterElection::commit`] then replicates and
+    /// applies it.
+    ///
+    /// `keys` are the keys locked by [`MasterElection::lock_keys`] the writes of which are ordered
+    /// by this one, the other written keys only change along with them. Writes of one key are
+    /// thus numbered in the order they are applied, the candidates apply them in the same order.
+    pub fn stage(&self, writes: Vec<MetaWrite>, keys: MetaKeysGuard) -> WSResult<StagedMetaWrite> {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            return Err(WsNetworkLogicErr::NotMaster {
+                this: nodes_config.this_node(),
+                master: nodes_config.master_node(),
+            }
+            .into());
+        }
+        let term = nodes_config.master_term();
+        let seq = self.seqs.lock().next_staged();
+        let entries = writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => MasterMetaEntry {
+                    key,
+                    value,
+                    deleted: false,
+                },
+                None => MasterMetaEntry {
+                    key,
+                    value: vec![],
+                    deleted: true,
+                },
+            })
+            .collect();
+        Ok(StagedMetaWrite {
+            term,
+            seq,
+            entries,
+            _keys: keys,
+        })
+    }
+
+    /// Replicate a staged write to the other candidates, and apply it here once a majority of
+    /// candidates, this node included, stored it. Fails otherwise, leaving the metadata here
+    /// untouched.
+    pub async fn commit(&self, staged: StagedMetaWrite) -> WSResult<()> {
+        let p2p = self.view.p2p();
+        let this = p2p.nodes_config.this_node();
+        let candidates = p2p.nodes_config.get_master_candidates();
+        let others: Vec<NodeID> = candidates.iter().copied().filter(|n| *n != this).collect();
+        let req = proto::cluster::ReplicateMasterMetaReq {
+            term: staged.term,
+            seq: staged.seq,
+            entries: staged.entries.clone(),
+        };
+        let calls = others.iter().map(|peer| {
+            self.rpc_caller_replicate
+                .call(p2p, *peer, req.clone(), Some(REPLICATE_TIMEOUT))
+        });
+        let results = futures::future::join_all(calls).await;
+
+        let mut acks = 1;
+        for (peer, res) in others.iter().zip(results) {
+            match res {
+                Ok(resp) if resp.success => acks += 1,
+                Ok(resp) => {
+                    tracing::warn!(
+                        "node {} rejected master meta seq {} at term {}",
+                        peer,
+                        staged.seq,
+                        resp.term
+                    );
+                }
+                Err(err) => {
+                    tracing::debug!("replicate master meta to {} failed: {:?}", peer, err);
+                }
+            }
+        }
+        let need = Self::majority(&candidates);
+        let res = if acks < need {
+            tracing::warn!("master meta seq {} not replicated, dropped", staged.seq);
+            Err(WsDataError::MasterMetaReplicateFailed { acks, need }.into())
+        } else {
+            // the keys are still locked, no later write of them is applied before
+            self.apply_entries(staged.entries, &HashSet::new())
+        };
+        // given up writes are settled too, so the later ones count as applied
+        let _ = self.seqs.lock().settle(staged.term, staged.seq, None);
+        self.applied_changed.notify_waiters();
+        res
+    }
+
+    /// Replicate master metadata and apply it, see [`MasterElection::commit`].
+    pub async fn replicate(&self, writes: Vec<MetaWrite>) -> WSResult<()> {
+        let keys = self
+            .lock_keys(writes.iter().map(|(key, _)| key.clone()))
+            .await;
+        let staged = self.stage(writes, keys)?;
+        self.commit(staged).await
+    }
+
+    fn apply_entries(
+        &self,
+        entries: Vec<MasterMetaEntry>,
+        skip: &HashSet<Vec<u8>>,
+    ) -> WSResult<()> {
+        let kv_store_engine = self.view.kv_store_engine();
+        for entry in entries {
+            if skip.contains(&entry.key) {
+                continue;
+            }
+            if entry.deleted {
+                let _ = kv_store_engine.del_raw(&entry.key, false)?;
+            } else {
+                let _ = kv_store_engine.set_raw(&entry.key, entry.value, false)?;
+            }
+        }
+        kv_store_engine.flush();
+        Ok(())
+    }
+
+    async fn handle_replicate(
+        &self,
+        responsor: RPCResponsor<proto::cluster::ReplicateMasterMetaReq>,
+        req: proto::cluster::ReplicateMasterMetaReq,
+    ) -> WSResult<()> {
+        let nodes_config = &self.view.p2p().nodes_config;
+        let cur_term = nodes_config.master_term();
+        // only the confirmed master of the term writes, a newer master's heartbeat comes first
+        if nodes_config.confirmed_master() != Some((req.term, responsor.node_id())) {
+            tracing::warn!(
+                "reject master meta seq {} of term {} from node {}, master is {:?}",
+                req.seq,
+                req.term,
+                responsor.node_id(),
+                nodes_config.confirmed_master()
+            );
+            return responsor
+                .send_resp(proto::cluster::ReplicateMasterMetaResp {
+                    success: false,
+                    term: cur_term,
+                })
+                .await;
+        }
+        let (term, seq) = (req.term, req.seq);
+        let diverged = {
+            let _hold = self.apply_lock.lock().await;
+            let ready = {
+                let mut seqs = self.seqs.lock();
+                if seqs.diverged(term, seq) {
+                    None
+                } else {
+                    Some(seqs.settle(term, seq, Some(req.entries)))
+                }
+            };
+            match ready {
+                Some(ready) => {
+                    if let Some(replicated) = self.syncing.lock().as_mut() {
+                        replicated.extend(ready.iter().map(|e| e.key.clone()));
+                    }
+                    let applied_any = !ready.is_empty();
+                    self.apply_entries(ready, &HashSet::new())?;
+                    if applied_any {
+                        self.applied_changed.notify_waiters();
+                    }
+                    false
+                }
+                None => true,
+            }
+        };
+
+        // only stored once the writes before it are, a write missing for long takes a snapshot
+        let deadline = tokio::time::Instant::now() + REPLICATE_GAP_WAIT;
+        let success = !diverged
+            && loop {
+                let applied_changed = self.applied_changed.notified();
+                tokio::pin!(applied_changed);
+                let _ = applied_changed.as_mut().enable();
+                if self.seqs.lock().applied.1 >= seq {
+                    break true;
+                }
+                if tokio::time::timeout_at(deadline, applied_changed)
+                    .await
+                    .is_err()
+                {
+                    break false;
+                }
+            };
+        if !success {
+            tracing::warn!(
+                "master meta seq {} of term {} not applicable here, sync at the next heartbeat",
+                seq,
+                term
+            );
+            self.state.lock().lagging_seq = Some(seq);
+        }
+        responsor
+            .send_resp(proto::cluster::ReplicateMasterMetaResp { success, term })
+            .await
+    }
+
+    /// Fetch all master metadata, after a restart or missing some replicated writes.
+    async fn sync_from_master(&self, master: NodeID) {
+        {
+            let mut syncing = self.syncing.lock();
+            if syncing.is_some() {
+                return;
+            }
+            *syncing = Some(HashSet::new());
+        }
+        let p2p = self.view.p2p();
+        let res = self
+            .rpc_caller_sync
+            .call(
+                p2p,
+                master,
+                proto::cluster::SyncMasterMetaReq {
+                    term: p2p.nodes_config.master_term(),
+                },
+                Some(REPLICATE_TIMEOUT),
+            )
+            .await;
+        let _hold = self.apply_lock.lock().await;
+        let replicated = self.syncing.lock().take().unwrap_or_default();
+        match res {
+            Ok(resp) if resp.success => {
+                let cnt = resp.entries.len();
+                // keys missing from the snapshot were deleted on master
+                let kv_store_engine = self.view.kv_store_engine();
+                let in_snapshot: HashSet<&[u8]> = resp.entries.iter().map(|e| &e.key[..]).collect();
+                let deleted: Vec<MasterMetaEntry> = master_meta_key_ids()
+                    .iter()
+                    .flat_map(|id| kv_store_engine.scan_raw_prefix(&[*id]))
+                    .filter(|(key, _)| !in_snapshot.contains(&key[..]))
+                    .map(|(key, _)| MasterMetaEntry {
+                        key,
+                        value: vec![],
+                        deleted: true,
+                    })
+                    .collect();
+                drop(in_snapshot);
+                if let Err(err) = self
+                    .apply_entries(resp.entries, &replicated)
+                    .and_then(|_| self.apply_entries(deleted, &replicated))
+                {
+                    tracing::warn!("apply master meta snapshot failed: {:?}", err);
+                    return;
+                }
+                // the writes received meanwhile after the snapshot
+                let ready = self.seqs.lock().synced(resp.term, resp.seq);
+                if let Err(err) = self.apply_entries(ready, &HashSet::new()) {
+                    tracing::warn!("apply master meta after snapshot failed: {:?}", err);
+                }
+                self.applied_changed.notify_waiters();
+                tracing::info!(
+                    "synced {} master meta entries from master {} up to seq {}",
+                    cnt,
+                    master,
+                    resp.seq
+                );
+            }
+            Ok(_) => {
+                tracing::debug!("node {} refused master meta sync", master);
+            }
+            Err(err) => {
+                tracing::debug!("sync master meta from {} failed: {:?}", master, err);
+            }
+        }
+    }
+
+    async fn handle_sync(
+        &self,
+        responsor: RPCResponsor<proto::cluster::SyncMasterMetaReq>,
+        _req: proto::cluster::SyncMasterMetaReq,
+    ) -> WSResult<()> {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            return responsor
+                .send_resp(proto::cluster::SyncMasterMetaResp {
+                    success: false,
+                    term: nodes_config.master_term(),
+                    seq: 0,
+                    entries: vec![],
+                })
+                .await;
+        }
+        // read the seq first, the scan then covers at least all writes up to it
+        let (applied_term, seq) = self.seqs.lock().applied;
+        let kv_store_engine = self.view.kv_store_engine();
+        let entries = master_meta_key_ids()
+            .iter()
+            .flat_map(|id| kv_store_engine.scan_raw_prefix(&[*id]))
+            .collect();
+        responsor
+            .send_resp(proto::cluster::SyncMasterMetaResp {
+                success: true,
+                term: applied_term,
+                seq,
+                entries: Self::entries_proto(entries),
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{MetaSeqs, View, REPLICATE_TIMEOUT};
+    use crate::general::{
+        data::m_kv_store_engine::{KeyType, KeyTypeFnKvIndex},
+        network::proto,
+        test_utils,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_master_meta_replicate() {
+        let (_hold, sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view1 = View::new(sys1);
+        let view2 = View::new(sys2);
+        let election = view1.master_election();
+        let kv_store_engine = view1.kv_store_engine();
+        let key = KeyTypeFnKvIndex(b"test_replicate_key").make_key();
+        let get = |key: &[u8]| kv_store_engine.get_raw(key, false).map(|(_, v)| v);
+
+        election
+            .replicate(vec![(key.clone(), Some(b"v1".to_vec()))])
+            .await
+            .unwrap();
+        assert_eq!(get(&key), Some(b"v1".to_vec()));
+
+        // applied once replicated, the next write of the key waits for it
+        let keys = election.lock_keys([key.clone()]).await;
+        let staged = election
+            .stage(vec![(key.clone(), Some(b"v2".to_vec()))], keys)
+            .unwrap();
+        assert_eq!(get(&key), Some(b"v1".to_vec()));
+        assert!(tokio::time::timeout(
+            Duration::from_millis(200),
+            election.lock_keys([key.clone()])
+        )
+        .await
+        .is_err());
+        election.commit(staged).await.unwrap();
+        assert_eq!(get(&key), Some(b"v2".to_vec()));
+
+        election.replicate(vec![(key.clone(), None)]).await.unwrap();
+        assert_eq!(get(&key), None);
+        assert!(election.key_locks.lock().is_empty());
+
+        // only the elected master writes master metadata
+        assert!(view2
+            .master_election()
+            .replicate(vec![(key.clone(), Some(b"v4".to_vec()))])
+            .await
+            .is_err());
+        let resp = view2
+            .master_election()
+            .rpc_caller_replicate
+            .call(
+                view2.p2p(),
+                0,
+                proto::cluster::ReplicateMasterMetaReq {
+                    term: view1.p2p().nodes_config.master_term(),
+                    seq: u64::MAX,
+                    entries: vec![proto::cluster::MasterMetaEntry {
+                        key: key.clone(),
+                        value: b"v4".to_vec(),
+                        deleted: false,
+                    }],
+                },
+                Some(REPLICATE_TIMEOUT),
+            )
+            .await
+            .unwrap();
+        assert!(!resp.success);
+        assert_eq!(get(&key), None);
+    }
+
+    #[test]
+    fn test_meta_seqs_in_order() {
+        let entry = |key: &[u8]| proto::cluster::MasterMetaEntry {
+            key: key.to_vec(),
+            value: vec![],
+            deleted: true,
+        };
+        let keys = |entries: Vec<proto::cluster::MasterMetaEntry>| {
+            entries.into_iter().map(|e| e.key).collect::<Vec<_>>()
+        };
+        let mut seqs = MetaSeqs::default();
+
+        // a gap holds the later writes back
+        assert!(seqs.settle(1, 2, Some(vec![entry(b"b")])).is_empty());
+        assert!(seqs.settle(1, 3, Some(vec![entry(b"c")])).is_empty());
+        assert_eq!(seqs.applied, (0, 0));
+        assert_eq!(
+            keys(seqs.settle(1, 1, Some(vec![entry(b"a")]))),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(seqs.applied, (1, 3));
+        assert!(seqs.settle(1, 3, Some(vec![entry(b"c")])).is_empty());
+
+        // given up by master, still counted
+        assert!(seqs.settle(1, 5, Some(vec![entry(b"e")])).is_empty());
+        assert_eq!(keys(seqs.settle(1, 4, None)), vec![b"e".to_vec()]);
+        assert_eq!(seqs.applied, (1, 5));
+
+        // a new master reused seq 5, only a snapshot repairs it
+        assert!(seqs.diverged(2, 5));
+        assert!(!seqs.diverged(1, 5));
+        assert!(seqs.settle(2, 7, Some(vec![entry(b"g")])).is_empty());
+        assert_eq!(keys(seqs.synced(2, 6)), vec![b"g".to_vec()]);
+        assert_eq!(seqs.applied, (2, 7));
+
+        // a deposed master's write doesn't fill a gap
+        assert!(seqs.settle(1, 8, Some(vec![entry(b"h")])).is_empty());
+        assert_eq!(seqs.applied, (2, 7));
+        assert_eq!(seqs.next_staged(), 8);
+        assert_eq!(seqs.next_staged(), 9);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_master_vote() {
+        let (_hold, sys1, _sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let view1 = View::new(sys1);
+        let election = view1.master_election();
+        let term = view1.p2p().nodes_config.master_term();
+        let vote = |term, candidate_id| proto::raft::VoteRequest {
+            term,
+            candidate_id,
+            last_log_index: u64::MAX,
+            last_log_term: u64::MAX,
+        };
+
+        // a stale term, a node that can't be master, or while the master is alive
+        assert!(
+            !election
+                .handle_vote(&vote(term.saturating_sub(1), 0))
+                .vote_granted
+        );
+        assert!(!election.handle_vote(&vote(term + 1, 1)).vote_granted);
+        assert!(!election.handle_vote(&vote(term + 1, 0)).vote_granted);
+        assert_eq!(view1.p2p().nodes_config.master_term(), term);
+    }
+}
@@ -0,0 +1,488 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use ws_derive::LogicalModule;
+
+use super::{
+    m_p2p::{MsgHandler, MsgSender, P2PModule, RPCCaller, RPCHandler, RPCResponsor},
+    proto::{self, cluster::NodeMember},
+    proto_ext::ProtoExtNodeMember,
+};
+use crate::{
+    config::{NodeConfig, NodesConfig},
+    logical_module_view_impl,
+    result::{WSResult, WSResultExt},
+    sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
+    util::JoinHandleWrapper,
+};
+
+/// Interval of the full member list broadcast from master,
+/// so that members missing an update converge eventually.
+const MEMBERS_RESYNC_INTERVAL: Duration = Duration::from_secs(10);
+const JOIN_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+logical_module_view_impl!(View);
+logical_module_view_impl!(View, p2p, P2PModule);
+logical_module_view_impl!(View, membership, Membership);
+
+/// Runtime cluster membership.
+///
+/// The master keeps the authoritative member list in its `NodesConfig`,
+/// workers register themselves with a join request after connecting to the master
+/// and unregister with a leave request. Every change bumps the member version and
+/// the full list is pushed to all members, which install it into their `NodesConfig`.
+#[derive(LogicalModule)]
+pub struct Membership {
+    rpc_caller_join: RPCCaller<proto::cluster::JoinClusterReq>,
+    rpc_handler_join: RPCHandler<proto::cluster::JoinClusterReq>,
+    rpc_caller_leave: RPCCaller<proto::cluster::LeaveClusterReq>,
+    rpc_handler_leave: RPCHandler<proto::cluster::LeaveClusterReq>,
+    msg_sender_update: MsgSender<proto::cluster::MembershipUpdate>,
+    msg_handler_update: MsgHandler<proto::cluster::MembershipUpdate>,
+    /// serialize member changes on master
+    change_lock: tokio::sync::Mutex<()>,
+    view: View,
+}
+
+#[async_trait]
+impl LogicalModule for Membership {
+    fn inner_new(args: LogicalModuleNewArgs) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            rpc_caller_join: RPCCaller::new(),
+            rpc_handler_join: RPCHandler::new(),
+            rpc_caller_leave: RPCCaller::new(),
+            rpc_handler_leave: RPCHandler::new(),
+            msg_sender_update: MsgSender::new(),
+            msg_handler_update: MsgHandler::new(),
+            change_lock: tokio::sync::Mutex::new(()),
+            view: View::new(args.logical_modules_ref.clone()),
+        }
+    }
+    async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
+        self.rpc_caller_join.regist(self.view.p2p());
+        self.rpc_caller_leave.regist(self.view.p2p());
+
+        let view = self.view.clone();
+        self.rpc_handler_join.regist(
+            self.view.p2p(),
+            move |responsor: RPCResponsor<proto::cluster::JoinClusterReq>,
+                  req: proto::cluster::JoinClusterReq| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let _ = view.membership()
+                        .handle_join(responsor, req)
+                        .await
+                        .todo_handle("handle join cluster request failed");
+                });
+                Ok(())
+            },
+        );
+        let view = self.view.clone();
+        self.rpc_handler_leave.regist(
+            self.view.p2p(),
+            move |responsor: RPCResponsor<proto::cluster::LeaveClusterReq>,
+                  req: proto::cluster::LeaveClusterReq| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let _ = view.membership()
+                        .handle_leave(responsor, req)
+                        .await
+                        .todo_handle("handle leave cluster request failed");
+                });
+                Ok(())
+            },
+        );
+        let view = self.view.clone();
+        self.msg_handler_update
+            .regist(self.view.p2p(), move |responser, update| {
+                if view.p2p().nodes_config.master_node() != Some(responser.node_id) {
+                    tracing::warn!(
+                        "ignore membership update from non-master node {}",
+                        responser.node_id
+                    );
+                    return Ok(());
+                }
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    view.membership()
+                        .install_members(update.term, update.version, &update.members)
+                        .await;
+                });
+                Ok(())
+            });
+
+        let p2p = self.view.p2p();
+        let mut tasks = vec![];
+        let view = self.view.clone();
+        // the master role may move to another meta node, so every node runs the resync loop
+        tasks.push(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(MEMBERS_RESYNC_INTERVAL).await;
+                if view.p2p().nodes_config.this_is_master() {
+                    view.membership().broadcast_members(None).await;
+                }
+            }
+        }));
+        if p2p.nodes_config.this_is_master() {
+            // start versions from the clock, so a restarted master publishes
+            // versions newer than the ones members already installed
+            let start_version = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64;
+            p2p.apply_members(
+                p2p.nodes_config.master_term(),
+                start_version,
+                p2p.nodes_config.all_nodes(),
+            )
+            .await;
+        } else {
+            let view = self.view.clone();
+            tasks.push(tokio::spawn(async move {
+                // the node leaves when drained, see `Executor::shutdown`
+                view.membership().join().await;
+            }));
+        }
+        Ok(tasks.into_iter().map(|t| t.into()).collect())
+    }
+}
+
+impl Membership {
+    fn members_proto(&self) -> Vec<NodeMember> {
+        self.view
+            .p2p()
+            .nodes_config
+            .all_nodes()
+            .iter()
+            .map(|(id, conf)| NodeMember::new_member(*id, conf))
+            .collect()
+    }
+
+    async fn install_members(&self, term: u64, version: u64, members: &[NodeMember]) {
+        let mut parsed = Vec::with_capacity(members.len());
+        for m in members {
+            match m.to_node_config() {
+                Ok(member) => parsed.push(member),
+                Err(err) => {
+                    tracing::warn!("skip invalid member {:?}: {:?}", m, err);
+                }
+            }
+        }
+        self.view.p2p().apply_members(term, version, parsed).await;
+    }
+
+    /// Retry until master accepts this node.
+    async fn join(&self) {
+        let p2p = self.view.p2p();
+        let (this_id, this_conf) = p2p.nodes_config.this.clone();
+        loop {
+            let res = match p2p.nodes_config.get_master_node() {
+                Ok(master) => {
+                    self.rpc_caller_join
+                        .call(
+                            p2p,
+                            master,
+                            proto::cluster::JoinClusterReq {
+                                member: Some(NodeMember::new_member(this_id, &this_conf)),
+                            },
+                            Some(JOIN_RETRY_INTERVAL),
+                        )
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(resp) if resp.success => {
+                    self.install_members(resp.term, resp.version, &resp.members)
+                        .await;
+                    tracing::info!("joined cluster with member version {}", resp.version);
+                    return;
+                }
+                Ok(resp) => {
+                    tracing::warn!("join cluster rejected: {}", resp.err_msg);
+                }
+                Err(err) => {
+                    tracing::debug!("join cluster failed, will retry: {:?}", err);
+                }
+            }
+            tokio::time::sleep(JOIN_RETRY_INTERVAL).await;
+        }
+    }
+
+    pub async fn leave(&self) -> WSResult<()> {
+        let p2p = self.view.p2p();
+        let resp = self
+            .rpc_caller_leave
+            .call(
+                p2p,
+                p2p.nodes_config.get_master_node()?,
+                proto::cluster::LeaveClusterReq {
+                    node_id: p2p.nodes_config.this_node(),
+                },
+                None,
+            )
+            .await?;
+        if !resp.success {
+            tracing::warn!("leave cluster rejected: {}", resp.err_msg);
+        }
+        Ok(())
+    }
+
+    /// Push the full member list to all peers except `skip`.
+    async fn broadcast_members(&self, skip: Option<NodeID>) {
+        let p2p = self.view.p2p();
+        let update = proto::cluster::MembershipUpdate {
+            version: p2p.nodes_config.members_version(),
+            members: self.members_proto(),
+            term: p2p.nodes_config.master_term(),
+        };
+        for (peer, _) in p2p.nodes_config.peers() {
+            if Some(peer) == skip {
+                continue;
+            }
+            if let Err(err) = self
+                .msg_sender_update
+                .send(p2p, peer, update.clone())
+                .await
+            {
+                tracing::debug!("send membership update to {} failed: {:?}", peer, err);
+            }
+        }
+    }
+
+    /// Apply a change on master, then tell everyone else.
+    async fn change_members(
+        &self,
+        change: impl FnOnce(&mut Vec<(NodeID, NodeConfig)>),
+        skip: Option<NodeID>,
+    ) -> u64 {
+        let p2p = self.view.p2p();
+        let mut members = p2p.nodes_config.all_nodes();
+        change(&mut members);
+        let version = p2p.nodes_config.members_version() + 1;
+        p2p.apply_members(p2p.nodes_config.master_term(), version, members)
+            .await;
+        self.broadcast_members(skip).await;
+        version
+    }
+
+    async fn handle_join(
+        &self,
+        responsor: RPCResponsor<proto::cluster::JoinClusterReq>,
+        req: proto::cluster::JoinClusterReq,
+    ) -> WSResult<()> {
+        let fail = |err_msg: String| proto::cluster::JoinClusterResp {
+            success: false,
+            err_msg,
+            version: 0,
+            members: vec![],
+            term: 0,
+        };
+        let Some(member) = req.member.as_ref() else {
+            return responsor
+                .send_resp(fail("join request without member".to_owned()))
+                .await;
+        };
+        let (id, conf) = match member.to_node_config() {
+            Ok(member) => member,
+            Err(err) => {
+                return responsor
+                    .send_resp(fail(format!("invalid member: {:?}", err)))
+                    .await
+            }
+        };
+
+        let _hold = self.change_lock.lock().await;
+        let p2p = self.view.p2p();
+        let nodes_config = &p2p.nodes_config;
+        let joining = p2p.joining_node(responsor.node_id());
+        let is_new = match check_join(
+            nodes_config,
+            joining.as_ref(),
+            responsor.node_id(),
+            id,
+            &conf,
+        ) {
+            Ok(is_new) => is_new,
+            Err(err_msg) => {
+                tracing::warn!("reject join of node {}: {}", id, err_msg);
+                return responsor.send_resp(fail(err_msg)).await;
+            }
+        };
+        let version = if is_new {
+            tracing::info!("node {} at {} joins the cluster", id, conf.addr);
+            self.change_members(|members| members.push((id, conf)), Some(id))
+                .await
+        } else {
+            // rejoin after restart
+            nodes_config.members_version()
+        };
+        responsor
+            .send_resp(proto::cluster::JoinClusterResp {
+                success: true,
+                err_msg: String::new(),
+                version,
+                members: self.members_proto(),
+                term: nodes_config.master_term(),
+            })
+            .await
+    }
+
+    async fn handle_leave(
+        &self,
+        responsor: RPCResponsor<proto::cluster::LeaveClusterReq>,
+        req: proto::cluster::LeaveClusterReq,
+    ) -> WSResult<()> {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if let Err(err_msg) = check_leave(nodes_config, responsor.node_id(), req.node_id) {
+            return responsor
+                .send_resp(proto::cluster::LeaveClusterResp {
+                    success: false,
+                    err_msg,
+                })
+                .await;
+        }
+
+        // answer before removing, the connection to the leaving node is closed on removal
+        responsor
+            .send_resp(proto::cluster::LeaveClusterResp {
+                success: true,
+                err_msg: String::new(),
+            })
+            .await?;
+        let _hold = self.change_lock.lock().await;
+        tracing::info!("node {} leaves the cluster", req.node_id);
+        let _ = self
+            .change_members(|members| members.retain(|(id, _)| *id != req.node_id), None)
+            .await;
+        Ok(())
+    }
+}
+
+/// Whether `sender` may join as member `id` with `conf`, `joining` is the config it connected
+/// with. Returns true for a new member, false for a member rejoining after a restart.
+fn check_join(
+    nodes_config: &NodesConfig,
+    joining: Option<&NodeConfig>,
+    sender: NodeID,
+    id: NodeID,
+    conf: &NodeConfig,
+) -> Result<bool, String> {
+    if !nodes_config.this_is_master() {
+        return Err("join request should be sent to master".to_owned());
+    }
+    if id != sender {
+        return Err(format!(
+            "node {} can't join on behalf of node {}",
+            sender, id
+        ));
+    }
+    if conf.addr.ip().is_unspecified() || conf.addr.port() == 0 {
+        return Err(format!("node {} can't be reached at {}", id, conf.addr));
+    }
+    // the master is configured statically or elected among the meta nodes
+    if conf.is_master() {
+        return Err(format!("node {} can't join as master", id));
+    }
+    if let Some(old) = nodes_config.get_nodeconfig(id) {
+        return if old.addr == conf.addr {
+            Ok(false)
+        } else {
+            Err(format!("node id {} is already used by {}", id, old.addr))
+        };
+    }
+    if let Some(other) = nodes_config.find_peer_by_addr(&conf.addr) {
+        return Err(format!(
+            "address {} is already used by node {}",
+            conf.addr, other
+        ));
+    }
+    match joining {
+        Some(joining) if joining.addr == conf.addr => Ok(true),
+        Some(joining) => Err(format!(
+            "node {} joins with address {} but connected as {}",
+            id, conf.addr, joining.addr
+        )),
+        None => Err(format!("node {} didn't connect for joining", id)),
+    }
+}
+
+/// Only a member itself may ask the master to remove it.
+fn check_leave(nodes_config: &NodesConfig, sender: NodeID, id: NodeID) -> Result<(), String> {
+    if !nodes_config.this_is_master() {
+        Err("leave request should be sent to master".to_owned())
+    } else if id == nodes_config.this_node() {
+        Err("master can't leave the cluster".to_owned())
+    } else if id != sender {
+        Err(format!(
+            "node {} can't leave on behalf of node {}",
+            sender, id
+        ))
+    } else if !nodes_config.node_exist(id) {
+        Err(format!("node {} is not a member", id))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_join, check_leave};
+    use crate::config::{NodeConfig, NodesConfig};
+    use std::collections::HashMap;
+
+    fn node(port: u16, spec: &str) -> NodeConfig {
+        NodeConfig::new(
+            format!("127.0.0.1:{}", port).parse().unwrap(),
+            None,
+            [spec.to_owned()].into_iter().collect(),
+        )
+    }
+
+    #[test]
+    fn test_join_and_leave_checks() {
+        let master = NodesConfig::new(
+            (1, node(2000, "master")),
+            HashMap::from([(2, node(3000, "worker"))]),
+            "/tmp".into(),
+        );
+        let joining = node(4000, "worker");
+
+        assert_eq!(
+            check_join(&master, Some(&joining), 3, 3, &joining),
+            Ok(true)
+        );
+        // rejoin after restart
+        assert_eq!(
+            check_join(&master, None, 2, 2, &node(3000, "worker")),
+            Ok(false)
+        );
+        // on behalf of another node, or with an address it didn't connect from
+        assert!(check_join(&master, Some(&joining), 4, 3, &joining).is_err());
+        assert!(check_join(&master, Some(&joining), 3, 3, &node(4001, "worker")).is_err());
+        assert!(check_join(&master, None, 3, 3, &joining).is_err());
+        // taken id or address, or claiming the master role
+        assert!(check_join(&master, Some(&joining), 2, 2, &joining).is_err());
+        let taken_addr = node(3000, "worker");
+        assert!(check_join(&master, Some(&taken_addr), 3, 3, &taken_addr).is_err());
+        let as_master = node(4000, "master");
+        assert!(check_join(&master, Some(&as_master), 3, 3, &as_master).is_err());
+
+        assert_eq!(check_leave(&master, 2, 2), Ok(()));
+        assert!(check_leave(&master, 3, 2).is_err());
+        assert!(check_leave(&master, 1, 1).is_err());
+        assert!(check_leave(&master, 3, 3).is_err());
+
+        // only the master handles them
+        let worker = NodesConfig::new(
+            (2, node(3000, "worker")),
+            HashMap::from([(1, node(2000, "master"))]),
+            "/tmp".into(),
+        );
+        assert!(check_join(&worker, Some(&joining), 3, 3, &joining).is_err());
+        assert!(check_leave(&worker, 2, 2).is_err());
+    }
+}
@@ -11,7 +11,7 @@ use super::{
     msg_pack::{MsgPack, RPCReq},
 };
 use crate::{
-    config::NodesConfig,
+    config::{NodeConfig, NodesConfig},
     logical_module_view_impl,
     result::{ErrCvt, WSResult, WSResultExt, WsNetworkConnErr, WsNetworkLogicErr},
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
@@ -127,6 +127,10 @@ impl<R: RPCReq> RPCHandler<R> {
     {
         p2p.regist_rpc_recv::<R, F>(req_handler);
     }
+    /// Stop handling the requests, they're left unanswered from now on.
+    pub fn unregist(&self, p2p: &P2PModule) {
+        p2p.unregist_rpc_recv::<R>();
+    }
     pub async fn call(
         &self,
         p2p: &P2PModule,
@@ -159,6 +163,9 @@ pub struct P2PModule {
     pub p2p_kernel: P2PQuicNode,
     // pub state_trans_tx: tokio::sync::broadcast::Sender<ModuleSignal>,
     pub nodes_config: NodesConfig,
+    /// nodes connected to the master but not yet accepted as members,
+    /// only reachable for answering their join request
+    joining_nodes: RwLock<HashMap<NodeID, NodeConfig>>,
     pub next_task_id: AtomicU32,
     view: P2PView,
 }
@@ -184,6 +191,7 @@ impl LogicalModule for P2PModule {
             dispatch_map: HashMap::new().into(),
             waiting_tasks: Default::default(),
             nodes_config,
+            joining_nodes: RwLock::new(HashMap::new()),
             next_task_id: AtomicU32::new(0),
             view: P2PView::new(args.logical_modules_ref.clone()),
         }
@@ -193,6 +201,10 @@ impl LogicalModule for P2PModule {
         let sub = self.p2p_kernel.start().await?;
         Ok(sub)
     }
+
+    async fn shutdown(&self) -> WSResult<()> {
+        self.p2p_kernel.shutdown().await
+    }
 }
 
 pub struct RPCResponsor<R: RPCReq> {
@@ -259,15 +271,56 @@ impl From<Bytes> for DispatchPayload {
 
 impl P2PModule {
     pub fn find_peer_id(&self, addr: &SocketAddr) -> Option<NodeID> {
-        self.nodes_config.peers.iter().find_map(
-            |(id, peer)| {
-                if peer.addr == *addr {
-                    Some(*id)
-                } else {
-                    None
-                }
-            },
-        )
+        self.nodes_config.find_peer_by_addr(addr).or_else(|| {
+            self.joining_nodes
+                .read()
+                .iter()
+                .find_map(|(id, conf)| if conf.addr == *addr { Some(*id) } else { None })
+        })
+    }
+    /// Returns false if the id is already taken by a member with another address.
+    pub fn add_joining_node(&self, id: NodeID, conf: NodeConfig) -> bool {
+        if self.nodes_config.node_exist(id) {
+            tracing::warn!(
+                "node {} at {} wants to join, but the id is already a member",
+                id,
+                conf.addr
+            );
+            return false;
+        }
+        tracing::info!("node {} at {} connected for joining", id, conf.addr);
+        let _ = self.joining_nodes.write().insert(id, conf);
+        true
+    }
+    /// The config a not yet accepted node connected with.
+    pub fn joining_node(&self, id: NodeID) -> Option<NodeConfig> {
+        self.joining_nodes.read().get(&id).cloned()
+    }
+    pub fn remove_joining_node(&self, id: NodeID) -> Option<NodeConfig> {
+        self.joining_nodes.write().remove(&id)
+    }
+    /// Install a member list published by the master and update the connections.
+    pub async fn apply_members(
+        &self,
+        term: u64,
+        version: u64,
+        members: impl IntoIterator<Item = (NodeID, NodeConfig)>,
+    ) {
+        let (added, removed) = self.nodes_config.apply_members(term, version, members);
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "membership v{} (term {}) applied, added {:?}, removed {:?}",
+            version,
+            term,
+            added.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            removed.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        for (id, _) in &added {
+            let _ = self.remove_joining_node(*id);
+        }
+        self.p2p_kernel.on_members_changed(added, removed).await;
     }
     // pub fn listen(&self) -> tokio::sync::broadcast::Receiver<ModuleSignal> {
     //     self.state_trans_tx.subscribe()
@@ -363,6 +416,13 @@ impl P2PModule {
         });
     }
 
+    fn unregist_rpc_recv<REQ>(&self)
+    where
+        REQ: RPCReq,
+    {
+        let _ = self.dispatch_map.write().remove(&REQ::default().msg_id());
+    }
+
     // pub fn regist_rpc<REQ, F>(&self, req_handler: F)
     // where
     //     REQ: RPCReq,
@@ -533,9 +593,12 @@ impl P2PModule {
         }
     }
     pub fn get_addr_by_id(&self, id: NodeID) -> WSResult<SocketAddr> {
-        self.nodes_config.peers.get(&id).map_or_else(
-            || Err(WsNetworkLogicErr::InvaidNodeID(id).into()),
-            |v| Ok(v.addr),
-        )
+        self.nodes_config
+            .get_nodeconfig(id)
+            .or_else(|| self.joining_nodes.read().get(&id).cloned())
+            .map_or_else(
+                || Err(WsNetworkLogicErr::InvaidNodeID(id).into()),
+                |v| Ok(v.addr),
+            )
     }
 }
@@ -16,6 +16,7 @@ use async_trait::async_trait;
 
 use parking_lot::{Mutex, RwLock};
 use prost::bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use qp2p::{Connection, ConnectionIncoming, Endpoint, WireMsg};
 use std::{
     collections::HashMap,
@@ -33,10 +34,17 @@ use ws_derive::LogicalModule;
 
 use crate::{
     // module_view::P2PQuicNodeLMView,
+    config::NodeConfig,
     logical_module_view_impl, result::{ErrCvt, WSResult, WSResultExt, WsNetworkConnErr, WsSerialErr}, sys::{BroadcastMsg, BroadcastSender, LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID}, util::JoinHandleWrapper
 };
 
-use super::m_p2p::{MsgId, P2PKernel, P2PModule, TaskId};
+use super::{
+    m_p2p::{MsgId, P2PKernel, P2PModule, TaskId},
+    p2p_auth::{self, MsgSeal, Nonce, Role, Transcript},
+};
+
+/// A peer not finishing the handshake in time is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 // #[derive(Default, Ord, PartialEq, PartialOrd, Eq, Clone, Copy)]
 // struct XId(pub [u8; 32]);
@@ -48,13 +56,15 @@ struct P2PQuicNodeLocked {
 struct P2PQuicNodeShared {
     locked: Mutex<P2PQuicNodeLocked>,
     btx: BroadcastSender,
+    /// set once `start` has bound the listening port, used to connect to peers joining later
+    endpoint: Mutex<Option<Endpoint>>,
     // shared_connection_map: tokio::sync::Mutex<HashMap<SocketAddr, ConnectionStuff>>,
     peer_connections: RwLock<
         HashMap<
             SocketAddr,
             Arc<(
-                // all the active connections to this peer
-                tokio::sync::RwLock<Vec<Connection>>,
+                // all the active connections to this peer, with their message tagging if authenticated
+                tokio::sync::RwLock<Vec<(Connection, Option<Arc<MsgSeal>>)>>,
                 // the round robin index for the above vector
                 AtomicUsize,
                 // the number of active connections to this peer, avoid locking the above vector to call len()
@@ -88,10 +98,122 @@ pub struct P2PQuicNode {
     shared: Arc<P2PQuicNodeShared>,
 }
 
+/// First message on every new connection, identifies the connecting node.
+#[derive(Serialize, Deserialize)]
+struct ConnHandshake {
+    node_id: NodeID,
+    node: NodeConfig,
+    nonce: Nonce,
+}
+
+/// Answer of the accepting node when `p2p_auth` is set.
+#[derive(Serialize, Deserialize)]
+struct ConnChallenge {
+    node_id: NodeID,
+    nonce: Nonce,
+    proof: Vec<u8>,
+}
+
+/// Last handshake message of the connecting node when `p2p_auth` is set.
+#[derive(Serialize, Deserialize)]
+struct ConnProof {
+    proof: Vec<u8>,
+}
+
 impl P2PQuicNode {
     fn p2p_base(&self) -> &P2PModule {
         self.logical_modules_view.p2p()
     }
+
+    /// Keep connecting to peer `n` until it is no longer a member.
+    fn new_connect_task(&self, n: NodeID, addr: SocketAddr) -> JoinHandle<()> {
+        let endpoint = self
+            .shared
+            .endpoint
+            .lock()
+            .clone()
+            .expect("endpoint should be bound before connecting to peers");
+        let shared = self.shared.clone();
+        let view = self.logical_modules_view.clone();
+        tokio::spawn(async move {
+            loop {
+                // peer left or was replaced by a node with another address
+                let still_member = view
+                    .p2p()
+                    .nodes_config
+                    .get_nodeconfig(n)
+                    .map_or(false, |conf| conf.addr == addr);
+                if !still_member {
+                    tracing::info!("stop connecting to {}, not a member anymore", n);
+                    break;
+                }
+                tracing::info!("try to connect to {}", n);
+                let res = endpoint.connect_to(&addr).await;
+                match res {
+                    Ok((connection, mut incoming)) => {
+                        tracing::info!("connected to {}", addr);
+                        let handshake = tokio::time::timeout(
+                            HANDSHAKE_TIMEOUT,
+                            connect_handshake(&view, n, &connection, &mut incoming),
+                        )
+                        .await
+                        .unwrap_or_else(|_| Err("handshake timeout".to_owned()));
+                        match handshake {
+                            Ok(auth) => {
+                                handle_connection(
+                                    addr,
+                                    &view,
+                                    shared.clone(),
+                                    &endpoint,
+                                    connection,
+                                    incoming,
+                                    auth,
+                                )
+                                .await;
+                            }
+                            Err(err) => {
+                                tracing::warn!("handshake with {} failed, {}, will retry", n, err);
+                                connection.close(Some(err));
+                            }
+                        }
+                        // tracing::info!("handled conflict_connection {}", addr);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "connect to {} failed, error: {:?}, will retry",
+                            addr,
+                            e
+                        );
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        })
+    }
+
+    /// Connect to newly added members and drop connections of removed ones.
+    pub async fn on_members_changed(
+        &self,
+        added: Vec<(NodeID, NodeConfig)>,
+        removed: Vec<(NodeID, NodeConfig)>,
+    ) {
+        for (n, conf) in removed {
+            let peer_conns = self.shared.peer_connections.write().remove(&conf.addr);
+            if let Some(peer_conns) = peer_conns {
+                for (conn, _) in peer_conns.0.write().await.drain(..) {
+                    conn.close(Some(format!("node {} left the cluster", n)));
+                }
+            }
+        }
+        if self.shared.endpoint.lock().is_none() {
+            // not started yet, `start` connects to the members at that time
+            return;
+        }
+        for (n, conf) in added {
+            let task = self.new_connect_task(n, conf.addr);
+            self.shared.locked.lock().sub_tasks.push(task);
+        }
+    }
 }
 
 #[async_trait]
@@ -105,6 +227,7 @@ impl LogicalModule for P2PQuicNode {
             shared: P2PQuicNodeShared {
                 btx: args.btx,
                 locked: Mutex::new(P2PQuicNodeLocked { sub_tasks: vec![] }),
+                endpoint: Mutex::new(None),
                 peer_connections: HashMap::new().into(),
             }
             .into(),
@@ -132,47 +255,9 @@ impl LogicalModule for P2PQuicNode {
 
         let mut net_tasks: Vec<JoinHandleWrapper> = vec![];
 
-        for (n, n_config) in &self.p2p_base().nodes_config.peers {
-            let endpoint = endpoint.clone();
-            let addr = n_config.addr;
-            let shared = shared.clone();
-            let view = self.logical_modules_view.clone();
-            let n = n.clone();
-            net_tasks.push(
-                tokio::spawn(async move {
-                    loop {
-                        tracing::info!("try to connect to {}", n);
-                        let res = endpoint.connect_to(&addr).await;
-                        match res {
-                            Ok((connection, incoming)) => {
-                                tracing::info!("connected to {}", addr);
-                                let _=connection.send((Bytes::new(),Bytes::new(), Bytes::from(this_addr.to_string()))).await;
-                                // send self addr
-                                handle_connection(
-                                    addr,
-                                    &view,
-                                    shared.clone(),
-                                    &endpoint,
-                                    connection,
-                                    incoming,
-                                )
-                                .await;
-                                // tracing::info!("handled conflict_connection {}", addr);
-                            }
-                            Err(e) => {
-                                tracing::warn!(
-                                    "connect to {} failed, error: {:?}, will retry",
-                                    addr,
-                                    e
-                                );
-                                
-                            }
-                        }
-                        tokio::time::sleep(Duration::from_secs(10)).await;
-                    }
-                })
-                .into(),
-            );
+        *shared.endpoint.lock() = Some(endpoint.clone());
+        for (n, n_config) in self.p2p_base().nodes_config.peers() {
+            net_tasks.push(self.new_connect_task(n, n_config.addr).into());
         }
 
         let view = self.logical_modules_view.clone();
@@ -184,30 +269,9 @@ impl LogicalModule for P2PQuicNode {
             loop {
                 tokio::select! {
                     next_incoming= incoming_conns.next() => {
-                        if let Some((connection, mut incoming)) = next_incoming {
-                            let res = incoming.next().await;
-                            
-                            match res {
-                                Ok(msg) => {
-                                    if let Some(WireMsg((_head, _, bytes))) = msg {
-                                        let addr=String::from_utf8(bytes.to_vec()).unwrap().parse::<SocketAddr>().unwrap();
-                                        // tracing::info!("recv connect from {}", addr);
-                                        if view.p2p().find_peer_id(&addr).is_none(){
-                                            // tracing::warn!("recv connect from unknown peer {}", addr);
-                                            continue;
-                                        }
-                                        // handle_conflict_connection(&view,&shared, &endpoint, connection, incoming);
-                                        new_handle_connection_task(addr,&view, shared.clone(), endpoint.clone(), connection, incoming);
-                                    }else{
-                                        tracing::warn!("didn't recv head"); 
-                                        continue;   
-                                    }
-                                }
-                                Err(err)=>{
-                                    tracing::info!("recv head error {:?}",err);
-                                    continue;
-                                }
-                            }
+                        if let Some((connection, incoming)) = next_incoming {
+                            // handshakes run aside, so a slow peer doesn't hold up the others
+                            new_accept_task(&view, shared.clone(), endpoint.clone(), connection, incoming);
                         }else{
                             // no more connections, system shutdown
                             let _ =shared.btx.send(BroadcastMsg::SysEnd).unwrap_or_else(|err|{
@@ -228,6 +292,7 @@ impl LogicalModule for P2PQuicNode {
                                 // system shutdown
                                 break;
                             },
+                            BroadcastMsg::SysDrain => {}
                         }
                     }
                 }
@@ -236,6 +301,29 @@ impl LogicalModule for P2PQuicNode {
 
         Ok(net_tasks)
     }
+    async fn shutdown(&self) -> WSResult<()> {
+        // stop listening, then close the connections to the peers
+        let _ = self.shared.btx.send(BroadcastMsg::SysEnd);
+        for task in self.shared.locked.lock().sub_tasks.drain(..) {
+            task.abort();
+        }
+        let peer_conns: Vec<_> = self
+            .shared
+            .peer_connections
+            .write()
+            .drain()
+            .map(|(_, conns)| conns)
+            .collect();
+        for conns in peer_conns {
+            for (conn, _) in conns.0.write().await.drain(..) {
+                conn.close(Some("node shutdown".to_owned()));
+            }
+        }
+        if let Some(endpoint) = self.shared.endpoint.lock().take() {
+            endpoint.close();
+        }
+        Ok(())
+    }
 }
 
 // fn handle_conflict_connection(
@@ -298,13 +386,12 @@ impl LogicalModule for P2PQuicNode {
 //     let res=endpoint.connect_to(&addr).await
 // }
 
-fn new_handle_connection_task(
-    remote_addr: SocketAddr,
+fn new_accept_task(
     view: &View,
     shared: Arc<P2PQuicNodeShared>,
     endpoint: Endpoint,
     connection: Connection,
-    incoming: ConnectionIncoming,
+    mut incoming: ConnectionIncoming,
 ) {
     let view = view.clone();
     shared
@@ -313,10 +400,172 @@ fn new_handle_connection_task(
         .lock()
         .sub_tasks
         .push(tokio::spawn(async move {
-            handle_connection(remote_addr,&view, shared, &endpoint, connection, incoming).await;
+            let handshake = tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                accept_handshake(&view, &connection, &mut incoming),
+            )
+            .await
+            .unwrap_or_else(|_| Err("handshake timeout".to_owned()));
+            let (remote_addr, auth) = match handshake {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!(
+                        "reject connection from {}, {}",
+                        connection.remote_address(),
+                        err
+                    );
+                    connection.close(Some(err));
+                    return;
+                }
+            };
+            handle_connection(
+                remote_addr,
+                &view,
+                shared,
+                &endpoint,
+                connection,
+                incoming,
+                auth,
+            )
+            .await;
         }));
 }
 
+async fn send_handshake_msg(connection: &Connection, msg: &impl Serialize) -> Result<(), String> {
+    let bytes = bincode::serialize(msg).unwrap();
+    connection
+        .send((Bytes::new(), Bytes::new(), Bytes::from(bytes)))
+        .await
+        .map_err(|err| format!("send handshake error {:?}", err))
+}
+
+async fn recv_handshake_msg<T: DeserializeOwned>(
+    incoming: &mut ConnectionIncoming,
+) -> Result<T, String> {
+    match incoming.next().await {
+        Ok(Some(WireMsg((_, _, bytes)))) => bincode::deserialize::<T>(&bytes)
+            .map_err(|err| format!("invalid handshake msg {:?}", err)),
+        Ok(None) => Err("closed during handshake".to_owned()),
+        Err(err) => Err(format!("recv handshake error {:?}", err)),
+    }
+}
+
+/// Introduce this node to `peer`, and with `p2p_auth` set check `peer` is the node answering
+/// and prove this node's identity to it. Returns the sealing of the later messages with
+/// `p2p_auth` set.
+async fn connect_handshake(
+    view: &View,
+    peer: NodeID,
+    connection: &Connection,
+    incoming: &mut ConnectionIncoming,
+) -> Result<Option<Arc<MsgSeal>>, String> {
+    let nodes_config = &view.p2p().nodes_config;
+    let (this_id, this_node) = nodes_config.this.clone();
+    let nonce = p2p_auth::new_nonce();
+    send_handshake_msg(
+        connection,
+        &ConnHandshake {
+            node_id: this_id,
+            node: this_node,
+            nonce,
+        },
+    )
+    .await?;
+    let Some(auth) = &nodes_config.p2p_auth else {
+        return Ok(None);
+    };
+
+    let challenge: ConnChallenge = recv_handshake_msg(incoming).await?;
+    if challenge.node_id != peer {
+        return Err(format!(
+            "node {} answered at the address of node {}",
+            challenge.node_id, peer
+        ));
+    }
+    let transcript = Transcript {
+        connecting: (this_id, nonce),
+        accepting: (peer, challenge.nonce),
+    };
+    if !transcript.verify(&auth.psk, Role::Accepting, &challenge.proof) {
+        return Err(format!("node {} doesn't hold the cluster key", peer));
+    }
+    send_handshake_msg(
+        connection,
+        &ConnProof {
+            proof: transcript.proof(&auth.psk, Role::Connecting),
+        },
+    )
+    .await?;
+    Ok(Some(Arc::new(MsgSeal::new(
+        &transcript,
+        &auth.psk,
+        Role::Connecting,
+    ))))
+}
+
+/// Identify the connecting node, with `p2p_auth` set it must prove it holds the cluster key
+/// for the `NodeID` it claims. The address it connects from isn't checked, nodes behind NAT
+/// connect from another one. Returns the address the node is known by, and the sealing of the
+/// later messages with `p2p_auth` set.
+async fn accept_handshake(
+    view: &View,
+    connection: &Connection,
+    incoming: &mut ConnectionIncoming,
+) -> Result<(SocketAddr, Option<Arc<MsgSeal>>), String> {
+    let p2p = view.p2p();
+    let handshake: ConnHandshake = recv_handshake_msg(incoming).await?;
+    let addr = handshake.node.addr;
+    let mut msg_auth = None;
+    if let Some(auth) = &p2p.nodes_config.p2p_auth {
+        let this_id = p2p.nodes_config.this.0;
+        let nonce = p2p_auth::new_nonce();
+        let transcript = Transcript {
+            connecting: (handshake.node_id, handshake.nonce),
+            accepting: (this_id, nonce),
+        };
+        send_handshake_msg(
+            connection,
+            &ConnChallenge {
+                node_id: this_id,
+                nonce,
+                proof: transcript.proof(&auth.psk, Role::Accepting),
+            },
+        )
+        .await?;
+        let proof: ConnProof = recv_handshake_msg(incoming).await?;
+        if !transcript.verify(&auth.psk, Role::Connecting, &proof.proof) {
+            return Err(format!(
+                "node {} at {} doesn't hold the cluster key",
+                handshake.node_id, addr
+            ));
+        }
+        msg_auth = Some(Arc::new(MsgSeal::new(
+            &transcript,
+            &auth.psk,
+            Role::Accepting,
+        )));
+    }
+
+    match p2p.find_peer_id(&addr) {
+        Some(id) if id == handshake.node_id => {}
+        Some(id) => {
+            return Err(format!(
+                "claims to be node {} at {}, which is node {}",
+                handshake.node_id, addr, id
+            ));
+        }
+        None => {
+            // only the master admits unknown nodes, they must send a join request next
+            if !p2p.nodes_config.this_is_master() {
+                return Err(format!("unknown node {} at {}", handshake.node_id, addr));
+            }
+            if !p2p.add_joining_node(handshake.node_id, handshake.node) {
+                return Err(format!("node {} is already a member", handshake.node_id));
+            }
+        }
+    }
+    Ok((addr, msg_auth))
+}
 
 /// remote_addr should be checked
 async fn handle_connection(
@@ -326,19 +575,22 @@ async fn handle_connection(
     _endpoint: &Endpoint,
     connection: Connection,
     mut incoming: ConnectionIncoming,
+    auth: Option<Arc<MsgSeal>>,
 ) {
     println!("\n---");
     println!("Listening on: {:?}", remote_addr);
     println!("---\n");
 
 
-    let remote_id = view.p2p().find_peer_id(&remote_addr).unwrap_or_else(|| {
-        panic!(
-            "remote_addr {:?} not found in peer_id_map {:?}",
-            remote_addr,
-            view.p2p().nodes_config
+    // the peer may have been removed since the handshake
+    let Some(remote_id) = view.p2p().find_peer_id(&remote_addr) else {
+        tracing::warn!(
+            "{} is not a member anymore, close its connection",
+            remote_addr
         );
-    });
+        connection.close(Some("not a member".to_owned()));
+        return;
+    };
 
     shared.reserve_peer_conn(remote_addr).await;
     let peer_conns = shared
@@ -348,16 +600,29 @@ async fn handle_connection(
         .unwrap()
         .clone();
     let conn_id = connection.id();
-    peer_conns.0.write().await.push(connection);
+    peer_conns.0.write().await.push((connection, auth.clone()));
     let _ = peer_conns.2.fetch_add(1, Ordering::Relaxed);
 
     loop {
         let res = incoming.next().await;
         match res {
             Ok(msg) => {
-                if let Some(WireMsg((_, _, mut bytes))) = msg {
-                    let headlen=bytes.split_to(1)[0];
-                    let head=bytes.split_to(headlen as usize);
+                if let Some(WireMsg((header, _, bytes))) = msg {
+                    let mut bytes = match &auth {
+                        Some(auth) => match auth.open(&header, &bytes) {
+                            Some(opened) => Bytes::from(opened),
+                            None => {
+                                tracing::warn!(
+                                    "message from {} failed authentication, close the connection",
+                                    remote_addr
+                                );
+                                break;
+                            }
+                        },
+                        None => bytes,
+                    };
+                    let headlen = bytes.split_to(1)[0];
+                    let head = bytes.split_to(headlen as usize);
                     match deserialize_msg_id_task_id(&head) {
                         Ok((msg_id, task_id)) => {
                             //返回结果未处理     曾俊
@@ -383,8 +648,17 @@ async fn handle_connection(
         }
     }
 
-    peer_conns.0.write().await.retain(|v| v.id() != conn_id);
+    let closed = {
+        let mut conns = peer_conns.0.write().await;
+        let closed = conns.iter().position(|(v, _)| v.id() == conn_id);
+        closed.map(|idx| conns.remove(idx).0)
+    };
+    if let Some(conn) = closed {
+        conn.close(Some("connection ended".to_owned()));
+    }
     let _ = peer_conns.2.fetch_sub(1, Ordering::Relaxed);
+    // a node that connected but never finished joining
+    let _ = view.p2p().remove_joining_node(remote_id);
 
     // loop over incoming messages
 
@@ -446,9 +720,17 @@ impl P2PKernel for P2PQuicNode {
                     Bytes::from(v)
                 };
 
-                if let Err(err) = reading_conns[idx]
+                let (conn, auth) = &reading_conns[idx];
+                let (header, bytes) = match auth {
+                    Some(auth) => {
+                        let (header, sealed) = auth.seal(&bytes);
+                        (Bytes::from(header), Bytes::from(sealed))
+                    }
+                    None => (Bytes::new(), bytes),
+                };
+                if let Err(err) = conn
                     .send((
-                        Bytes::new(),
+                        header,
                         Bytes::new(),
                         bytes,
                         // Bytes::new(),
@@ -1,7 +1,10 @@
 pub mod http_handler;
+pub mod m_master_election;
+pub mod m_membership;
 pub mod m_p2p;
 pub mod m_p2p_quic;
 pub mod msg_pack;
+pub mod p2p_auth;
 pub mod proto_ext;
 pub mod rpc_model;
 
@@ -24,5 +27,8 @@ pub mod proto {
     pub mod remote_sys {
         include!(concat!(env!("OUT_DIR"), "/remote_sys.rs"));
     }
+    pub mod cluster {
+        include!(concat!(env!("OUT_DIR"), "/cluster.rs"));
+    }
     // include!(concat!(env!("OUT_DIR"), "/data.rs"));
 }
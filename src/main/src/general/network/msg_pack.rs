@@ -91,6 +91,16 @@ define_msg_ids!(
                         return false;
                     }
                 }
+                proto::kv::kv_request::Op::Cas(kv_cas_request) => {
+                    if kv_cas_request.kv.is_none() {
+                        return false;
+                    }
+                }
+                proto::kv::kv_request::Op::Watch(kv_watch_request) => {
+                    if kv_watch_request.range.is_none() {
+                        return false;
+                    }
+                }
             }
         }
         true
@@ -128,7 +138,7 @@ define_msg_ids!(
     (proto::GetOneDataResponse, _pack, { true }),
     (proto::kv::KvLockRequest, pack, {
         match pack.read_0_write_1_unlock_2 {
-            0 | 1 | 2 => true,
+            0 | 1 | 2 | 3 => true,
             _ => false,
         }
     }),
@@ -152,7 +162,31 @@ define_msg_ids!(
     (proto::AddWaitTargetReq, _pack, { true }),
     (proto::AddWaitTargetResp, _pack, { true }),
     (proto::ListenForTaskDoneReq, _pack, { true }),
-    (proto::ListenForTaskDoneResp, _pack, { true })
+    (proto::ListenForTaskDoneResp, _pack, { true }),
+    (proto::cluster::JoinClusterReq, pack, {
+        pack.member.is_some()
+    }),
+    (proto::cluster::JoinClusterResp, _pack, { true }),
+    (proto::cluster::LeaveClusterReq, _pack, { true }),
+    (proto::cluster::LeaveClusterResp, _pack, { true }),
+    (proto::cluster::MembershipUpdate, _pack, { true }),
+    (proto::cluster::ReplicateMasterMetaReq, _pack, { true }),
+    (proto::cluster::ReplicateMasterMetaResp, _pack, { true }),
+    (proto::cluster::SyncMasterMetaReq, _pack, { true }),
+    (proto::cluster::SyncMasterMetaResp, _pack, { true }),
+    (proto::metric::AppCallMetrics, _pack, { true }),
+    (proto::kv::KvScanKeysRequest, pack, { pack.range.is_some() }),
+    (proto::kv::KvScanKeysResponse, _pack, { true }),
+    (proto::kv::KvWatchRequest, pack, { pack.range.is_some() }),
+    (proto::kv::KvWatchResponse, _pack, { true }),
+    (proto::DataItemDigestRequest, _pack, { true }),
+    (proto::DataItemDigestResponse, _pack, { true }),
+    (proto::DataRepairRequest, pack, { !pack.targets.is_empty() }),
+    (proto::DataRepairResponse, _pack, { true }),
+    (proto::kv::KvFencingTokenRequest, _pack, { true }),
+    (proto::kv::KvFencingTokenResponse, _pack, { true }),
+    (proto::DataMetaDeleteRequest, _pack, { true }),
+    (proto::DataMetaDeleteResponse, _pack, { true })
 );
 
 pub trait RPCReq: MsgPack + Default {
@@ -219,6 +253,46 @@ impl RPCReq for proto::ListenForTaskDoneReq {
     type Resp = proto::ListenForTaskDoneResp;
 }
 
+impl RPCReq for proto::cluster::JoinClusterReq {
+    type Resp = proto::cluster::JoinClusterResp;
+}
+
+impl RPCReq for proto::cluster::LeaveClusterReq {
+    type Resp = proto::cluster::LeaveClusterResp;
+}
+
+impl RPCReq for proto::cluster::ReplicateMasterMetaReq {
+    type Resp = proto::cluster::ReplicateMasterMetaResp;
+}
+
+impl RPCReq for proto::cluster::SyncMasterMetaReq {
+    type Resp = proto::cluster::SyncMasterMetaResp;
+}
+
+impl RPCReq for proto::kv::KvScanKeysRequest {
+    type Resp = proto::kv::KvScanKeysResponse;
+}
+
+impl RPCReq for proto::kv::KvWatchRequest {
+    type Resp = proto::kv::KvWatchResponse;
+}
+
+impl RPCReq for proto::DataItemDigestRequest {
+    type Resp = proto::DataItemDigestResponse;
+}
+
+impl RPCReq for proto::DataRepairRequest {
+    type Resp = proto::DataRepairResponse;
+}
+
+impl RPCReq for proto::kv::KvFencingTokenRequest {
+    type Resp = proto::kv::KvFencingTokenResponse;
+}
+
+impl RPCReq for proto::DataMetaDeleteRequest {
+    type Resp = proto::DataMetaDeleteResponse;
+}
+
 // impl RPCReq for proto::kv::KvLockWaitAcquireNotifyRequest {
 //     type Resp = proto::kv::KvLockWaitAcquireNotifyResponse;
 // }
@@ -0,0 +1,287 @@
+//! Mutual authentication of p2p connections by the cluster pre-shared key, `p2p_auth` in the
+//! node config.
+//!
+//! The connecting node sends its `NodeID` and a nonce, the accepting node answers with its own
+//! `NodeID`, nonce and proof, then the connecting node sends its proof. A proof is an HMAC-SHA256
+//! under the key of the prover's role, both `NodeID`s and both nonces, so it only vouches for the
+//! ids of that one connection and can't be replayed or reflected back to its sender.
+//!
+//! The TLS session of the QUIC transport isn't tied to the proofs, so a node relaying the
+//! handshake between two members could read the messages or take the connection over after it.
+//! Every later message is therefore sealed by [`MsgSeal`] with ChaCha20-Poly1305, under a key of
+//! the sender's role derived from the cluster key and the handshake, with a sequence number as
+//! the nonce.
+use crate::sys::NodeID;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key,
+};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    collections::BTreeSet,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Separates these proofs from any other use of the key.
+const PROOF_DOMAIN: &[u8] = b"waverless-p2p-auth-v1";
+/// Separates the message key of a connection from the proofs.
+const SESSION_DOMAIN: &[u8] = b"waverless-p2p-session-v1";
+/// Separates the keys of the two senders of a connection.
+const SEAL_DOMAIN: &[u8] = b"waverless-p2p-seal-v1";
+
+/// Sequence numbers a receiver remembers, messages may arrive out of order on separate streams.
+const REPLAY_WINDOW: usize = 4096;
+
+const SEQ_LEN: usize = 8;
+
+pub type Nonce = [u8; 32];
+
+pub fn new_nonce() -> Nonce {
+    let mut nonce = [0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Connecting,
+    Accepting,
+}
+
+impl Role {
+    fn byte(self) -> u8 {
+        match self {
+            Role::Connecting => 0,
+            Role::Accepting => 1,
+        }
+    }
+
+    fn peer(self) -> Role {
+        match self {
+            Role::Connecting => Role::Accepting,
+            Role::Accepting => Role::Connecting,
+        }
+    }
+}
+
+/// Identities and nonces exchanged on one connection.
+pub struct Transcript {
+    pub connecting: (NodeID, Nonce),
+    pub accepting: (NodeID, Nonce),
+}
+
+impl Transcript {
+    /// Proof of the node in `role` that it holds `psk`.
+    pub fn proof(&self, psk: &str, role: Role) -> Vec<u8> {
+        self.mac(psk, role).finalize().into_bytes().to_vec()
+    }
+
+    pub fn verify(&self, psk: &str, role: Role, proof: &[u8]) -> bool {
+        // constant time
+        self.mac(psk, role).verify_slice(proof).is_ok()
+    }
+
+    fn mac(&self, psk: &str, role: Role) -> HmacSha256 {
+        let mut mac = self.keyed(psk, PROOF_DOMAIN);
+        mac.update(&[role.byte()]);
+        mac
+    }
+
+    /// Key of the messages after the handshake, only the two nodes of this connection know it.
+    fn session_key(&self, psk: &str) -> Vec<u8> {
+        self.keyed(psk, SESSION_DOMAIN)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn keyed(&self, psk: &str, domain: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(psk.as_bytes()).expect("hmac takes keys of any size");
+        mac.update(domain);
+        mac.update(&self.connecting.0.to_le_bytes());
+        mac.update(&self.connecting.1);
+        mac.update(&self.accepting.0.to_le_bytes());
+        mac.update(&self.accepting.1);
+        mac
+    }
+}
+
+/// Seals the messages one side sends on an authenticated connection and opens the ones it
+/// receives.
+pub struct MsgSeal {
+    /// of the messages this side sends
+    sealing: ChaCha20Poly1305,
+    /// of the messages the peer sends
+    opening: ChaCha20Poly1305,
+    next_seq: AtomicU64,
+    received: Mutex<ReceivedSeqs>,
+}
+
+impl MsgSeal {
+    /// For the node in `role` once the handshake of `transcript` succeeded.
+    pub fn new(transcript: &Transcript, psk: &str, role: Role) -> Self {
+        let session_key = transcript.session_key(psk);
+        Self {
+            sealing: Self::cipher(&session_key, role),
+            opening: Self::cipher(&session_key, role.peer()),
+            next_seq: AtomicU64::new(0),
+            received: Mutex::new(ReceivedSeqs::default()),
+        }
+    }
+
+    /// Seal the next message sent, returns its header, the sequence number, and the encrypted
+    /// message.
+    pub fn seal(&self, msg: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let header = seq.to_le_bytes().to_vec();
+        let sealed = self
+            .sealing
+            .encrypt(&Self::nonce(seq).into(), Payload { msg, aad: &header })
+            .expect("a message fits in a chacha20 stream");
+        (header, sealed)
+    }
+
+    /// The message, if it was sealed by the peer of this connection and wasn't received before.
+    pub fn open(&self, header: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let seq = u64::from_le_bytes(header.try_into().ok()?);
+        let msg = self
+            .opening
+            .decrypt(
+                &Self::nonce(seq).into(),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .ok()?;
+        self.received.lock().insert(seq).then_some(msg)
+    }
+
+    fn cipher(session_key: &[u8], sender: Role) -> ChaCha20Poly1305 {
+        let mut mac = HmacSha256::new_from_slice(session_key).expect("hmac takes keys of any size");
+        mac.update(SEAL_DOMAIN);
+        mac.update(&[sender.byte()]);
+        ChaCha20Poly1305::new(Key::from_slice(&mac.finalize().into_bytes()))
+    }
+
+    /// Unique per message of a sender, its key isn't used by the other one.
+    fn nonce(seq: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..SEQ_LEN].copy_from_slice(&seq.to_le_bytes());
+        nonce
+    }
+}
+
+/// Sequence numbers received so far, every number below `floor` counts as received.
+#[derive(Default)]
+struct ReceivedSeqs {
+    floor: u64,
+    above: BTreeSet<u64>,
+}
+
+impl ReceivedSeqs {
+    /// false for a number received before or too old to tell
+    fn insert(&mut self, seq: u64) -> bool {
+        if seq < self.floor || !self.above.insert(seq) {
+            return false;
+        }
+        while self.above.remove(&self.floor) {
+            self.floor += 1;
+        }
+        if self.above.len() > REPLAY_WINDOW {
+            let oldest = *self.above.iter().next().unwrap();
+            let _ = self.above.remove(&oldest);
+            self.floor = oldest + 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{new_nonce, MsgSeal, Role, Transcript};
+
+    #[test]
+    fn test_transcript_proof() {
+        let transcript = Transcript {
+            connecting: (2, new_nonce()),
+            accepting: (1, new_nonce()),
+        };
+        let proof = transcript.proof("key", Role::Connecting);
+        assert!(transcript.verify("key", Role::Connecting, &proof));
+
+        // another key, a reflected proof, or another claimed id
+        assert!(!transcript.verify("other key", Role::Connecting, &proof));
+        assert!(!transcript.verify("key", Role::Accepting, &proof));
+        let impersonating = Transcript {
+            connecting: (3, transcript.connecting.1),
+            accepting: transcript.accepting,
+        };
+        assert!(!impersonating.verify("key", Role::Connecting, &proof));
+        // a proof of an earlier connection
+        let replayed = Transcript {
+            connecting: transcript.connecting,
+            accepting: (1, new_nonce()),
+        };
+        assert!(!replayed.verify("key", Role::Connecting, &proof));
+    }
+
+    #[test]
+    fn test_msg_seal() {
+        let transcript = Transcript {
+            connecting: (2, new_nonce()),
+            accepting: (1, new_nonce()),
+        };
+        let connecting = MsgSeal::new(&transcript, "key", Role::Connecting);
+        let accepting = MsgSeal::new(&transcript, "key", Role::Accepting);
+
+        let first = connecting.seal(b"first message");
+        let second = connecting.seal(b"second message");
+        // encrypted
+        assert!(!first
+            .1
+            .windows(b"first".len())
+            .any(|w| w == b"first".as_slice()));
+        // out of order is fine, a replay or a changed message isn't
+        assert_eq!(
+            accepting.open(&second.0, &second.1).unwrap(),
+            b"second message"
+        );
+        assert_eq!(
+            accepting.open(&first.0, &first.1).unwrap(),
+            b"first message"
+        );
+        assert!(accepting.open(&first.0, &first.1).is_none());
+        let (header, mut changed) = connecting.seal(b"third");
+        changed[0] ^= 1;
+        assert!(accepting.open(&header, &changed).is_none());
+        let (header, sealed) = connecting.seal(b"fourth");
+        let moved = (u64::from_le_bytes(header.clone().try_into().unwrap()) + 1).to_le_bytes();
+        assert!(accepting.open(&moved, &sealed).is_none());
+        assert!(accepting.open(&header, &sealed).is_some());
+        let reply = accepting.seal(b"reply");
+        assert_eq!(connecting.open(&reply.0, &reply.1).unwrap(), b"reply");
+        // reflected back to its sender
+        let reflected = connecting.seal(b"reflected");
+        assert!(connecting.open(&reflected.0, &reflected.1).is_none());
+
+        // a relaying node without the key, or a message of another connection
+        let other_key = MsgSeal::new(&transcript, "other key", Role::Connecting);
+        let forged = other_key.seal(b"forged");
+        assert!(accepting.open(&forged.0, &forged.1).is_none());
+        let other_conn = Transcript {
+            connecting: transcript.connecting,
+            accepting: (1, new_nonce()),
+        };
+        let other_conn = MsgSeal::new(&other_conn, "key", Role::Connecting);
+        let moved = other_conn.seal(b"moved");
+        assert!(accepting.open(&moved.0, &moved.1).is_none());
+        assert!(accepting.open(b"short", b"short").is_none());
+    }
+}
@@ -5,12 +5,14 @@ use crate::general::data::m_data_general::dataitem::DataItemSource;
 use crate::general::data::m_data_general::DataItemIdx;
 use crate::general::data::m_dist_lock::DistLockOpe;
 use crate::general::network::proto::distribute_task_req::{
-    DataEventTriggerNew, DataEventTriggerWrite, Trigger,
+    DataEventTriggerExpire, DataEventTriggerNew, DataEventTriggerWrite, Trigger,
 };
 
 use super::proto::{self, kv::KvResponse, FileData};
 
-use crate::result::{WSError, WSResult, WsDataError};
+use crate::config::NodeConfig;
+use crate::result::{WSError, WSResult, WsDataError, WsNetworkLogicErr};
+use crate::sys::NodeID;
 use std::fs::File;
 use std::path::PathBuf;
 use std::{ops::Range, path::Path};
@@ -267,19 +269,28 @@ impl AsRef<[u8]> for proto::DataItem {
 
 pub trait ProtoExtKvResponse {
     fn new_lock(lock_id: u32) -> KvResponse;
-    fn new_get(idxs: Vec<DataItemIdx>, values: Vec<Vec<u8>>) -> KvResponse;
+    /// `version` is the key's version, 0 when it doesn't exist
+    fn new_get(idxs: Vec<DataItemIdx>, values: Vec<Vec<u8>>, version: u64) -> KvResponse;
     fn new_put_or_del(kv: proto::kv::KvPair) -> KvResponse;
+    fn new_cas(success: bool, version: u64) -> KvResponse;
+    fn new_lock_grant(lock_id: u32, fencing_token: u64) -> KvResponse;
+    fn new_get_range(kvs: Vec<proto::kv::KvPair>, continuation: Vec<u8>) -> KvResponse;
+    fn new_del_range(kvs: Vec<proto::kv::KvPair>, continuation: Vec<u8>) -> KvResponse;
+    fn new_watch(watch: proto::kv::KvWatchResponse) -> KvResponse;
     fn lock_id(&self) -> Option<u32>;
     fn get_kvs(&self) -> Option<&proto::kv::kv_response::KvGetResponse>;
 }
 
 impl ProtoExtKvResponse for KvResponse {
-    fn new_get(idxs: Vec<DataItemIdx>, values: Vec<Vec<u8>>) -> KvResponse {
+    fn new_get(idxs: Vec<DataItemIdx>, values: Vec<Vec<u8>>, version: u64) -> KvResponse {
         KvResponse {
             resp: Some(proto::kv::kv_response::Resp::Get(
                 proto::kv::kv_response::KvGetResponse {
                     idxs: idxs.iter().map(|v| *v as u32).collect(),
                     values,
+                    kvs: vec![],
+                    continuation: vec![],
+                    version,
                 },
             )),
         }
@@ -287,10 +298,60 @@ impl ProtoExtKvResponse for KvResponse {
     fn new_put_or_del(kv: proto::kv::KvPair) -> KvResponse {
         KvResponse {
             resp: Some(proto::kv::kv_response::Resp::PutOrDel(
-                proto::kv::kv_response::KvPutOrDelResponse { kv: Some(kv) },
+                proto::kv::kv_response::KvPutOrDelResponse {
+                    kv: Some(kv),
+                    kvs: vec![],
+                    continuation: vec![],
+                },
+            )),
+        }
+    }
+    fn new_cas(success: bool, version: u64) -> KvResponse {
+        KvResponse {
+            resp: Some(proto::kv::kv_response::Resp::Cas(
+                proto::kv::kv_response::KvCasResponse { success, version },
+            )),
+        }
+    }
+    fn new_lock_grant(lock_id: u32, fencing_token: u64) -> KvResponse {
+        KvResponse {
+            resp: Some(proto::kv::kv_response::Resp::LockGrant(
+                proto::kv::kv_response::KvLockGrantResponse {
+                    lock_id,
+                    fencing_token,
+                },
+            )),
+        }
+    }
+    fn new_get_range(kvs: Vec<proto::kv::KvPair>, continuation: Vec<u8>) -> KvResponse {
+        KvResponse {
+            resp: Some(proto::kv::kv_response::Resp::Get(
+                proto::kv::kv_response::KvGetResponse {
+                    idxs: vec![],
+                    values: vec![],
+                    kvs,
+                    continuation,
+                    version: 0,
+                },
+            )),
+        }
+    }
+    fn new_del_range(kvs: Vec<proto::kv::KvPair>, continuation: Vec<u8>) -> KvResponse {
+        KvResponse {
+            resp: Some(proto::kv::kv_response::Resp::PutOrDel(
+                proto::kv::kv_response::KvPutOrDelResponse {
+                    kv: None,
+                    kvs,
+                    continuation,
+                },
             )),
         }
     }
+    fn new_watch(watch: proto::kv::KvWatchResponse) -> KvResponse {
+        KvResponse {
+            resp: Some(proto::kv::kv_response::Resp::Watch(watch)),
+        }
+    }
     fn new_lock(lock_id: u32) -> KvResponse {
         KvResponse {
             resp: Some(proto::kv::kv_response::Resp::LockId(lock_id)),
@@ -298,10 +359,12 @@ impl ProtoExtKvResponse for KvResponse {
     }
     fn lock_id(&self) -> Option<u32> {
         match self.resp.as_ref().unwrap() {
-            proto::kv::kv_response::Resp::Get(_) | proto::kv::kv_response::Resp::PutOrDel(_) => {
-                None
-            }
+            proto::kv::kv_response::Resp::Get(_)
+            | proto::kv::kv_response::Resp::PutOrDel(_)
+            | proto::kv::kv_response::Resp::Cas(_)
+            | proto::kv::kv_response::Resp::Watch(_) => None,
             proto::kv::kv_response::Resp::LockId(id) => Some(*id),
+            proto::kv::kv_response::Resp::LockGrant(grant) => Some(grant.lock_id),
         }
     }
     fn get_kvs(&self) -> Option<&proto::kv::kv_response::KvGetResponse> {
@@ -309,22 +372,84 @@ impl ProtoExtKvResponse for KvResponse {
             proto::kv::kv_response::Resp::Get(res) => Some(res),
             proto::kv::kv_response::Resp::PutOrDel(_) => None,
             proto::kv::kv_response::Resp::LockId(_) => None,
+            proto::kv::kv_response::Resp::Cas(_) => None,
+            proto::kv::kv_response::Resp::LockGrant(_) => None,
+            proto::kv::kv_response::Resp::Watch(_) => None,
+        }
+    }
+}
+
+pub trait ProtoExtKeyRange {
+    fn new_range(start: Vec<u8>, end: Vec<u8>) -> Self;
+    fn new_prefix(prefix: Vec<u8>) -> Self;
+    /// Covers more than the single key `start`.
+    fn is_scan(&self) -> bool;
+    fn contains(&self, key: &[u8]) -> bool;
+}
+
+impl ProtoExtKeyRange for proto::kv::KeyRange {
+    fn new_range(start: Vec<u8>, end: Vec<u8>) -> Self {
+        proto::kv::KeyRange {
+            start,
+            end,
+            prefix: false,
+        }
+    }
+    fn new_prefix(prefix: Vec<u8>) -> Self {
+        proto::kv::KeyRange {
+            start: prefix,
+            end: vec![],
+            prefix: true,
+        }
+    }
+    fn is_scan(&self) -> bool {
+        self.prefix || !self.end.is_empty()
+    }
+    fn contains(&self, key: &[u8]) -> bool {
+        if self.prefix {
+            key.starts_with(&self.start)
+        } else if self.end.is_empty() {
+            key == self.start.as_slice()
+        } else {
+            self.start.as_slice() <= key && key < self.end.as_slice()
         }
     }
 }
 
 pub trait KvRequestExt {
     fn new_set(kv: proto::kv::KvPair) -> Self;
+    /// The key expires `ttl_ms` after the put
+    fn new_set_with_ttl(kv: proto::kv::KvPair, ttl_ms: u64) -> Self;
     fn new_get(key: Vec<u8>, idxs: Vec<DataItemIdx>) -> Self;
     fn new_delete(key: Vec<u8>) -> Self;
+    /// `expected_version` 0 means the key must not exist
+    fn new_cas(kv: proto::kv::KvPair, expected_version: u64) -> Self;
+    fn new_cas_delete(key: Vec<u8>, expected_version: u64) -> Self;
+    /// `continuation` is empty for the first page
+    fn new_get_range(
+        range: proto::kv::KeyRange,
+        idxs: Vec<DataItemIdx>,
+        limit: u32,
+        continuation: Vec<u8>,
+    ) -> Self;
+    fn new_delete_range(range: proto::kv::KeyRange, limit: u32, continuation: Vec<u8>) -> Self;
     fn new_lock(ope: DistLockOpe, key: Vec<u8>) -> Self;
+    /// `from_revision` is the `revision` of the last watch response, 0 for the first watch
+    fn new_watch(range: proto::kv::KeyRange, from_revision: u64, timeout_ms: u32) -> Self;
 }
 
 impl KvRequestExt for proto::kv::KvRequest {
     fn new_set(kv: proto::kv::KvPair) -> Self {
+        Self::new_set_with_ttl(kv, 0)
+    }
+    fn new_set_with_ttl(kv: proto::kv::KvPair, ttl_ms: u64) -> Self {
         proto::kv::KvRequest {
             op: Some(proto::kv::kv_request::Op::Set(
-                proto::kv::kv_request::KvPutRequest { kv: Some(kv) },
+                proto::kv::kv_request::KvPutRequest {
+                    kv: Some(kv),
+                    fencing_token: 0,
+                    ttl_ms,
+                },
             )),
         }
     }
@@ -333,10 +458,9 @@ impl KvRequestExt for proto::kv::KvRequest {
             op: Some(proto::kv::kv_request::Op::Get(
                 proto::kv::kv_request::KvGetRequest {
                     idxs: idxs.iter().map(|v| *v as u32).collect(),
-                    range: Some(proto::kv::KeyRange {
-                        start: key,
-                        end: vec![],
-                    }),
+                    range: Some(proto::kv::KeyRange::new_range(key, vec![])),
+                    limit: 0,
+                    continuation: vec![],
                 },
             )),
         }
@@ -345,10 +469,62 @@ impl KvRequestExt for proto::kv::KvRequest {
         proto::kv::KvRequest {
             op: Some(proto::kv::kv_request::Op::Delete(
                 proto::kv::kv_request::KvDeleteRequest {
-                    range: Some(proto::kv::KeyRange {
-                        start: key,
-                        end: vec![],
+                    range: Some(proto::kv::KeyRange::new_range(key, vec![])),
+                    limit: 0,
+                    continuation: vec![],
+                },
+            )),
+        }
+    }
+    fn new_cas(kv: proto::kv::KvPair, expected_version: u64) -> Self {
+        proto::kv::KvRequest {
+            op: Some(proto::kv::kv_request::Op::Cas(
+                proto::kv::kv_request::KvCasRequest {
+                    kv: Some(kv),
+                    expected_version,
+                    delete: false,
+                },
+            )),
+        }
+    }
+    fn new_cas_delete(key: Vec<u8>, expected_version: u64) -> Self {
+        proto::kv::KvRequest {
+            op: Some(proto::kv::kv_request::Op::Cas(
+                proto::kv::kv_request::KvCasRequest {
+                    kv: Some(proto::kv::KvPair {
+                        key,
+                        values: vec![],
                     }),
+                    expected_version,
+                    delete: true,
+                },
+            )),
+        }
+    }
+    fn new_get_range(
+        range: proto::kv::KeyRange,
+        idxs: Vec<DataItemIdx>,
+        limit: u32,
+        continuation: Vec<u8>,
+    ) -> Self {
+        proto::kv::KvRequest {
+            op: Some(proto::kv::kv_request::Op::Get(
+                proto::kv::kv_request::KvGetRequest {
+                    idxs: idxs.iter().map(|v| *v as u32).collect(),
+                    range: Some(range),
+                    limit,
+                    continuation,
+                },
+            )),
+        }
+    }
+    fn new_delete_range(range: proto::kv::KeyRange, limit: u32, continuation: Vec<u8>) -> Self {
+        proto::kv::KvRequest {
+            op: Some(proto::kv::kv_request::Op::Delete(
+                proto::kv::kv_request::KvDeleteRequest {
+                    range: Some(range),
+                    limit,
+                    continuation,
                 },
             )),
         }
@@ -363,10 +539,21 @@ impl KvRequestExt for proto::kv::KvRequest {
                     } else {
                         vec![]
                     },
-                    range: Some(proto::kv::KeyRange {
-                        start: key,
-                        end: vec![],
-                    }),
+                    range: Some(proto::kv::KeyRange::new_range(key, vec![])),
+                    ttl_ms: 0,
+                    renew: false,
+                },
+            )),
+        }
+    }
+    fn new_watch(range: proto::kv::KeyRange, from_revision: u64, timeout_ms: u32) -> Self {
+        proto::kv::KvRequest {
+            op: Some(proto::kv::kv_request::Op::Watch(
+                proto::kv::KvWatchRequest {
+                    range: Some(range),
+                    from_revision,
+                    timeout_ms,
+                    limit: 0,
                 },
             )),
         }
@@ -513,6 +700,7 @@ impl ProtoExtDataEventTrigger for DataEventTrigger {
             DataEventTrigger::New | DataEventTrigger::NewWithCondition { .. } => {
                 Trigger::EventNew(DataEventTriggerNew { key, opeid })
             }
+            DataEventTrigger::Expire => Trigger::EventExpire(DataEventTriggerExpire { key }),
         }
     }
 }
@@ -535,9 +723,44 @@ impl ProtoExtDataScheduleContext for proto::DataScheduleContext {
 }
 
 // Example usage in tests
+pub trait ProtoExtNodeMember: Sized {
+    fn new_member(id: NodeID, conf: &NodeConfig) -> Self;
+    fn to_node_config(&self) -> WSResult<(NodeID, NodeConfig)>;
+}
+
+impl ProtoExtNodeMember for proto::cluster::NodeMember {
+    fn new_member(id: NodeID, conf: &NodeConfig) -> Self {
+        Self {
+            node_id: id,
+            addr: conf.addr.to_string(),
+            spec: conf.spec.iter().cloned().collect(),
+            domain: conf.domain().unwrap_or_default().to_owned(),
+        }
+    }
+    fn to_node_config(&self) -> WSResult<(NodeID, NodeConfig)> {
+        let addr = self
+            .addr
+            .parse()
+            .map_err(|_| WsNetworkLogicErr::InvalidNodeAddr {
+                node: self.node_id,
+                addr: self.addr.clone(),
+            })?;
+        let domain = if self.domain.is_empty() {
+            None
+        } else {
+            Some(self.domain.clone())
+        };
+        Ok((
+            self.node_id,
+            NodeConfig::new(addr, domain, self.spec.iter().cloned().collect()),
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::general::app::condition::TriggerCondition;
 
     #[test]
     fn test_data_event_trigger_conversion() {
@@ -555,7 +778,7 @@ mod tests {
 
         // Test WriteWithCondition (should produce same proto as Write)
         let write_cond_trigger = DataEventTrigger::WriteWithCondition {
-            condition: "test_condition".to_string(),
+            condition: TriggerCondition::parse("size > 0").unwrap(),
         }
         .into_proto_trigger(key.clone(), opeid);
         if let Trigger::EventWrite(trigger) = write_cond_trigger {
@@ -576,7 +799,7 @@ mod tests {
 
         // Test NewWithCondition (should produce same proto as New)
         let new_cond_trigger = DataEventTrigger::NewWithCondition {
-            condition: "test_condition".to_string(),
+            condition: TriggerCondition::parse("size > 0").unwrap(),
         }
         .into_proto_trigger(key.clone(), opeid);
         if let Trigger::EventNew(trigger) = new_cond_trigger {
@@ -585,5 +808,30 @@ mod tests {
         } else {
             panic!("Expected EventNew trigger");
         }
+
+        // Test Expire
+        let expire_trigger = DataEventTrigger::Expire.into_proto_trigger(key.clone(), opeid);
+        if let Trigger::EventExpire(trigger) = expire_trigger {
+            assert_eq!(trigger.key, key);
+        } else {
+            panic!("Expected EventExpire trigger");
+        }
+    }
+
+    #[test]
+    fn test_key_range_contains() {
+        let key = proto::kv::KeyRange::new_range(b"user_42".to_vec(), vec![]);
+        assert!(key.contains(b"user_42"));
+        assert!(!key.contains(b"user_42_a"));
+
+        let prefix = proto::kv::KeyRange::new_prefix(b"user_42_".to_vec());
+        assert!(prefix.contains(b"user_42_a"));
+        assert!(!prefix.contains(b"user_43_a"));
+
+        let range = proto::kv::KeyRange::new_range(b"b".to_vec(), b"d".to_vec());
+        assert!(range.contains(b"b"));
+        assert!(range.contains(b"c_long"));
+        assert!(!range.contains(b"d"));
+        assert!(!range.contains(b"a"));
     }
 }
@@ -60,25 +60,25 @@ spec: [meta,worker]
     ))
     .unwrap();
 
-    let sys1 = Sys::new(NodesConfig {
-        peers: {
+    let sys1 = Sys::new(NodesConfig::new(
+        (1, node1.clone()),
+        {
             let mut temp = HashMap::new();
             let _ = temp.insert(0, node0.clone());
             temp
         },
-        this: (1, node1.clone()),
-        file_dir: "test_temp_dir2".into(),
-    });
+        "test_temp_dir2".into(),
+    ));
 
-    let sys0 = Sys::new(NodesConfig {
-        peers: {
+    let sys0 = Sys::new(NodesConfig::new(
+        (0, node0.clone()),
+        {
             let mut temp = HashMap::new();
             let _ = temp.insert(1, node1.clone());
             temp
         },
-        this: (0, node0.clone()),
-        file_dir: "test_temp_dir1".into(),
-    });
+        "test_temp_dir1".into(),
+    ));
 
     tracing::info!("starting sys1");
     let sys0_handle = sys0.test_start_all().await;
@@ -38,7 +38,13 @@ pub mod util;
 async fn main() {
     start_tracing();
     let args = CmdArgs::parse();
-    let config = config::read_config(args.this_id, args.files_dir);
+    let config = match config::read_config(args.this_id, args.files_dir) {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("invalid node config: {:?}", err);
+            std::process::exit(1);
+        }
+    };
     tracing::info!("config: {:?}", config);
     // dist_kv_raft::tikvraft_proxy::start();
     let mut sys = Sys::new(config);
@@ -107,7 +113,7 @@ pub fn start_tracing() {
 pub fn new_test_systems() -> Vec<Sys> {
     let mut systems = vec![];
     for i in 1..4 {
-        let config = config::read_config(i, "node_config.yaml");
+        let config = config::read_config(i, "node_config.yaml").unwrap();
         tracing::info!("config: {:?}", config);
         systems.push(Sys::new(config));
     }
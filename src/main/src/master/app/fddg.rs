@@ -2,7 +2,7 @@ use crate::general::data::m_data_general::DATA_UID_PREFIX_FN_KV;
 use crate::new_map;
 use crate::util::container::sync_trie::SyncedTrie;
 use crate::{
-    general::app::{AppType, FnMeta},
+    general::app::{AppType, FnMeta, KeyPattern},
     result::WSResult,
 };
 use std::collections::HashMap;
@@ -69,6 +69,27 @@ impl FDDGMgmt {
         binded_funcs
     }
 
+    /// Native apps bind raw data ids, others bind keys of the function kv space.
+    /// Only the literal prefix goes into the trie, the whole pattern is checked by `FnMeta::triggered_by`.
+    fn trigger_key(app_type: AppType, key_pattern: &KeyPattern) -> String {
+        if app_type == AppType::Native {
+            key_pattern.literal_prefix().to_owned()
+        } else {
+            format!("{}{}", DATA_UID_PREFIX_FN_KV, key_pattern.literal_prefix())
+        }
+    }
+
+    /// The key seen by the function, that is the data id without the function kv prefix.
+    pub fn trigger_data_key(app_type: AppType, data_unique_id: &str) -> &str {
+        if app_type == AppType::Native {
+            data_unique_id
+        } else {
+            data_unique_id
+                .strip_prefix(DATA_UID_PREFIX_FN_KV)
+                .unwrap_or(data_unique_id)
+        }
+    }
+
     pub fn add_fn_trigger(
         &self,
         (app_name, app_type): (&str, AppType),
@@ -80,15 +101,7 @@ impl FDDGMgmt {
                     continue;
                 };
 
-                let mut _hold = None;
-                let insert_key: &str = if app_type == AppType::Native {
-                    // without uid prefix
-                    &*key_pattern.0
-                } else {
-                    // with uid prefix
-                    _hold = Some(format!("{}{}", DATA_UID_PREFIX_FN_KV, key_pattern.0));
-                    &*_hold.as_ref().unwrap()
-                };
+                let insert_key = Self::trigger_key(app_type, key_pattern);
 
                 // let new_map_cb = || {
 
@@ -0,0 +1,70 @@
+use crate::general::app::{schedule::FnSchedule, AppMeta};
+use crate::sys::NodeID;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The latest tick of a scheduled function, kept by master as `KeyTypeFnScheduleTick`.
+///
+/// Recorded before it's dispatched to `node` and again once the node took it, a new master
+/// dispatches a tick not taken yet again, the worker drops it if it took it before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FnScheduleTick {
+    pub tick_ms: i64,
+    pub node: NodeID,
+    pub dispatched: bool,
+}
+
+/// Ticks recorded before they were tracked as pending were all dispatched.
+impl From<i64> for FnScheduleTick {
+    fn from(tick_ms: i64) -> Self {
+        Self {
+            tick_ms,
+            node: 0,
+            dispatched: true,
+        }
+    }
+}
+
+// scheduled functions of all apps
+// - need update when app uploaded
+// - ticked by master, see `MasterAppMgmt::tick_schedules`
+pub struct FnScheduleMgmt {
+    // app name -> fn name -> schedule
+    schedules: RwLock<HashMap<String, HashMap<String, FnSchedule>>>,
+}
+
+impl FnScheduleMgmt {
+    pub fn new() -> Self {
+        Self {
+            schedules: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the schedules of the app, functions removed from it stop firing.
+    pub fn update_app(&self, app_name: &str, app_meta: &AppMeta) {
+        let fns: HashMap<String, FnSchedule> = app_meta
+            .fns
+            .iter()
+            .filter_map(|(fn_name, fn_meta)| Some((fn_name.clone(), fn_meta.schedule.clone()?)))
+            .collect();
+        let mut schedules = self.schedules.write();
+        if fns.is_empty() {
+            let _ = schedules.remove(app_name);
+        } else {
+            let _ = schedules.insert(app_name.to_owned(), fns);
+        }
+    }
+
+    // return (app, fn, schedule)
+    pub fn all(&self) -> Vec<(String, String, FnSchedule)> {
+        self.schedules
+            .read()
+            .iter()
+            .flat_map(|(app, fns)| {
+                fns.iter()
+                    .map(move |(func, schedule)| (app.clone(), func.clone(), schedule.clone()))
+            })
+            .collect()
+    }
+}
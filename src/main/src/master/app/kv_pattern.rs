@@ -0,0 +1,67 @@
+use crate::general::app::{version, AppMeta, FnMeta};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+// ttls and replica counts of function kv keys, by the patterns in app.yaml
+// - need update when app uploaded
+// - applied by master when planning writes, see `DataMaster::rpc_handler_data_version_and_sche`
+pub struct KvPatternMgmt {
+    // app name -> fn name -> fn meta, only functions having a ttl or a replica count
+    fns: RwLock<HashMap<String, HashMap<String, FnMeta>>>,
+}
+
+impl KvPatternMgmt {
+    pub fn new() -> Self {
+        Self {
+            fns: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the patterns of the app.
+    pub fn update_app(&self, app_name: &str, app_meta: &AppMeta) {
+        let fns: HashMap<String, FnMeta> = app_meta
+            .fns
+            .iter()
+            .filter(|(_, fn_meta)| {
+                fn_meta.data_accesses.as_ref().map_or(false, |accesses| {
+                    accesses
+                        .values()
+                        .any(|access| access.ttl_ms.is_some() || access.replicas.is_some())
+                })
+            })
+            .map(|(fn_name, fn_meta)| (fn_name.clone(), fn_meta.clone()))
+            .collect();
+        let mut all = self.fns.write();
+        if fns.is_empty() {
+            let _ = all.remove(app_name);
+        } else {
+            let _ = all.insert(app_name.to_owned(), fns);
+        }
+    }
+
+    /// Ttl of `key` put by `app_func` (`<app>/<fn>`) without one, 0 when the key never expires.
+    pub fn default_ttl_ms(&self, app_func: &str, key: &str) -> u64 {
+        let Some((app, func)) = app_func.split_once('/') else {
+            return 0;
+        };
+        // pinned versions take the ttls of the active one
+        let app = version::split_versioned_app(app).0;
+        self.fns
+            .read()
+            .get(app)
+            .and_then(|fns| fns.get(func))
+            .and_then(|fn_meta| fn_meta.default_ttl_ms(key))
+            .unwrap_or(0)
+    }
+
+    /// Copies to keep of `key`, the most asked by any function's pattern, 0 when none asks.
+    pub fn replicas(&self, key: &str) -> u32 {
+        self.fns
+            .read()
+            .values()
+            .flat_map(|fns| fns.values())
+            .filter_map(|fn_meta| fn_meta.replicas(key))
+            .max()
+            .unwrap_or(0)
+    }
+}
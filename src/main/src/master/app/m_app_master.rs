@@ -1,26 +1,44 @@
 use crate::general::app::m_executor::Executor;
 use crate::general::app::{AppMeta, AppMetaManager};
+use crate::general::data::m_kv_store_engine::{
+    KeyType, KeyTypeAppMeta, KeyTypeFnScheduleTick, KvAdditionalConf, KvStoreEngine,
+};
+use crate::general::network::m_master_election::MasterElection;
 use crate::general::network::m_p2p::P2PModule;
 use crate::logical_module_view_impl;
 use crate::master::app::fddg::FDDGMgmt;
+use crate::master::app::fn_schedule::{FnScheduleMgmt, FnScheduleTick};
+use crate::master::app::kv_pattern::KvPatternMgmt;
 use crate::master::m_master::Master;
 use crate::result::WSResult;
 use crate::sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef};
 use crate::util::JoinHandleWrapper;
 use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use ws_derive::LogicalModule;
 
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 logical_module_view_impl!(MasterAppMgmtView);
 // access general app
 logical_module_view_impl!(MasterAppMgmtView, appmeta_manager, AppMetaManager);
 logical_module_view_impl!(MasterAppMgmtView, p2p, P2PModule);
 logical_module_view_impl!(MasterAppMgmtView, executor, Executor);
 logical_module_view_impl!(MasterAppMgmtView, master, Option<Master>);
+logical_module_view_impl!(MasterAppMgmtView, kv_store_engine, KvStoreEngine);
+logical_module_view_impl!(MasterAppMgmtView, master_election, MasterElection);
+logical_module_view_impl!(MasterAppMgmtView, app_master, Option<MasterAppMgmt>);
 
 #[derive(LogicalModule)]
 pub struct MasterAppMgmt {
     view: MasterAppMgmtView,
     pub fddg: FDDGMgmt,
+    pub schedules: FnScheduleMgmt,
+    pub kv_patterns: KvPatternMgmt,
+    /// (app, fn) of the schedule ticks being dispatched
+    dispatching: Mutex<HashSet<(String, String)>>,
 }
 
 #[async_trait]
@@ -32,6 +50,9 @@ impl LogicalModule for MasterAppMgmt {
         Self {
             view: MasterAppMgmtView::new(args.logical_modules_ref.clone()),
             fddg: FDDGMgmt::new(),
+            schedules: FnScheduleMgmt::new(),
+            kv_patterns: KvPatternMgmt::new(),
+            dispatching: Mutex::new(HashSet::new()),
         }
     }
 
@@ -41,7 +62,17 @@ impl LogicalModule for MasterAppMgmt {
     }
 
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
-        Ok(vec![])
+        // kv store is opened in start, so uploaded apps are loaded here instead of init
+        self.load_persisted_apps().await?;
+
+        let view = self.view.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SCHEDULE_CHECK_INTERVAL).await;
+                view.app_master().tick_schedules().await;
+            }
+        });
+        Ok(vec![task.into()])
     }
 }
 
@@ -51,9 +82,140 @@ impl MasterAppMgmt {
             self.fddg
                 .add_fn_trigger((&app_name, app_meta.app_type), (&fn_name, &fn_meta))?;
         }
+        self.schedules.update_app(app_name, app_meta);
+        self.kv_patterns.update_app(app_name, app_meta);
         Ok(())
     }
 
+    /// Fire the due ticks of scheduled functions, only the master does it.
+    ///
+    /// A tick is recorded as pending on its node and replicated to the master candidates before
+    /// it's dispatched, then recorded as dispatched. A pending tick, left by a failed dispatch or
+    /// by the previous master, is dispatched again to the same node, which runs each tick once.
+    async fn tick_schedules(&self) {
+        if !self.view.p2p().nodes_config.this_is_master() {
+            return;
+        }
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        for (app, func, schedule) in self.schedules.all() {
+            if self
+                .dispatching
+                .lock()
+                .contains(&(app.clone(), func.clone()))
+            {
+                continue;
+            }
+            let key = KeyTypeFnScheduleTick {
+                app: app.as_bytes(),
+                func: func.as_bytes(),
+            };
+            let last = self
+                .view
+                .kv_store_engine()
+                .get(&key, false, KvAdditionalConf::default())
+                .map(|(_, last)| last);
+            let (tick_ms, node) = match last {
+                // newly scheduled, ticks before now don't fire
+                None => {
+                    let first = FnScheduleTick {
+                        tick_ms: now_ms,
+                        node: 0,
+                        dispatched: true,
+                    };
+                    if let Err(err) = self.record_tick(&key, first).await {
+                        tracing::warn!("record schedule of {}/{} failed: {:?}", app, func, err);
+                    }
+                    continue;
+                }
+                Some(last) if !last.dispatched => (last.tick_ms, Some(last.node)),
+                Some(last) => match schedule.due_tick(last.tick_ms, now_ms) {
+                    Some(tick_ms) => (tick_ms, None),
+                    None => continue,
+                },
+            };
+            let nodes_config = &self.view.p2p().nodes_config;
+            let node = match node.filter(|node| nodes_config.get_nodeconfig(*node).is_some()) {
+                Some(node) => node,
+                // a new tick, or the node of the pending one left
+                None => {
+                    let node = match self.view.master().select_schedule_node(&app, &func).await {
+                        Ok(node) => node,
+                        Err(err) => {
+                            tracing::warn!("schedule {}/{} failed: {:?}", app, func, err);
+                            continue;
+                        }
+                    };
+                    let pending = FnScheduleTick {
+                        tick_ms,
+                        node,
+                        dispatched: false,
+                    };
+                    if let Err(err) = self.record_tick(&key, pending).await {
+                        tracing::warn!(
+                            "record schedule tick of {}/{} failed: {:?}",
+                            app,
+                            func,
+                            err
+                        );
+                        continue;
+                    }
+                    node
+                }
+            };
+
+            let _ = self.dispatching.lock().insert((app.clone(), func.clone()));
+            let view = self.view.clone();
+            let _ = tokio::spawn(async move {
+                let app_master = view.app_master();
+                let key = KeyTypeFnScheduleTick {
+                    app: app.as_bytes(),
+                    func: func.as_bytes(),
+                };
+                let dispatched = FnScheduleTick {
+                    tick_ms,
+                    node,
+                    dispatched: true,
+                };
+                let res = match view
+                    .master()
+                    .trigger_schedule_call(&app, &func, tick_ms, node)
+                    .await
+                {
+                    Ok(()) => app_master.record_tick(&key, dispatched).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = res {
+                    tracing::warn!(
+                        "dispatch schedule tick {} of {}/{} to node {} failed, will retry: {:?}",
+                        tick_ms,
+                        app,
+                        func,
+                        node,
+                        err
+                    );
+                }
+                let _ = app_master.dispatching.lock().remove(&(app, func));
+            });
+        }
+    }
+
+    async fn record_tick(
+        &self,
+        key: &KeyTypeFnScheduleTick<'_>,
+        tick: FnScheduleTick,
+    ) -> WSResult<()> {
+        self.view
+            .master_election()
+            .replicate(vec![(
+                key.make_key(),
+                Some(bincode::serialize(&tick).unwrap()),
+            )])
+            .await
+    }
+
     async fn load_apps(&self) -> WSResult<()> {
         // load app triggers to fddg
         // - for each native apps
@@ -61,8 +223,26 @@ impl MasterAppMgmt {
             self.update_app(app_name, app_meta).await?;
         }
 
-        // - for each existing apps
+        Ok(())
+    }
 
+    /// Load the triggers of uploaded apps, whose metas the data master keeps in the kv store.
+    pub async fn load_persisted_apps(&self) -> WSResult<()> {
+        let prefix = [KeyTypeAppMeta(&[]).id()];
+        for (key, value) in self.view.kv_store_engine().scan_raw_prefix(&prefix) {
+            let decoded = bincode::deserialize::<Vec<u8>>(&key[prefix.len()..])
+                .and_then(|app| Ok((app, bincode::deserialize::<Vec<u8>>(&value)?)))
+                .and_then(|(app, meta)| Ok((app, AppMeta::decode(&meta)?)));
+            let (app, meta) = match decoded {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    tracing::warn!("skip undecodable persisted app meta: {:?}", err);
+                    continue;
+                }
+            };
+            self.update_app(&String::from_utf8_lossy(&app), &meta)
+                .await?;
+        }
         Ok(())
     }
 }
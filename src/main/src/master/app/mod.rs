@@ -1,4 +1,6 @@
 pub mod fddg;
+pub mod fn_schedule;
+pub mod kv_pattern;
 pub mod m_app_master;
 
 #[cfg(test)]
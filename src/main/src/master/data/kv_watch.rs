@@ -0,0 +1,323 @@
+use crate::general::data::m_data_general::unix_now_ms;
+use crate::general::network::proto::kv::{KeyRange, KvWatchEvent, KvWatchRequest, KvWatchResponse};
+use crate::general::network::proto_ext::ProtoExtKeyRange;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Events kept for the watches resuming from an older revision.
+const KV_WATCH_BACKLOG: usize = 10000;
+/// Max events returned by one watch.
+const KV_WATCH_MAX_EVENTS: usize = 1000;
+const KV_WATCH_DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a watch waits for its first event.
+pub const KV_WATCH_MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+// recent puts and deletes of function kv keys, ordered by revision
+// - the revision is reserved under the key's meta lock, so the events of a key are in the order
+//   of its versions; the event is published once the write is replicated, and seen by watches
+//   only after all the events reserved before it
+// - only kept in memory, watches older than the backlog or than this master are told they're compacted
+pub struct KvWatchHub {
+    backlog: Mutex<Backlog>,
+    published: Notify,
+}
+
+struct Backlog {
+    events: VecDeque<KvWatchEvent>,
+    // the revisions below aren't kept
+    first_kept: u64,
+    next_revision: u64,
+    // reserved revisions not visible yet, with their event once published
+    pending: BTreeMap<u64, Option<KvWatchEvent>>,
+}
+
+impl Backlog {
+    /// Revisions up to it are visible.
+    fn latest_revision(&self) -> u64 {
+        match self.pending.keys().next() {
+            Some(first_pending) => first_pending - 1,
+            None => self.next_revision - 1,
+        }
+    }
+}
+
+/// A reserved revision, given up when dropped without publishing.
+pub struct KvWatchTicket<'a> {
+    hub: &'a KvWatchHub,
+    revision: u64,
+    published: bool,
+}
+
+impl KvWatchTicket<'_> {
+    /// `key` is the function kv key, `version` the one written or deleted.
+    pub fn publish(mut self, key: Vec<u8>, delete: bool, version: u64) {
+        self.published = true;
+        self.hub.settle(
+            self.revision,
+            Some(KvWatchEvent {
+                key,
+                delete,
+                version,
+                revision: self.revision,
+            }),
+        );
+    }
+}
+
+impl Drop for KvWatchTicket<'_> {
+    fn drop(&mut self) {
+        if !self.published {
+            self.hub.settle(self.revision, None);
+        }
+    }
+}
+
+impl KvWatchHub {
+    pub fn new() -> Self {
+        // above the revisions of the last master, unless it published more than 1000 events a ms
+        let first_revision = unix_now_ms() * 1000;
+        Self {
+            backlog: Mutex::new(Backlog {
+                events: VecDeque::new(),
+                first_kept: first_revision,
+                next_revision: first_revision,
+                pending: BTreeMap::new(),
+            }),
+            published: Notify::new(),
+        }
+    }
+
+    /// Reserves the revision of an event, taken under the key's meta lock.
+    pub fn reserve(&self) -> KvWatchTicket<'_> {
+        let mut backlog = self.backlog.lock();
+        let revision = backlog.next_revision;
+        backlog.next_revision += 1;
+        let _ = backlog.pending.insert(revision, None);
+        KvWatchTicket {
+            hub: self,
+            revision,
+            published: false,
+        }
+    }
+
+    /// Fills the reserved `revision`, none when its write failed, and shows the events no longer
+    /// waiting for an earlier one.
+    fn settle(&self, revision: u64, event: Option<KvWatchEvent>) {
+        let mut shown = false;
+        {
+            let mut guard = self.backlog.lock();
+            let backlog = &mut *guard;
+            match event {
+                Some(event) => {
+                    let _ = backlog.pending.insert(revision, Some(event));
+                }
+                None => {
+                    let _ = backlog.pending.remove(&revision);
+                }
+            }
+            while let Some(entry) = backlog.pending.first_entry() {
+                // still waiting for its write
+                if entry.get().is_none() {
+                    break;
+                }
+                if let Some(event) = entry.remove() {
+                    backlog.events.push_back(event);
+                    shown = true;
+                }
+            }
+            while backlog.events.len() > KV_WATCH_BACKLOG {
+                let dropped = backlog.events.pop_front().unwrap();
+                backlog.first_kept = dropped.revision + 1;
+            }
+        }
+        if shown {
+            self.published.notify_waiters();
+        }
+    }
+
+    fn latest_revision(&self) -> u64 {
+        self.backlog.lock().latest_revision()
+    }
+
+    /// Events in `range` after `from_revision`, at most `limit` of them.
+    fn collect(&self, range: &KeyRange, from_revision: u64, limit: usize) -> KvWatchResponse {
+        let backlog = self.backlog.lock();
+        let latest = backlog.latest_revision();
+        if from_revision + 1 < backlog.first_kept {
+            return KvWatchResponse {
+                events: vec![],
+                revision: latest,
+                compacted: true,
+                err_msg: String::new(),
+            };
+        }
+        let mut events = vec![];
+        // all the events up to it are checked
+        let mut revision = latest.max(from_revision);
+        let start = backlog
+            .events
+            .partition_point(|event| event.revision <= from_revision);
+        for event in backlog.events.range(start..) {
+            if !range.contains(&event.key) {
+                continue;
+            }
+            if events.len() == limit {
+                revision = event.revision - 1;
+                break;
+            }
+            events.push(event.clone());
+        }
+        KvWatchResponse {
+            events,
+            revision,
+            compacted: false,
+            err_msg: String::new(),
+        }
+    }
+
+    /// Waits for the events of the watched keys after `from_revision` until the timeout,
+    ///  answers with all the events there are once some come.
+    pub async fn watch(&self, req: &KvWatchRequest) -> KvWatchResponse {
+        let Some(range) = req.range.as_ref() else {
+            return KvWatchResponse {
+                revision: req.from_revision,
+                err_msg: "no key range to watch".to_owned(),
+                ..Default::default()
+            };
+        };
+        let from_revision = match req.from_revision {
+            0 => self.latest_revision(),
+            from_revision => from_revision,
+        };
+        let limit = match req.limit as usize {
+            0 => KV_WATCH_MAX_EVENTS,
+            limit => limit.min(KV_WATCH_MAX_EVENTS),
+        };
+        let timeout = match req.timeout_ms {
+            0 => KV_WATCH_DEFAULT_TIMEOUT,
+            timeout_ms => Duration::from_millis(timeout_ms as u64).min(KV_WATCH_MAX_TIMEOUT),
+        };
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let published = self.published.notified();
+            tokio::pin!(published);
+            // registered before collecting, so an event published in between isn't missed
+            let _ = published.as_mut().enable();
+            let resp = self.collect(range, from_revision, limit);
+            if !resp.events.is_empty() || resp.compacted {
+                return resp;
+            }
+            if tokio::time::timeout_at(deadline, published).await.is_err() {
+                return resp;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KvWatchHub;
+    use crate::general::network::proto::kv::{KeyRange, KvWatchRequest};
+    use crate::general::network::proto_ext::ProtoExtKeyRange;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn watch_req(range: KeyRange, from_revision: u64) -> KvWatchRequest {
+        KvWatchRequest {
+            range: Some(range),
+            from_revision,
+            timeout_ms: 100,
+            limit: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_watch_resume() {
+        let hub = KvWatchHub::new();
+        let start = hub.latest_revision();
+        hub.reserve().publish(b"user_1".to_vec(), false, 1);
+        hub.reserve().publish(b"order_1".to_vec(), false, 1);
+        hub.reserve().publish(b"user_1".to_vec(), true, 1);
+
+        let prefix = KeyRange::new_prefix(b"user_".to_vec());
+        let resp = hub.watch(&watch_req(prefix.clone(), start)).await;
+        assert!(!resp.compacted);
+        let got: Vec<_> = resp.events.iter().map(|e| (e.delete, e.version)).collect();
+        assert_eq!(got, vec![(false, 1), (true, 1)]);
+        assert_eq!(resp.revision, start + 3);
+
+        // resume in the middle, paged by the limit
+        let mut req = watch_req(prefix.clone(), resp.events[0].revision);
+        req.limit = 1;
+        let resp = hub.watch(&req).await;
+        assert_eq!(resp.events.len(), 1);
+        assert!(resp.events[0].delete);
+
+        // nothing new, times out with the revision to continue from
+        let resp = hub.watch(&watch_req(prefix.clone(), resp.revision)).await;
+        assert!(resp.events.is_empty());
+        assert_eq!(resp.revision, start + 3);
+
+        // older than the backlog
+        let resp = hub.watch(&watch_req(prefix, start - 10)).await;
+        assert!(resp.compacted);
+    }
+
+    #[tokio::test]
+    async fn test_kv_watch_reserved_order() {
+        let hub = KvWatchHub::new();
+        let start = hub.latest_revision();
+        let first = hub.reserve();
+        let failed = hub.reserve();
+        let third = hub.reserve();
+        third.publish(b"k".to_vec(), false, 2);
+
+        // the later write replicated first waits for the earlier one
+        let range = KeyRange::new_prefix(b"k".to_vec());
+        let resp = hub.watch(&watch_req(range.clone(), start)).await;
+        assert!(resp.events.is_empty());
+        assert_eq!(resp.revision, start);
+
+        first.publish(b"k".to_vec(), false, 1);
+        drop(failed);
+        let resp = hub.watch(&watch_req(range, start)).await;
+        let got: Vec<_> = resp.events.iter().map(|e| e.version).collect();
+        assert_eq!(got, vec![1, 2]);
+        assert_eq!(resp.revision, start + 3);
+    }
+
+    #[tokio::test]
+    async fn test_kv_watch_wakes_on_publish() {
+        let hub = Arc::new(KvWatchHub::new());
+        let hub2 = hub.clone();
+        let waiting = tokio::spawn(async move {
+            let mut req = watch_req(KeyRange::new_range(b"k".to_vec(), vec![]), 0);
+            req.timeout_ms = 5000;
+            hub2.watch(&req).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        hub.reserve().publish(b"other".to_vec(), false, 1);
+        hub.reserve().publish(b"k".to_vec(), false, 2);
+        let resp = waiting.await.unwrap();
+        assert_eq!(resp.events.len(), 1);
+        assert_eq!(resp.events[0].version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_kv_watch_dropped_ticket() {
+        let hub = KvWatchHub::new();
+        let start = hub.latest_revision();
+        // the write failed after reserving
+        drop(hub.reserve());
+        hub.reserve().publish(b"k".to_vec(), false, 1);
+
+        let resp = hub
+            .watch(&watch_req(KeyRange::new_prefix(b"k".to_vec()), start))
+            .await;
+        let got: Vec<_> = resp.events.iter().map(|e| e.version).collect();
+        assert_eq!(got, vec![1]);
+        assert_eq!(resp.revision, start + 2);
+    }
+}
@@ -1,28 +1,40 @@
+use crate::general::app::condition::ConditionInput;
 use crate::general::app::m_executor::Executor;
-use crate::general::app::AffinityPattern;
+use crate::general::app::version;
 use crate::general::app::AppMeta;
 use crate::general::app::AppMetaManager;
-use crate::general::app::DataEventTrigger;
 use crate::general::data::m_data_general::CacheModeVisitor;
+use crate::general::network::m_master_election::{MasterElection, MetaWrite};
 use crate::general::network::m_p2p::{P2PModule, RPCCaller, RPCHandler, RPCResponsor};
 use crate::general::network::proto::{
     self, DataVersionScheduleRequest, DataVersionScheduleResponse,
 };
 use crate::general::network::proto_ext::ProtoExtDataScheduleContext;
+use crate::master::data::kv_watch::KvWatchHub;
 use crate::master::m_master::{FunctionTriggerContext, Master};
-use crate::result::WsDataError;
+use crate::master::m_metric_observor::MetricObservor;
 use crate::result::{WSResult, WSResultExt};
+use crate::result::{WsDataError, WsNetworkLogicErr};
 use crate::sys::{LogicalModulesRef, NodeID};
 use crate::util::JoinHandleWrapper;
 use crate::{
     general::data::{
         m_data_general::{
-            CacheMode, DataGeneral, DataSetMetaBuilder, DataSplit, EachNodeSplit,
+            checksum, data_expire_at, data_expired, erasure::ErasureLayout, fn_kv_key_of_unique_id,
+            new_data_unique_id_fn_kv, unix_now_ms, CacheMode, DataGeneral, DataSetMetaBuilder,
+            DataSetMetaV2, DataSplit, EachNodeSplit, GetOrDelDataArg, GetOrDelDataArgType,
             CACHE_MODE_MAP_COMMON_KV_MASK, CACHE_MODE_TIME_FOREVER_MASK,
         },
-        m_kv_store_engine::{KeyType, KeyTypeDataSetMeta, KvAdditionalConf, KvStoreEngine},
+        m_kv_store_engine::{
+            self, KeyType, KeyTypeAppMeta, KeyTypeDataExpiry, KeyTypeDataExpiryQueue,
+            KeyTypeDataFence, KeyTypeDataSetMeta, KeyTypeFnKvIndex, KvAdditionalConf,
+            KvStoreEngine,
+        },
+    },
+    master::app::{
+        fddg::{FDDGMgmt, FuncTriggerType},
+        m_app_master::MasterAppMgmt,
     },
-    master::app::{fddg::FuncTriggerType, m_app_master::MasterAppMgmt},
 };
 use crate::{
     general::network::http_handler::HttpHandler,
@@ -30,9 +42,10 @@ use crate::{
     sys::{LogicalModule, LogicalModuleNewArgs},
 };
 use async_trait::async_trait;
-use rand::{thread_rng, Rng};
-use std::collections::HashSet;
-use std::time::Duration;
+use parking_lot::Mutex;
+use rand::{seq::SliceRandom, thread_rng};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use ws_derive::LogicalModule;
 
 logical_module_view_impl!(DataMasterView);
@@ -45,12 +58,98 @@ logical_module_view_impl!(DataMasterView, http_handler, Box<dyn HttpHandler>);
 logical_module_view_impl!(DataMasterView, kv_store_engine, KvStoreEngine);
 logical_module_view_impl!(DataMasterView, executor, Executor);
 logical_module_view_impl!(DataMasterView, master, Option<Master>);
+logical_module_view_impl!(DataMasterView, master_election, MasterElection);
+logical_module_view_impl!(DataMasterView, metric_observor, Option<MetricObservor>);
+
+/// Max keys returned by one function kv range scan.
+pub const KV_SCAN_MAX_LIMIT: usize = 1000;
+/// How often data outliving its ttl is reclaimed.
+const DATA_EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// Most expired data reclaimed by one sweep, the rest waits for the next.
+const DATA_EXPIRY_SWEEP_BATCH: usize = 1000;
+/// How often the replicas of the next batch of data are checked and repaired.
+const DATA_REPAIR_INTERVAL: Duration = Duration::from_secs(10);
+/// Data checked by one repair round, the next round goes on after the last one checked.
+const DATA_REPAIR_BATCH: usize = 100;
+/// A member not answering digest requests this long counts as gone and its splits move,
+/// shorter outages are left for the node to come back.
+const DATA_REPAIR_UNREACHABLE_GONE_AFTER: Duration = Duration::from_secs(120);
+
+/// Nodes holding every item of the data whole, a function there reads it without transfer.
+fn nodes_holding_whole_data(splits: &[DataSplit]) -> HashSet<NodeID> {
+    let Some(first) = splits.first() else {
+        return HashSet::new();
+    };
+    first
+        .splits
+        .iter()
+        .map(|split| split.node_id)
+        .filter(|&node| splits.iter().all(|split| split.held_whole_by(node)))
+        .collect()
+}
+
+/// Members that stopped answering the repair, by when they were first seen failing.
+#[derive(Default)]
+struct UnreachableReplicas {
+    since: HashMap<NodeID, Instant>,
+}
+
+impl UnreachableReplicas {
+    fn answered(&mut self, node: NodeID) {
+        let _ = self.since.remove(&node);
+    }
+
+    /// Record a failed request at `now`, true once the node failed for long enough to count as gone.
+    fn failed(&mut self, node: NodeID, now: Instant) -> bool {
+        let since = *self.since.entry(node).or_insert(now);
+        now.duration_since(since) >= DATA_REPAIR_UNREACHABLE_GONE_AFTER
+    }
+}
+
+/// Split the digests answered by the replicas of one range into healthy and broken replicas.
+///
+/// Healthy ones match the digest the writer recorded when there is one, else the digest most
+/// replicas agree on, the primary's on a tie.
+fn classify_replica_digests(
+    digests: Vec<(NodeID, Vec<u8>)>,
+    recorded: Option<&[u8]>,
+) -> (Vec<NodeID>, Vec<NodeID>) {
+    let agreed = match recorded {
+        Some(recorded) => Some(recorded.to_vec()),
+        None => {
+            let mut agreed: Option<(&Vec<u8>, usize)> = None;
+            for (_, digest) in &digests {
+                let count = digests.iter().filter(|(_, d)| d == digest).count();
+                if agreed.map_or(true, |(_, most)| count > most) {
+                    agreed = Some((digest, count));
+                }
+            }
+            agreed.map(|(digest, _)| digest.clone())
+        }
+    };
+    let (healthy, broken): (Vec<_>, Vec<_>) = digests
+        .into_iter()
+        .partition(|(_, digest)| Some(digest) == agreed.as_ref());
+    (
+        healthy.into_iter().map(|(node, _)| node).collect(),
+        broken.into_iter().map(|(node, _)| node).collect(),
+    )
+}
 
 #[derive(LogicalModule)]
 pub struct DataMaster {
     view: DataMasterView,
     rpc_handler: RPCHandler<proto::DataVersionScheduleRequest>,
     rpc_caller_data_meta_update: RPCCaller<proto::DataMetaUpdateRequest>,
+    rpc_handler_scan_keys: RPCHandler<proto::kv::KvScanKeysRequest>,
+    rpc_handler_watch: RPCHandler<proto::kv::KvWatchRequest>,
+    rpc_handler_delete_meta: RPCHandler<proto::DataMetaDeleteRequest>,
+    rpc_caller_data_digest: RPCCaller<proto::DataItemDigestRequest>,
+    rpc_caller_data_repair: RPCCaller<proto::DataRepairRequest>,
+    kv_watch: KvWatchHub,
+    /// first meta key the next repair round checks, empty to start over
+    repair_cursor: Mutex<Vec<u8>>,
+    unreachable_replicas: Mutex<UnreachableReplicas>,
 }
 #[async_trait]
 impl LogicalModule for DataMaster {
@@ -62,6 +161,14 @@ impl LogicalModule for DataMaster {
             rpc_handler: RPCHandler::new(),
             view: DataMasterView::new(args.logical_modules_ref.clone()),
             rpc_caller_data_meta_update: RPCCaller::new(),
+            rpc_handler_scan_keys: RPCHandler::new(),
+            rpc_handler_watch: RPCHandler::new(),
+            rpc_handler_delete_meta: RPCHandler::new(),
+            rpc_caller_data_digest: RPCCaller::new(),
+            rpc_caller_data_repair: RPCCaller::new(),
+            kv_watch: KvWatchHub::new(),
+            repair_cursor: Mutex::new(vec![]),
+            unreachable_replicas: Mutex::new(UnreachableReplicas::default()),
             // rpc_caller_add_wait_target: RPCCaller::new(),
             // view: DataMasterView::new(args.logical_modules_ref.clone()),
         }
@@ -74,6 +181,8 @@ impl LogicalModule for DataMaster {
         tracing::info!("start as master");
         let view = self.view.clone();
         let _ = self.rpc_caller_data_meta_update.regist(view.p2p());
+        let _ = self.rpc_caller_data_digest.regist(view.p2p());
+        let _ = self.rpc_caller_data_repair.regist(view.p2p());
         // let _ = self.rpc_caller_add_wait_target.regist(view.p2p());
         let _ = self
             .rpc_handler
@@ -87,17 +196,736 @@ impl LogicalModule for DataMaster {
 
                 Ok(())
             });
-        Ok(vec![])
+        let view = self.view.clone();
+        self.rpc_handler_scan_keys
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let resp = view.data_master().scan_fn_kv_keys(&req);
+                    if let Err(err) = responsor.send_resp(resp).await {
+                        tracing::error!("send kv scan keys resp failed with err: {}", err);
+                    }
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        self.rpc_handler_watch
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let resp = view.data_master().watch_fn_kv(&req).await;
+                    if let Err(err) = responsor.send_resp(resp).await {
+                        tracing::error!("send kv watch resp failed with err: {}", err);
+                    }
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        self.rpc_handler_delete_meta
+            .regist(self.view.p2p(), move |responsor, req| {
+                let view = view.clone();
+                let _ = tokio::spawn(async move {
+                    let resp = match view.data_master().delete_data_meta(&req).await {
+                        Ok(resp) => resp,
+                        Err(err) => {
+                            tracing::warn!("delete data meta failed: {:?}", err);
+                            return;
+                        }
+                    };
+                    if let Err(err) = responsor.send_resp(resp).await {
+                        tracing::error!("send delete data meta resp failed with err: {}", err);
+                    }
+                });
+                Ok(())
+            });
+        let view = self.view.clone();
+        let sweeper = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DATA_EXPIRY_SWEEP_INTERVAL).await;
+                view.data_master().reclaim_expired_data().await;
+            }
+        });
+        let view = self.view.clone();
+        let repairer = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DATA_REPAIR_INTERVAL).await;
+                view.data_master().repair_data().await;
+            }
+        });
+        Ok(vec![sweeper.into(), repairer.into()])
     }
 }
 
 impl DataMaster {
+    /// Function kv keys in the requested range, ordered by key.
+    fn scan_fn_kv_keys(&self, req: &proto::kv::KvScanKeysRequest) -> proto::kv::KvScanKeysResponse {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            return proto::kv::KvScanKeysResponse {
+                keys: vec![],
+                continuation: vec![],
+                err_msg: format!(
+                    "node {} is not master, master is {:?}",
+                    nodes_config.this_node(),
+                    nodes_config.master_node()
+                ),
+            };
+        }
+        let Some(range) = req.range.as_ref() else {
+            return proto::kv::KvScanKeysResponse {
+                keys: vec![],
+                continuation: vec![],
+                err_msg: "no key range to scan".to_owned(),
+            };
+        };
+        let mut start = KeyTypeFnKvIndex(&range.start).make_key();
+        let end = if range.prefix {
+            m_kv_store_engine::prefix_end(&start)
+        } else {
+            Some(KeyTypeFnKvIndex(&range.end).make_key())
+        };
+        if !req.continuation.is_empty() {
+            start = start.max(KeyTypeFnKvIndex(&req.continuation).make_key());
+        }
+        let limit = match req.limit as usize {
+            0 => KV_SCAN_MAX_LIMIT,
+            limit => limit.min(KV_SCAN_MAX_LIMIT),
+        };
+        // one more to know whether there are keys left
+        let mut keys: Vec<Vec<u8>> = self
+            .view
+            .kv_store_engine()
+            .scan_raw_range(&start, end.as_deref(), limit + 1)
+            .into_iter()
+            .map(|(mut k, _)| k.split_off(1))
+            .collect();
+        let continuation = if keys.len() > limit {
+            // the first key not returned
+            keys.pop().unwrap()
+        } else {
+            vec![]
+        };
+        // expired keys are invisible until they're reclaimed
+        keys.retain(|key| {
+            !data_expired(self.view.kv_store_engine(), &new_data_unique_id_fn_kv(key))
+        });
+        proto::kv::KvScanKeysResponse {
+            keys,
+            continuation,
+            err_msg: String::new(),
+        }
+    }
+
+    /// Events of function kv keys in the watched range, see `KvWatchHub::watch`.
+    async fn watch_fn_kv(&self, req: &proto::kv::KvWatchRequest) -> proto::kv::KvWatchResponse {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            return proto::kv::KvWatchResponse {
+                revision: req.from_revision,
+                err_msg: format!(
+                    "node {} is not master, master is {:?}",
+                    nodes_config.this_node(),
+                    nodes_config.master_node()
+                ),
+                ..Default::default()
+            };
+        }
+        self.kv_watch.watch(req).await
+    }
+
+    /// Ttl of the write, the default of the writing function's key pattern when it has none.
+    fn write_ttl_ms(&self, req: &DataVersionScheduleRequest) -> u64 {
+        if req.ttl_ms != 0 {
+            return req.ttl_ms;
+        }
+        let Some(proto::data_schedule_context::OpeRole::FuncCall(call)) =
+            req.context.as_ref().and_then(|ctx| ctx.ope_role.as_ref())
+        else {
+            return 0;
+        };
+        match fn_kv_key_of_unique_id(&req.unique_id).map(std::str::from_utf8) {
+            Some(Ok(key)) => self
+                .view
+                .app_master()
+                .kv_patterns
+                .default_ttl_ms(&call.app_func, key),
+            _ => 0,
+        }
+    }
+
+    /// Copies kept of each split of the write: the upload's own count, else the most asked by
+    /// the key's patterns, else the cluster's replication factor.
+    fn write_replicas(&self, req: &DataVersionScheduleRequest) -> usize {
+        if req.replicas != 0 {
+            return req.replicas as usize;
+        }
+        let by_pattern = match fn_kv_key_of_unique_id(&req.unique_id).map(std::str::from_utf8) {
+            Some(Ok(key)) => self.view.app_master().kv_patterns.replicas(key),
+            _ => 0,
+        };
+        if by_pattern != 0 {
+            return by_pattern as usize;
+        }
+        self.view.p2p().nodes_config.replication_factor as usize
+    }
+
+    /// Reed-solomon layout for a file large enough, `None` keeps the item replicated.
+    fn erasure_layout(&self, size: usize, is_file: bool, node_cnt: usize) -> Option<ErasureLayout> {
+        let conf = self.view.p2p().nodes_config.erasure_coding.as_ref()?;
+        if !is_file || (size as u64) < conf.min_size {
+            return None;
+        }
+        let layout =
+            ErasureLayout::new(size, conf.data_shards as usize, conf.parity_shards as usize);
+        if layout.shard_cnt() > node_cnt {
+            tracing::warn!(
+                "{} erasure shards need as many nodes, only {} in cluster, replicating instead",
+                layout.shard_cnt(),
+                node_cnt
+            );
+            return None;
+        }
+        Some(layout)
+    }
+
+    /// Delete the data outliving its ttl from the meta and the nodes, only the master does it.
+    async fn reclaim_expired_data(&self) {
+        if !self.view.p2p().nodes_config.this_is_master() {
+            return;
+        }
+        let start = [KeyTypeDataExpiryQueue {
+            expire_at: 0,
+            uid: &[],
+        }
+        .id()];
+        // the queue is ordered by expiry time, so the scan stops at the data still alive
+        let end = KeyTypeDataExpiryQueue {
+            expire_at: unix_now_ms() + 1,
+            uid: &[],
+        }
+        .make_key();
+        let expired: Vec<Vec<u8>> = self
+            .view
+            .kv_store_engine()
+            .scan_raw_range(&start, Some(&end), DATA_EXPIRY_SWEEP_BATCH)
+            .into_iter()
+            .map(|(key, _)| key[1 + 8..].to_vec())
+            .collect();
+        for unique_id in expired {
+            if let Err(err) = self.reclaim_expired(&unique_id).await {
+                tracing::warn!(
+                    "reclaim expired data({:?}) failed, will retry: {:?}",
+                    String::from_utf8_lossy(&unique_id),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Drops the meta on master and the candidates first, so the data is gone for good even when
+    /// deleting the items fails on some nodes.
+    async fn reclaim_expired(&self, unique_id: &[u8]) -> WSResult<()> {
+        let kv_store_engine = self.view.kv_store_engine();
+        tracing::debug!(
+            "reclaim expired data({:?})",
+            String::from_utf8_lossy(unique_id)
+        );
+        let metakey_bytes = KeyTypeDataSetMeta(unique_id).make_key();
+        // held until committed, so the meta read below has the earlier writes applied
+        let meta_keys = self
+            .view
+            .master_election()
+            .lock_keys([metakey_bytes.clone()])
+            .await;
+        let (meta, staged, watch_ticket) = {
+            let update_version_lock = kv_store_engine.with_rwlock(&metakey_bytes);
+            let _guard = update_version_lock.write();
+            // written again meanwhile, the write requeued it
+            if !data_expired(kv_store_engine, unique_id) {
+                return Ok(());
+            }
+            let meta = kv_store_engine
+                .get(
+                    &KeyTypeDataSetMeta(unique_id),
+                    true,
+                    KvAdditionalConf::default(),
+                )
+                .map(|(_, meta)| meta);
+            let staged = self
+                .view
+                .master_election()
+                .stage(self.meta_removal(unique_id), meta_keys)?;
+            (meta, staged, self.kv_watch.reserve())
+        };
+        self.view.master_election().commit(staged).await?;
+
+        // an unsettled revision holds back the later watch events
+        match (&meta, fn_kv_key_of_unique_id(unique_id)) {
+            (Some(meta), Some(key)) => watch_ticket.publish(key.to_vec(), true, meta.version),
+            _ => drop(watch_ticket),
+        }
+        if let Some(meta) = meta {
+            // the nodes delete the items without the meta
+            if let Err(err) = self
+                .view
+                .data_general()
+                .get_or_del_datas(GetOrDelDataArg {
+                    meta: Some(meta),
+                    unique_id: unique_id.to_vec(),
+                    ty: GetOrDelDataArgType::Delete,
+                })
+                .await
+            {
+                tracing::warn!(
+                    "delete items of expired data({:?}) failed: {:?}",
+                    String::from_utf8_lossy(unique_id),
+                    err
+                );
+            }
+        }
+        self.trigger_expire_events(unique_id);
+        Ok(())
+    }
+
+    /// Drops the meta of deleted data on master and the candidates, only when the data is at the
+    /// `expected_version` if the request has one.
+    async fn delete_data_meta(
+        &self,
+        req: &proto::DataMetaDeleteRequest,
+    ) -> WSResult<proto::DataMetaDeleteResponse> {
+        let kv_store_engine = self.view.kv_store_engine();
+        let unique_id = &req.unique_id[..];
+        let metakey_bytes = KeyTypeDataSetMeta(unique_id).make_key();
+        // held until committed, so the meta read below has the earlier writes applied
+        let meta_keys = self
+            .view
+            .master_election()
+            .lock_keys([metakey_bytes.clone()])
+            .await;
+        let (meta, staged, watch_ticket) = {
+            let update_version_lock = kv_store_engine.with_rwlock(&metakey_bytes);
+            let _guard = update_version_lock.write();
+            let meta = kv_store_engine
+                .get(
+                    &KeyTypeDataSetMeta(unique_id),
+                    true,
+                    KvAdditionalConf::default(),
+                )
+                .map(|(_, meta)| meta);
+            if let Some(expected) = &req.expected_version {
+                // expired data counts as gone
+                let current_version = match &meta {
+                    Some(meta) if !data_expired(kv_store_engine, unique_id) => meta.version,
+                    _ => 0,
+                };
+                if expected.version != current_version {
+                    return Ok(proto::DataMetaDeleteResponse {
+                        serialized_meta: vec![],
+                        current_version: Some(proto::DataVersionCond {
+                            version: current_version,
+                        }),
+                    });
+                }
+            }
+            let Some(meta) = meta else {
+                return Ok(proto::DataMetaDeleteResponse::default());
+            };
+            let staged = self
+                .view
+                .master_election()
+                .stage(self.meta_removal(unique_id), meta_keys)?;
+            (meta, staged, self.kv_watch.reserve())
+        };
+        self.view.master_election().commit(staged).await?;
+        if let Some(key) = fn_kv_key_of_unique_id(unique_id) {
+            watch_ticket.publish(key.to_vec(), true, meta.version);
+        }
+        Ok(proto::DataMetaDeleteResponse {
+            serialized_meta: bincode::serialize(&meta).unwrap(),
+            current_version: None,
+        })
+    }
+
+    /// Writes dropping the meta of the data with all master keeps along, under the meta lock.
+    fn meta_removal(&self, unique_id: &[u8]) -> Vec<MetaWrite> {
+        let mut writes = vec![
+            (KeyTypeDataSetMeta(unique_id).make_key(), None),
+            (KeyTypeDataFence(unique_id).make_key(), None),
+            (KeyTypeDataExpiry(unique_id).make_key(), None),
+        ];
+        let expire_at = data_expire_at(self.view.kv_store_engine(), unique_id);
+        if expire_at != 0 {
+            writes.push((
+                KeyTypeDataExpiryQueue {
+                    expire_at,
+                    uid: unique_id,
+                }
+                .make_key(),
+                None,
+            ));
+        }
+        if let Some(key) = fn_kv_key_of_unique_id(unique_id) {
+            writes.push((KeyTypeFnKvIndex(key).make_key(), None));
+        }
+        writes
+    }
+
+    /// Copy the splits lost with a gone node or broken on a live one back from a healthy
+    /// replica, only the master does it. Each round checks the next `DATA_REPAIR_BATCH` data,
+    /// so all data is covered in turn without loading the nodes all at once.
+    async fn repair_data(&self) {
+        if !self.view.p2p().nodes_config.this_is_master() {
+            return;
+        }
+        let kv_store_engine = self.view.kv_store_engine();
+        let prefix = [KeyTypeDataSetMeta(&[]).id()];
+        let end = m_kv_store_engine::prefix_end(&prefix);
+        let start = {
+            let cursor = self.repair_cursor.lock();
+            if cursor.is_empty() {
+                prefix.to_vec()
+            } else {
+                cursor.clone()
+            }
+        };
+        let keys: Vec<Vec<u8>> = kv_store_engine
+            .scan_raw_range(&start, end.as_deref(), DATA_REPAIR_BATCH)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        *self.repair_cursor.lock() = match keys.last() {
+            // the smallest key after the last one
+            Some(last) if keys.len() == DATA_REPAIR_BATCH => {
+                let mut next = last.clone();
+                next.push(0);
+                next
+            }
+            _ => vec![],
+        };
+        let unique_ids: Vec<Vec<u8>> = keys
+            .iter()
+            .filter_map(|key| bincode::deserialize(&key[1..]).ok())
+            .collect();
+        for unique_id in unique_ids {
+            // the sweeper reclaims it soon
+            if data_expired(kv_store_engine, &unique_id) {
+                continue;
+            }
+            if let Err(err) = self.repair_data_splits(&unique_id).await {
+                tracing::warn!(
+                    "repair data({:?}) failed, will retry: {:?}",
+                    String::from_utf8_lossy(&unique_id),
+                    err
+                );
+            }
+        }
+    }
+
+    async fn repair_data_splits(&self, unique_id: &[u8]) -> WSResult<()> {
+        let kv_store_engine = self.view.kv_store_engine();
+        let Some((_, meta)) = kv_store_engine.get(
+            &KeyTypeDataSetMeta(unique_id),
+            false,
+            KvAdditionalConf::default(),
+        ) else {
+            return Ok(());
+        };
+
+        let p2p = self.view.p2p();
+        let mut synced_nodes = HashSet::new();
+        // (idx, offset, size, gone node, new node)
+        let mut moved = vec![];
+        let mut repaired = false;
+        for (idx, data_split) in meta.datas_splits.iter().enumerate() {
+            let erasure = ErasureLayout::of(data_split).is_some();
+            let mut checked = vec![];
+            for group in data_split.replica_groups() {
+                let placed: Vec<NodeID> = group.iter().map(|split| split.node_id).collect();
+                let recorded =
+                    checksum::recorded_digest(&meta, idx as u8, group[0].data_size as usize);
+                let Some((healthy, broken, gone)) = self
+                    .check_replicas(unique_id, idx as u8, meta.version, &placed, recorded)
+                    .await
+                else {
+                    // written again meanwhile, the write places all replicas
+                    return Ok(());
+                };
+                synced_nodes.extend(healthy.iter().copied());
+                checked.push((group, placed, healthy, broken, gone));
+            }
+            // a node holding any healthy shard rebuilds a lost one from the other shards
+            let shard_holders: Vec<NodeID> = checked
+                .iter()
+                .flat_map(|(_, _, healthy, _, _)| healthy.iter().copied())
+                .collect();
+            // shards of one item stay on distinct nodes
+            let mut item_nodes: Vec<NodeID> = data_split.splits.iter().map(|s| s.node_id).collect();
+
+            for (group, placed, healthy, broken, gone) in checked {
+                if broken.is_empty() && gone.is_empty() {
+                    continue;
+                }
+                let (source, erasure_shard) = match (healthy.first(), shard_holders.first()) {
+                    (Some(&source), _) => (source, None),
+                    (None, Some(&source)) if erasure => (
+                        source,
+                        Some(proto::ErasureShardRef {
+                            data_offset: group[0].data_offset,
+                        }),
+                    ),
+                    _ => {
+                        tracing::error!(
+                            "no healthy replica left for data({:?}) idx({}), broken: {:?}, gone: {:?}",
+                            String::from_utf8_lossy(unique_id),
+                            idx,
+                            broken,
+                            gone
+                        );
+                        continue;
+                    }
+                };
+                let taken = if erasure { &item_nodes } else { &placed };
+
+                // the gone nodes are replaced by nodes not holding the split yet
+                let mut candidates: Vec<NodeID> = p2p
+                    .nodes_config
+                    .all_nodes()
+                    .into_iter()
+                    .map(|(node, _)| node)
+                    .filter(|node| !taken.contains(node))
+                    .collect();
+                candidates.shuffle(&mut thread_rng());
+                let replacements: Vec<(NodeID, NodeID)> =
+                    gone.iter().copied().zip(candidates).collect();
+                if replacements.len() < gone.len() {
+                    tracing::warn!(
+                        "not enough nodes to replace {} gone replicas of data({:?}) idx({})",
+                        gone.len(),
+                        String::from_utf8_lossy(unique_id),
+                        idx
+                    );
+                }
+                if erasure {
+                    item_nodes.extend(replacements.iter().map(|(_, new_node)| *new_node));
+                }
+                let targets: Vec<u32> = broken
+                    .iter()
+                    .copied()
+                    .chain(replacements.iter().map(|(_, new_node)| *new_node))
+                    .map(|node| node as u32)
+                    .collect();
+                if targets.is_empty() {
+                    continue;
+                }
+
+                tracing::info!(
+                    "repair data({:?}) idx({}) from node {} to nodes {:?}",
+                    String::from_utf8_lossy(unique_id),
+                    idx,
+                    source,
+                    targets
+                );
+                let resp = self
+                    .rpc_caller_data_repair
+                    .call(
+                        p2p,
+                        source,
+                        proto::DataRepairRequest {
+                            unique_id: unique_id.to_vec(),
+                            idx: idx as u32,
+                            version: meta.version,
+                            targets,
+                            erasure_shard,
+                        },
+                        Some(Duration::from_secs(60)),
+                    )
+                    .await?;
+                if !resp.message.is_empty() {
+                    tracing::warn!(
+                        "repair data({:?}) idx({}) partly failed: {}",
+                        String::from_utf8_lossy(unique_id),
+                        idx,
+                        resp.message
+                    );
+                }
+                synced_nodes.extend(resp.copied.iter().map(|&node| node as NodeID));
+                repaired |= !resp.copied.is_empty();
+                for (gone_node, new_node) in replacements {
+                    if resp.copied.contains(&(new_node as u32)) {
+                        moved.push((
+                            idx,
+                            group[0].data_offset,
+                            group[0].data_size,
+                            gone_node,
+                            new_node,
+                        ));
+                    }
+                }
+            }
+        }
+        if !repaired {
+            return Ok(());
+        }
+
+        let metakey_bytes = KeyTypeDataSetMeta(unique_id).make_key();
+        // held until committed, so the meta read below has the earlier writes applied
+        let meta_keys = self
+            .view
+            .master_election()
+            .lock_keys([metakey_bytes.clone()])
+            .await;
+        let (new_meta, staged) = {
+            let update_version_lock = kv_store_engine.with_rwlock(&metakey_bytes);
+            let _guard = update_version_lock.write();
+            let Some((_, mut new_meta)) = kv_store_engine.get(
+                &KeyTypeDataSetMeta(unique_id),
+                true,
+                KvAdditionalConf::default(),
+            ) else {
+                return Ok(());
+            };
+            // written again meanwhile, the copies are outdated anyway
+            if new_meta.version != meta.version {
+                return Ok(());
+            }
+            for (idx, offset, size, gone_node, new_node) in moved {
+                if let Some(split) = new_meta.datas_splits[idx].splits.iter_mut().find(|split| {
+                    split.node_id == gone_node
+                        && split.data_offset == offset
+                        && split.data_size == size
+                }) {
+                    split.node_id = new_node;
+                }
+            }
+            new_meta.synced_nodes = synced_nodes;
+            let staged = self.view.master_election().stage(
+                vec![(
+                    metakey_bytes.clone(),
+                    Some(bincode::serialize(&new_meta).unwrap()),
+                )],
+                meta_keys,
+            )?;
+            (new_meta, staged)
+        };
+        self.view.master_election().commit(staged).await?;
+        self.notify_meta_update(unique_id, &new_meta);
+        Ok(())
+    }
+
+    /// Split the replicas of one item into healthy, broken and gone ones,
+    /// `None` when a replica already holds a newer version.
+    ///
+    /// Healthy replicas hold the item at `version`, see [`classify_replica_digests`].
+    /// Replicas not in the cluster are gone, as are members not answering for
+    /// `DATA_REPAIR_UNREACHABLE_GONE_AFTER`, shorter outages are skipped.
+    async fn check_replicas(
+        &self,
+        unique_id: &[u8],
+        idx: u8,
+        version: u64,
+        placed: &[NodeID],
+        recorded: Option<&[u8]>,
+    ) -> Option<(Vec<NodeID>, Vec<NodeID>, Vec<NodeID>)> {
+        let p2p = self.view.p2p();
+        let (alive, mut gone): (Vec<NodeID>, Vec<NodeID>) = placed
+            .iter()
+            .partition(|&&node| p2p.nodes_config.node_exist(node));
+        let resps = futures::future::join_all(alive.iter().map(|&node| {
+            self.rpc_caller_data_digest.call(
+                p2p,
+                node,
+                proto::DataItemDigestRequest {
+                    unique_id: unique_id.to_vec(),
+                    idxs: vec![idx as u32],
+                },
+                Some(Duration::from_secs(30)),
+            )
+        }))
+        .await;
+
+        let mut digests: Vec<(NodeID, Vec<u8>)> = vec![];
+        let mut broken = vec![];
+        for (&node, resp) in alive.iter().zip(resps) {
+            if resp.is_ok() {
+                self.unreachable_replicas.lock().answered(node);
+            }
+            match resp {
+                Ok(resp) if resp.version > version => return None,
+                Ok(mut resp)
+                    if resp.version == version
+                        && resp.digests.first().map_or(false, |d| !d.is_empty()) =>
+                {
+                    digests.push((node, resp.digests.swap_remove(0)))
+                }
+                Ok(_) => broken.push(node),
+                Err(err) => {
+                    tracing::warn!("node {} not answering digest request: {}", node, err);
+                    if self
+                        .unreachable_replicas
+                        .lock()
+                        .failed(node, Instant::now())
+                    {
+                        gone.push(node);
+                    }
+                }
+            }
+        }
+
+        let (healthy, broken_digest) = classify_replica_digests(digests, recorded);
+        broken.extend(broken_digest);
+        Some((healthy, broken, gone))
+    }
+
+    /// Functions bound by `trigger_by_expire` are told the data expired.
+    fn trigger_expire_events(&self, unique_id: &[u8]) {
+        let Ok(unique_id_str) = std::str::from_utf8(unique_id) else {
+            return;
+        };
+        let binded_funcs = self
+            .view
+            .app_master()
+            .fddg
+            .get_binded_funcs(unique_id_str, FuncTriggerType::DataDelete);
+        for (app_name, (app_type, fn_names)) in binded_funcs {
+            let trigger_data_key = FDDGMgmt::trigger_data_key(app_type, unique_id_str);
+            for (fn_name, fnmeta) in fn_names {
+                if fnmeta.expiry_triggered_by(trigger_data_key).is_none() {
+                    continue;
+                }
+                let view = self.view.clone();
+                let app_name = app_name.clone();
+                let unique_id = unique_id.to_vec();
+                let _ = tokio::spawn(async move {
+                    if let Err(e) = view
+                        .master()
+                        .trigger_expire_call(&app_name, &fn_name, unique_id)
+                        .await
+                    {
+                        tracing::error!(
+                            "Failed to trigger function {}/{} on expiry: {:?}",
+                            app_name,
+                            fn_name,
+                            e
+                        );
+                    }
+                });
+            }
+        }
+    }
+
+    /// Plans the splits of a write, with the functions it triggers once committed.
     async fn plan_for_write_data(
         &self,
         data_unique_id: &[u8],
         context: &proto::DataScheduleContext,
         func_trigger_type: FuncTriggerType,
-    ) -> WSResult<(Vec<CacheMode>, Vec<DataSplit>, Vec<NodeID>)> {
+        replicas: usize,
+    ) -> WSResult<(
+        Vec<CacheMode>,
+        Vec<DataSplit>,
+        Vec<NodeID>,
+        Vec<FunctionTriggerContext>,
+    )> {
         // 如果不是有效的 UTF-8 字符串，直接返回空结果
         let data_unique_id_str = match std::str::from_utf8(data_unique_id) {
             Ok(s) => s,
@@ -108,6 +936,7 @@ impl DataMaster {
                         .cache_mode,
                     vec![],
                     vec![],
+                    vec![],
                 ))
             }
         };
@@ -120,43 +949,72 @@ impl DataMaster {
             .get_binded_funcs(data_unique_id_str, func_trigger_type);
 
         // 收集所有调度节点作为缓存节点
-        let cache_nodes = HashSet::new();
+        let mut cache_nodes: Vec<NodeID> = vec![];
+        let mut triggers = vec![];
+
+        let existing_meta = self
+            .view
+            .kv_store_engine()
+            .get(
+                &KeyTypeDataSetMeta(data_unique_id),
+                true,
+                KvAdditionalConf::default(),
+            )
+            .filter(|_| !data_expired(self.view.kv_store_engine(), data_unique_id));
+        let is_new = existing_meta.is_none();
+        // 已有数据时, 函数优先调度到持有数据的节点
+        let data_nodes = existing_meta.map_or_else(HashSet::new, |(_, meta)| {
+            nodes_holding_whole_data(&meta.datas_splits)
+        });
+        let data_sz: u64 = context.each_data_sz_bytes.iter().map(|sz| *sz as u64).sum();
 
         // 对每个绑定的函数进行调度
-        for (app_name, (_, fn_names)) in &binded_funcs {
+        for (app_name, (app_type, fn_names)) in &binded_funcs {
             for (fn_name, fnmeta) in fn_names {
-                let target_nodes: Vec<NodeID> = if let Some(affinity) = fnmeta.affinity.clone() {
-                    match affinity.nodes {
-                        AffinityPattern::All => self
-                            .view
-                            .p2p()
-                            .nodes_config
-                            .all_nodes_iter()
-                            .filter(|(id, _)| {
-                                **id != self.view.p2p().nodes_config.get_master_node()
-                            })
-                            .map(|(id, _)| *id)
-                            .collect(),
-                        AffinityPattern::List(nodes) => nodes,
-                        AffinityPattern::NodeCount(_) => {
-                            todo!()
-                            // self.view
-                            //     .p2p()
-                            //     .nodes_config
-                            //     .all_nodes_iter()
-                            //     .filter(|(id, _)| id != self.view.p2p().nodes_config.get_master_node())
-                        }
+                let trigger_data_key = FDDGMgmt::trigger_data_key(*app_type, data_unique_id_str);
+                let Some((_, event)) = fnmeta.triggered_by(trigger_data_key) else {
+                    continue;
+                };
+                if event.only_new() && !is_new {
+                    continue;
+                }
+                if let Some(condition) = event.condition() {
+                    let holds = condition.eval(&ConditionInput {
+                        key: trigger_data_key,
+                        size: data_sz,
+                        value: (!context.trigger_value.is_empty())
+                            .then_some(&*context.trigger_value),
+                    });
+                    if !holds {
+                        tracing::debug!(
+                            "data {:?} doesn't satisfy condition of {}/{}",
+                            data_unique_id_str,
+                            app_name,
+                            fn_name
+                        );
+                        continue;
+                    }
+                }
+                let target_nodes = match self.view.master().select_nodes_by_affinity(
+                    app_name,
+                    fn_name,
+                    fnmeta,
+                    &data_nodes,
+                ) {
+                    Ok(nodes) => nodes,
+                    Err(err) => {
+                        tracing::warn!("skip trigger {}/{}: {}", app_name, fn_name, err);
+                        continue;
                     }
-                } else {
-                    vec![self.view.master().select_node()]
                 };
-                // 选择调度节点 (暂时不考虑亲和性规则)
-                // let target_node = ;
 
                 // 将调度节点加入缓存节点集合
-                // let _ = cache_nodes.insert(target_node);
+                for &node in &target_nodes {
+                    if !cache_nodes.contains(&node) {
+                        cache_nodes.push(node);
+                    }
+                }
 
-                // 发送触发请求并处理可能的错误
                 tracing::debug!(
                     "data {:?} write trigger function {}/{} on nodes {:?}",
                     data_unique_id,
@@ -172,24 +1030,11 @@ impl DataMaster {
                     data_unique_id: data_unique_id.to_vec(),
                     target_nodes: target_nodes, // 只在选中的节点上触发
                     timeout: Duration::from_secs(60),
-                    event_type: DataEventTrigger::Write, // 使用Write事件类型
+                    event_type: event.clone(),
                     src_task_id: context.src_task_id.clone().unwrap(),
                 };
 
-                // async call with unique task, don't block current task
-                let view = self.view.clone();
-                let app_name = app_name.clone();
-                let fn_name = fn_name.clone();
-                let _ = tokio::spawn(async move {
-                    if let Err(e) = view.master().trigger_func_call(ctx).await {
-                        tracing::error!(
-                            "Failed to trigger function {}/{}: {:?}",
-                            app_name,
-                            fn_name,
-                            e
-                        );
-                    }
-                });
+                triggers.push(ctx);
                 // if let Err(e) = self
                 //     .rpc_caller_add_wait_target
                 //     .call(
@@ -218,47 +1063,78 @@ impl DataMaster {
             }
         }
 
-        // 将缓存节点集合转换为向量
-        let cache_nodes: Vec<NodeID> = cache_nodes.into_iter().collect();
+        // 每个分片放在 replicas 个不同节点上, 缓存节点优先, 第一个为主分片节点
+        let all_nodes: Vec<NodeID> = self
+            .view
+            .p2p()
+            .nodes_config
+            .all_nodes()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        if replicas > all_nodes.len() {
+            tracing::warn!(
+                "data({:?}) asks for {} replicas, only {} nodes in cluster",
+                data_unique_id,
+                replicas,
+                all_nodes.len()
+            );
+        }
 
         // 根据缓存节点生成数据分片
+        let filepaths = context.filepath();
         let mut splits = Vec::new();
-        for sz in context.each_data_sz_bytes.iter() {
-            // 选择主分片节点 (使用第一个缓存节点或随机选择)
-            let primary_node = cache_nodes.first().copied().unwrap_or_else(|| {
-                let p2p = self.view.p2p();
-                let rand_node_idx = thread_rng().gen_range(0..p2p.nodes_config.node_cnt());
-                let mut iter = p2p.nodes_config.all_nodes_iter();
-                for _ in 0..rand_node_idx {
-                    let _ = iter.next();
+        let mut erasure_items = Vec::new();
+        for (idx, sz) in context.each_data_sz_bytes.iter().enumerate() {
+            // 大文件按纠删码切分, 每个分片放在不同节点
+            let is_file = filepaths.get(idx).map_or(false, Option::is_some);
+            if let Some(layout) = self.erasure_layout(*sz as usize, is_file, all_nodes.len()) {
+                let nodes: Vec<NodeID> = all_nodes
+                    .choose_multiple(&mut thread_rng(), layout.shard_cnt())
+                    .copied()
+                    .collect();
+                match layout.plan_split(&nodes) {
+                    Ok(split) => {
+                        splits.push(split);
+                        erasure_items.push(idx as u8);
+                        continue;
+                    }
+                    Err(err) => tracing::warn!(
+                        "data({:?}) idx({}) replicated instead of erasure coded: {:?}",
+                        data_unique_id,
+                        idx,
+                        err
+                    ),
                 }
-                *iter
-                    .next()
-                    .expect("node count doesn't match all_nodes_iter")
-                    .0
-            });
+            }
 
-            // 创建主分片
-            let mut split = DataSplit {
-                splits: vec![EachNodeSplit {
-                    node_id: primary_node,
-                    data_offset: 0,
-                    data_size: *sz,
-                    cache_mode: 0,
-                }],
-            };
+            let mut placements = cache_nodes.clone();
+            let more = replicas
+                .min(all_nodes.len())
+                .saturating_sub(placements.len());
+            if more > 0 {
+                placements.extend(
+                    all_nodes
+                        .iter()
+                        .filter(|node| !cache_nodes.contains(node))
+                        .copied()
+                        .collect::<Vec<_>>()
+                        .choose_multiple(&mut thread_rng(), more),
+                );
+            }
 
-            // 为每个缓存节点添加完整数据副本 (除了主分片节点)
-            for &cache_node in cache_nodes.iter() {
-                if cache_node != primary_node {
-                    split.splits.push(EachNodeSplit {
-                        node_id: cache_node,
+            // 主分片及其副本覆盖相同的数据范围
+            let split = DataSplit {
+                splits: placements
+                    .into_iter()
+                    .map(|node_id| EachNodeSplit {
+                        node_id,
                         data_offset: 0,
                         data_size: *sz,
                         cache_mode: 0,
-                    });
-                }
-            }
+                    })
+                    .collect(),
+            };
 
             splits.push(split);
         }
@@ -274,13 +1150,110 @@ impl DataMaster {
                 .cache_mode_time_auto(idx as u8)
                 .cache_mode_pos_auto(idx as u8);
         }
+        for idx in erasure_items {
+            let _ = builder.cache_mode_redundancy_erasure(idx);
+        }
         let cache_modes = builder.build().cache_mode;
         tracing::debug!(
             "planned for write data({:?}) cache_modes: {:?}",
             data_unique_id,
             cache_modes
         );
-        Ok((cache_modes, splits, cache_nodes))
+        Ok((cache_modes, splits, cache_nodes, triggers))
+    }
+
+    /// Runs the functions triggered by a committed write, only then the data is at the version
+    /// they are told about.
+    fn fire_write_triggers(&self, triggers: Vec<FunctionTriggerContext>, splits: &[DataSplit]) {
+        // 函数节点持有整份数据即为本地调度
+        let local_nodes = nodes_holding_whole_data(splits);
+        for ctx in triggers {
+            for node in &ctx.target_nodes {
+                self.view
+                    .metric_observor()
+                    .record_trigger_locality(&ctx.app_name, local_nodes.contains(node));
+            }
+            // async call with unique task, don't block current task
+            let view = self.view.clone();
+            let _ = tokio::spawn(async move {
+                let (app_name, fn_name) = (ctx.app_name.clone(), ctx.fn_name.clone());
+                if let Err(e) = view.master().trigger_func_call(ctx).await {
+                    tracing::error!(
+                        "Failed to trigger function {}/{}: {:?}",
+                        app_name,
+                        fn_name,
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Push the meta to the nodes holding its splits.
+    fn notify_meta_update(&self, unique_id: &[u8], new_meta: &DataSetMetaV2) {
+        tracing::debug!(
+            "updating meta({:?}) to peers for data({:?})",
+            new_meta,
+            unique_id
+        );
+        let need_notify_nodes = {
+            let mut need_notify_nodes = HashSet::new();
+            for one_data_splits in &new_meta.datas_splits {
+                for data_split in &one_data_splits.splits {
+                    let _ = need_notify_nodes.insert(data_split.node_id);
+                }
+            }
+            // TODO: do we need to notify cache nodes?
+            need_notify_nodes
+        };
+
+        for need_notify_node in need_notify_nodes {
+            let view = self.view.clone();
+            let serialized_meta = bincode::serialize(new_meta).unwrap();
+            let unique_id = unique_id.to_vec();
+            let version = new_meta.version;
+            let _ = tokio::spawn(async move {
+                let p2p = view.p2p();
+                let display_id = std::str::from_utf8(&unique_id)
+                    .map_or_else(|_err| format!("{:?}", unique_id), |ok| ok.to_owned());
+                tracing::debug!(
+                    "updating version for data({:?}) to node: {}, this_node:  {}",
+                    display_id,
+                    need_notify_node,
+                    p2p.nodes_config.this_node()
+                );
+
+                tracing::debug!(
+                    "async notify `DataMetaUpdateRequest` to node {}",
+                    need_notify_node
+                );
+                let resp = view
+                    .data_master()
+                    .rpc_caller_data_meta_update
+                    .call(
+                        p2p,
+                        need_notify_node,
+                        proto::DataMetaUpdateRequest {
+                            unique_id,
+                            version,
+                            serialized_meta,
+                        },
+                        Some(Duration::from_secs(60)),
+                    )
+                    .await;
+                if let Err(err) = resp {
+                    tracing::error!(
+                        "notify `DataMetaUpdateRequest` to node {} failed: {}",
+                        need_notify_node,
+                        err
+                    );
+                } else if let Ok(ok) = resp {
+                    if ok.version != version {
+                        tracing::error!("notify `DataMetaUpdateRequest` to node {} failed: version mismatch, expect: {}, remote: {}", need_notify_node, version, ok.version);
+                    }
+                }
+            });
+        }
     }
 
     /// Check the dataset sync flow here:
@@ -291,6 +1264,15 @@ impl DataMaster {
         responsor: RPCResponsor<DataVersionScheduleRequest>,
         req: DataVersionScheduleRequest,
     ) -> WSResult<()> {
+        let nodes_config = &self.view.p2p().nodes_config;
+        if !nodes_config.this_is_master() {
+            // a standby master candidate, the sender will retry on the elected master
+            return Err(WsNetworkLogicErr::NotMaster {
+                this: nodes_config.this_node(),
+                master: nodes_config.master_node(),
+            }
+            .into());
+        }
         let kv_store_engine = self.view.kv_store_engine();
         let ctx = req
             .context
@@ -300,14 +1282,40 @@ impl DataMaster {
         let metakey_bytes = metakey.make_key();
         tracing::debug!("check version for data({:?})", req.unique_id);
 
+        // if app meta data, the app master binds it after the write is replicated
+        let uploaded_app = match ctx.ope_role.as_ref() {
+            Some(proto::data_schedule_context::OpeRole::UploadApp(
+                proto::DataOpeRoleUploadApp {
+                    app,
+                    app_meta_encoded,
+                },
+            )) if version::split_versioned_app(app).1.is_none() => {
+                let meta = AppMeta::decode(app_meta_encoded).map_err(|e| {
+                    WsDataError::DataDecodeError {
+                        reason: format!("err: {:?}", e),
+                        data_type: "AppMeta in data schedule context".to_owned(),
+                    }
+                })?;
+                Some((app, app_meta_encoded, meta))
+            }
+            // a version stored aside, it binds the triggers once activated
+            _ => None,
+        };
+
         // now we expand the meta
-        let (new_meta, cache_nodes) = {
+        let planned = {
             // the we will make the  split plan and cache plan
             //  then expand the meta
             //  this process will fail if other write updated the unique id
-            let (item_cache_modes, new_splits, cache_nodes) = self
-                .plan_for_write_data(&req.unique_id, ctx, FuncTriggerType::DataWrite)
+            let (item_cache_modes, new_splits, cache_nodes, triggers) = self
+                .plan_for_write_data(
+                    &req.unique_id,
+                    ctx,
+                    FuncTriggerType::DataWrite,
+                    self.write_replicas(&req),
+                )
                 .await?;
+            let item_cnt = new_splits.len();
 
             tracing::debug!(
                 "master planned for write data({:?}) cache_modes: {:?}, fetching meta lock",
@@ -315,139 +1323,184 @@ impl DataMaster {
                 item_cache_modes
             );
 
+            // held until committed, so the meta read below has the earlier writes applied
+            let meta_keys = self
+                .view
+                .master_election()
+                .lock_keys([metakey_bytes.clone()])
+                .await;
             let update_version_lock = kv_store_engine.with_rwlock(&metakey_bytes);
             let _guard = update_version_lock.write();
             tracing::debug!("master got meta lock for data({:?})", req.unique_id);
 
             let dataset_meta = kv_store_engine.get(&metakey, true, KvAdditionalConf::default());
 
-            // let takeonce=Some((new_meta,new_))
-            let set_meta = if let Some((_kv_version, set_meta)) = dataset_meta {
-                tracing::debug!("update dataset meta for data({:?})", req.unique_id);
-                let version = set_meta.version;
-                let mut builder = DataSetMetaBuilder::from(set_meta);
-                // version
-                let _ = builder.version(version + 1);
-                // data splits bf cache mod
-                let _ = builder.set_data_splits(new_splits);
-                // cache mode
-                let _ = builder.set_cache_mode_for_all(item_cache_modes);
-                builder.build()
-            } else {
-                tracing::debug!("new dataset meta for data({:?})", req.unique_id);
-                let mut builder = DataSetMetaBuilder::new(ctx.filepath());
-                // version
-                let _ = builder.version(1);
-                // data splits bf cache mod
-                let _ = builder.set_data_splits(new_splits);
-                // cache mode
-                let _ = builder.set_cache_mode_for_all(item_cache_modes);
-                builder.build()
-            };
-
-            // ##  update version local
-            tracing::debug!(
-                "update version local for data({:?}), the updated meta is {:?}",
-                req.unique_id,
-                set_meta
-            );
-            let _ = kv_store_engine.set(KeyTypeDataSetMeta(&req.unique_id), &set_meta, true)?;
-            kv_store_engine.flush();
-            (set_meta, cache_nodes)
-        };
+            let now_ms = unix_now_ms();
+            let expire_at = data_expire_at(kv_store_engine, &req.unique_id);
+            // expired data not reclaimed yet counts as absent
+            let expired = expire_at != 0 && expire_at <= now_ms;
 
-        // if app meta data, update app master binded
-        {
-            let ctx = req.context.unwrap();
-            if let proto::data_schedule_context::OpeRole::UploadApp(proto::DataOpeRoleUploadApp {
-                app,
-                app_meta_encoded,
-            }) = ctx.ope_role.as_ref().unwrap()
-            {
-                tracing::debug!("update app meta for data({:?})", req.unique_id);
-                let meta: AppMeta = bincode::deserialize(&app_meta_encoded).map_err(|e| {
-                    WsDataError::DataDecodeError {
-                        reason: format!("err: {:?}", e),
-                        data_type: "AppMeta in data schedule context".to_owned(),
-                    }
-                })?;
-                self.view
-                    .app_master()
-                    .update_app(app, &meta)
-                    .await
-                    .map_err(|e| {
-                        tracing::error!("update app meta failed when schedule app data: {:?}", e);
-                        e
-                    })?;
-            }
-        }
-
-        // update version peers
-        {
-            tracing::debug!(
-                "updating meta({:?}) to peers for data({:?})",
-                new_meta,
-                req.unique_id
-            );
-            let need_notify_nodes = {
-                let mut need_notify_nodes = HashSet::new();
-                for one_data_splits in &new_meta.datas_splits {
-                    for data_split in &one_data_splits.splits {
-                        let _ = need_notify_nodes.insert(data_split.node_id);
-                    }
-                }
-                // TODO: do we need to notify cache nodes?
-                need_notify_nodes
+            // write conditions, checked under the meta lock so no other write slips in between
+            let current_version = match &dataset_meta {
+                Some((_, meta)) if !expired => meta.version,
+                _ => 0,
             };
+            let fenced_by = kv_store_engine
+                .get(
+                    &KeyTypeDataFence(&req.unique_id),
+                    false,
+                    KvAdditionalConf::default(),
+                )
+                .map_or(0, |(_, token)| token);
+            match &req.expected_version {
+                Some(expected) if expected.version != current_version => {
+                    Err(DataVersionScheduleResponse {
+                        current_version: Some(proto::DataVersionCond {
+                            version: current_version,
+                        }),
+                        ..Default::default()
+                    })
+                }
+                _ if req.fencing_token != 0 && req.fencing_token < fenced_by => {
+                    Err(DataVersionScheduleResponse {
+                        fenced_by,
+                        ..Default::default()
+                    })
+                }
+                _ => {
+                    // let takeonce=Some((new_meta,new_))
+                    let set_meta = if let Some((_kv_version, set_meta)) = dataset_meta {
+                        tracing::debug!("update dataset meta for data({:?})", req.unique_id);
+                        let version = set_meta.version;
+                        let mut builder = DataSetMetaBuilder::from(set_meta);
+                        // version
+                        let _ = builder.version(version + 1);
+                        // data splits bf cache mod
+                        let _ = builder.set_data_splits(new_splits);
+                        if ctx.digests.len() == item_cnt {
+                            let _ = builder.set_digests(ctx.digests.clone());
+                        }
+                        // cache mode
+                        let _ = builder.set_cache_mode_for_all(item_cache_modes);
+                        builder.build()
+                    } else {
+                        tracing::debug!("new dataset meta for data({:?})", req.unique_id);
+                        let mut builder = DataSetMetaBuilder::new(ctx.filepath());
+                        // version
+                        let _ = builder.version(1);
+                        // data splits bf cache mod
+                        let _ = builder.set_data_splits(new_splits);
+                        if ctx.digests.len() == item_cnt {
+                            let _ = builder.set_digests(ctx.digests.clone());
+                        }
+                        // cache mode
+                        let _ = builder.set_cache_mode_for_all(item_cache_modes);
+                        builder.build()
+                    };
 
-            for need_notify_node in need_notify_nodes {
-                let view = self.view.clone();
-                let serialized_meta = bincode::serialize(&new_meta).unwrap();
-                let unique_id = req.unique_id.clone();
-                let version = new_meta.version;
-                let _ = tokio::spawn(async move {
-                    let p2p = view.p2p();
-                    let display_id = std::str::from_utf8(&unique_id)
-                        .map_or_else(|_err| format!("{:?}", unique_id), |ok| ok.to_owned());
-                    tracing::debug!(
-                        "updating version for data({:?}) to node: {}, this_node:  {}",
-                        display_id,
-                        need_notify_node,
-                        p2p.nodes_config.this_node()
-                    );
-
+                    // ##  update version local
                     tracing::debug!(
-                        "async notify `DataMetaUpdateRequest` to node {}",
-                        need_notify_node
+                        "update version local for data({:?}), the updated meta is {:?}",
+                        req.unique_id,
+                        set_meta
                     );
-                    let resp = view
-                        .data_master()
-                        .rpc_caller_data_meta_update
-                        .call(
-                            p2p,
-                            need_notify_node,
-                            proto::DataMetaUpdateRequest {
-                                unique_id,
-                                version,
-                                serialized_meta,
-                            },
-                            Some(Duration::from_secs(60)),
-                        )
-                        .await;
-                    if let Err(err) = resp {
-                        tracing::error!(
-                            "notify `DataMetaUpdateRequest` to node {} failed: {}",
-                            need_notify_node,
-                            err
-                        );
-                    } else if let Ok(ok) = resp {
-                        if ok.version != version {
-                            tracing::error!("notify `DataMetaUpdateRequest` to node {} failed: version mismatch, expect: {}, remote: {}", need_notify_node, version, ok.version);
+                    let mut writes = vec![(
+                        metakey_bytes.clone(),
+                        Some(bincode::serialize(&set_meta).unwrap()),
+                    )];
+                    if req.fencing_token > fenced_by {
+                        writes.push((
+                            KeyTypeDataFence(&req.unique_id).make_key(),
+                            Some(bincode::serialize(&req.fencing_token).unwrap()),
+                        ));
+                    }
+                    let new_expire_at = match self.write_ttl_ms(&req) {
+                        0 => 0,
+                        ttl_ms => now_ms + ttl_ms,
+                    };
+                    // a write without ttl makes expiring data live forever
+                    if new_expire_at != expire_at {
+                        writes.push((
+                            KeyTypeDataExpiry(&req.unique_id).make_key(),
+                            Some(bincode::serialize(&new_expire_at).unwrap()),
+                        ));
+                        // requeued at the new time
+                        for (at, queued) in [(expire_at, None), (new_expire_at, Some(()))] {
+                            if at != 0 {
+                                writes.push((
+                                    KeyTypeDataExpiryQueue {
+                                        expire_at: at,
+                                        uid: &req.unique_id,
+                                    }
+                                    .make_key(),
+                                    queued.map(|()| bincode::serialize(&()).unwrap()),
+                                ));
+                            }
                         }
                     }
-                });
+                    if let Some(key) = fn_kv_key_of_unique_id(&req.unique_id) {
+                        writes.push((
+                            KeyTypeFnKvIndex(key).make_key(),
+                            Some(bincode::serialize(&()).unwrap()),
+                        ));
+                    }
+                    // kept for rebuilding the triggers after restart or master takeover
+                    if let Some((app, app_meta_encoded, _)) = &uploaded_app {
+                        writes.push((
+                            KeyTypeAppMeta(app.as_bytes()).make_key(),
+                            Some(bincode::serialize(*app_meta_encoded).unwrap()),
+                        ));
+                    }
+                    // the meta key orders the writes of this item, the candidates apply them
+                    //  in that order
+                    let staged = self.view.master_election().stage(writes, meta_keys)?;
+                    Ok((
+                        set_meta,
+                        cache_nodes,
+                        triggers,
+                        staged,
+                        self.kv_watch.reserve(),
+                    ))
+                }
+            }
+        };
+        let (new_meta, cache_nodes, triggers, staged, watch_ticket) = match planned {
+            Ok(planned) => planned,
+            Err(refused) => {
+                tracing::debug!(
+                    "write of data({:?}) refused, expected version {:?}, fencing token {}: {:?}",
+                    req.unique_id,
+                    req.expected_version,
+                    req.fencing_token,
+                    refused
+                );
+                return responsor.send_resp(refused).await;
             }
+        };
+
+        // a new master must find this meta, so answer only after most candidates stored it
+        self.view.master_election().commit(staged).await?;
+        // an unsettled revision holds back the later watch events
+        match fn_kv_key_of_unique_id(&req.unique_id) {
+            Some(key) => watch_ticket.publish(key.to_vec(), false, new_meta.version),
+            None => drop(watch_ticket),
         }
+        // refused or failed writes returned above and trigger nothing
+        self.fire_write_triggers(triggers, &new_meta.datas_splits);
+
+        if let Some((app, _, meta)) = &uploaded_app {
+            tracing::debug!("update app meta for data({:?})", req.unique_id);
+            self.view
+                .app_master()
+                .update_app(app, meta)
+                .await
+                .map_err(|e| {
+                    tracing::error!("update app meta failed when schedule app data: {:?}", e);
+                    e
+                })?;
+        }
+        // update version peers
+        self.notify_meta_update(&req.unique_id, &new_meta);
 
         tracing::debug!(
             "data:{:?} version required({}) and schedule done, caller will do following thing after receive `DataVersionScheduleResponse`",
@@ -466,6 +1519,8 @@ impl DataMaster {
                     .map(|v| v.into())
                     .collect(),
                 cache_nodes,
+                current_version: None,
+                fenced_by: 0,
             })
             .await
         {
@@ -526,3 +1581,180 @@ impl DataMaster {
     //     Ok(())
     // }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        classify_replica_digests, DataMasterView, UnreachableReplicas,
+        DATA_REPAIR_UNREACHABLE_GONE_AFTER,
+    };
+    use crate::general::{
+        app::{AppMetaYaml, AppType, FnMeta},
+        data::m_data_general::{
+            dataitem::DataItemArgWrapper, new_data_unique_id_fn_kv, DataWriteCond,
+        },
+        network::{
+            proto::{self, FnTaskId},
+            proto_ext::data_ope_role::ProtoExtDataOpeRole,
+        },
+        test_utils,
+    };
+    use crate::result::{WSError, WSResult, WsDataError};
+    use prometheus_client::encoding::text::encode;
+    use std::time::{Duration, Instant};
+
+    /// Binds function `f` of `app` to writes of the function kv keys matching `key_pattern`.
+    fn bind_write_trigger(master: &DataMasterView, app: &str, key_pattern: &str) {
+        let yaml = format!(
+            "fns:\n  f:\n    kvs:\n      \"{}\": [set, trigger_by_write]\n",
+            key_pattern
+        );
+        let mut yaml = AppMetaYaml::parse(app, &yaml).unwrap();
+        let fnmeta = FnMeta::try_from((AppType::Wasm, yaml.fns.remove("f").unwrap())).unwrap();
+        master
+            .app_master()
+            .fddg
+            .add_fn_trigger((app, AppType::Wasm), ("f", &fnmeta))
+            .unwrap();
+    }
+
+    /// Functions of `app` fired so far, each counted by the trigger locality metric.
+    fn fired_triggers(master: &DataMasterView, app: &str) -> u64 {
+        let mut text = String::new();
+        encode(&mut text, &master.metric_observor().registry).unwrap();
+        text.lines()
+            .filter(|line| line.starts_with("trigger_locality") && line.contains(app))
+            .filter_map(|line| line.rsplit(' ').next()?.parse::<u64>().ok())
+            .sum()
+    }
+
+    async fn put_fn_kv(worker: &DataMasterView, key: &[u8], cond: DataWriteCond) -> WSResult<u64> {
+        let this_node = worker.p2p().nodes_config.this_node();
+        worker
+            .data_general()
+            .write_data_if(
+                new_data_unique_id_fn_kv(key),
+                vec![DataItemArgWrapper::from_bytes(key.to_vec())],
+                Some((
+                    this_node,
+                    proto::DataOpeType::Write,
+                    proto::data_schedule_context::OpeRole::new_fn_call(
+                        "test_app",
+                        "test_func",
+                        this_node,
+                    ),
+                    FnTaskId {
+                        task_id: 0,
+                        call_node_id: this_node,
+                    },
+                )),
+                cond,
+            )
+            .await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refused_cas_fires_no_trigger() {
+        // sys1 is the master, sys2 the only worker
+        let (_hold, sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let (master, worker) = (DataMasterView::new(sys1), DataMasterView::new(sys2));
+        let app = "test_refused_cas_app";
+        bind_write_trigger(&master, app, "cas_trigger_{}");
+
+        let res = put_fn_kv(
+            &worker,
+            b"cas_trigger_1",
+            DataWriteCond {
+                expected_version: Some(7),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(WSError::WsDataError(WsDataError::VersionMismatch { .. }))
+        ));
+        assert_eq!(fired_triggers(&master, app), 0);
+
+        // the same write applied fires
+        let _ = put_fn_kv(
+            &worker,
+            b"cas_trigger_1",
+            DataWriteCond {
+                expected_version: Some(0),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(fired_triggers(&master, app), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fenced_write_fires_nothing() {
+        let (_hold, sys1, sys2) = test_utils::get_test_sys().await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let (master, worker) = (DataMasterView::new(sys1), DataMasterView::new(sys2));
+        let app = "test_fenced_write_app";
+        bind_write_trigger(&master, app, "fenced_{}");
+        let watch = |from_revision| proto::kv::KvWatchRequest {
+            range: Some(proto::kv::KeyRange::new_prefix(b"fenced_".to_vec())),
+            from_revision,
+            timeout_ms: 500,
+            limit: 0,
+        };
+        let start = master.data_master().watch_fn_kv(&watch(0)).await.revision;
+
+        let fenced = |fencing_token| DataWriteCond {
+            fencing_token,
+            ..Default::default()
+        };
+        let _ = put_fn_kv(&worker, b"fenced_1", fenced(5)).await.unwrap();
+        let res = put_fn_kv(&worker, b"fenced_1", fenced(3)).await;
+        assert!(matches!(
+            res,
+            Err(WSError::WsDataError(WsDataError::StaleFencingToken { .. }))
+        ));
+
+        // only the write holding the newer token
+        assert_eq!(fired_triggers(&master, app), 1);
+        let events = master.data_master().watch_fn_kv(&watch(start)).await.events;
+        let got: Vec<_> = events.iter().map(|event| event.version).collect();
+        assert_eq!(got, vec![1]);
+    }
+
+    #[test]
+    fn test_classify_replica_digests() {
+        let digests = vec![
+            (1, b"stale".to_vec()),
+            (2, b"good".to_vec()),
+            (3, b"stale".to_vec()),
+        ];
+        // the recorded digest wins over the majority
+        assert_eq!(
+            classify_replica_digests(digests.clone(), Some(&b"good"[..])),
+            (vec![2], vec![1, 3])
+        );
+        assert_eq!(
+            classify_replica_digests(digests, None),
+            (vec![1, 3], vec![2])
+        );
+        // the primary on a tie
+        let tie = vec![(1, b"a".to_vec()), (2, b"b".to_vec())];
+        assert_eq!(classify_replica_digests(tie, None), (vec![1], vec![2]));
+    }
+
+    #[test]
+    fn test_unreachable_replicas_gone_after_repeated_failures() {
+        let mut unreachable = UnreachableReplicas::default();
+        let start = Instant::now();
+        assert!(!unreachable.failed(1, start));
+        assert!(!unreachable.failed(1, start + Duration::from_secs(10)));
+        assert!(unreachable.failed(1, start + DATA_REPAIR_UNREACHABLE_GONE_AFTER));
+
+        // answering in between starts over
+        unreachable.answered(1);
+        assert!(!unreachable.failed(1, start + DATA_REPAIR_UNREACHABLE_GONE_AFTER * 2));
+    }
+}
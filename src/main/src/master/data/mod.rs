@@ -1,2 +1,3 @@
+pub mod kv_watch;
 pub mod m_data_master;
 pub mod m_master_kv;
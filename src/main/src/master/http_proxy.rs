@@ -0,0 +1,368 @@
+//! Master relaying http requests to the workers when `master_proxy` is set, for clients
+//! behind NAT or not following redirects, so the workers' http ports can stay private.
+//!
+//! The method, path with query and end to end headers are passed on, and both bodies are
+//! streamed as they come. A worker refusing the connection ran nothing and the request goes to
+//! another one. Once a worker started reading the body it can't be sent again: a draining
+//! worker's 503 goes back to the client, and any other failure is answered with 502 instead of
+//! running the request twice.
+use crate::{config::NodesConfig, sys::NodeID};
+use axum::{
+    body::{Body, Bytes, HttpBody, StreamBody},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{stream, Future, Stream};
+use parking_lot::Mutex;
+use std::{io, sync::Arc, time::Duration};
+
+/// Workers tried for one request before giving up.
+const PROXY_MAX_ATTEMPTS: usize = 3;
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Headers of one connection, not passed through.
+const HOP_BY_HOP_HEADERS: [&str; 10] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    // the body is streamed chunked
+    "content-length",
+];
+
+lazy_static::lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .connect_timeout(PROXY_CONNECT_TIMEOUT)
+        .build()
+        .unwrap();
+}
+
+/// A client request relayed as it came.
+pub struct ProxiedRequest {
+    pub method: Method,
+    /// path and query, starts with '/'
+    pub path: String,
+    pub headers: HeaderMap,
+    body: PendingBody,
+}
+
+impl From<Request<Body>> for ProxiedRequest {
+    fn from(req: Request<Body>) -> Self {
+        let (parts, body) = req.into_parts();
+        Self {
+            method: parts.method,
+            path: parts
+                .uri
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_owned(),
+            headers: parts.headers,
+            body: PendingBody(Arc::new(Mutex::new(Some(body)))),
+        }
+    }
+}
+
+/// Body of the client request, it stays here until a worker starts reading it.
+struct PendingBody(Arc<Mutex<Option<Body>>>);
+
+impl PendingBody {
+    /// Chunks of the body, taken on the first read, empty once another worker took it.
+    fn stream(&self) -> impl Stream<Item = Result<Bytes, axum::Error>> + Send + 'static {
+        let pending = self.0.clone();
+        stream::unfold(None, move |body: Option<Body>| {
+            let pending = pending.clone();
+            async move {
+                let mut body = match body {
+                    Some(body) => body,
+                    None => pending.lock().take()?,
+                };
+                let chunk = body.data().await?.map_err(axum::Error::new);
+                Some((chunk, Some(body)))
+            }
+        })
+    }
+
+    fn taken(&self) -> bool {
+        self.0.lock().is_none()
+    }
+}
+
+/// The headers meant for the peer past this hop.
+fn end_to_end_headers<'a>(headers: &[(&'a str, &'a [u8])]) -> Vec<(&'a str, &'a [u8])> {
+    // connection names more headers of this hop
+    let named: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !named.contains(&name)
+        })
+        .copied()
+        .collect()
+}
+
+/// Relay the request to the worker `schedule` picks, it's given the workers tried already.
+/// `schedule` failing for the first worker answers the request, later it ends the retries.
+pub async fn proxy_to_worker<F, Fut>(
+    nodes_config: &NodesConfig,
+    req: ProxiedRequest,
+    mut schedule: F,
+) -> Response
+where
+    F: FnMut(Vec<NodeID>) -> Fut,
+    Fut: Future<Output = Result<NodeID, Response>>,
+{
+    let mut tried = vec![];
+    let mut last_err = String::new();
+    for _ in 0..PROXY_MAX_ATTEMPTS {
+        let node = match schedule(tried.clone()).await {
+            Ok(node) => node,
+            Err(resp) if tried.is_empty() => return resp,
+            // no other worker to retry on
+            Err(_) => break,
+        };
+        tried.push(node);
+        match forward(nodes_config, node, &req).await {
+            Ok(resp) => return resp,
+            Err(ForwardErr::Retry(err)) => {
+                tracing::warn!("proxy {} to node {} failed, {}", req.path, node, err);
+                last_err = err;
+            }
+            Err(ForwardErr::Fatal(err)) => {
+                tracing::warn!("proxy {} to node {} failed, {}", req.path, node, err);
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("err: node {} failed {}: {}", node, req.path, err),
+                )
+                    .into_response();
+            }
+        }
+    }
+    (
+        StatusCode::BAD_GATEWAY,
+        format!(
+            "err: no worker answered {}, tried nodes {:?}, last: {}",
+            req.path, tried, last_err
+        ),
+    )
+        .into_response()
+}
+
+/// Why a worker produced no response.
+enum ForwardErr {
+    /// the worker ran nothing, another one may take the request
+    Retry(String),
+    /// the worker may have run the request
+    Fatal(String),
+}
+
+/// The worker's response, `Err` when it produced none.
+async fn forward(
+    nodes_config: &NodesConfig,
+    node: NodeID,
+    req: &ProxiedRequest,
+) -> Result<Response, ForwardErr> {
+    let conf = nodes_config
+        .get_nodeconfig(node)
+        .ok_or_else(|| ForwardErr::Retry(format!("node {} left the cluster", node)))?;
+    let url = format!("{}{}", conf.http_url().trim_end_matches('/'), req.path);
+    let method = reqwest::Method::from_bytes(req.method.as_str().as_bytes())
+        .map_err(|err| ForwardErr::Fatal(err.to_string()))?;
+    let mut builder = CLIENT
+        .request(method, &url)
+        .body(reqwest::Body::wrap_stream(req.body.stream()));
+    let client_headers: Vec<(&str, &[u8])> = req
+        .headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .collect();
+    for (name, value) in end_to_end_headers(&client_headers) {
+        // the worker's own, set from the url
+        if name == "host" {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    let resp = builder.send().await.map_err(|err| {
+        if err.is_connect() && !req.body.taken() {
+            ForwardErr::Retry(err.to_string())
+        } else {
+            ForwardErr::Fatal(err.to_string())
+        }
+    })?;
+    // a draining worker ran nothing, the body is still here if it didn't read it
+    if resp.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE && !req.body.taken() {
+        return Err(ForwardErr::Retry(format!("node {} is unavailable", node)));
+    }
+
+    let status = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut headers = HeaderMap::new();
+    let worker_headers: Vec<(&str, &[u8])> = resp
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .collect();
+    for (name, value) in end_to_end_headers(&worker_headers) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_bytes(value),
+        ) {
+            let _ = headers.append(name, value);
+        }
+    }
+    let body = stream::try_unfold(resp, |mut resp| async move {
+        let chunk = resp
+            .chunk()
+            .await
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok::<_, io::Error>(chunk.map(|chunk| (chunk, resp)))
+    });
+    Ok((status, headers, StreamBody::new(body)).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::proxy_to_worker;
+    use crate::config::{NodeConfig, NodesConfig};
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Method, Request, Uri},
+        routing::{any, post},
+        Router,
+    };
+    use std::{
+        collections::HashMap,
+        net::{SocketAddr, TcpListener},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::io::AsyncReadExt;
+
+    /// A node whose http port, the p2p port plus one, is free.
+    fn node_on_free_port(spec: &str) -> (NodeConfig, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_port = listener.local_addr().unwrap().port();
+        let addr = SocketAddr::from(([127, 0, 0, 1], http_port - 1));
+        let conf = NodeConfig::new(addr, None, [spec.to_owned()].into_iter().collect());
+        (conf, listener)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_retries_on_another_worker() {
+        let (master, _master_port) = node_on_free_port("master");
+        // nothing listens for worker 2
+        let (down, down_port) = node_on_free_port("worker");
+        drop(down_port);
+        let (up, up_port) = node_on_free_port("worker");
+        let app = Router::new().route(
+            "/echo/f",
+            any(
+                |method: Method, uri: Uri, headers: HeaderMap, body: String| async move {
+                    let seen = format!(
+                        "{} {} {:?} {:?} {}",
+                        method,
+                        uri,
+                        headers.get("x-token"),
+                        headers.get("x-hop"),
+                        body
+                    );
+                    ([("x-worker", "3")], seen)
+                },
+            ),
+        );
+        let _ = tokio::spawn(
+            axum::Server::from_tcp(up_port)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let nodes_config = NodesConfig::new(
+            (1, master),
+            HashMap::from([(2, down), (3, up)]),
+            "/tmp".into(),
+        );
+
+        // the body is still here after worker 2 refused the connection
+        let (mut body_tx, body) = Body::channel();
+        let _ = tokio::spawn(async move {
+            for chunk in ["hel", "lo"] {
+                body_tx.send_data(chunk.into()).await.unwrap();
+            }
+        });
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri("/echo/f?x=1")
+            .header("x-token", "t")
+            .header("connection", "x-hop")
+            .header("x-hop", "h")
+            .body(body)
+            .unwrap();
+        let resp = proxy_to_worker(&nodes_config, req.into(), |tried| async move {
+            Ok(if tried.is_empty() { 2 } else { 3 })
+        })
+        .await;
+        assert_eq!(resp.status(), axum::http::StatusCode::OK);
+        assert_eq!(resp.headers().get("x-worker").unwrap(), "3");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"PUT /echo/f?x=1 Some("t") None hello"#);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_no_retry_after_worker_took_request() {
+        let (master, _master_port) = node_on_free_port("master");
+        // worker 2 reads the request and hangs up without answering
+        let (cut, cut_port) = node_on_free_port("worker");
+        cut_port.set_nonblocking(true).unwrap();
+        let cut_port = tokio::net::TcpListener::from_std(cut_port).unwrap();
+        let _ = tokio::spawn(async move {
+            while let Ok((mut conn, _)) = cut_port.accept().await {
+                let mut buf = [0; 1024];
+                let _ = conn.read(&mut buf).await;
+            }
+        });
+        let (up, up_port) = node_on_free_port("worker");
+        let runs = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/echo/f",
+            post({
+                let runs = runs.clone();
+                move |body: String| async move {
+                    let _ = runs.fetch_add(1, Ordering::SeqCst);
+                    body
+                }
+            }),
+        );
+        let _ = tokio::spawn(
+            axum::Server::from_tcp(up_port)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        let nodes_config = NodesConfig::new(
+            (1, master),
+            HashMap::from([(2, cut), (3, up)]),
+            "/tmp".into(),
+        );
+
+        let req = Request::post("/echo/f").body(Body::from("hello")).unwrap();
+        let resp = proxy_to_worker(&nodes_config, req.into(), |tried| async move {
+            Ok(if tried.is_empty() { 2 } else { 3 })
+        })
+        .await;
+        assert_eq!(resp.status(), axum::http::StatusCode::BAD_GATEWAY);
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+}
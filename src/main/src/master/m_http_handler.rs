@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 use axum::{
-    http::{HeaderValue, StatusCode},
+    body::Body,
+    http::{HeaderValue, Request, StatusCode},
     response::{IntoResponse, Redirect, Response},
     Router,
 };
@@ -18,12 +19,16 @@ use crate::{
         },
     },
     logical_module_view_impl,
-    result::WSResult,
+    result::{WSError, WSResult, WsFuncError, WsNetworkLogicErr},
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef},
     util::{JoinHandleWrapper, WithBind},
 };
 
-use super::{m_master::Master, m_metric_observor::MetricObservor};
+use super::{
+    http_proxy::{self, ProxiedRequest},
+    m_master::Master,
+    m_metric_observor::MetricObservor,
+};
 
 logical_module_view_impl!(MasterHttpHandlerView);
 logical_module_view_impl!(MasterHttpHandlerView, p2p, P2PModule);
@@ -80,6 +85,18 @@ impl MasterHttpHandler {
     }
 }
 
+fn schedule_err_response(err: WSError) -> Response {
+    match err {
+        WSError::WsFuncError(
+            WsFuncError::AppNotFound { .. } | WsFuncError::FuncNotFound { .. },
+        ) => (StatusCode::NOT_FOUND, format!("err: {:?}", err)).into_response(),
+        WSError::WsFuncError(WsFuncError::NoNodeMatchesAffinity { .. }) => {
+            (StatusCode::SERVICE_UNAVAILABLE, format!("err: {:?}", err)).into_response()
+        }
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("err: {:?}", err)).into_response(),
+    }
+}
+
 // fn construct_target_path(node_config: &NodeConfig, sub_api: &str) -> String {
 //     if let Some(d) = node_config.get_http_domain() {
 //         format!("{}/{}", d, sub_api)
@@ -99,11 +116,24 @@ impl HttpHandler for MasterHttpHandler {
     // fn alloc_local_req_id(&self) -> ReqId {
     //     self.local_req_id_allocator.alloc()
     // }
-    async fn handle_request(&self, app: &str, _http_text: String) -> Response {
-        tracing::debug!("master handle_request {}", app);
-        if app == "metrics" {
+    /// `route` is `app` or `app/fn`
+    async fn handle_request(&self, route: &str, req: Request<Body>) -> Response {
+        tracing::debug!("master handle_request {}", route);
+        if route == "metrics" {
             return self.handle_prometheus();
         }
+        let (app, func) = route.split_once('/').unwrap_or((route, ""));
+
+        let nodes_config = &self.view.p2p().nodes_config;
+        // a standby candidate, the elected master schedules
+        if !nodes_config.this_is_master() {
+            let err: WSError = WsNetworkLogicErr::NotMaster {
+                this: nodes_config.this_node(),
+                master: nodes_config.master_node(),
+            }
+            .into();
+            return (StatusCode::SERVICE_UNAVAILABLE, format!("err: {:?}", err)).into_response();
+        }
 
         // let view = self.view.clone();
         // if !view.p2p().nodes_config.this.1.is_master() {
@@ -130,8 +160,28 @@ impl HttpHandler for MasterHttpHandler {
         //     }
         // }
 
+        if nodes_config.master_proxy {
+            let master = self.view.master();
+            let req = ProxiedRequest::from(req);
+            return http_proxy::proxy_to_worker(nodes_config, req, |tried| async move {
+                master
+                    .handle_http_schedule(app, func, &tried)
+                    .await
+                    .map_err(schedule_err_response)
+            })
+            .await;
+        }
+
         // 选择节点
-        let node = self.view.master().handle_http_schedule(app).await;
+        let node = match self
+            .view
+            .master()
+            .handle_http_schedule(app, func, &[])
+            .await
+        {
+            Ok(node) => node,
+            Err(err) => return schedule_err_response(err),
+        };
         tracing::debug!("scheduled node is {:?}", node);
 
         // if self.view.p2p().nodes_config.this.0 == node {
@@ -152,13 +202,13 @@ impl HttpHandler for MasterHttpHandler {
         //     StatusCode::OK.into_response()
         // } else {
         // 转发
-        let target_node = self
-            .view
-            .p2p()
-            .nodes_config
-            .peers
-            .get(&(node as u32))
-            .unwrap();
+        let Some(target_node) = self.view.p2p().nodes_config.get_nodeconfig(node) else {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("scheduled node {} left the cluster", node),
+            )
+                .into_response();
+        };
 
         tracing::debug!("scheduled target_node is {:?}", target_node);
         let url = target_node.http_url();
@@ -169,7 +219,12 @@ impl HttpHandler for MasterHttpHandler {
             // 否则，返回原URL
             &url
         };
-        let target_path = format!("{}/{}", url, app);
+        // the query goes along, the method and body are kept by the temporary redirect
+        let target_path = format!(
+            "{}{}",
+            url,
+            req.uri().path_and_query().map_or("/", |path| path.as_str())
+        );
 
         // target_node.set_port(target_node.port() + 1);
         tracing::debug!("redirect to {}", target_path);
@@ -1,6 +1,5 @@
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::Hasher,
+    collections::HashSet,
     mem::take,
     sync::atomic::{AtomicU32, Ordering},
     time::Duration,
@@ -8,83 +7,44 @@ use std::{
 
 use async_trait::async_trait;
 use axum::response::Redirect;
-use rand::Rng;
 use ws_derive::LogicalModule;
 
+use super::{
+    m_metric_observor::MetricObservor,
+    node_selector::{affinity_candidates, new_node_selector, NodeSelector, NodeWeighteFetcher},
+};
+
 use crate::{
     config::NodesConfig,
     general::{
-        app::{m_executor::Executor, AppMetaManager, DataEventTrigger},
+        app::{m_executor::Executor, AffinityPattern, AppMetaManager, DataEventTrigger, FnMeta},
         network::{
             m_p2p::{P2PModule, RPCCaller},
-            proto::{self, distribute_task_req::Trigger, DistributeTaskReq},
+            proto::{self, distribute_task_req::Trigger, DistributeTaskReq, DistributeTaskResp},
             proto_ext::ProtoExtDataEventTrigger,
         },
     },
     logical_module_view_impl,
-    result::{WSResult, WSResultExt, WsFuncError},
+    result::{WSResult, WSResultExt, WsFuncError, WsNetworkLogicErr},
     sys::{LogicalModule, LogicalModuleNewArgs, LogicalModulesRef, NodeID},
     util::JoinHandleWrapper,
 };
 
-#[allow(dead_code)]
-trait NodeWeighteFetcher: Send + Sync + 'static {
-    // NOTE: get weight return node weight
-    // larger is better
-    fn get_node_weight(&self, id: NodeID) -> f64;
-}
-
-#[allow(dead_code)]
-struct StrawNodeSelector {
-    weight_fetcher: Box<dyn NodeWeighteFetcher>,
-}
-
-// NOTE: Straw2 algorithm
-impl NodeSelector for StrawNodeSelector {
-    fn select_node(&self, all_node_cnt: usize, fn_name: &str) -> NodeID {
-        // NOTE: 1 is an impossible value for straw
-        let mut max_straw: f64 = 1.0;
-        let mut node_id: NodeID = 1;
-        // NOTE: node id is [1,all_node_cnt]
-        for i in 1..all_node_cnt + 1 {
-            let mut hasher = DefaultHasher::new();
-            hasher.write(fn_name.as_bytes());
-            hasher.write_u64(i as u64);
-            let hash = hasher.finish() % 63336;
-            let weight = self.weight_fetcher.get_node_weight(i as NodeID);
-            let straw = ((hash as f64) / 65536.0).ln() / weight;
-            if (max_straw - 1.0).abs() < 0.000001 || max_straw < straw {
-                max_straw = straw;
-                node_id = i as NodeID;
-            }
-        }
-        return node_id;
-    }
-}
-
-pub trait NodeSelector: Send + Sync + 'static {
-    fn select_node(&self, all_node_cnt: usize, fn_name: &str) -> NodeID;
-}
-
-#[allow(dead_code)]
-struct HashNodeSelector;
-
-impl NodeSelector for HashNodeSelector {
-    fn select_node(&self, all_node_cnt: usize, fn_name: &str) -> NodeID {
-        // hash
-        let mut hasher = DefaultHasher::new();
-        hasher.write(fn_name.as_bytes());
-        let n = hasher.finish();
-
-        (n % all_node_cnt as u64 + 1) as NodeID
-    }
-}
-
 logical_module_view_impl!(MasterView);
 logical_module_view_impl!(MasterView, p2p, P2PModule);
 logical_module_view_impl!(MasterView, master, Option<Master>);
 logical_module_view_impl!(MasterView, appmeta_manager, AppMetaManager);
 logical_module_view_impl!(MasterView, executor, Executor);
+logical_module_view_impl!(MasterView, metric_observor, Option<MetricObservor>);
+
+/// Node weights from the loads the nodes report.
+struct ReportedLoadWeight(MasterView);
+
+impl NodeWeighteFetcher for ReportedLoadWeight {
+    fn get_node_weight(&self, id: NodeID, app: &str) -> f64 {
+        self.0.metric_observor().node_weight(id, app)
+    }
+}
 
 #[derive(Clone)]
 pub struct FunctionTriggerContext {
@@ -105,6 +65,7 @@ pub struct Master {
     view: MasterView,
     // task_id_allocator: AtomicU32,
     ope_id_allocator: AtomicU32,
+    node_selector: Box<dyn NodeSelector>,
 }
 
 #[async_trait]
@@ -118,6 +79,12 @@ impl LogicalModule for Master {
             rpc_caller_distribute_task: RPCCaller::default(),
             ope_id_allocator: AtomicU32::new(0),
             rpc_caller_add_wait_target: RPCCaller::default(),
+            node_selector: new_node_selector(
+                args.nodes_config.node_selector,
+                Box::new(ReportedLoadWeight(MasterView::new(
+                    args.logical_modules_ref.clone(),
+                ))),
+            ),
         }
     }
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
@@ -129,35 +96,101 @@ impl LogicalModule for Master {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum ScheduleWorkload {
     JavaAppConstruct,
+    /// app versions are managed by workers
+    AppMgmt,
 }
 
 pub struct TargetNode(pub NodeID);
 
 impl TargetNode {
-    pub fn http_redirect(&self, nodesconf: &NodesConfig) -> Redirect {
+    /// Redirect the request of `path` to the node, `path` starts with '/'
+    pub fn http_redirect(&self, nodesconf: &NodesConfig, path: &str) -> WSResult<Redirect> {
         tracing::debug!("node_id : {:?}", self.0);
-        let conf = nodesconf.get_nodeconfig(self.0);
+        // the node might have left the cluster after being scheduled
+        let conf = nodesconf
+            .get_nodeconfig(self.0)
+            .ok_or(WsNetworkLogicErr::InvaidNodeID(self.0))?;
         tracing::debug!("conf.http_url() : {:?}", &conf.http_url().clone());
-        Redirect::temporary(&conf.http_url())
+        Ok(Redirect::temporary(&format!(
+            "{}{}",
+            conf.http_url().trim_end_matches('/'),
+            path
+        )))
     }
 }
 
 impl Master {
     pub fn schedule(&self, wl: ScheduleWorkload) -> TargetNode {
         match wl {
-            ScheduleWorkload::JavaAppConstruct => {
-                let workers = self.view.p2p().nodes_config.get_worker_nodes();
-                // random select one
-                let mut rng = rand::thread_rng();
-                let idx = rng.gen_range(0..workers.len());
-                TargetNode(*workers.iter().nth(idx).unwrap())
+            ScheduleWorkload::JavaAppConstruct | ScheduleWorkload::AppMgmt => {
+                TargetNode(self.select_node("", ""))
+            }
+        }
+    }
+
+    /// Like `schedule` skipping the workers in `exclude`, `None` when no other is left.
+    pub fn schedule_excluding(
+        &self,
+        wl: ScheduleWorkload,
+        exclude: &[NodeID],
+    ) -> Option<TargetNode> {
+        match wl {
+            ScheduleWorkload::JavaAppConstruct | ScheduleWorkload::AppMgmt => {
+                let mut workers = self.sorted_workers();
+                workers.retain(|node| !exclude.contains(node));
+                (!workers.is_empty()).then(|| TargetNode(self.select_node_from(&workers, "", "")))
             }
         }
     }
-    pub async fn handle_http_schedule(&self, _app: &str) -> NodeID {
-        self.select_node()
+    /// A worker for an http call of `func` of `app` allowed by its affinity rules, or by the rules
+    /// of every http function of the app when `func` is empty, skipping the ones in `exclude`.
+    pub async fn handle_http_schedule(
+        &self,
+        app: &str,
+        func: &str,
+        exclude: &[NodeID],
+    ) -> WSResult<NodeID> {
+        let app_meta = self
+            .view
+            .appmeta_manager()
+            .get_app_meta(app)
+            .await?
+            .ok_or_else(|| WsFuncError::AppNotFound {
+                app: app.to_owned(),
+            })?;
+        let fns = if func.is_empty() {
+            app_meta.0.fns()
+        } else {
+            let _ = app_meta
+                .0
+                .get_fn_meta(func)
+                .ok_or_else(|| WsFuncError::FuncNotFound {
+                    app: app.to_owned(),
+                    func: func.to_owned(),
+                })?;
+            vec![func.to_owned()]
+        };
+        let mut candidates = self.sorted_workers();
+        for func in fns {
+            let fnmeta = app_meta.0.get_fn_meta(&func).unwrap();
+            if fnmeta.allow_http_call().is_none() {
+                continue;
+            }
+            let allowed = self.affinity_candidates(app, &func, fnmeta)?;
+            candidates.retain(|node| allowed.contains(node));
+        }
+        candidates.retain(|node| !exclude.contains(node));
+        if candidates.is_empty() {
+            return Err(WsFuncError::NoNodeMatchesAffinity {
+                app: app.to_owned(),
+                func: func.to_owned(),
+            }
+            .into());
+        }
+        Ok(self.select_node_from(&candidates, app, func))
     }
     // pub async fn schedule_one_trigger(&self, app: String, func: String, trigger_data: Trigger) {
     //     match self
@@ -184,47 +217,255 @@ impl Master {
     //         }
     //     }
     // }
-    pub fn select_node(&self) -> NodeID {
-        let workers = self.view.p2p().nodes_config.get_worker_nodes();
-        let mut rng = rand::thread_rng();
-        let idx = rng.gen_range(0..workers.len());
-        workers.iter().nth(idx).unwrap().clone()
+    /// A worker to run `func` of `app` by the configured `NodeSelectPolicy`,
+    /// `func` is empty when the call is not known yet.
+    pub fn select_node(&self, app: &str, func: &str) -> NodeID {
+        self.select_node_from(&self.sorted_workers(), app, func)
     }
 
-    /// Trigger a function execution on target nodes
-    ///
-    /// # Arguments
-    /// * `ctx` - The context containing function and target information
-    ///
-    /// # Returns
-    /// * `WSResult<()>` - Result indicating success or failure
-    pub async fn trigger_func_call(&self, ctx: FunctionTriggerContext) -> WSResult<()> {
-        // Validate function exists and is executable
+    fn sorted_workers(&self) -> Vec<NodeID> {
+        let mut workers: Vec<NodeID> = self
+            .view
+            .p2p()
+            .nodes_config
+            .get_worker_nodes()
+            .into_iter()
+            .collect();
+        // same order on every call, so hashing policies are stable
+        workers.sort();
+        workers
+    }
+
+    fn select_node_from(&self, candidates: &[NodeID], app: &str, func: &str) -> NodeID {
+        let node = self.node_selector.select_node(candidates, app, func);
+        self.view.metric_observor().note_scheduled(node);
+        node
+    }
+
+    /// One of `candidates`, one in `near` when there is.
+    fn select_node_near(
+        &self,
+        candidates: &[NodeID],
+        near: &HashSet<NodeID>,
+        app: &str,
+        func: &str,
+    ) -> NodeID {
+        let local: Vec<NodeID> = candidates
+            .iter()
+            .filter(|node| near.contains(node))
+            .copied()
+            .collect();
+        if local.is_empty() {
+            self.select_node_from(candidates, app, func)
+        } else {
+            self.select_node_from(&local, app, func)
+        }
+    }
+
+    /// Workers allowed by the affinity and anti-affinity rules of `func`, not empty.
+    fn affinity_candidates(&self, app: &str, func: &str, fnmeta: &FnMeta) -> WSResult<Vec<NodeID>> {
+        let with_instance = if fnmeta
+            .anti_affinity
+            .as_ref()
+            .map_or(false, |anti| anti.instances)
+        {
+            self.view.metric_observor().nodes_with_instance(app)
+        } else {
+            HashSet::new()
+        };
+        let candidates = affinity_candidates(
+            &self.view.p2p().nodes_config.all_nodes(),
+            fnmeta.affinity.as_ref(),
+            fnmeta.anti_affinity.as_ref(),
+            &with_instance,
+        );
+        if candidates.is_empty() {
+            return Err(WsFuncError::NoNodeMatchesAffinity {
+                app: app.to_owned(),
+                func: func.to_owned(),
+            }
+            .into());
+        }
+        Ok(candidates)
+    }
+
+    /// One worker to run a call of `func` by its affinity rules.
+    pub fn select_node_by_affinity(
+        &self,
+        app: &str,
+        func: &str,
+        fnmeta: &FnMeta,
+    ) -> WSResult<NodeID> {
+        if fnmeta.affinity.is_none() && fnmeta.anti_affinity.is_none() {
+            return Ok(self.select_node(app, func));
+        }
+        let candidates = self.affinity_candidates(app, func, fnmeta)?;
+        Ok(self.select_node_from(&candidates, app, func))
+    }
+
+    /// Workers a data trigger runs `func` on: every allowed one for `*` or a node list,
+    /// n distinct ones for a node count, one without affinity. The picked ones are those in
+    /// `near` holding the data when allowed, then the ones chosen by the `NodeSelectPolicy`.
+    pub fn select_nodes_by_affinity(
+        &self,
+        app: &str,
+        func: &str,
+        fnmeta: &FnMeta,
+        near: &HashSet<NodeID>,
+    ) -> WSResult<Vec<NodeID>> {
+        let count = match fnmeta.affinity.as_ref().map(|affinity| &affinity.nodes) {
+            Some(AffinityPattern::All | AffinityPattern::List(_)) => {
+                return self.affinity_candidates(app, func, fnmeta);
+            }
+            Some(AffinityPattern::NodeCount(count)) => *count,
+            None => 1,
+        };
+        let mut candidates = self.affinity_candidates(app, func, fnmeta)?;
+        if candidates.len() < count {
+            tracing::warn!(
+                "{}/{} wants {} nodes but only {} match its affinity",
+                app,
+                func,
+                count,
+                candidates.len()
+            );
+        }
+        let mut selected = vec![];
+        while selected.len() < count && !candidates.is_empty() {
+            let node = self.select_node_near(&candidates, near, app, func);
+            candidates.retain(|&candidate| candidate != node);
+            selected.push(node);
+        }
+        Ok(selected)
+    }
+
+    /// Validate function exists and is executable by a trigger, returns its meta
+    async fn check_async_fn(&self, app: &str, func: &str) -> WSResult<FnMeta> {
         let app_meta = self
             .view
             .appmeta_manager()
-            .get_app_meta(&ctx.app_name)
+            .get_app_meta(app)
             .await?
             .ok_or_else(|| WsFuncError::AppNotFound {
-                app: ctx.app_name.clone(),
+                app: app.to_owned(),
             })?;
 
-        let fn_meta =
-            app_meta
-                .0
-                .get_fn_meta(&ctx.fn_name)
-                .ok_or_else(|| WsFuncError::FuncNotFound {
-                    app: ctx.app_name.clone(),
-                    func: ctx.fn_name.clone(),
-                })?;
+        let fn_meta = app_meta
+            .0
+            .get_fn_meta(func)
+            .ok_or_else(|| WsFuncError::FuncNotFound {
+                app: app.to_owned(),
+                func: func.to_owned(),
+            })?;
 
         if !fn_meta.sync_async.asyncable() {
             return Err(WsFuncError::FuncHttpNotSupported {
-                fname: ctx.fn_name,
+                fname: func.to_owned(),
                 fmeta: fn_meta.clone(),
             }
             .into());
         }
+        Ok(fn_meta.clone())
+    }
+
+    /// The worker to run the ticks of a scheduled function on, see
+    /// [`Master::trigger_schedule_call`].
+    pub async fn select_schedule_node(&self, app: &str, func: &str) -> WSResult<NodeID> {
+        let fnmeta = self.check_async_fn(app, func).await?;
+        self.select_node_by_affinity(app, func, &fnmeta)
+    }
+
+    /// Dispatch one tick of a scheduled function to `node`, fails unless the node took it.
+    ///
+    /// Dispatching a tick again is harmless, the node runs each tick once.
+    pub async fn trigger_schedule_call(
+        &self,
+        app: &str,
+        func: &str,
+        tick_ms: i64,
+        node: NodeID,
+    ) -> WSResult<()> {
+        let resp = self
+            .dispatch_trigger_to(
+                node,
+                app,
+                func,
+                Trigger::ScheduleTick(proto::distribute_task_req::ScheduleTick { tick_ms }),
+            )
+            .await?;
+        if !resp.success {
+            return Err(WsFuncError::TriggerRejected {
+                app: app.to_owned(),
+                func: func.to_owned(),
+                node,
+                reason: resp.err_msg,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Tell a function bound by `trigger_by_expire` that the data of `key` expired
+    pub async fn trigger_expire_call(&self, app: &str, func: &str, key: Vec<u8>) -> WSResult<()> {
+        self.dispatch_trigger(
+            app,
+            func,
+            Trigger::EventExpire(proto::distribute_task_req::DataEventTriggerExpire { key }),
+        )
+        .await
+    }
+
+    /// Run a trigger not caused by any task on one worker
+    async fn dispatch_trigger(&self, app: &str, func: &str, trigger: Trigger) -> WSResult<()> {
+        let fnmeta = self.check_async_fn(app, func).await?;
+
+        let node = self.select_node_by_affinity(app, func, &fnmeta)?;
+        let resp = self.dispatch_trigger_to(node, app, func, trigger).await?;
+        if !resp.success {
+            tracing::warn!(
+                "trigger of {}/{} rejected by node {}: {}",
+                app,
+                func,
+                node,
+                resp.err_msg
+            );
+        }
+        Ok(())
+    }
+
+    async fn dispatch_trigger_to(
+        &self,
+        node: NodeID,
+        app: &str,
+        func: &str,
+        trigger: Trigger,
+    ) -> WSResult<DistributeTaskResp> {
+        tracing::debug!("trigger {}/{} by {:?} on node {}", app, func, trigger, node);
+        self.rpc_caller_distribute_task
+            .call(
+                self.view.p2p(),
+                node,
+                DistributeTaskReq {
+                    app: app.to_owned(),
+                    func: func.to_owned(),
+                    task_id: Some(self.view.executor().register_sub_task()),
+                    trigger_src_task_id: None,
+                    trigger: Some(trigger),
+                },
+                Some(Duration::from_secs(60)),
+            )
+            .await
+    }
+
+    /// Trigger a function execution on target nodes
+    ///
+    /// # Arguments
+    /// * `ctx` - The context containing function and target information
+    ///
+    /// # Returns
+    /// * `WSResult<()>` - Result indicating success or failure
+    pub async fn trigger_func_call(&self, ctx: FunctionTriggerContext) -> WSResult<()> {
+        let _ = self.check_async_fn(&ctx.app_name, &ctx.fn_name).await?;
 
         tracing::debug!("trigger func call for data({:?})", ctx.data_unique_id);
 
@@ -233,7 +474,10 @@ impl Master {
         let opeid = self.ope_id_allocator.fetch_add(1, Ordering::Relaxed);
 
         // Create trigger using the ProtoExtDataEventTrigger trait
-        let trigger = DataEventTrigger::Write.into_proto_trigger(ctx.data_unique_id.clone(), opeid);
+        let trigger = ctx
+            .event_type
+            .clone()
+            .into_proto_trigger(ctx.data_unique_id.clone(), opeid);
 
         // Create and send tasks to target nodes
         let mut each_node_calling = vec![];
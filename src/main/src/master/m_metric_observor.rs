@@ -9,10 +9,18 @@ use crate::{
     util::JoinHandleWrapper,
 };
 use async_trait::async_trait;
+use dashmap::DashMap;
 use prometheus_client::registry::Registry;
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 use ws_derive::LogicalModule;
 
-use self::prometheus::{Metrics, RscLabels, RscType};
+use self::prometheus::{
+    AppCallLabels, AppVersionLabels, CallResult, Locality, Metrics, RscLabels, RscType,
+    TriggerLocalityLabels,
+};
 
 // pub struct NodeRscMetric {
 //     used_cpu: f64,
@@ -63,9 +71,44 @@ pub mod prometheus {
         MemUsed,
     }
 
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+    pub struct AppCallLabels {
+        pub app: String,
+        pub version: u64,
+        pub result: CallResult,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+    pub enum CallResult {
+        Success,
+        Failure,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+    pub struct AppVersionLabels {
+        pub app: String,
+        pub version: u64,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+    pub struct TriggerLocalityLabels {
+        pub app: String,
+        pub locality: Locality,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+    pub enum Locality {
+        /// the node holds the data the function is triggered by
+        Local,
+        Remote,
+    }
+
     pub struct Metrics {
         pub requests: Family<RequestLabels, Counter>,
         pub rscs: Family<RscLabels, Gauge<f64, AtomicU64>>,
+        pub app_calls: Family<AppCallLabels, Counter>,
+        pub app_call_latency_ms: Family<AppVersionLabels, Counter>,
+        pub trigger_locality: Family<TriggerLocalityLabels, Counter>,
     }
 
     pub fn new_registry_and_metrics() -> (Metrics, Registry) {
@@ -73,6 +116,9 @@ pub mod prometheus {
         let metrics = Metrics {
             requests: Family::default(),
             rscs: Family::default(),
+            app_calls: Family::default(),
+            app_call_latency_ms: Family::default(),
+            trigger_locality: Family::default(),
         };
         registry.register(
             "requests",
@@ -80,12 +126,45 @@ pub mod prometheus {
             metrics.requests.clone(),
         );
         registry.register("rscs", "Resource usage record", metrics.rscs.clone());
+        registry.register(
+            "app_calls",
+            "Function calls of each app version",
+            metrics.app_calls.clone(),
+        );
+        registry.register(
+            "app_call_latency_ms",
+            "Summed latency of the function calls of each app version",
+            metrics.app_call_latency_ms.clone(),
+        );
+        registry.register(
+            "trigger_locality",
+            "Data triggered function calls by whether their node holds the data, local over all is the hit rate",
+            metrics.trigger_locality.clone(),
+        );
         (metrics, registry)
     }
 }
 
 pub struct NodeFnCacheMetric();
 
+/// Loads older than this are from a node that stopped reporting.
+const NODE_LOAD_STALE_AFTER: Duration = Duration::from_secs(5);
+/// Weight of a node whose load is unknown or stale.
+const UNKNOWN_LOAD_WEIGHT: f64 = 0.5;
+/// Free cpu or memory below this counts as this, so a full node still gets a small weight.
+const MIN_FREE_FRACTION: f64 = 0.05;
+/// Weight multiplier of a node with a warm instance of the app.
+const WARM_INSTANCE_BONUS: f64 = 2.0;
+
+/// Latest load reported by a node.
+struct NodeLoad {
+    metric: proto::metric::RscMetric,
+    warm_apps: HashSet<String>,
+    reported_at: Instant,
+    /// tasks master scheduled to the node since the report, not counted in it yet
+    scheduled_since: u32,
+}
+
 logical_module_view_impl!(MetricObservorView);
 logical_module_view_impl!(MetricObservorView, p2p, P2PModule);
 logical_module_view_impl!(MetricObservorView, metric_observor, Option<MetricObservor>);
@@ -96,7 +175,9 @@ pub struct MetricObservor {
     metrics: Metrics,
     // node_rsc_metric: SkipMap<NodeID, proto::metric::RscMetric>,
     view: MetricObservorView,
+    node_loads: DashMap<NodeID, NodeLoad>,
     msg_handler: MsgHandler<proto::metric::RscMetric>,
+    app_call_handler: MsgHandler<proto::metric::AppCallMetrics>,
 }
 
 #[async_trait]
@@ -111,7 +192,9 @@ impl LogicalModule for MetricObservor {
             metrics,
 
             view: MetricObservorView::new(args.logical_modules_ref.clone()),
+            node_loads: DashMap::new(),
             msg_handler: MsgHandler::default(),
+            app_call_handler: MsgHandler::default(),
         }
     }
     async fn start(&self) -> WSResult<Vec<JoinHandleWrapper>> {
@@ -124,14 +207,112 @@ impl LogicalModule for MetricObservor {
                 let _ = ob.insert_node_rsc_metric(responser.node_id, msg);
                 Ok(())
             });
+        let view = self.view.clone();
+        self.app_call_handler
+            .regist(self.view.p2p(), move |_responser, msg| {
+                view.metric_observor().insert_app_call_metrics(msg);
+                Ok(())
+            });
 
         Ok(vec![])
     }
 }
 
 impl MetricObservor {
-    fn insert_node_rsc_metric(&self, nid: NodeID, msg: proto::metric::RscMetric) {
+    /// Larger is better: the free cpu and memory shares of the node over its running tasks,
+    /// doubled when it has a warm instance of `app`.
+    pub fn node_weight(&self, node: NodeID, app: &str) -> f64 {
+        let Some(load) = self.node_loads.get(&node) else {
+            return UNKNOWN_LOAD_WEIGHT;
+        };
+        if load.reported_at.elapsed() > NODE_LOAD_STALE_AFTER {
+            return UNKNOWN_LOAD_WEIGHT;
+        }
+        let metric = &load.metric;
+        let cpu_free = (1.0 - metric.cpu_used as f64 / 100.0).max(MIN_FREE_FRACTION);
+        let mem_free = if metric.mem_all > 0.0 {
+            (1.0 - metric.mem_used as f64 / metric.mem_all as f64).max(MIN_FREE_FRACTION)
+        } else {
+            1.0
+        };
+        let tasks = (metric.running_tasks + load.scheduled_since) as f64;
+        let mut weight = cpu_free * mem_free / (1.0 + tasks);
+        if load.warm_apps.contains(app) {
+            weight *= WARM_INSTANCE_BONUS;
+        }
+        weight
+    }
+
+    /// Nodes whose last report has a warm instance of `app`.
+    pub fn nodes_with_instance(&self, app: &str) -> HashSet<NodeID> {
+        self.node_loads
+            .iter()
+            .filter(|load| load.warm_apps.contains(app))
+            .map(|load| *load.key())
+            .collect()
+    }
+
+    /// Count a task scheduled to `node` until its next report.
+    pub fn note_scheduled(&self, node: NodeID) {
+        if let Some(mut load) = self.node_loads.get_mut(&node) {
+            load.scheduled_since += 1;
+        }
+    }
+
+    /// Count a data triggered call of `app` placed on a node holding the data or not.
+    pub fn record_trigger_locality(&self, app: &str, local: bool) {
+        let _ = self
+            .metrics
+            .trigger_locality
+            .get_or_create(&TriggerLocalityLabels {
+                app: app.to_owned(),
+                locality: if local {
+                    Locality::Local
+                } else {
+                    Locality::Remote
+                },
+            })
+            .inc();
+    }
+
+    fn insert_app_call_metrics(&self, msg: proto::metric::AppCallMetrics) {
+        for call in msg.calls {
+            for (result, cnt) in [
+                (CallResult::Success, call.success),
+                (CallResult::Failure, call.failure),
+            ] {
+                let _ = self
+                    .metrics
+                    .app_calls
+                    .get_or_create(&AppCallLabels {
+                        app: call.app.clone(),
+                        version: call.version,
+                        result,
+                    })
+                    .inc_by(cnt);
+            }
+            let _ = self
+                .metrics
+                .app_call_latency_ms
+                .get_or_create(&AppVersionLabels {
+                    app: call.app,
+                    version: call.version,
+                })
+                .inc_by(call.latency_ms_sum);
+        }
+    }
+
+    fn insert_node_rsc_metric(&self, nid: NodeID, mut msg: proto::metric::RscMetric) {
         // let _ = self.node_rsc_metric.insert(nid, msg);
+        let _ = self.node_loads.insert(
+            nid,
+            NodeLoad {
+                warm_apps: std::mem::take(&mut msg.warm_apps).into_iter().collect(),
+                metric: msg.clone(),
+                reported_at: Instant::now(),
+                scheduled_since: 0,
+            },
+        );
         let _ = self
             .metrics
             .rscs
@@ -1,5 +1,7 @@
 pub mod app;
 pub mod data;
+pub mod http_proxy;
 pub mod m_http_handler;
 pub mod m_master;
 pub mod m_metric_observor;
+pub mod node_selector;
@@ -0,0 +1,293 @@
+//! Policies of picking the worker running a function, chosen by `node_selector` in the node config.
+//!
+//! The load aware ones weigh nodes by `NodeWeighteFetcher`, which master backs with the
+//! cpu, memory, running tasks and warm apps each node reports to `MetricObservor`.
+//! The candidates are the workers allowed by the function's affinity rules, see `affinity_candidates`.
+use crate::{
+    config::{NodeConfig, NodeSelectPolicy},
+    general::app::{AffinityPattern, AffinityRule, AntiAffinityRule},
+    sys::NodeID,
+};
+use rand::Rng;
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::Hasher,
+};
+
+pub trait NodeWeighteFetcher: Send + Sync + 'static {
+    // NOTE: get weight return node weight
+    // larger is better
+    fn get_node_weight(&self, id: NodeID, app: &str) -> f64;
+}
+
+pub trait NodeSelector: Send + Sync + 'static {
+    /// One of `candidates` to run `func` of `app`, `candidates` is not empty.
+    fn select_node(&self, candidates: &[NodeID], app: &str, func: &str) -> NodeID;
+}
+
+pub fn new_node_selector(
+    policy: NodeSelectPolicy,
+    weight_fetcher: Box<dyn NodeWeighteFetcher>,
+) -> Box<dyn NodeSelector> {
+    match policy {
+        NodeSelectPolicy::Random => Box::new(RandomNodeSelector),
+        NodeSelectPolicy::LeastLoaded => Box::new(LeastLoadedNodeSelector { weight_fetcher }),
+        NodeSelectPolicy::Straw2 => Box::new(StrawNodeSelector { weight_fetcher }),
+        NodeSelectPolicy::ConsistentHash => Box::new(HashNodeSelector),
+    }
+}
+
+/// Workers allowed by the rules of a function, sorted so hashing policies are stable.
+///
+/// Nodes must have all tags of `affinity` and none of the tags of `anti_affinity`, a node list
+/// restricts them further. Nodes in `with_instance` are avoided when `anti_affinity` asks so,
+/// unless every allowed node has an instance.
+pub fn affinity_candidates(
+    nodes: &[(NodeID, NodeConfig)],
+    affinity: Option<&AffinityRule>,
+    anti_affinity: Option<&AntiAffinityRule>,
+    with_instance: &HashSet<NodeID>,
+) -> Vec<NodeID> {
+    let mut candidates: Vec<NodeID> = nodes
+        .iter()
+        .filter(|(_, config)| config.is_worker())
+        .filter(|(id, config)| {
+            affinity.map_or(true, |affinity| {
+                affinity.tags.iter().all(|tag| tag.matches(config))
+                    && match &affinity.nodes {
+                        AffinityPattern::List(list) => list.contains(id),
+                        AffinityPattern::All | AffinityPattern::NodeCount(_) => true,
+                    }
+            })
+        })
+        .filter(|(_, config)| {
+            anti_affinity.map_or(true, |anti| {
+                !anti.tags.iter().any(|tag| tag.matches(config))
+            })
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    candidates.sort();
+    if anti_affinity.map_or(false, |anti| anti.instances)
+        && candidates.iter().any(|id| !with_instance.contains(id))
+    {
+        candidates.retain(|id| !with_instance.contains(id));
+    }
+    candidates
+}
+
+fn hash_of(key: &str, salt: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key.as_bytes());
+    hasher.write_u64(salt);
+    hasher.finish()
+}
+
+struct RandomNodeSelector;
+
+impl NodeSelector for RandomNodeSelector {
+    fn select_node(&self, candidates: &[NodeID], _app: &str, _func: &str) -> NodeID {
+        candidates[rand::thread_rng().gen_range(0..candidates.len())]
+    }
+}
+
+struct LeastLoadedNodeSelector {
+    weight_fetcher: Box<dyn NodeWeighteFetcher>,
+}
+
+impl NodeSelector for LeastLoadedNodeSelector {
+    fn select_node(&self, candidates: &[NodeID], app: &str, _func: &str) -> NodeID {
+        // ties are broken randomly, so idle nodes share the calls
+        let offset = rand::thread_rng().gen_range(0..candidates.len());
+        let mut best = candidates[offset];
+        let mut best_weight = self.weight_fetcher.get_node_weight(best, app);
+        for i in 1..candidates.len() {
+            let node = candidates[(offset + i) % candidates.len()];
+            let weight = self.weight_fetcher.get_node_weight(node, app);
+            if weight > best_weight {
+                best = node;
+                best_weight = weight;
+            }
+        }
+        best
+    }
+}
+
+struct StrawNodeSelector {
+    weight_fetcher: Box<dyn NodeWeighteFetcher>,
+}
+
+// NOTE: Straw2 algorithm
+impl NodeSelector for StrawNodeSelector {
+    fn select_node(&self, candidates: &[NodeID], app: &str, func: &str) -> NodeID {
+        let key = format!("{}/{}", app, func);
+        let mut max_straw = f64::NEG_INFINITY;
+        let mut node_id = candidates[0];
+        for &node in candidates {
+            // (0, 1], so ln is finite
+            let hash = (hash_of(&key, node as u64) % 65536 + 1) as f64 / 65536.0;
+            let weight = self
+                .weight_fetcher
+                .get_node_weight(node, app)
+                .max(f64::MIN_POSITIVE);
+            let straw = hash.ln() / weight;
+            if straw > max_straw {
+                max_straw = straw;
+                node_id = node;
+            }
+        }
+        node_id
+    }
+}
+
+/// Points of each node on the hash ring.
+const HASH_RING_VNODES: u64 = 64;
+
+struct HashNodeSelector;
+
+impl NodeSelector for HashNodeSelector {
+    fn select_node(&self, candidates: &[NodeID], app: &str, func: &str) -> NodeID {
+        let key = hash_of(&format!("{}/{}", app, func), 0);
+        // the first point clockwise from the key, the smallest one when wrapping around
+        let mut next: Option<(u64, NodeID)> = None;
+        let mut first: Option<(u64, NodeID)> = None;
+        for &node in candidates {
+            for vnode in 0..HASH_RING_VNODES {
+                let point = hash_of(&node.to_string(), vnode);
+                if point >= key && next.map_or(true, |(p, _)| point < p) {
+                    next = Some((point, node));
+                }
+                if first.map_or(true, |(p, _)| point < p) {
+                    first = Some((point, node));
+                }
+            }
+        }
+        next.or(first).unwrap().1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{affinity_candidates, new_node_selector, NodeWeighteFetcher};
+    use crate::{
+        config::{NodeConfig, NodeSelectPolicy},
+        general::app::{AffinityPattern, AffinityRule, AntiAffinityRule, NodeTag},
+        sys::NodeID,
+    };
+    use std::collections::HashSet;
+
+    struct FixedWeight;
+
+    impl NodeWeighteFetcher for FixedWeight {
+        fn get_node_weight(&self, id: NodeID, app: &str) -> f64 {
+            match (id, app) {
+                (3, _) => 10.0,
+                (2, "warm") => 100.0,
+                _ => 1.0,
+            }
+        }
+    }
+
+    #[test]
+    fn test_node_selectors() {
+        let candidates: Vec<NodeID> = vec![1, 2, 3, 4];
+
+        let least_loaded = new_node_selector(NodeSelectPolicy::LeastLoaded, Box::new(FixedWeight));
+        assert_eq!(least_loaded.select_node(&candidates, "app", "f"), 3);
+        assert_eq!(least_loaded.select_node(&candidates, "warm", "f"), 2);
+
+        // straw2 picks nodes in proportion to their weight
+        let straw = new_node_selector(NodeSelectPolicy::Straw2, Box::new(FixedWeight));
+        let picked_3 = (0..1000)
+            .filter(|i| straw.select_node(&candidates, "app", &format!("f{}", i)) == 3)
+            .count();
+        assert!(picked_3 > 700, "node 3 picked {} times", picked_3);
+
+        // consistent hash only moves the functions of a removed node
+        let hash = new_node_selector(NodeSelectPolicy::ConsistentHash, Box::new(FixedWeight));
+        for i in 0..100 {
+            let func = format!("f{}", i);
+            let before = hash.select_node(&candidates, "app", &func);
+            assert_eq!(before, hash.select_node(&candidates, "app", &func));
+            let after = hash.select_node(&[1, 2, 4], "app", &func);
+            if before != 3 {
+                assert_eq!(before, after);
+            }
+        }
+
+        let random = new_node_selector(NodeSelectPolicy::Random, Box::new(FixedWeight));
+        assert!(candidates.contains(&random.select_node(&candidates, "app", "f")));
+    }
+
+    #[test]
+    fn test_affinity_candidates() {
+        let node = |id: NodeID, spec: &[&str]| {
+            (
+                id,
+                NodeConfig::new(
+                    format!("127.0.0.1:{}", 2500 + id).parse().unwrap(),
+                    None,
+                    spec.iter().map(|s| s.to_string()).collect(),
+                ),
+            )
+        };
+        let nodes = vec![
+            node(1, &["master", "meta"]),
+            node(2, &["worker", "ssd", "zone-a"]),
+            node(3, &["worker", "ssd", "zone-b"]),
+            node(4, &["worker", "zone-a"]),
+        ];
+        let affinity = |tags: &[&str], nodes: AffinityPattern| AffinityRule {
+            tags: tags
+                .iter()
+                .map(|tag| NodeTag::Custom(tag.to_string()))
+                .collect(),
+            nodes,
+        };
+        let anti = |tags: &[&str], instances: bool| AntiAffinityRule {
+            tags: tags
+                .iter()
+                .map(|tag| NodeTag::Custom(tag.to_string()))
+                .collect(),
+            instances,
+        };
+        let none = HashSet::new();
+
+        assert_eq!(
+            affinity_candidates(&nodes, None, None, &none),
+            vec![2, 3, 4]
+        );
+        let ssd = affinity(&["ssd"], AffinityPattern::NodeCount(1));
+        assert_eq!(
+            affinity_candidates(&nodes, Some(&ssd), None, &none),
+            vec![2, 3]
+        );
+        let listed = affinity(&["zone-a"], AffinityPattern::List(vec![1, 2, 3]));
+        assert_eq!(
+            affinity_candidates(&nodes, Some(&listed), None, &none),
+            vec![2]
+        );
+        let no_ssd = anti(&["ssd"], false);
+        assert_eq!(
+            affinity_candidates(&nodes, None, Some(&no_ssd), &none),
+            vec![4]
+        );
+
+        // instances are avoided only while some allowed node has none
+        let spread = anti(&[], true);
+        let with_instance: HashSet<NodeID> = [2, 4].into_iter().collect();
+        assert_eq!(
+            affinity_candidates(&nodes, None, Some(&spread), &with_instance),
+            vec![3]
+        );
+        assert_eq!(
+            affinity_candidates(
+                &nodes,
+                Some(&ssd),
+                Some(&spread),
+                &[2, 3].into_iter().collect()
+            ),
+            vec![2, 3]
+        );
+    }
+}
@@ -11,7 +11,7 @@ use zip_extract::ZipExtractError;
 
 use crate::{
     general::{
-        app::{m_executor::EventCtx, AppMeta, FnMeta},
+        app::{m_executor::EventCtx, AppMeta, AppMetaProblem, FnMeta},
         data::m_data_general::{DataItemIdx, DataSetMetaV2, DataSplitIdx, EachNodeSplit},
         network::{proto, rpc_model::HashValue},
     },
@@ -31,7 +31,20 @@ pub enum WsNetworkLogicErr {
     DecodeError(DecodeError),
     MsgIdNotDispatchable(u32),
     InvaidNodeID(NodeID),
-    TaskJoinError { err: tokio::task::JoinError },
+    InvalidNodeAddr {
+        node: NodeID,
+        addr: String,
+    },
+    NotMaster {
+        this: NodeID,
+        master: Option<NodeID>,
+    },
+    NoMaster {
+        this: NodeID,
+    },
+    TaskJoinError {
+        err: tokio::task::JoinError,
+    },
 }
 
 #[derive(Debug)]
@@ -111,8 +124,10 @@ pub enum WsSerialErr {
 
 #[derive(Error, Debug)]
 pub enum WsFormatErr {
-    #[error("KeyPatternFormatErr: {key_pattern}, '{{' '}}' should appear in pair, only '_' is allowed to appear as symbol")]
-    KeyPatternFormatErr { key_pattern: String },
+    #[error("KeyPatternFormatErr: {key_pattern}, {reason}")]
+    KeyPatternFormatErr { key_pattern: String, reason: String },
+    #[error("NodeConfigErr: {field}, {reason}")]
+    NodeConfigErr { field: String, reason: String },
 }
 
 #[derive(Debug)]
@@ -201,11 +216,86 @@ pub enum WsFuncError {
         func: String,
         trigger_type: EventCtx,
     },
+    FnTimeout {
+        app: String,
+        func: String,
+        timeout_ms: u64,
+    },
+    FnCpuTimeExceeded {
+        app: String,
+        func: String,
+        cpu_time_ms: u64,
+    },
+    FnMemoryExceeded {
+        app: String,
+        func: String,
+        max_memory_mb: u64,
+    },
+    /// every problem found in app.yaml
+    InvalidAppMeta {
+        app: String,
+        problems: Vec<AppMetaProblem>,
+    },
+    InvalidAppName {
+        app: String,
+        reason: String,
+    },
+    AppVersionNotFound {
+        app: String,
+        version: u64,
+    },
+    /// versions are immutable, a concurrent upload took the version
+    AppVersionExists {
+        app: String,
+        version: u64,
+    },
+    AppNoPreviousVersion {
+        app: String,
+    },
+    InvalidAppCanary {
+        app: String,
+        reason: String,
+    },
+    /// the node is draining and takes no new calls
+    NodeDraining,
+    /// the worker didn't take a trigger dispatched by master
+    TriggerRejected {
+        app: String,
+        func: String,
+        node: NodeID,
+        reason: String,
+    },
+    /// no worker satisfies the affinity and anti-affinity rules of the function
+    NoNodeMatchesAffinity {
+        app: String,
+        func: String,
+    },
 }
 
 #[derive(Debug)]
 pub enum WsDataError {
     InvalidDataType,
+    MasterMetaReplicateFailed {
+        acks: usize,
+        need: usize,
+    },
+    /// master refused a function kv range scan
+    KvScanFailed {
+        reason: String,
+    },
+    /// master refused a function kv watch
+    KvWatchFailed {
+        reason: String,
+    },
+    /// master refused to issue a fencing token for a lock grant
+    FencingTokenFailed {
+        reason: String,
+    },
+    /// a key of a function kv txn couldn't be locked
+    KvTxnLockFailed {
+        key: Vec<u8>,
+        context: String,
+    },
     DataSetNotFound {
         uniqueid: Vec<u8>,
     },
@@ -332,6 +422,16 @@ pub enum WsDataError {
     UnknownCachePosMode {
         mode: u16,
     },
+    /// content doesn't match the checksum recorded for it
+    ChecksumMismatch {
+        unique_id: Vec<u8>,
+        idx: DataItemIdx,
+        context: String,
+    },
+    /// reed-solomon coding of an erasure coded item failed
+    ErasureCodingFailed {
+        reason: String,
+    },
     ItemIdxOutOfRange {
         wanted: DataItemIdx,
         len: u8,
@@ -341,6 +441,11 @@ pub enum WsDataError {
         expected: u64,
         actual: u64,
     },
+    /// a write carried the fencing token of a lock holder that was superseded
+    StaleFencingToken {
+        token: u64,
+        fenced_by: u64,
+    },
     SizeMismatch {
         expected: usize, // 预期的数据大小
         actual: usize,   // 实际的数据大小
@@ -458,6 +563,12 @@ impl From<WsPermissionErr> for WSError {
     }
 }
 
+impl From<WsFormatErr> for WSError {
+    fn from(e: WsFormatErr) -> Self {
+        WSError::WsFormatErr(e)
+    }
+}
+
 impl From<WsFuncError> for WSError {
     fn from(e: WsFuncError) -> Self {
         WSError::WsFuncError(e)
@@ -11,7 +11,10 @@ use crate::{
         },
         m_metric_publisher::MetricPublisher,
         m_os::OperatingSystem,
-        network::{http_handler::HttpHandlerDispatch, m_p2p::P2PModule},
+        network::{
+            http_handler::HttpHandlerDispatch, m_master_election::MasterElection,
+            m_membership::Membership, m_p2p::P2PModule,
+        },
     },
     master::{
         app::m_app_master::MasterAppMgmt, data::m_data_master::DataMaster, m_master::Master,
@@ -23,8 +26,15 @@ use crate::{
 use crate::{result::WSResult, util::JoinHandleWrapper};
 use async_trait::async_trait;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::Mutex;
 
+/// How long a drain waits for the module tasks to end after the modules are shut down.
+const SUB_TASKS_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Sys {
     logical_modules: Arc<Option<LogicalModules>>,
     sub_tasks: Mutex<Vec<JoinHandleWrapper>>,
@@ -53,14 +63,38 @@ impl Sys {
         LogicalModulesRef::new(self.logical_modules.clone())
     }
 
+    /// Run the node until it ends or is drained.
+    ///
+    /// SIGTERM, ctrl-c and [`LogicalModulesRef::request_drain`] drain the node: the modules are
+    /// shut down in the reverse order they started, see [`LogicalModule::shutdown`], then the
+    /// module tasks are given [`SUB_TASKS_JOIN_TIMEOUT`] to end before they are aborted.
     pub async fn wait_for_end(&mut self) {
-        if let Err(err) = (*self.logical_modules).as_ref().unwrap().start(self).await {
+        let modules = (*self.logical_modules).as_ref().unwrap();
+        let mut brx = modules.btx.subscribe();
+        if let Err(err) = modules.start(self).await {
             panic!("start logical nodes error: {:?}", err);
         }
         tracing::info!("modules all started, waiting for end");
-        for task in self.sub_tasks.lock().await.iter_mut() {
-            task.join().await;
+        let mut sub_tasks = std::mem::take(&mut *self.sub_tasks.lock().await);
+        let reason = tokio::select! {
+            _ = join_all(&mut sub_tasks) => return,
+            reason = drain_requested(&mut brx) => reason,
+        };
+        tracing::info!("draining node for {}", reason);
+        modules.shutdown().await;
+        if tokio::time::timeout(SUB_TASKS_JOIN_TIMEOUT, join_all(&mut sub_tasks))
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "module tasks didn't end in {:?} after shutdown, aborting them",
+                SUB_TASKS_JOIN_TIMEOUT
+            );
+            for task in sub_tasks.iter_mut() {
+                task.abort();
+            }
         }
+        tracing::info!("node drained");
     }
 
     #[cfg(test)]
@@ -81,6 +115,52 @@ impl Sys {
     }
 }
 
+/// Join the tasks in order, the joined ones are skipped if this is cancelled and called again.
+async fn join_all(tasks: &mut [JoinHandleWrapper]) {
+    for task in tasks.iter_mut() {
+        task.join().await;
+    }
+}
+
+/// Resolves once the node should drain, with the reason.
+async fn drain_requested(brx: &mut BroadcastReceiver) -> &'static str {
+    // without a SIGTERM listener the node still drains on ctrl-c and drain requests
+    #[cfg(unix)]
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => Some(sigterm),
+        Err(err) => {
+            tracing::warn!(
+                "listen for SIGTERM failed, draining on ctrl-c and drain requests only: {:?}",
+                err
+            );
+            None
+        }
+    };
+    loop {
+        // there is no SIGTERM off unix
+        let terminated = async {
+            #[cfg(unix)]
+            match sigterm.as_mut() {
+                Some(sigterm) => {
+                    let _ = sigterm.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+            #[cfg(not(unix))]
+            std::future::pending::<()>().await;
+        };
+        tokio::select! {
+            _ = terminated => return "SIGTERM",
+            _ = tokio::signal::ctrl_c() => return "ctrl-c",
+            msg = brx.recv() => match msg {
+                Ok(BroadcastMsg::SysDrain) => return "drain request",
+                Ok(BroadcastMsg::SysEnd) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => std::future::pending::<()>().await,
+            },
+        }
+    }
+}
+
 pub type NodeID = u32;
 
 #[derive(Clone)]
@@ -109,6 +189,11 @@ pub trait LogicalModule: Send + Sync + 'static {
     async fn init(&self) -> WSResult<()> {
         Ok(())
     }
+    /// Called when the node is drained, in the reverse order of `start`,
+    /// so the modules a module depends on are still running.
+    async fn shutdown(&self) -> WSResult<()> {
+        Ok(())
+    }
     // async fn listen_async_signal(&self) -> tokio::sync::broadcast::Receiver<LogicalModuleState>;
     // fn listen_sync_signal(&self) -> tokio::sync::broadcast::Receiver<LogicalModuleState>;
 }
@@ -116,9 +201,12 @@ pub trait LogicalModule: Send + Sync + 'static {
 #[derive(Clone, Debug)]
 pub enum BroadcastMsg {
     SysEnd,
+    /// drain and shut down the node, see [`Sys::wait_for_end`]
+    SysDrain,
 }
 
 pub type BroadcastSender = tokio::sync::broadcast::Sender<BroadcastMsg>;
+pub type BroadcastReceiver = tokio::sync::broadcast::Receiver<BroadcastMsg>;
 
 #[derive(Clone)]
 pub struct LogicalModulesRef {
@@ -130,6 +218,27 @@ impl LogicalModulesRef {
         let inner = Arc::downgrade(&inner);
         LogicalModulesRef { inner }
     }
+
+    /// Start the master modules after this node is elected master, see
+    /// [`LogicalModules::start_master_modules`].
+    pub async fn start_master_modules(&self) -> WSResult<bool> {
+        let Some(modules) = self.inner.upgrade() else {
+            return Ok(false);
+        };
+        match modules.as_ref() {
+            Some(modules) => modules.start_master_modules().await,
+            None => Ok(false),
+        }
+    }
+
+    /// Drain and shut down the node, see [`Sys::wait_for_end`].
+    pub fn request_drain(&self) {
+        if let Some(modules) = self.inner.upgrade() {
+            if let Some(modules) = modules.as_ref() {
+                let _ = modules.btx.send(BroadcastMsg::SysDrain);
+            }
+        }
+    }
 }
 // impl LogicalModulesRef {
 //     fn setup(&mut self, modules: Arc<LogicalModules>) {
@@ -261,7 +370,9 @@ macro_rules! start_module {
 macro_rules! start_modules {
     ([$( $module:ident,$modulety:ty ),*], [$( $master_module:ident,$master_modulety:ty ),*],[$( $worker_module:ident,$worker_modulety:ty ),*]) => {
         pub struct LogicalModules {
-
+            pub btx: BroadcastSender,
+            /// tasks of the master modules, `None` until they are started on the elected master
+            master_tasks: Mutex<Option<Vec<JoinHandleWrapper>>>,
             $( pub $module : $modulety, )*
             $( pub $master_module : Option<$master_modulety>, )*
             $( pub $worker_module : Option<$worker_modulety>, )*
@@ -269,7 +380,7 @@ macro_rules! start_modules {
 
         impl LogicalModules {
             pub fn new(config: NodesConfig) -> Arc<Option<LogicalModules>> {
-                let (broadcast_tx, _broadcast_rx) = tokio::sync::broadcast::channel::<BroadcastMsg>(1);
+                let (broadcast_tx, _broadcast_rx) = tokio::sync::broadcast::channel::<BroadcastMsg>(16);
                 let arc = Arc::new(None);
                 let args = LogicalModuleNewArgs {
                     btx: broadcast_tx,
@@ -286,15 +397,18 @@ macro_rules! start_modules {
 
 
                 let mut logical_modules = LogicalModules {
+                    btx: args.btx.clone(),
+                    master_tasks: Mutex::new(None),
                     $( $module : <$modulety>::new(args.clone()), )*
                     $( $master_module : None, )*
                     $( $worker_module : None, )*
                 };
-                let is_master = config.this.1.is_master();
-                assert!(is_master || config.this.1.is_worker());
-                if is_master {
+                assert!(config.this.1.is_master() || config.this.1.is_worker());
+                // master candidates keep the master modules on standby for taking over
+                if config.this.1.can_be_master() {
                     $( logical_modules.$master_module = Some(<$master_modulety>::new(args.clone())); )*
-                } else {
+                }
+                if config.this.1.is_worker() {
                     $( logical_modules.$worker_module = Some(<$worker_modulety>::new(args.clone())); )*
                 }
                 let _ = unsafe { util::unsafe_mut(&*arc) }.replace(logical_modules);
@@ -305,10 +419,6 @@ macro_rules! start_modules {
                     init_module!(self, sys, $module);
                 )*
 
-                $(
-                    init_module_opt!(self, sys, $master_module);
-                )*
-
                 $(
                     init_module_opt!(self, sys, $worker_module);
                 )*
@@ -317,9 +427,10 @@ macro_rules! start_modules {
                     start_module!(self, sys, $module);
                 )*
 
-                $(
-                    start_module_opt!(self, sys, $master_module);
-                )*
+                // the other candidates start them when they take over
+                if self.p2p.nodes_config.this_is_master() {
+                    let _ = self.start_master_modules().await?;
+                }
 
                 $(
                     start_module_opt!(self, sys, $worker_module);
@@ -327,6 +438,52 @@ macro_rules! start_modules {
                 // assert!(self.start_cnt == ALL_MODULES_COUNT.add(0));
                 Ok(())
             }
+            /// Start the master modules, once this node is the elected master.
+            ///
+            /// Returns false if they were started before.
+            pub async fn start_master_modules(&self) -> WSResult<bool> {
+                let mut master_tasks = self.master_tasks.lock().await;
+                if master_tasks.is_some() {
+                    return Ok(false);
+                }
+                let mut tasks = vec![];
+                $(
+                    if let Some(m) = self.$master_module.as_ref() {
+                        m.init().await?;
+                    }
+                )*
+                $(
+                    if let Some(m) = self.$master_module.as_ref() {
+                        tasks.append(&mut m.start().await?);
+                    }
+                )*
+                *master_tasks = Some(tasks);
+                Ok(true)
+            }
+            /// Shut the modules down in the reverse order they started.
+            pub async fn shutdown(&self) {
+                let mut shutdowns = vec![];
+                $(
+                    shutdowns.push((stringify!($module), self.$module.shutdown()));
+                )*
+                let master_started = self.master_tasks.lock().await.is_some();
+                $(
+                    if let Some(m) = self.$master_module.as_ref().filter(|_| master_started) {
+                        shutdowns.push((stringify!($master_module), m.shutdown()));
+                    }
+                )*
+                $(
+                    if let Some(m) = self.$worker_module.as_ref() {
+                        shutdowns.push((stringify!($worker_module), m.shutdown()));
+                    }
+                )*
+                for (name, shutdown) in shutdowns.into_iter().rev() {
+                    tracing::debug!("shutdown module {}", name);
+                    if let Err(err) = shutdown.await {
+                        tracing::warn!("shutdown module {} failed, err: {:?}", name, err);
+                    }
+                }
+            }
         }
     };
 }
@@ -339,12 +496,16 @@ start_modules!(
     [
         p2p,
         P2PModule,
+        membership,
+        Membership,
         metric_publisher,
         MetricPublisher,
         os,
         OperatingSystem,
         kv_store_engine,
         KvStoreEngine,
+        master_election,
+        MasterElection,
         appmeta_manager,
         AppMetaManager,
         data_general,
@@ -76,17 +76,36 @@ impl From<std::thread::JoinHandle<()>> for JoinHandleWrapper {
 
 // TODO: remove unwrap
 impl JoinHandleWrapper {
+    /// Wait for the task or thread to end, does nothing if it was already joined.
+    ///
+    /// A task join cancelled halfway can be joined again.
     pub async fn join(&mut self) {
         match self {
-            Self::Task(handle) => handle.take().unwrap().await.unwrap(),
+            Self::Task(handle) => {
+                let Some(h) = handle.as_mut() else {
+                    return;
+                };
+                let res = h.await;
+                *handle = None;
+                res.unwrap()
+            }
             Self::Thread(handle) => {
-                let handle = handle.take().unwrap();
+                let Some(handle) = handle.take() else {
+                    return;
+                };
                 tokio::task::spawn_blocking(|| handle.join().unwrap())
                     .await
                     .unwrap()
             }
         }
     }
+
+    /// Abort the task if it is still running, threads can't be aborted and are left running.
+    pub fn abort(&mut self) {
+        if let Self::Task(Some(handle)) = self {
+            handle.abort();
+        }
+    }
 }
 
 pub enum WithBind<'a, T> {
@@ -7,7 +7,7 @@ use crate::{
     util::{JoinHandleWrapper, WithBind},
 };
 use async_trait::async_trait;
-use axum::{response::Response, Router};
+use axum::{body::Body, http::Request, response::Response, Router};
 use parking_lot::Mutex;
 use ws_derive::LogicalModule;
 
@@ -50,7 +50,7 @@ impl HttpHandler for WorkerHttpHandler {
         WithBind::MutexGuardOpt(guard)
     }
 
-    async fn handle_request(&self, _route: &str, _http_text: String) -> Response {
+    async fn handle_request(&self, _route: &str, _req: Request<Body>) -> Response {
         // tracing::debug!("handle_request {}", route);
         unreachable!("handle_request deprecated");
     }